@@ -1,17 +1,52 @@
 
-use rand_core::OsRng; // requires 'getrandom' feature
+use rand_core::{CryptoRng, OsRng, RngCore}; // OsRng requires 'getrandom' feature
 use chacha20poly1305::{
     ChaCha20Poly1305,
-    aead::generic_array::{GenericArray, typenum::Unsigned},
-    aead::{Aead, AeadCore, KeyInit}
+    XChaCha20Poly1305,
+    aead::generic_array::{GenericArray, typenum::{Unsigned, U32}},
+    aead::{Aead, AeadCore, KeyInit, KeySizeUser, Nonce, Payload}
 };
 pub use k256;
-use k256::{ecdh::EphemeralSecret, EncodedPoint};
+use k256::{ecdh::EphemeralSecret, elliptic_curve::sec1::ToEncodedPoint, EncodedPoint};
+use hkdf::Hkdf;
+use sha2::Sha256;
+pub use zeroize;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(debug_assertions)]
+use sha2::Digest;
+#[cfg(debug_assertions)]
+use std::{collections::{HashSet, VecDeque}, sync::{Mutex, OnceLock}};
+#[cfg(feature = "aes")]
+use aes_gcm::Aes256Gcm;
+
+// Version byte at the start of every ciphertext: version || algorithm || nonce || ciphertext.
+// Version 1 ciphertexts are encrypted under keys from derive_key; older unversioned ones are rejected.
+pub const FRAME_VERSION: u8 = 1;
+// Version byte for encrypt_x frames (192-bit nonce), so they can't be mistaken for 96-bit nonce frames.
+pub const FRAME_VERSION_X: u8 = 2;
+// Version byte for encrypt_chunked streams.
+pub const FRAME_VERSION_CHUNKED: u8 = 3;
+// Largest chunk_size a chunked stream may declare, so a forged header can't make decrypt_chunked
+// allocate an arbitrarily large buffer.
+pub const MAX_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+// Random id written into every chunked stream's header and bound into each chunk's AAD.
+const STREAM_ID_LEN: usize = 16;
 
 
 pub fn generate_ecdh_keys() -> (EphemeralSecret, k256::PublicKey) {
+    generate_ecdh_keys_from_rng(OsRng)
+}
 
-    let ecdh_private_key = EphemeralSecret::random(&mut OsRng);
+// Same as generate_ecdh_keys, from a caller's RNG. Tests seed one (e.g. ChaChaRng::seed_from_u64)
+// for reproducible keypairs and shared secrets; anything else should use generate_ecdh_keys.
+pub fn generate_ecdh_keys_from_rng(mut rng: impl RngCore + CryptoRng) -> (EphemeralSecret, k256::PublicKey) {
+
+    let ecdh_private_key = EphemeralSecret::random(&mut rng);
     let ecdh_public_key= k256::PublicKey::from_sec1_bytes(
         EncodedPoint::from(ecdh_private_key.public_key()).as_ref()
     ).expect("alice's public key is invalid");
@@ -19,30 +54,599 @@ pub fn generate_ecdh_keys() -> (EphemeralSecret, k256::PublicKey) {
     (ecdh_private_key, ecdh_public_key)
 }
 
+// Long-lived keypair, for nodes that need to recompute the same shared secret after a restart
+// (e.g. re-deriving the SHARE_KEY secret when a peer reconnects). Persist it with save_static_secret.
+pub fn generate_static_ecdh_keys() -> (k256::SecretKey, k256::PublicKey) {
+    let secret_key = k256::SecretKey::random(&mut OsRng);
+    let public_key = secret_key.public_key();
+    (secret_key, public_key)
+}
+
+pub fn static_secret_to_bytes(secret_key: &k256::SecretKey) -> Zeroizing<Vec<u8>> {
+    Zeroizing::new(secret_key.to_bytes().to_vec())
+}
+
+pub fn static_secret_from_bytes(bytes: &[u8]) -> Result<k256::SecretKey, EcdhError> {
+    // from_slice would also zero-pad shorter slices, so insist on the exact length we persisted
+    let bytes = k256::FieldBytes::from_exact_iter(bytes.iter().copied())
+        .ok_or(EcdhError::InvalidSecretKey)?;
+    k256::SecretKey::from_bytes(&bytes).map_err(|_| EcdhError::InvalidSecretKey)
+}
+
+pub fn save_static_secret(path: impl AsRef<Path>, secret_key: &k256::SecretKey) -> std::io::Result<()> {
+    std::fs::write(path, static_secret_to_bytes(secret_key))
+}
+
+pub fn load_static_secret(path: impl AsRef<Path>) -> Result<k256::SecretKey, EcdhError> {
+    let bytes = Zeroizing::new(std::fs::read(path)?);
+    static_secret_from_bytes(&bytes)
+}
+
+// Parse a peer's SEC1 encoded public key (e.g. from an AVS_PUBLIC_KEY record), rejecting malformed
+// encodings and points off the curve instead of panicking.
+pub fn public_key_from_sec1(bytes: &[u8]) -> Result<k256::PublicKey, EcdhError> {
+    let public_key = k256::PublicKey::from_sec1_bytes(bytes).map_err(|_| EcdhError::InvalidPublicKey)?;
+    validate_public_key(&public_key)?;
+    Ok(public_key)
+}
+
+// Public keys are exchanged (e.g. in AVS_PUBLIC_KEY records) as compressed SEC1 points: a 0x02/0x03 tag
+// and the x coordinate, 33 bytes instead of the 65 of an uncompressed point.
+pub const COMPRESSED_PUBLIC_KEY_LEN: usize = 33;
+
+pub fn public_key_to_sec1_compressed(public_key: &k256::PublicKey) -> Vec<u8> {
+    public_key.to_encoded_point(true).as_bytes().to_vec()
+}
+
+// public_key_from_sec1, insisting on the compressed encoding public_key_to_sec1_compressed puts,
+// so a peer that puts uncompressed points is caught here rather than being parsed one way and stored another.
+pub fn public_key_from_sec1_compressed(bytes: &[u8]) -> Result<k256::PublicKey, EcdhError> {
+    if bytes.len() != COMPRESSED_PUBLIC_KEY_LEN || !matches!(bytes[0], 0x02 | 0x03) {
+        return Err(EcdhError::InvalidPublicKey);
+    }
+    public_key_from_sec1(bytes)
+}
+
+// secp256k1 has cofactor 1, so the identity is the only degenerate point: a shared secret with it
+// is predictable. k256::PublicKey can't normally hold it, this guards keys built some other way.
+pub fn validate_public_key(public_key: &k256::PublicKey) -> Result<(), EcdhError> {
+    if public_key.to_projective() == k256::ProjectivePoint::IDENTITY {
+        return Err(EcdhError::InvalidPublicKey);
+    }
+    Ok(())
+}
+
+// A 32 byte AEAD key, the only kind of key encrypt/decrypt accept, so a raw ECDH secret (or a slice of
+// the wrong length) can't be passed in by mistake. Wiped from memory on drop.
+pub struct SharedSecret([u8; 32]);
+
+impl SharedSecret {
+    // ECDH followed by derive_key, without the raw secret leaving this function
+    pub fn derive(ecdh_private_key: &EphemeralSecret, public_key: &k256::PublicKey, info: &[u8]) -> Self {
+        derive_key(&compute_shared_secret(ecdh_private_key, public_key), info)
+    }
+
+    pub fn as_key(&self) -> &GenericArray<u8, U32> {
+        GenericArray::from_slice(&self.0)
+    }
+}
+
+// For keys from another KDF, e.g. a password hash
+impl From<[u8; 32]> for SharedSecret {
+    fn from(key: [u8; 32]) -> Self {
+        SharedSecret(key)
+    }
+}
+
+impl std::fmt::Debug for SharedSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SharedSecret(..)")
+    }
+}
+
+impl Zeroize for SharedSecret {
+    fn zeroize(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl Drop for SharedSecret {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+impl ZeroizeOnDrop for SharedSecret {}
+
+// SharedSecrets derived for one `info` context, per peer, so the ECDH scalar multiplication runs once
+// per peer instead of on every message. `K` is whatever names the peer (a peer id, or its encoded
+// public key). An entry is derived again if the peer's public key changes, so a rotated key is never
+// paired with the secret of the old one.
+pub struct SharedSecretCache<K> {
+    info: Vec<u8>,
+    secrets: HashMap<K, (k256::PublicKey, SharedSecret)>,
+    derivations: u64,
+}
+
+impl<K: Eq + Hash> SharedSecretCache<K> {
+
+    pub fn new(info: &[u8]) -> Self {
+        SharedSecretCache { info: info.to_vec(), secrets: HashMap::new(), derivations: 0 }
+    }
+
+    // The cached secret for `peer`, or SharedSecret::derive when there's none yet for `public_key`
+    pub fn get_or_derive(
+        &mut self,
+        peer: K,
+        ecdh_private_key: &EphemeralSecret,
+        public_key: &k256::PublicKey
+    ) -> &SharedSecret {
+        let derivations = &mut self.derivations;
+        let info = &self.info;
+        let entry = self.secrets.entry(peer)
+            .and_modify(|(cached_public_key, secret)| {
+                if cached_public_key != public_key {
+                    *derivations += 1;
+                    *cached_public_key = *public_key;
+                    *secret = SharedSecret::derive(ecdh_private_key, public_key, info);
+                }
+            })
+            .or_insert_with(|| {
+                *derivations += 1;
+                (*public_key, SharedSecret::derive(ecdh_private_key, public_key, info))
+            });
+        &entry.1
+    }
+
+    // Forgets the peer's secret (wiped on drop), e.g. once it leaves. Returns false if none was cached.
+    pub fn remove(&mut self, peer: &K) -> bool {
+        self.secrets.remove(peer).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.secrets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.secrets.is_empty()
+    }
+
+    // How many times ECDH has run, cache misses and key changes included
+    pub fn derivations(&self) -> u64 {
+        self.derivations
+    }
+}
+
+// Raw ECDH secrets aren't uniformly distributed, so run them through HKDF-SHA256 before using them as
+// an AEAD key. `info` is a context string (e.g. b"fhe-position-v1"): different contexts give unrelated keys.
+pub fn derive_key(shared_secret: &[u8], info: &[u8]) -> SharedSecret {
+    let hkdf = Hkdf::<Sha256>::new(None, shared_secret);
+    let mut key = SharedSecret([0u8; 32]);
+    hkdf.expand(info, &mut key.0)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+// The raw secret is wiped from memory when the returned buffer is dropped.
+// (EphemeralSecret and k256's SharedSecret already zeroize themselves on drop.)
 pub fn compute_shared_secret(
     ecdh_private_key: &EphemeralSecret,
     public_key: &k256::PublicKey
-) -> Vec<u8> {
+) -> Zeroizing<Vec<u8>> {
     let shared_secret = ecdh_private_key.diffie_hellman(public_key);
-    let shared_secret_key = shared_secret.raw_secret_bytes().to_vec();
+    let shared_secret_key = Zeroizing::new(shared_secret.raw_secret_bytes().to_vec());
     shared_secret_key
 }
 
-pub fn encrypt(cleartext: &[u8], shared_secret: &[u8]) -> Vec<u8> {
-    let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(shared_secret));
-    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
-    let mut obsf = cipher.encrypt(&nonce, cleartext).unwrap();
-    obsf.splice(..0, nonce.iter().copied());
-    obsf
+pub fn compute_shared_secret_static(
+    secret_key: &k256::SecretKey,
+    public_key: &k256::PublicKey
+) -> Zeroizing<Vec<u8>> {
+    let shared_secret = k256::ecdh::diffie_hellman(secret_key.to_nonzero_scalar(), public_key.as_affine());
+    Zeroizing::new(shared_secret.raw_secret_bytes().to_vec())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EcdhError {
+    // nothing to encrypt or decrypt
+    EmptyInput,
+    // ciphertext is shorter than the nonce prepended to it
+    TruncatedNonce,
+    // ciphertext has its nonce, but is shorter than the authentication tag after it (e.g. a cut-off record)
+    Truncated,
+    // ciphertext was framed with another (or no) version byte
+    UnsupportedVersion(u8),
+    // ciphertext is tagged with an unknown algorithm, or one compiled out (e.g. AES without the `aes` feature)
+    UnsupportedAlgorithm(u8),
+    // authentication failed: wrong shared secret, or a corrupted/tampered ciphertext
+    Aead,
+    // chunk size is zero or above MAX_CHUNK_SIZE, or a chunk is larger than the stream's chunk size
+    InvalidChunkSize,
+    // reading or writing a chunked stream failed (including a stream that ends early)
+    Io(std::io::ErrorKind),
+    // persisted static secret key isn't a valid 32 byte secp256k1 scalar
+    InvalidSecretKey,
+    // peer public key isn't a valid SEC1 encoded secp256k1 point, or is the identity
+    InvalidPublicKey,
+    // a NonceStrategy::Counter has used every nonce
+    NonceExhausted,
 }
 
-pub fn decrypt(obsf: &[u8], shared_secret: &[u8]) -> Vec<u8> {
-    type NonceSize = <ChaCha20Poly1305 as AeadCore>::NonceSize;
-    let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(shared_secret));
-    let (nonce, ciphertext) = obsf.split_at(NonceSize::to_usize());
+impl From<std::io::Error> for EcdhError {
+    fn from(e: std::io::Error) -> Self {
+        EcdhError::Io(e.kind())
+    }
+}
+
+impl std::fmt::Display for EcdhError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EcdhError::EmptyInput => write!(f, "empty input"),
+            EcdhError::TruncatedNonce => write!(f, "ciphertext is shorter than its nonce"),
+            EcdhError::Truncated => write!(f, "ciphertext is shorter than its nonce and authentication tag"),
+            EcdhError::UnsupportedVersion(version) => write!(f, "unsupported ciphertext version {version}"),
+            EcdhError::UnsupportedAlgorithm(tag) => write!(f, "unsupported AEAD algorithm tag {tag}"),
+            EcdhError::Aead => write!(f, "AEAD decryption failed (wrong key or corrupted ciphertext)"),
+            EcdhError::InvalidChunkSize => write!(f, "invalid chunk size"),
+            EcdhError::Io(kind) => write!(f, "chunked stream I/O failed: {kind}"),
+            EcdhError::InvalidSecretKey => write!(f, "invalid static secret key"),
+            EcdhError::InvalidPublicKey => write!(f, "invalid public key (malformed SEC1 encoding or identity point)"),
+            EcdhError::NonceExhausted => write!(f, "nonce counter exhausted, rotate the key"),
+        }
+    }
+}
+
+impl std::error::Error for EcdhError {}
+
+// AEAD used by encrypt. Its tag is written after the version byte so decrypt can dispatch on it.
+// Aes256Gcm is faster on hardware with AES-NI, and needs the `aes` feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AeadAlgorithm {
+    ChaCha20Poly1305,
+    #[cfg(feature = "aes")]
+    Aes256Gcm,
+}
+
+impl AeadAlgorithm {
+    pub const CHACHA20_POLY1305_TAG: u8 = 1;
+    // reserved even when the `aes` feature is off, so decrypt can report AES ciphertexts as unsupported
+    pub const AES_256_GCM_TAG: u8 = 2;
+
+    pub fn tag(&self) -> u8 {
+        match self {
+            AeadAlgorithm::ChaCha20Poly1305 => Self::CHACHA20_POLY1305_TAG,
+            #[cfg(feature = "aes")]
+            AeadAlgorithm::Aes256Gcm => Self::AES_256_GCM_TAG,
+        }
+    }
+
+    pub fn from_tag(tag: u8) -> Result<Self, EcdhError> {
+        match tag {
+            Self::CHACHA20_POLY1305_TAG => Ok(AeadAlgorithm::ChaCha20Poly1305),
+            #[cfg(feature = "aes")]
+            Self::AES_256_GCM_TAG => Ok(AeadAlgorithm::Aes256Gcm),
+            _ => Err(EcdhError::UnsupportedAlgorithm(tag)),
+        }
+    }
+}
+
+// How encrypt picks nonces. Random nonces are safe with any number of senders sharing a key.
+// Counter gives sequential nonces (0, 1, 2, ... big-endian in the nonce's last 8 bytes) that can't collide,
+// but only while a single sender encrypts under the key and never restarts the count.
+#[derive(Debug, Default)]
+pub enum NonceStrategy {
+    #[default]
+    Random,
+    Counter(AtomicU64),
+}
+
+impl NonceStrategy {
+    pub fn counter() -> Self {
+        NonceStrategy::Counter(AtomicU64::new(0))
+    }
+
+    fn next_nonce<C: AeadCore>(&self) -> Result<Nonce<C>, EcdhError> {
+        match self {
+            NonceStrategy::Random => Ok(C::generate_nonce(&mut OsRng)),
+            NonceStrategy::Counter(counter) => {
+                let count = counter.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| count.checked_add(1))
+                    .map_err(|_| EcdhError::NonceExhausted)?;
+                let mut nonce = Nonce::<C>::default();
+                let counter_start = nonce.len() - 8;
+                nonce[counter_start..].copy_from_slice(&count.to_be_bytes());
+                Ok(nonce)
+            }
+        }
+    }
+}
+
+// Debug builds remember the last MAX_SEEN_NONCES (key, nonce) pairs they encrypt with and panic on a repeat,
+// to catch logic bugs like a restarted counter. Pairs are kept as SHA-256 digests and the oldest is forgotten
+// past the cap, so a long-running debug node holds at most ~1 MiB. Release builds compile the check out.
+#[cfg(debug_assertions)]
+const MAX_SEEN_NONCES: usize = 1 << 14;
+
+#[cfg(debug_assertions)]
+#[derive(Default)]
+struct SeenNonces {
+    digests: HashSet<[u8; 32]>,
+    // insertion order, oldest first
+    order: VecDeque<[u8; 32]>,
+}
+
+#[cfg(debug_assertions)]
+impl SeenNonces {
+    // false if `digest` is among the last MAX_SEEN_NONCES inserted
+    fn insert(&mut self, digest: [u8; 32]) -> bool {
+        if !self.digests.insert(digest) {
+            return false;
+        }
+        self.order.push_back(digest);
+        if self.order.len() > MAX_SEEN_NONCES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.digests.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(debug_assertions)]
+fn check_nonce_reuse(key: &SharedSecret, nonce: &[u8]) {
+    static SEEN_NONCES: OnceLock<Mutex<SeenNonces>> = OnceLock::new();
+    let digest: [u8; 32] = Sha256::new().chain_update(key.as_key()).chain_update(nonce).finalize().into();
+    let fresh = SEEN_NONCES.get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(digest);
+    assert!(fresh, "AEAD nonce reuse: the same (key, nonce) pair was used twice");
+}
+
+#[cfg(not(debug_assertions))]
+fn check_nonce_reuse(_key: &SharedSecret, _nonce: &[u8]) {}
+
+fn cipher<C: KeyInit + KeySizeUser<KeySize = U32>>(key: &SharedSecret) -> C {
+    C::new(key.as_key())
+}
+
+// header || nonce || ciphertext, with the nonce length taken from the AEAD.
+// The header is authenticated along with `aad`, so relabelling a frame's version or algorithm fails to decrypt.
+fn seal<C: Aead + AeadCore + KeyInit + KeySizeUser<KeySize = U32>>(
+    header: &[u8],
+    cleartext: &[u8],
+    key: &SharedSecret,
+    aad: &[u8],
+    nonces: &NonceStrategy
+) -> Result<Vec<u8>, EcdhError> {
+    if cleartext.is_empty() {
+        return Err(EcdhError::EmptyInput);
+    }
+    let cipher = cipher::<C>(key);
+    let nonce = nonces.next_nonce::<C>()?;
+    check_nonce_reuse(key, &nonce);
+    let aad = [header, aad].concat();
+    let mut obsf = cipher.encrypt(&nonce, Payload { msg: cleartext, aad: &aad })
+        .map_err(|_| EcdhError::Aead)?;
+    obsf.splice(..0, header.iter().chain(nonce.iter()).copied());
+    Ok(obsf)
+}
+
+// Strips the version byte, checking it matches the expected frame
+fn frame_body(obsf: &[u8], version: u8) -> Result<&[u8], EcdhError> {
+    let (frame_version, obsf) = obsf.split_first().ok_or(EcdhError::EmptyInput)?;
+    if *frame_version != version {
+        return Err(EcdhError::UnsupportedVersion(*frame_version));
+    }
+    Ok(obsf)
+}
+
+// nonce || ciphertext, with the header already stripped (and passed back in, to authenticate it as seal did)
+fn open<C: Aead + AeadCore + KeyInit + KeySizeUser<KeySize = U32>>(
+    header: &[u8],
+    obsf: &[u8],
+    key: &SharedSecret,
+    aad: &[u8]
+) -> Result<Vec<u8>, EcdhError> {
+    let nonce_size = <C as AeadCore>::NonceSize::to_usize();
+    if obsf.len() < nonce_size {
+        return Err(EcdhError::TruncatedNonce);
+    }
+    // the AEAD would also reject it, but as a failed authentication, which reads like a wrong key
+    if obsf.len() < nonce_size + <C as AeadCore>::TagSize::to_usize() {
+        return Err(EcdhError::Truncated);
+    }
+    let cipher = cipher::<C>(key);
+    let (nonce, ciphertext) = obsf.split_at(nonce_size);
     let nonce = GenericArray::from_slice(nonce);
-    let plaintext: Vec<u8> = cipher.decrypt(nonce, ciphertext).unwrap();
-    plaintext
+    let aad = [header, aad].concat();
+    cipher.decrypt(nonce, Payload { msg: ciphertext, aad: &aad }).map_err(|_| EcdhError::Aead)
+}
+
+// `key` should come from derive_key (or another KDF), not a raw ECDH shared secret.
+// `aad` is authenticated but not encrypted (e.g. the Kademlia record key the ciphertext is stored under),
+// and decrypt must be given the same aad, so a ciphertext can't be replayed in another context.
+pub fn encrypt(cleartext: &[u8], key: &SharedSecret, aad: &[u8], algorithm: AeadAlgorithm) -> Result<Vec<u8>, EcdhError> {
+    encrypt_with_nonces(cleartext, key, aad, algorithm, &NonceStrategy::Random)
+}
+
+// encrypt, with nonces from `nonces`. A NonceStrategy::Counter must be kept for as long as the key is used.
+pub fn encrypt_with_nonces(
+    cleartext: &[u8],
+    key: &SharedSecret,
+    aad: &[u8],
+    algorithm: AeadAlgorithm,
+    nonces: &NonceStrategy
+) -> Result<Vec<u8>, EcdhError> {
+    let header = [FRAME_VERSION, algorithm.tag()];
+    match algorithm {
+        AeadAlgorithm::ChaCha20Poly1305 => seal::<ChaCha20Poly1305>(&header, cleartext, key, aad, nonces),
+        #[cfg(feature = "aes")]
+        AeadAlgorithm::Aes256Gcm => seal::<Aes256Gcm>(&header, cleartext, key, aad, nonces),
+    }
+}
+
+// The algorithm is read from the ciphertext's tag byte.
+pub fn decrypt(obsf: &[u8], key: &SharedSecret, aad: &[u8]) -> Result<Vec<u8>, EcdhError> {
+    let obsf = frame_body(obsf, FRAME_VERSION)?;
+    let (tag, obsf) = obsf.split_first().ok_or(EcdhError::TruncatedNonce)?;
+    let header = [FRAME_VERSION, *tag];
+    match AeadAlgorithm::from_tag(*tag)? {
+        AeadAlgorithm::ChaCha20Poly1305 => open::<ChaCha20Poly1305>(&header, obsf, key, aad),
+        #[cfg(feature = "aes")]
+        AeadAlgorithm::Aes256Gcm => open::<Aes256Gcm>(&header, obsf, key, aad),
+    }
+}
+
+// XChaCha20Poly1305 variant with a 192-bit random nonce, for large or frequently re-encrypted payloads
+// (e.g. FHE ciphertexts) where 96-bit random nonces risk a collision over a long session.
+pub fn encrypt_x(cleartext: &[u8], key: &SharedSecret, aad: &[u8]) -> Result<Vec<u8>, EcdhError> {
+    seal::<XChaCha20Poly1305>(&[FRAME_VERSION_X], cleartext, key, aad, &NonceStrategy::Random)
+}
+
+pub fn decrypt_x(obsf: &[u8], key: &SharedSecret, aad: &[u8]) -> Result<Vec<u8>, EcdhError> {
+    open::<XChaCha20Poly1305>(&[FRAME_VERSION_X], frame_body(obsf, FRAME_VERSION_X)?, key, aad)
+}
+
+// Streams `reader` into `writer` in chunks of `chunk_size` bytes, so multi-megabyte payloads (serialized
+// FHE keys and ciphertexts) don't need to be held in memory twice.
+// Layout: version || stream id || chunk_size (u32 BE) || chunks, each chunk: nonce || last flag || length (u32 BE) || ciphertext.
+// Every chunk has its own XChaCha20Poly1305 nonce, and the stream id, chunk size, its sequence number and
+// last flag are authenticated as AAD, so reordered, dropped, truncated or spliced-in chunks from another
+// stream under the same key fail to decrypt.
+pub fn encrypt_chunked(
+    mut reader: impl Read,
+    mut writer: impl Write,
+    key: &SharedSecret,
+    chunk_size: usize
+) -> Result<(), EcdhError> {
+    if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+        return Err(EcdhError::InvalidChunkSize);
+    }
+    let chunk_size_u32 = chunk_size as u32;
+    let cipher = cipher::<XChaCha20Poly1305>(key);
+
+    // read one chunk ahead, to know whether the current chunk is the last one
+    let mut chunk = vec![0u8; chunk_size];
+    let mut next_chunk = vec![0u8; chunk_size];
+    let mut len = read_chunk(&mut reader, &mut chunk)?;
+    if len == 0 {
+        return Err(EcdhError::EmptyInput);
+    }
+
+    let mut stream_id = [0u8; STREAM_ID_LEN];
+    OsRng.fill_bytes(&mut stream_id);
+    writer.write_all(&[FRAME_VERSION_CHUNKED])?;
+    writer.write_all(&stream_id)?;
+    writer.write_all(&chunk_size_u32.to_be_bytes())?;
+
+    for sequence in 0u64.. {
+        let next_len = if len == chunk_size { read_chunk(&mut reader, &mut next_chunk)? } else { 0 };
+        let last = next_len == 0;
+
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        check_nonce_reuse(key, &nonce);
+        let aad = chunk_aad(&stream_id, chunk_size_u32, sequence, last);
+        let ciphertext = cipher.encrypt(&nonce, Payload { msg: &chunk[..len], aad: &aad })
+            .map_err(|_| EcdhError::Aead)?;
+
+        writer.write_all(&nonce)?;
+        writer.write_all(&[last as u8])?;
+        writer.write_all(&(ciphertext.len() as u32).to_be_bytes())?;
+        writer.write_all(&ciphertext)?;
+
+        if last {
+            break;
+        }
+        std::mem::swap(&mut chunk, &mut next_chunk);
+        len = next_len;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn decrypt_chunked(
+    mut reader: impl Read,
+    mut writer: impl Write,
+    key: &SharedSecret
+) -> Result<(), EcdhError> {
+    type NonceSize = <XChaCha20Poly1305 as AeadCore>::NonceSize;
+    type TagSize = <XChaCha20Poly1305 as AeadCore>::TagSize;
+
+    let mut version = [0u8; 1];
+    read_exact_or(&mut reader, &mut version, EcdhError::EmptyInput)?;
+    if version[0] != FRAME_VERSION_CHUNKED {
+        return Err(EcdhError::UnsupportedVersion(version[0]));
+    }
+    let mut stream_id = [0u8; STREAM_ID_LEN];
+    reader.read_exact(&mut stream_id)?;
+    let mut chunk_size = [0u8; 4];
+    reader.read_exact(&mut chunk_size)?;
+    let chunk_size_u32 = u32::from_be_bytes(chunk_size);
+    let chunk_size = chunk_size_u32 as usize;
+    if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+        return Err(EcdhError::InvalidChunkSize);
+    }
+    let cipher = cipher::<XChaCha20Poly1305>(key);
+
+    let mut ciphertext = Vec::with_capacity(chunk_size + TagSize::to_usize());
+    for sequence in 0u64.. {
+        let mut nonce = GenericArray::<u8, NonceSize>::default();
+        read_exact_or(&mut reader, &mut nonce, EcdhError::TruncatedNonce)?;
+        let mut header = [0u8; 5];
+        reader.read_exact(&mut header)?;
+        let last = match header[0] {
+            0 => false,
+            1 => true,
+            _ => return Err(EcdhError::Aead),
+        };
+        let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        if len > chunk_size + TagSize::to_usize() {
+            return Err(EcdhError::InvalidChunkSize);
+        }
+
+        ciphertext.resize(len, 0);
+        reader.read_exact(&mut ciphertext)?;
+        let aad = chunk_aad(&stream_id, chunk_size_u32, sequence, last);
+        let cleartext = cipher.decrypt(&nonce, Payload { msg: &ciphertext, aad: &aad })
+            .map_err(|_| EcdhError::Aead)?;
+        writer.write_all(&cleartext)?;
+
+        if last {
+            break;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn chunk_aad(stream_id: &[u8; STREAM_ID_LEN], chunk_size: u32, sequence: u64, last: bool) -> [u8; STREAM_ID_LEN + 13] {
+    let mut aad = [0u8; STREAM_ID_LEN + 13];
+    aad[..STREAM_ID_LEN].copy_from_slice(stream_id);
+    aad[STREAM_ID_LEN..STREAM_ID_LEN + 4].copy_from_slice(&chunk_size.to_be_bytes());
+    aad[STREAM_ID_LEN + 4..STREAM_ID_LEN + 12].copy_from_slice(&sequence.to_be_bytes());
+    aad[STREAM_ID_LEN + 12] = last as u8;
+    aad
+}
+
+// Fills `buf` unless the reader hits EOF first, returning the number of bytes read.
+fn read_chunk(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+fn read_exact_or(reader: &mut impl Read, buf: &mut [u8], on_eof: EcdhError) -> Result<(), EcdhError> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => on_eof,
+        kind => EcdhError::Io(kind),
+    })
 }
 
 pub fn add(left: u64, right: u64) -> u64 {
@@ -53,9 +657,420 @@ pub fn add(left: u64, right: u64) -> u64 {
 mod tests {
     use super::*;
 
+    fn key(byte: u8) -> SharedSecret {
+        SharedSecret::from([byte; 32])
+    }
+
     #[test]
     fn it_works() {
         let result = add(2, 2);
         assert_eq!(result, 4);
     }
+
+    #[test]
+    fn decrypt_rejects_corrupted_ciphertexts() {
+        let obsf = encrypt(b"position", &key(1), b"", AeadAlgorithm::ChaCha20Poly1305).unwrap();
+        assert_eq!(decrypt(&obsf, &key(1), b"").unwrap(), b"position");
+
+        let mut flipped = obsf.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert_eq!(decrypt(&flipped, &key(1), b""), Err(EcdhError::Aead));
+        assert_eq!(decrypt(&obsf, &key(2), b""), Err(EcdhError::Aead));
+        assert_eq!(decrypt(&obsf[..4], &key(1), b""), Err(EcdhError::TruncatedNonce));
+        // version + algorithm + 12 byte nonce, then up to the whole 16 byte tag
+        assert_eq!(decrypt(&[], &key(1), b""), Err(EcdhError::EmptyInput));
+        assert_eq!(decrypt(&obsf[..14], &key(1), b""), Err(EcdhError::Truncated));
+        assert_eq!(decrypt(&obsf[..14 + 15], &key(1), b""), Err(EcdhError::Truncated));
+        assert_eq!(decrypt(&obsf[..14 + 16], &key(1), b""), Err(EcdhError::Aead));
+        let mut unversioned = obsf.clone();
+        unversioned[0] = 0;
+        assert_eq!(decrypt(&unversioned, &key(1), b""), Err(EcdhError::UnsupportedVersion(0)));
+        assert_eq!(decrypt(&[], &key(1), b""), Err(EcdhError::EmptyInput));
+        assert_eq!(encrypt(b"", &key(1), b"", AeadAlgorithm::ChaCha20Poly1305), Err(EcdhError::EmptyInput));
+    }
+
+    #[test]
+    fn derive_key_separates_contexts() {
+        let shared_secret = [0x0b_u8; 32];
+        let position_key = derive_key(&shared_secret, b"fhe-position-v1");
+
+        // HKDF-SHA256, no salt
+        assert_eq!(position_key.as_key().as_slice(), [
+            0xc2, 0xae, 0x60, 0xf6, 0xcb, 0x0c, 0x15, 0x7c, 0xa4, 0x97, 0x0e, 0x05, 0xdd, 0x64, 0x77, 0xbd,
+            0xc0, 0x1b, 0x58, 0x2d, 0xaa, 0x73, 0xa1, 0x09, 0xab, 0x3c, 0x3b, 0x72, 0xfc, 0x58, 0x09, 0xcb,
+        ]);
+        assert_ne!(position_key.as_key(), derive_key(&shared_secret, b"fhe-private-key-v1").as_key());
+
+        let obsf = encrypt(b"position", &position_key, b"", AeadAlgorithm::ChaCha20Poly1305).unwrap();
+        assert_eq!(obsf[0], FRAME_VERSION);
+        assert_eq!(decrypt(&obsf, &derive_key(&shared_secret, b"fhe-private-key-v1"), b""), Err(EcdhError::Aead));
+    }
+
+    #[test]
+    fn decrypt_rejects_mismatched_aad() {
+        let obsf = encrypt(b"position", &key(1), b"POSITION_alice", AeadAlgorithm::ChaCha20Poly1305).unwrap();
+        assert_eq!(decrypt(&obsf, &key(1), b"POSITION_alice").unwrap(), b"position");
+        assert_eq!(decrypt(&obsf, &key(1), b"POSITION_bob"), Err(EcdhError::Aead));
+        assert_eq!(decrypt(&obsf, &key(1), b""), Err(EcdhError::Aead));
+    }
+
+    #[test]
+    fn encrypt_chunked_round_trips_5mb() {
+        let cleartext: Vec<u8> = (0..5 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let mut obsf = Vec::new();
+        encrypt_chunked(cleartext.as_slice(), &mut obsf, &key(1), 64 * 1024).unwrap();
+        assert_eq!(obsf[0], FRAME_VERSION_CHUNKED);
+
+        let mut decrypted = Vec::new();
+        decrypt_chunked(obsf.as_slice(), &mut decrypted, &key(1)).unwrap();
+        assert_eq!(decrypted, cleartext);
+
+        // payload that isn't a multiple of the chunk size
+        let mut obsf = Vec::new();
+        encrypt_chunked(&cleartext[..100_001], &mut obsf, &key(1), 64 * 1024).unwrap();
+        let mut decrypted = Vec::new();
+        decrypt_chunked(obsf.as_slice(), &mut decrypted, &key(1)).unwrap();
+        assert_eq!(decrypted, &cleartext[..100_001]);
+    }
+
+    #[test]
+    fn decrypt_chunked_rejects_swapped_and_truncated_chunks() {
+        let chunk_size = 1024;
+        let cleartext = vec![7u8; chunk_size * 3];
+        let mut obsf = Vec::new();
+        encrypt_chunked(cleartext.as_slice(), &mut obsf, &key(1), chunk_size).unwrap();
+
+        // version || stream id || chunk_size, then 3 full chunks of nonce || last flag || length || ciphertext
+        let header_len = 1 + 16 + 4;
+        let frame_len = 24 + 1 + 4 + chunk_size + 16;
+        assert_eq!(obsf.len(), header_len + 3 * frame_len);
+
+        let mut swapped = obsf.clone();
+        let (first, rest) = swapped[header_len..].split_at_mut(frame_len);
+        first.swap_with_slice(&mut rest[..frame_len]);
+        assert_eq!(decrypt_chunked(swapped.as_slice(), &mut Vec::new(), &key(1)), Err(EcdhError::Aead));
+
+        // dropping the last chunk leaves a stream that never finishes
+        let truncated = &obsf[..header_len + 2 * frame_len];
+        assert_eq!(decrypt_chunked(truncated, &mut Vec::new(), &key(1)), Err(EcdhError::TruncatedNonce));
+
+        assert_eq!(decrypt_chunked(obsf.as_slice(), &mut Vec::new(), &key(2)), Err(EcdhError::Aead));
+        assert_eq!(encrypt_chunked(cleartext.as_slice(), &mut Vec::new(), &key(1), 0), Err(EcdhError::InvalidChunkSize));
+        assert_eq!(encrypt_chunked(&[][..], &mut Vec::new(), &key(1), chunk_size), Err(EcdhError::EmptyInput));
+    }
+
+    #[test]
+    fn decrypt_chunked_rejects_chunks_spliced_from_another_stream() {
+        let chunk_size = 1024;
+        let mut first = Vec::new();
+        encrypt_chunked(vec![1u8; chunk_size * 2].as_slice(), &mut first, &key(1), chunk_size).unwrap();
+        let mut second = Vec::new();
+        encrypt_chunked(vec![2u8; chunk_size * 2].as_slice(), &mut second, &key(1), chunk_size).unwrap();
+
+        // same key, same sequence number and last flag: only the stream id tells the two last chunks apart
+        let header_len = 1 + 16 + 4;
+        let frame_len = 24 + 1 + 4 + chunk_size + 16;
+        let mut spliced = first.clone();
+        spliced[header_len + frame_len..].copy_from_slice(&second[header_len + frame_len..]);
+        assert_eq!(decrypt_chunked(spliced.as_slice(), &mut Vec::new(), &key(1)), Err(EcdhError::Aead));
+    }
+
+    #[test]
+    fn decrypt_chunked_rejects_out_of_range_chunk_sizes() {
+        let mut obsf = Vec::new();
+        encrypt_chunked(vec![7u8; 100].as_slice(), &mut obsf, &key(1), 1024).unwrap();
+
+        let chunk_size_at = 1 + 16;
+        for chunk_size in [0, MAX_CHUNK_SIZE as u32 + 1, u32::MAX] {
+            let mut forged = obsf.clone();
+            forged[chunk_size_at..chunk_size_at + 4].copy_from_slice(&chunk_size.to_be_bytes());
+            assert_eq!(decrypt_chunked(forged.as_slice(), &mut Vec::new(), &key(1)), Err(EcdhError::InvalidChunkSize));
+        }
+        // within range, but not the size the stream was encrypted with
+        let mut forged = obsf.clone();
+        forged[chunk_size_at..chunk_size_at + 4].copy_from_slice(&2048u32.to_be_bytes());
+        assert_eq!(decrypt_chunked(forged.as_slice(), &mut Vec::new(), &key(1)), Err(EcdhError::Aead));
+
+        let oversized = encrypt_chunked(&[7u8; 8][..], &mut Vec::new(), &key(1), MAX_CHUNK_SIZE + 1);
+        assert_eq!(oversized, Err(EcdhError::InvalidChunkSize));
+    }
+
+    #[test]
+    fn static_keys_reproduce_shared_secret_after_reload() {
+        let (alice_secret, alice_public_key) = generate_static_ecdh_keys();
+        let (bob_secret, bob_public_key) = generate_static_ecdh_keys();
+
+        let path = std::env::temp_dir().join(format!("ecdh-static-secret-{}", std::process::id()));
+        save_static_secret(&path, &alice_secret).unwrap();
+
+        // two independent "restarts" load the persisted key and derive the same secret
+        let first = compute_shared_secret_static(&load_static_secret(&path).unwrap(), &bob_public_key);
+        let second = compute_shared_secret_static(&load_static_secret(&path).unwrap(), &bob_public_key);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first, compute_shared_secret_static(&bob_secret, &alice_public_key));
+
+        let reloaded = static_secret_from_bytes(&static_secret_to_bytes(&alice_secret)).unwrap();
+        assert_eq!(reloaded.public_key(), alice_public_key);
+        assert_eq!(static_secret_from_bytes(&[0u8; 32]), Err(EcdhError::InvalidSecretKey));
+        assert_eq!(static_secret_from_bytes(&[1u8; 31]), Err(EcdhError::InvalidSecretKey));
+    }
+
+    #[test]
+    fn public_key_from_sec1_rejects_invalid_points() {
+        let (_, public_key) = generate_ecdh_keys();
+        let encoded = EncodedPoint::from(public_key);
+        assert_eq!(public_key_from_sec1(encoded.as_bytes()).unwrap(), public_key);
+        assert!(validate_public_key(&public_key).is_ok());
+
+        // SEC1 encoding of the point at infinity
+        assert_eq!(public_key_from_sec1(&[0x00]), Err(EcdhError::InvalidPublicKey));
+        // truncated point
+        assert_eq!(public_key_from_sec1(&encoded.as_bytes()[..20]), Err(EcdhError::InvalidPublicKey));
+        // x coordinate outside the field
+        let mut out_of_field = [0xff_u8; 33];
+        out_of_field[0] = 0x02;
+        assert_eq!(public_key_from_sec1(&out_of_field), Err(EcdhError::InvalidPublicKey));
+        // unknown tag byte
+        let mut bad_tag = encoded.as_bytes().to_vec();
+        bad_tag[0] = 0x09;
+        assert_eq!(public_key_from_sec1(&bad_tag), Err(EcdhError::InvalidPublicKey));
+        assert_eq!(public_key_from_sec1(&[]), Err(EcdhError::InvalidPublicKey));
+    }
+
+    #[test]
+    fn public_keys_round_trip_through_the_compressed_encoding() {
+        let (_, public_key) = generate_ecdh_keys();
+        let compressed = public_key_to_sec1_compressed(&public_key);
+        assert_eq!(compressed.len(), COMPRESSED_PUBLIC_KEY_LEN);
+        assert_eq!(public_key_from_sec1_compressed(&compressed).unwrap(), public_key);
+
+        // the uncompressed encoding of the same point is a valid key, but not the one we exchange
+        let uncompressed = public_key.to_encoded_point(false);
+        assert_eq!(uncompressed.len(), 65);
+        assert_eq!(public_key_from_sec1(uncompressed.as_bytes()).unwrap(), public_key);
+        assert_eq!(public_key_from_sec1_compressed(uncompressed.as_bytes()), Err(EcdhError::InvalidPublicKey));
+        assert_eq!(public_key_from_sec1_compressed(&[0x00]), Err(EcdhError::InvalidPublicKey));
+    }
+
+    #[test]
+    fn seeded_keygen_is_reproducible() {
+        use rand_chacha::{ChaChaRng, rand_core::SeedableRng};
+
+        let keypair = |seed| generate_ecdh_keys_from_rng(ChaChaRng::seed_from_u64(seed));
+        let (alice_secret, alice_public_key) = keypair(42);
+        let (alice_secret_again, alice_public_key_again) = keypair(42);
+        let (bob_secret, bob_public_key) = keypair(7);
+        assert_eq!(alice_public_key, alice_public_key_again);
+        assert_ne!(alice_public_key, bob_public_key);
+
+        let shared_secret = compute_shared_secret(&alice_secret, &bob_public_key);
+        assert_eq!(shared_secret, compute_shared_secret(&alice_secret_again, &bob_public_key));
+        assert_eq!(shared_secret, compute_shared_secret(&bob_secret, &alice_public_key));
+    }
+
+    #[test]
+    fn secret_material_is_zeroized_on_drop() {
+        fn assert_zeroize_on_drop<T: zeroize::ZeroizeOnDrop>(_: &T) {}
+
+        let (alice_secret, _) = generate_ecdh_keys();
+        let (bob_secret, bob_public_key) = generate_ecdh_keys();
+        assert_zeroize_on_drop(&alice_secret);
+        assert_zeroize_on_drop(&bob_secret);
+
+        let shared_secret: Zeroizing<Vec<u8>> = compute_shared_secret(&alice_secret, &bob_public_key);
+        assert_eq!(shared_secret.len(), 32);
+        assert_zeroize_on_drop(&shared_secret);
+    }
+
+    #[test]
+    fn encrypt_round_trips_with_each_algorithm() {
+        let algorithms = [
+            AeadAlgorithm::ChaCha20Poly1305,
+            #[cfg(feature = "aes")]
+            AeadAlgorithm::Aes256Gcm,
+        ];
+        for algorithm in algorithms {
+            let obsf = encrypt(b"position", &key(1), b"POSITION_alice", algorithm).unwrap();
+            assert_eq!(&obsf[..2], &[FRAME_VERSION, algorithm.tag()]);
+            assert_eq!(decrypt(&obsf, &key(1), b"POSITION_alice").unwrap(), b"position");
+            assert_eq!(decrypt(&obsf, &key(1), b"POSITION_bob"), Err(EcdhError::Aead));
+        }
+    }
+
+    #[test]
+    fn decrypt_dispatches_on_algorithm_tag() {
+        // a ChaCha20Poly1305 ciphertext relabelled as AES-256-GCM
+        let mut obsf = encrypt(b"position", &key(1), b"", AeadAlgorithm::ChaCha20Poly1305).unwrap();
+        obsf[1] = AeadAlgorithm::AES_256_GCM_TAG;
+
+        // without the `aes` feature there is no AES path at all, with it AES fails to authenticate
+        #[cfg(not(feature = "aes"))]
+        assert_eq!(decrypt(&obsf, &key(1), b""), Err(EcdhError::UnsupportedAlgorithm(AeadAlgorithm::AES_256_GCM_TAG)));
+        #[cfg(feature = "aes")]
+        assert_eq!(decrypt(&obsf, &key(1), b""), Err(EcdhError::Aead));
+
+        obsf[1] = 0xff;
+        assert_eq!(decrypt(&obsf, &key(1), b""), Err(EcdhError::UnsupportedAlgorithm(0xff)));
+    }
+
+    #[test]
+    fn algorithm_tag_is_authenticated() {
+        let obsf = encrypt(b"position", &key(1), b"POSITION_alice", AeadAlgorithm::ChaCha20Poly1305).unwrap();
+        let (header, body) = obsf.split_at(2);
+        let (nonce, ciphertext) = body.split_at(12);
+        let nonce = GenericArray::from_slice(nonce);
+        let chacha = cipher::<ChaCha20Poly1305>(&key(1));
+
+        // the tag covers version || algorithm, so a frame relabelled as another algorithm doesn't verify
+        let aad = [header, b"POSITION_alice"].concat();
+        assert_eq!(chacha.decrypt(nonce, Payload { msg: ciphertext, aad: &aad }).unwrap(), b"position");
+        let relabelled = [&[FRAME_VERSION, AeadAlgorithm::AES_256_GCM_TAG][..], b"POSITION_alice"].concat();
+        assert!(chacha.decrypt(nonce, Payload { msg: ciphertext, aad: &relabelled }).is_err());
+    }
+
+    #[test]
+    fn encrypt_x_round_trips_with_192_bit_nonce() {
+        let cleartext = vec![7u8; 4096];
+        let obsf = encrypt_x(&cleartext, &key(1), b"POSITION_alice").unwrap();
+        // version || 24 byte nonce || ciphertext || 16 byte tag
+        assert_eq!(obsf[0], FRAME_VERSION_X);
+        assert_eq!(obsf.len(), 1 + 24 + cleartext.len() + 16);
+        assert_eq!(decrypt_x(&obsf, &key(1), b"POSITION_alice").unwrap(), cleartext);
+        assert_eq!(decrypt_x(&obsf, &key(1), b"POSITION_bob"), Err(EcdhError::Aead));
+        assert_eq!(decrypt_x(&obsf[..20], &key(1), b"POSITION_alice"), Err(EcdhError::TruncatedNonce));
+    }
+
+    #[test]
+    fn decrypt_x_rejects_12_byte_nonce_ciphertexts() {
+        // long enough to hold a 24 byte nonce and a tag, so it's the authentication that fails
+        let obsf = encrypt(b"encrypted position", &key(1), b"", AeadAlgorithm::ChaCha20Poly1305).unwrap();
+        assert_eq!(decrypt_x(&obsf, &key(1), b""), Err(EcdhError::UnsupportedVersion(FRAME_VERSION)));
+
+        // even relabelled as an X frame, the 12 byte nonce doesn't authenticate
+        let mut relabelled = obsf.clone();
+        relabelled[0] = FRAME_VERSION_X;
+        assert_eq!(decrypt_x(&relabelled, &key(1), b""), Err(EcdhError::Aead));
+
+        let obsf_x = encrypt_x(b"position", &key(1), b"").unwrap();
+        assert_eq!(decrypt(&obsf_x, &key(1), b""), Err(EcdhError::UnsupportedVersion(FRAME_VERSION_X)));
+    }
+
+    #[test]
+    fn shared_secret_round_trips_between_two_parties() {
+        fn assert_zeroize_on_drop<T: zeroize::ZeroizeOnDrop>(_: &T) {}
+
+        let (alice_secret, alice_public_key) = generate_ecdh_keys();
+        let (bob_secret, bob_public_key) = generate_ecdh_keys();
+        let alice_key = SharedSecret::derive(&alice_secret, &bob_public_key, b"fhe-position-v1");
+        let bob_key = SharedSecret::derive(&bob_secret, &alice_public_key, b"fhe-position-v1");
+        assert_zeroize_on_drop(&alice_key);
+        assert_eq!(alice_key.as_key(), bob_key.as_key());
+        assert_eq!(format!("{alice_key:?}"), "SharedSecret(..)");
+
+        let obsf = encrypt(b"position", &alice_key, b"POSITION_alice", AeadAlgorithm::ChaCha20Poly1305).unwrap();
+        assert_eq!(decrypt(&obsf, &bob_key, b"POSITION_alice").unwrap(), b"position");
+
+        let other_context = SharedSecret::derive(&bob_secret, &alice_public_key, b"fhe-private-key-v1");
+        assert_eq!(decrypt(&obsf, &other_context, b"POSITION_alice"), Err(EcdhError::Aead));
+
+        let mut zeroized = key(1);
+        zeroized.zeroize();
+        assert_eq!(zeroized.as_key().as_slice(), [0u8; 32]);
+    }
+
+    #[test]
+    fn frame_version_is_authenticated() {
+        let obsf = encrypt_x(b"position", &key(1), b"POSITION_alice").unwrap();
+        let (nonce, ciphertext) = obsf[1..].split_at(24);
+        let nonce = GenericArray::from_slice(nonce);
+        let xchacha = cipher::<XChaCha20Poly1305>(&key(1));
+
+        // the tag covers the version byte as well as the caller's aad
+        let aad = [&[FRAME_VERSION_X][..], b"POSITION_alice"].concat();
+        assert_eq!(xchacha.decrypt(nonce, Payload { msg: ciphertext, aad: &aad }).unwrap(), b"position");
+        assert!(xchacha.decrypt(nonce, Payload { msg: ciphertext, aad: b"POSITION_alice" }).is_err());
+    }
+
+    #[test]
+    fn shared_secret_cache_derives_once_per_peer_key() {
+        let (alice_secret, alice_public_key) = generate_ecdh_keys();
+        let (bob_secret, bob_public_key) = generate_ecdh_keys();
+        let mut alice_cache = SharedSecretCache::new(b"fhe-position-v1");
+        let bob_key = SharedSecret::derive(&bob_secret, &alice_public_key, b"fhe-position-v1");
+        let seal = |msg: &[u8], key: &SharedSecret| encrypt(msg, key, b"", AeadAlgorithm::ChaCha20Poly1305).unwrap();
+
+        let first = seal(b"position", alice_cache.get_or_derive("bob", &alice_secret, &bob_public_key));
+        let second = seal(b"moved", alice_cache.get_or_derive("bob", &alice_secret, &bob_public_key));
+        assert_eq!(alice_cache.derivations(), 1);
+        assert_eq!(decrypt(&first, &bob_key, b"").unwrap(), b"position");
+        assert_eq!(decrypt(&second, &bob_key, b"").unwrap(), b"moved");
+
+        // bob's key changed: the old secret must not be reused
+        let (new_bob_secret, new_bob_public_key) = generate_ecdh_keys();
+        let third = seal(b"position", alice_cache.get_or_derive("bob", &alice_secret, &new_bob_public_key));
+        assert_eq!((alice_cache.derivations(), alice_cache.len()), (2, 1));
+        assert_eq!(decrypt(&third, &bob_key, b""), Err(EcdhError::Aead));
+        let new_bob_key = SharedSecret::derive(&new_bob_secret, &alice_public_key, b"fhe-position-v1");
+        assert_eq!(decrypt(&third, &new_bob_key, b"").unwrap(), b"position");
+
+        assert!(alice_cache.remove(&"bob") && alice_cache.is_empty());
+    }
+
+    fn random_key() -> SharedSecret {
+        let mut key = [0u8; 32];
+        rand_core::RngCore::fill_bytes(&mut OsRng, &mut key);
+        SharedSecret::from(key)
+    }
+
+    #[test]
+    fn counter_nonces_are_sequential() {
+        // a fresh key, so no other test's nonces count as reuse
+        let key = random_key();
+        let nonces = NonceStrategy::counter();
+        for expected in 0u64..3 {
+            let obsf = encrypt_with_nonces(b"position", &key, b"", AeadAlgorithm::ChaCha20Poly1305, &nonces).unwrap();
+            // version || algorithm || 12 byte nonce
+            let nonce = &obsf[2..14];
+            assert_eq!(&nonce[..4], &[0u8; 4]);
+            assert_eq!(&nonce[4..], &expected.to_be_bytes());
+            assert_eq!(decrypt(&obsf, &key, b"").unwrap(), b"position");
+        }
+
+        let exhausted = NonceStrategy::Counter(AtomicU64::new(u64::MAX));
+        assert_eq!(
+            encrypt_with_nonces(b"position", &key, b"", AeadAlgorithm::ChaCha20Poly1305, &exhausted),
+            Err(EcdhError::NonceExhausted)
+        );
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "AEAD nonce reuse")]
+    fn reused_nonces_panic() {
+        let key = random_key();
+        // two counters under one key both start at nonce 0
+        let first = NonceStrategy::counter();
+        let restarted = NonceStrategy::counter();
+        encrypt_with_nonces(b"position", &key, b"", AeadAlgorithm::ChaCha20Poly1305, &first).unwrap();
+        let _ = encrypt_with_nonces(b"position", &key, b"", AeadAlgorithm::ChaCha20Poly1305, &restarted);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn seen_nonces_forget_the_oldest_past_the_cap() {
+        let digest = |i: usize| {
+            let mut digest = [0; 32];
+            digest[..8].copy_from_slice(&(i as u64).to_be_bytes());
+            digest
+        };
+        let mut seen = SeenNonces::default();
+        for i in 0..=MAX_SEEN_NONCES {
+            assert!(seen.insert(digest(i)), "{i}");
+        }
+        assert_eq!(seen.digests.len(), MAX_SEEN_NONCES);
+        // the most recent pair is still caught, the first one has been forgotten
+        assert!(!seen.insert(digest(MAX_SEEN_NONCES)));
+        assert!(seen.insert(digest(0)));
+    }
 }
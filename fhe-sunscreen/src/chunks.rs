@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use libp2p::kad;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// FHE records (~870kb positions, serialized keys) are split into sub-records of this size,
+// so Kademlia's value and packet limits can stay at realistic sizes.
+pub const RECORD_CHUNK_SIZE: usize = 64 * 1024;
+// Largest record value a node stores: one chunk, with headroom for small unchunked records.
+pub const MAX_RECORD_VALUE_BYTES: usize = RECORD_CHUNK_SIZE + 4 * 1024;
+// Largest record a manifest may describe, so a forged manifest can't make a node buffer without limit.
+// Serialized FHE keys, the largest records, are a few MB.
+pub const MAX_CHUNKED_RECORD_BYTES: u64 = 64 * 1024 * 1024;
+// Largest Kademlia message: a single record plus protocol overhead.
+pub const MAX_KADEMLIA_PACKET_SIZE: usize = 2 * RECORD_CHUNK_SIZE;
+
+// First byte of a manifest record. Unchunked record values are signed, starting with records::SIGNED_RECORD_TAG,
+// so a manifest is never mistaken for a payload. Chunks are raw slices of a signed record, so they're told
+// apart by their key (parse_chunk_key) instead, before their value is looked at.
+pub const CHUNK_MANIFEST_TAG: u8 = 0xff;
+
+// Stored under the record's own key: <key> -> manifest, <key>_<chunk_index> -> chunk.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkManifest {
+    pub chunk_count: u32,
+    pub total_len: u64,
+    pub sha256: [u8; 32],
+}
+
+impl ChunkManifest {
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut value = vec![CHUNK_MANIFEST_TAG];
+        bincode::serialize_into(&mut value, self).expect("bincode::serialize(ChunkManifest)");
+        value
+    }
+
+    // None if the record value isn't a manifest
+    pub fn decode(value: &[u8]) -> Option<Result<ChunkManifest, ChunkError>> {
+        match value.split_first() {
+            Some((&CHUNK_MANIFEST_TAG, manifest)) => {
+                Some(bincode::deserialize(manifest).map_err(|_| ChunkError::MalformedManifest))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChunkError {
+    MalformedManifest,
+    // a manifest's total length is over MAX_CHUNKED_RECORD_BYTES, or its chunk count doesn't match it
+    InvalidManifest(String),
+    // a chunk arrived for a record with no manifest, or an index past the manifest's chunk count
+    UnexpectedChunk(String),
+    // a chunk is larger than RECORD_CHUNK_SIZE
+    OversizedChunk(String),
+    // the reassembled payload doesn't match the manifest's length or digest
+    DigestMismatch(String),
+}
+
+impl fmt::Display for ChunkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkError::MalformedManifest => write!(f, "malformed chunk manifest"),
+            ChunkError::InvalidManifest(key) => write!(f, "invalid chunk manifest for {key}"),
+            ChunkError::UnexpectedChunk(key) => write!(f, "unexpected chunk {key}"),
+            ChunkError::OversizedChunk(key) => write!(f, "chunk {key} is larger than {RECORD_CHUNK_SIZE} bytes"),
+            ChunkError::DigestMismatch(key) => write!(f, "reassembled {key} doesn't match its manifest"),
+        }
+    }
+}
+
+impl std::error::Error for ChunkError {}
+
+pub fn form_chunk_key(key: &kad::RecordKey, chunk_index: u32) -> kad::RecordKey {
+    let mut chunk_key = key.to_vec();
+    chunk_key.extend_from_slice(format!("_{chunk_index}").as_bytes());
+    kad::RecordKey::new(&chunk_key)
+}
+
+// (record key, chunk index) for a chunk key. Peer ids never end in "_<digits>", so unchunked keys return None.
+pub fn parse_chunk_key(chunk_key: &kad::RecordKey) -> Option<(kad::RecordKey, u32)> {
+    let key_str = std::str::from_utf8(chunk_key.as_ref()).ok()?;
+    let (key, chunk_index) = key_str.rsplit_once('_')?;
+    if !chunk_index.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((kad::RecordKey::new(&key), chunk_index.parse().ok()?))
+}
+
+// Splits a record into a manifest under its own key, followed by its chunks.
+pub fn split_record(record: kad::Record, chunk_size: usize) -> Vec<kad::Record> {
+    let manifest = ChunkManifest {
+        chunk_count: record.value.chunks(chunk_size).len() as u32,
+        total_len: record.value.len() as u64,
+        sha256: Sha256::digest(&record.value).into(),
+    };
+
+    let chunks = record.value.chunks(chunk_size)
+        .enumerate()
+        .map(|(chunk_index, chunk)| kad::Record {
+            key: form_chunk_key(&record.key, chunk_index as u32),
+            value: chunk.to_vec(),
+            publisher: record.publisher,
+            expires: record.expires,
+        });
+
+    std::iter::once(kad::Record { value: manifest.encode(), ..record.clone() })
+        .chain(chunks)
+        .collect()
+}
+
+struct PendingRecord {
+    manifest: ChunkManifest,
+    publisher: Option<libp2p::PeerId>,
+    chunks: Vec<Option<Vec<u8>>>,
+}
+
+// Collects the chunks of records whose manifest has been fetched. A record is only handed back
+// once every chunk has arrived (in any order) and the payload matches the manifest.
+#[derive(Default)]
+pub struct ChunkAssembler {
+    pending: HashMap<kad::RecordKey, PendingRecord>,
+}
+
+impl ChunkAssembler {
+
+    // Start (or restart) reassembling `key`, returning the chunk keys to fetch.
+    // Manifests are only accepted for records split into RECORD_CHUNK_SIZE chunks, up to MAX_CHUNKED_RECORD_BYTES.
+    pub fn start(
+        &mut self,
+        key: kad::RecordKey,
+        publisher: Option<libp2p::PeerId>,
+        manifest: ChunkManifest
+    ) -> Result<Vec<kad::RecordKey>, ChunkError> {
+        if manifest.total_len > MAX_CHUNKED_RECORD_BYTES
+            || u64::from(manifest.chunk_count) != manifest.total_len.div_ceil(RECORD_CHUNK_SIZE as u64)
+        {
+            return Err(ChunkError::InvalidManifest(String::from_utf8_lossy(key.as_ref()).into_owned()));
+        }
+        let chunk_keys = (0..manifest.chunk_count).map(|i| form_chunk_key(&key, i)).collect();
+        self.pending.insert(key, PendingRecord {
+            chunks: vec![None; manifest.chunk_count as usize],
+            manifest,
+            publisher,
+        });
+        Ok(chunk_keys)
+    }
+
+    // Returns the reassembled record once its last chunk arrives
+    pub fn add_chunk(&mut self, chunk_key: &kad::RecordKey, chunk: Vec<u8>) -> Result<Option<kad::Record>, ChunkError> {
+        let unexpected = || ChunkError::UnexpectedChunk(String::from_utf8_lossy(chunk_key.as_ref()).into_owned());
+        let (key, chunk_index) = parse_chunk_key(chunk_key).ok_or_else(unexpected)?;
+        let pending = self.pending.get_mut(&key).ok_or_else(unexpected)?;
+        let slot = pending.chunks.get_mut(chunk_index as usize).ok_or_else(unexpected)?;
+        if chunk.len() > RECORD_CHUNK_SIZE {
+            return Err(ChunkError::OversizedChunk(String::from_utf8_lossy(chunk_key.as_ref()).into_owned()));
+        }
+        *slot = Some(chunk);
+
+        if pending.chunks.iter().any(Option::is_none) {
+            return Ok(None);
+        }
+
+        let pending = self.pending.remove(&key).expect("pending record");
+        let value: Vec<u8> = pending.chunks.into_iter().flatten().flatten().collect();
+        let digest: [u8; 32] = Sha256::digest(&value).into();
+        if value.len() as u64 != pending.manifest.total_len || digest != pending.manifest.sha256 {
+            return Err(ChunkError::DigestMismatch(String::from_utf8_lossy(key.as_ref()).into_owned()));
+        }
+
+        Ok(Some(kad::Record {
+            key,
+            value,
+            publisher: pending.publisher,
+            expires: None,
+        }))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fetch_all(assembler: &mut ChunkAssembler, records: &[kad::Record]) -> Result<Option<kad::Record>, ChunkError> {
+        let manifest = ChunkManifest::decode(&records[0].value).unwrap().unwrap();
+        let chunk_keys = assembler.start(records[0].key.clone(), records[0].publisher, manifest)?;
+        assert_eq!(chunk_keys.len(), records.len() - 1);
+
+        // chunks arrive out of order
+        let mut assembled = None;
+        for record in records[1..].iter().rev() {
+            assert!(assembled.is_none(), "assembled before every chunk arrived");
+            assembled = assembler.add_chunk(&record.key, record.value.clone())?;
+        }
+        Ok(assembled)
+    }
+
+    #[test]
+    fn split_record_reassembles_byte_for_byte() {
+        let publisher = libp2p::PeerId::random();
+        let payload: Vec<u8> = (0..2 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let record = kad::Record {
+            key: kad::RecordKey::new(&format!("POSITION_{publisher}")),
+            value: payload.clone(),
+            publisher: Some(publisher),
+            expires: None,
+        };
+
+        let records = split_record(record.clone(), RECORD_CHUNK_SIZE);
+        assert_eq!(records.len(), 1 + 32);
+        assert_eq!(records[0].key, record.key);
+        assert_eq!(records[1].key, kad::RecordKey::new(&format!("POSITION_{publisher}_0")));
+        assert!(records.iter().all(|r| r.value.len() <= MAX_RECORD_VALUE_BYTES));
+        assert_eq!(parse_chunk_key(&records[32].key), Some((record.key.clone(), 31)));
+        assert_eq!(parse_chunk_key(&record.key), None);
+
+        let mut assembler = ChunkAssembler::default();
+        let assembled = fetch_all(&mut assembler, &records).unwrap().expect("all chunks arrived");
+        assert_eq!(assembled.key, record.key);
+        assert_eq!(assembled.publisher, Some(publisher));
+        assert!(assembled.value == payload);
+
+        // a corrupted chunk is caught once the record is reassembled
+        let mut corrupted = records.clone();
+        corrupted[5].value[0] ^= 1;
+        assert!(matches!(fetch_all(&mut assembler, &corrupted), Err(ChunkError::DigestMismatch(_))));
+        assert!(matches!(
+            assembler.add_chunk(&records[1].key, records[1].value.clone()),
+            Err(ChunkError::UnexpectedChunk(_))
+        ));
+    }
+
+    #[test]
+    fn manifests_and_chunks_out_of_bounds_are_rejected() {
+        let key = kad::RecordKey::new(&"POSITION_alice");
+        let manifest = |chunk_count, total_len| ChunkManifest { chunk_count, total_len, sha256: [0; 32] };
+        let mut assembler = ChunkAssembler::default();
+
+        // a chunk count that doesn't match the length, either way
+        for (chunk_count, total_len) in [(1, 2 * RECORD_CHUNK_SIZE as u64), (u32::MAX, 10), (0, 10)] {
+            let result = assembler.start(key.clone(), None, manifest(chunk_count, total_len));
+            assert!(matches!(result, Err(ChunkError::InvalidManifest(_))));
+        }
+        let too_long = MAX_CHUNKED_RECORD_BYTES + 1;
+        let chunk_count = too_long.div_ceil(RECORD_CHUNK_SIZE as u64) as u32;
+        let result = assembler.start(key.clone(), None, manifest(chunk_count, too_long));
+        assert!(matches!(result, Err(ChunkError::InvalidManifest(_))));
+
+        // a chunk over RECORD_CHUNK_SIZE is rejected before it's buffered
+        let total_len = 2 * RECORD_CHUNK_SIZE as u64;
+        assert_eq!(assembler.start(key.clone(), None, manifest(2, total_len)).unwrap().len(), 2);
+        let chunk_key = form_chunk_key(&key, 0);
+        let result = assembler.add_chunk(&chunk_key, vec![0; RECORD_CHUNK_SIZE + 1]);
+        assert!(matches!(result, Err(ChunkError::OversizedChunk(_))));
+        assert!(assembler.pending[&key].chunks.iter().all(Option::is_none));
+    }
+}
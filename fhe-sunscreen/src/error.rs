@@ -0,0 +1,133 @@
+use crate::chunks::ChunkError;
+use crate::records::{RecordSignatureError, StaleRecord};
+use crate::{PositionError, PositionRecordError};
+
+// What a node's command and record handlers fail with, so callers (and tests) can match on the kind of failure
+#[derive(Debug, thiserror::Error)]
+pub enum AppError {
+    // no peer id (or shared key) is known for this name or peer
+    #[error("peer not found: {0}")]
+    PeerNotFound(String),
+    // a shared FHE key or ciphertext that couldn't be decrypted, e.g. under the wrong key
+    #[error("decryption failed: {0}")]
+    DecryptionFailed(String),
+    // a record or message that couldn't be encoded or decoded
+    #[error("serialization failed: {0}")]
+    Serialization(String),
+    // a DHT, dial, gossipsub or socket operation that failed
+    #[error("network error: {0}")]
+    Network(String),
+    // a malformed command, or a record rejected as invalid (unsigned, stale, out of range)
+    #[error("{0}")]
+    InvalidInput(String),
+    #[error("FHE error: {0}")]
+    Fhe(#[from] sunscreen::Error),
+}
+
+impl From<ecdh::EcdhError> for AppError {
+    fn from(e: ecdh::EcdhError) -> Self {
+        AppError::DecryptionFailed(e.to_string())
+    }
+}
+
+impl From<bincode::Error> for AppError {
+    fn from(e: bincode::Error) -> Self {
+        AppError::Serialization(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for AppError {
+    fn from(e: serde_json::Error) -> Self {
+        AppError::Serialization(e.to_string())
+    }
+}
+
+impl From<std::str::Utf8Error> for AppError {
+    fn from(e: std::str::Utf8Error) -> Self {
+        AppError::Serialization(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for AppError {
+    fn from(e: std::io::Error) -> Self {
+        AppError::Network(e.to_string())
+    }
+}
+
+impl From<PositionRecordError> for AppError {
+    fn from(e: PositionRecordError) -> Self {
+        AppError::Serialization(e.to_string())
+    }
+}
+
+impl From<ChunkError> for AppError {
+    fn from(e: ChunkError) -> Self {
+        AppError::Serialization(e.to_string())
+    }
+}
+
+impl From<libp2p::kad::store::Error> for AppError {
+    fn from(e: libp2p::kad::store::Error) -> Self {
+        AppError::Network(e.to_string())
+    }
+}
+
+impl From<libp2p::swarm::DialError> for AppError {
+    fn from(e: libp2p::swarm::DialError) -> Self {
+        AppError::Network(e.to_string())
+    }
+}
+
+impl From<libp2p::gossipsub::PublishError> for AppError {
+    fn from(e: libp2p::gossipsub::PublishError) -> Self {
+        AppError::Network(e.to_string())
+    }
+}
+
+impl From<PositionError> for AppError {
+    fn from(e: PositionError) -> Self {
+        AppError::InvalidInput(e.to_string())
+    }
+}
+
+impl From<RecordSignatureError> for AppError {
+    fn from(e: RecordSignatureError) -> Self {
+        AppError::InvalidInput(e.to_string())
+    }
+}
+
+impl From<StaleRecord> for AppError {
+    fn from(e: StaleRecord) -> Self {
+        AppError::InvalidInput(e.to_string())
+    }
+}
+
+impl From<libp2p::identity::ParseError> for AppError {
+    fn from(e: libp2p::identity::ParseError) -> Self {
+        AppError::InvalidInput(e.to_string())
+    }
+}
+
+impl From<String> for AppError {
+    fn from(e: String) -> Self {
+        AppError::InvalidInput(e)
+    }
+}
+
+impl From<&str> for AppError {
+    fn from(e: &str) -> Self {
+        AppError::InvalidInput(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_errors_are_network_errors() {
+        let e = AppError::from(std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset by peer"));
+        assert!(matches!(e, AppError::Network(_)), "{e:?}");
+        assert_eq!(e.to_string(), "network error: reset by peer");
+    }
+}
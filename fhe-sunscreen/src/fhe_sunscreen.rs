@@ -1,11 +1,18 @@
 
 use std::fmt::{Display, Debug};
 use std::fmt;
+use std::borrow::Cow;
+use std::io::{self, Read};
 use hex;
 use serde::{Deserialize, Serialize};
 // elliptic curve Diffie-Hellman
 use k256::ecdh::EphemeralSecret;
 use ecdh;
+use ecdh::zeroize::Zeroizing;
+// password-based key derivation for sealing keys at rest
+use argon2::Argon2;
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
 
 // FHE libs
 use seal_fhe::{ToBytes, FromBytes};
@@ -21,35 +28,511 @@ use sunscreen::{
     Ciphertext,
     CompiledFheProgram,
     FheRuntime,
+    FheProgramInput,
     Error,
     Compiler
 };
 
-use crate::UserKeyPair;
+use crate::error::AppError;
+use crate::keys::form_encrypted_fhe_key;
 
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Position {
     pub x: f64,
     pub y: f64,
+    // altitude, optional in MOVE so 2D moves like {"x":1,"y":2} still parse
+    #[serde(default)]
+    pub z: f64,
+}
+
+// Largest coordinate magnitude that converts into a Rational (a ratio of two i64s)
+pub const MAX_POSITION_COORDINATE: f64 = i64::MAX as f64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PositionError {
+    NotFinite { field: &'static str, value: f64 },
+    OutOfRange { field: &'static str, value: f64 },
+    // outside the map, see User::max_coordinate
+    OutsideMap { field: &'static str, value: f64, max_coordinate: f64 },
+    OutOfFixedPointRange { field: &'static str, value: f64 },
+    NotWholeNumber { field: &'static str, value: f64 },
+}
+
+impl Display for PositionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PositionError::NotFinite { field, value } => {
+                write!(f, "position.{field} must be a finite number, got {value}")
+            }
+            PositionError::OutOfRange { field, value } => {
+                write!(f, "position.{field} = {value} is outside the representable range ±{MAX_POSITION_COORDINATE}")
+            }
+            PositionError::OutsideMap { field, value, max_coordinate } => {
+                write!(f, "position.{field} = {value} is outside the map range ±{max_coordinate}")
+            }
+            PositionError::OutOfFixedPointRange { field, value } => {
+                write!(f, "position.{field} = {value} is outside the 16.16 fixed-point range ±{MAX_FIXED_POINT_COORDINATE}")
+            }
+            PositionError::NotWholeNumber { field, value } => {
+                write!(f, "relative move {field} = {value} must be a whole number of steps")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PositionError {}
+
+impl From<PositionError> for Error {
+    fn from(e: PositionError) -> Error {
+        sunscreen::RuntimeError::fhe_type_error(&e.to_string()).into()
+    }
+}
+
+impl Position {
+    // Check each coordinate can be converted to a Rational before encrypting it
+    pub fn validate(&self) -> Result<(), PositionError> {
+        for (field, value) in [("x", self.x), ("y", self.y), ("z", self.z)] {
+            if !value.is_finite() {
+                return Err(PositionError::NotFinite { field, value });
+            }
+            if value.abs() >= MAX_POSITION_COORDINATE {
+                return Err(PositionError::OutOfRange { field, value });
+            }
+        }
+        Ok(())
+    }
+
+    // validate, and also keep every coordinate within ±max_coordinate
+    pub fn validate_within(&self, max_coordinate: f64) -> Result<(), PositionError> {
+        self.validate()?;
+        for (field, value) in [("x", self.x), ("y", self.y), ("z", self.z)] {
+            if value.abs() > max_coordinate {
+                return Err(PositionError::OutsideMap { field, value, max_coordinate });
+            }
+        }
+        Ok(())
+    }
+
+    // Integer steps for a relative move, each coordinate must be a whole number
+    pub fn to_steps(&self) -> Result<[i64; 3], PositionError> {
+        self.validate()?;
+        let to_step = |field: &'static str, value: f64| {
+            if value.fract() != 0.0 {
+                return Err(PositionError::NotWholeNumber { field, value });
+            }
+            Ok(value as i64)
+        };
+        Ok([to_step("x", self.x)?, to_step("y", self.y)?, to_step("z", self.z)?])
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct EncryptedPosition {
+    pub x: Ciphertext,
+    pub y: Ciphertext,
+    pub z: Ciphertext,
+    // FHE type the coordinates are encrypted as, records from before this field are Rational
+    #[serde(default)]
+    pub coordinate_type: CoordinateType,
+    // move_position runs since the coordinates were freshly encrypted, see AVS::estimate_noise_budget
+    #[serde(default)]
+    pub hops: u32,
+}
+
+// FHE type position coordinates are encrypted as, chosen per AVS with AVS::setup_with.
+// Rational keeps arbitrary fractions but every addition multiplies numerators and denominators,
+// so its noise budget drains quickly over many moves. Signed only holds whole numbers but adding
+// is a plain ciphertext addition, so it stays exact for far more hops. Fractional sits in between:
+// fixed-precision fractions (FRACTIONAL_INT_BITS integer bits) that also add without multiplying.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum CoordinateType {
+    #[default]
+    Rational,
+    Signed,
+    Fractional,
+}
+
+pub const FRACTIONAL_INT_BITS: usize = 64;
+pub type FractionalCoordinate = Fractional<FRACTIONAL_INT_BITS>;
+
+impl std::str::FromStr for CoordinateType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "rational" => Ok(CoordinateType::Rational),
+            "signed" => Ok(CoordinateType::Signed),
+            "fractional" => Ok(CoordinateType::Fractional),
+            _ => Err(format!("unknown coordinate type {s}, expected rational, signed or fractional")),
+        }
+    }
+}
+
+impl Display for CoordinateType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoordinateType::Rational => write!(f, "rational"),
+            CoordinateType::Signed => write!(f, "signed"),
+            CoordinateType::Fractional => write!(f, "fractional"),
+        }
+    }
+}
+
+// Encrypt one coordinate as `coordinate_type`, Signed coordinates must be whole numbers
+fn encrypt_coordinate(
+    runtime: &FheRuntime,
+    coordinate_type: CoordinateType,
+    field: &'static str,
+    value: f64,
+    public_key: &PublicKey
+) -> Result<Ciphertext, Error> {
+    Ok(match coordinate_type {
+        CoordinateType::Rational => runtime.encrypt(Rational::try_from(value)?, public_key)?,
+        CoordinateType::Signed => {
+            if value.fract() != 0.0 {
+                return Err(PositionError::NotWholeNumber { field, value }.into());
+            }
+            runtime.encrypt(Signed::from(value as i64), public_key)?
+        }
+        CoordinateType::Fractional => runtime.encrypt(FractionalCoordinate::from(value), public_key)?,
+    })
+}
+
+fn decrypt_coordinate(
+    runtime: &FheRuntime,
+    coordinate_type: CoordinateType,
+    ciphertext: &Ciphertext,
+    private_key: &PrivateKey
+) -> Result<f64, Error> {
+    Ok(match coordinate_type {
+        CoordinateType::Rational => runtime.decrypt::<Rational>(ciphertext, private_key)?.into(),
+        CoordinateType::Signed => {
+            let value: i64 = runtime.decrypt::<Signed>(ciphertext, private_key)?.into();
+            value as f64
+        }
+        CoordinateType::Fractional => runtime.decrypt::<FractionalCoordinate>(ciphertext, private_key)?.into(),
+    })
+}
+
+// 16.16 fixed-point coordinates: a size-optimized alternative to Rational positions.
+// Each coordinate is stored as round(value * 65536) in an i32, so precision is 1/65536 units
+// and coordinates must lie within ±32768. It encrypts into a single Cipher<Signed> per coordinate,
+// where a Rational needs a numerator and a denominator ciphertext. Fixed-point moves are 2D, z is dropped.
+pub const FIXED_POINT_SCALE: f64 = 65536.0;
+pub const MAX_FIXED_POINT_COORDINATE: f64 = (i32::MAX as f64 + 1.0) / FIXED_POINT_SCALE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FixedPosition {
+    pub x: i32,
+    pub y: i32,
+}
+
+impl FixedPosition {
+
+    pub fn from_position(position: &Position) -> Result<FixedPosition, PositionError> {
+        position.validate()?;
+        let to_fixed = |field: &'static str, value: f64| {
+            let fixed = (value * FIXED_POINT_SCALE).round();
+            if fixed < i32::MIN as f64 || fixed > i32::MAX as f64 {
+                return Err(PositionError::OutOfFixedPointRange { field, value });
+            }
+            Ok(fixed as i32)
+        };
+        Ok(FixedPosition {
+            x: to_fixed("x", position.x)?,
+            y: to_fixed("y", position.y)?,
+        })
+    }
+
+    pub fn to_position(self) -> Position {
+        Position {
+            x: self.x as f64 / FIXED_POINT_SCALE,
+            y: self.y as f64 / FIXED_POINT_SCALE,
+            z: 0.0,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EncryptedFixedPosition {
     pub x: Ciphertext,
     pub y: Ciphertext
 }
 
+// Whole-number grid positions moved by relative steps: each coordinate is a Cipher<Signed>.
+// Used both for the player's position and for a signed delta, the AVS only ever adds deltas to it.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EncryptedRelativePosition {
+    pub x: Ciphertext,
+    pub y: Ciphertext,
+    pub z: Ciphertext
+}
+
+// Format tag at the start of a bincode position record: tag || bincode(EncryptedPosition).
+// Older records are untagged JSON, which always starts with '{', so the two can be told apart.
+pub const POSITION_RECORD_BINCODE: u8 = 1;
+const POSITION_RECORD_LEGACY_JSON: u8 = b'{';
+// Header of a zstd compressed record: tag || zstd(record). Records without it are read as-is.
+pub const RECORD_ZSTD: u8 = 2;
+pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+// Upper bound on a decompressed record, so a small malicious record can't expand without limit
+const MAX_DECOMPRESSED_RECORD_BYTES: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum PositionRecordError {
+    Empty,
+    UnknownFormat(u8),
+    Bincode(bincode::Error),
+    Json(serde_json::Error),
+    Zstd(io::Error),
+}
+
+impl Display for PositionRecordError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PositionRecordError::Empty => write!(f, "empty position record"),
+            PositionRecordError::UnknownFormat(tag) => write!(f, "unknown position record format tag {tag}"),
+            PositionRecordError::Bincode(e) => write!(f, "malformed bincode position record: {e}"),
+            PositionRecordError::Json(e) => write!(f, "malformed legacy JSON position record: {e}"),
+            PositionRecordError::Zstd(e) => write!(f, "malformed compressed position record: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for PositionRecordError {}
+
+// Codec for EncryptedPosition records stored in Kademlia. bincode stores the ciphertext bytes as-is,
+// where JSON writes each byte as a decimal number and inflates the ~870kb record.
+pub fn encode_position_record(position: &EncryptedPosition) -> Result<Vec<u8>, bincode::Error> {
+    let mut record = vec![POSITION_RECORD_BINCODE];
+    bincode::serialize_into(&mut record, position)?;
+    Ok(record)
+}
+
+// Compresses an encoded record at a zstd level (1-22, higher is smaller but slower).
+// Ciphertext coefficients are far from uniformly random bytes, so they compress well.
+pub fn compress_record(record: &[u8], level: i32) -> io::Result<Vec<u8>> {
+    let mut compressed = vec![RECORD_ZSTD];
+    zstd::stream::copy_encode(record, &mut compressed, level)?;
+    Ok(compressed)
+}
+
+// Inverse of compress_record. Uncompressed records are returned unchanged.
+pub fn decompress_record(record: &[u8]) -> io::Result<Cow<'_, [u8]>> {
+    match record.split_first() {
+        Some((&RECORD_ZSTD, compressed)) => {
+            let mut decompressed = Vec::new();
+            zstd::stream::Decoder::new(compressed)?
+                .take(MAX_DECOMPRESSED_RECORD_BYTES + 1)
+                .read_to_end(&mut decompressed)?;
+            if decompressed.len() as u64 > MAX_DECOMPRESSED_RECORD_BYTES {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "decompressed record is too large"));
+            }
+            Ok(Cow::Owned(decompressed))
+        }
+        _ => Ok(Cow::Borrowed(record)),
+    }
+}
+
+// Also reads zstd compressed records, and untagged JSON records published before the bincode format.
+pub fn decode_position_record(bytes: &[u8]) -> Result<EncryptedPosition, PositionRecordError> {
+    let bytes = decompress_record(bytes).map_err(PositionRecordError::Zstd)?;
+    match bytes.first() {
+        None => Err(PositionRecordError::Empty),
+        Some(&POSITION_RECORD_BINCODE) => bincode::deserialize(&bytes[1..]).map_err(PositionRecordError::Bincode),
+        Some(&POSITION_RECORD_LEGACY_JSON) => serde_json::from_slice(&bytes).map_err(PositionRecordError::Json),
+        Some(&tag) => Err(PositionRecordError::UnknownFormat(tag)),
+    }
+}
+
+// Size of an encoded EncryptedPosition record vs. the plaintext Position it encrypts
+#[derive(Debug, Clone, PartialEq)]
+pub struct CiphertextExpansion {
+    pub plaintext_bytes: usize,
+    pub ciphertext_bytes: usize,
+}
+
+impl CiphertextExpansion {
+
+    pub fn measure(position: &EncryptedPosition) -> Result<CiphertextExpansion, bincode::Error> {
+        Ok(CiphertextExpansion {
+            plaintext_bytes: std::mem::size_of::<Position>(),
+            ciphertext_bytes: encode_position_record(position)?.len(),
+        })
+    }
+
+    pub fn ratio(&self) -> f64 {
+        self.ciphertext_bytes as f64 / self.plaintext_bytes as f64
+    }
+}
+
+impl Display for CiphertextExpansion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} plaintext bytes -> {} ciphertext bytes (expansion: {:.0}x)",
+            self.plaintext_bytes,
+            self.ciphertext_bytes,
+            self.ratio()
+        )
+    }
+}
+
+// Unbounded: BFV has no comparisons, so the move can't be clamped to a map size (see README)
 #[fhe_program(scheme="bfv")]
 pub fn move_position(
     x1: Cipher<Rational>,
     y1: Cipher<Rational>,
+    z1: Cipher<Rational>,
     x2: Cipher<Rational>,
-    y2: Cipher<Rational>
-) -> (Cipher<Rational>, Cipher<Rational>) {
+    y2: Cipher<Rational>,
+    z2: Cipher<Rational>
+) -> (Cipher<Rational>, Cipher<Rational>, Cipher<Rational>) {
+    (x1+x2, y1+y2, z1+z2)
+}
+
+// move_position for CoordinateType::Signed
+#[fhe_program(scheme="bfv")]
+pub fn move_position_signed(
+    x1: Cipher<Signed>,
+    y1: Cipher<Signed>,
+    z1: Cipher<Signed>,
+    x2: Cipher<Signed>,
+    y2: Cipher<Signed>,
+    z2: Cipher<Signed>
+) -> (Cipher<Signed>, Cipher<Signed>, Cipher<Signed>) {
+    (x1+x2, y1+y2, z1+z2)
+}
+
+// move_position for CoordinateType::Fractional
+#[fhe_program(scheme="bfv")]
+pub fn move_position_fractional(
+    x1: Cipher<FractionalCoordinate>,
+    y1: Cipher<FractionalCoordinate>,
+    z1: Cipher<FractionalCoordinate>,
+    x2: Cipher<FractionalCoordinate>,
+    y2: Cipher<FractionalCoordinate>,
+    z2: Cipher<FractionalCoordinate>
+) -> (Cipher<FractionalCoordinate>, Cipher<FractionalCoordinate>, Cipher<FractionalCoordinate>) {
+    (x1+x2, y1+y2, z1+z2)
+}
+
+// range² - |a - b|², which is non-negative when b is within `range` of a. BFV has no comparisons,
+// so the AVS returns the encrypted difference and only the key holder learns whether it's in range.
+#[fhe_program(scheme="bfv")]
+pub fn distance_within_range(
+    ax: Cipher<Rational>,
+    ay: Cipher<Rational>,
+    az: Cipher<Rational>,
+    bx: Cipher<Rational>,
+    by: Cipher<Rational>,
+    bz: Cipher<Rational>,
+    range_squared: Rational
+) -> Cipher<Rational> {
+    let dx = ax - bx;
+    let dy = ay - by;
+    let dz = az - bz;
+    range_squared - (dx * dx + dy * dy + dz * dz)
+}
+
+// |a - b|², which is zero exactly when a and b are on the same spot. BFV has no equality test either,
+// so only the key holder learns whether they collided (see User::decrypt_collision).
+#[fhe_program(scheme="bfv")]
+pub fn positions_equal(
+    ax: Cipher<Rational>,
+    ay: Cipher<Rational>,
+    az: Cipher<Rational>,
+    bx: Cipher<Rational>,
+    by: Cipher<Rational>,
+    bz: Cipher<Rational>
+) -> Cipher<Rational> {
+    let dx = ax - bx;
+    let dy = ay - by;
+    let dz = az - bz;
+    dx * dx + dy * dy + dz * dz
+}
+
+// Adds a signed step to the previous grid position. BFV has no comparisons, so the program
+// can't clamp the result; out-of-bounds moves have to be caught by a separate range check.
+#[fhe_program(scheme="bfv")]
+pub fn move_relative(
+    x: Cipher<Signed>,
+    y: Cipher<Signed>,
+    z: Cipher<Signed>,
+    dx: Cipher<Signed>,
+    dy: Cipher<Signed>,
+    dz: Cipher<Signed>
+) -> (Cipher<Signed>, Cipher<Signed>, Cipher<Signed>) {
+    (x+dx, y+dy, z+dz)
+}
+
+#[fhe_program(scheme="bfv")]
+pub fn move_fixed_position(
+    x1: Cipher<Signed>,
+    y1: Cipher<Signed>,
+    x2: Cipher<Signed>,
+    y2: Cipher<Signed>
+) -> (Cipher<Signed>, Cipher<Signed>) {
     (x1+x2, y1+y2)
 }
 
+// HKDF context for the key that encrypts FHE private keys shared with peers over ECDH
+pub const PEER_FHE_KEY_INFO: &[u8] = b"fhe-private-key-v1";
+
+// Length of the random Argon2 salt prepended to a sealed private key
+pub const SEAL_SALT_LEN: usize = 16;
+
+#[derive(Debug)]
+pub enum SealError {
+    // blob is shorter than the salt, or was not produced by seal_private_key
+    Malformed,
+    // AEAD authentication failed: wrong password, or the blob was tampered with
+    WrongPassword,
+    KeyDerivation(argon2::Error),
+}
+
+impl Display for SealError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SealError::Malformed => write!(f, "sealed private key is malformed"),
+            SealError::WrongPassword => write!(f, "wrong password for sealed private key"),
+            SealError::KeyDerivation(e) => write!(f, "could not derive sealing key: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for SealError {}
+
+// Argon2id with its default cost parameters, stretched into a 32 byte ChaCha20Poly1305 key
+fn derive_sealing_key(password: &str, salt: &[u8]) -> Result<ecdh::SharedSecret, SealError> {
+    let mut sealing_key = Zeroizing::new([0u8; 32]);
+    Argon2::default()
+        .hash_password_into(password.as_bytes(), salt, sealing_key.as_mut_slice())
+        .map_err(SealError::KeyDerivation)?;
+    Ok(ecdh::SharedSecret::from(*sealing_key))
+}
+
+// An FHE private key encrypted for a peer over ECDH, with the sender's ECDH public key
+// so the peer can derive the same shared secret. Stored under ENCRYPTED_FHE_KEY_<sender peer_id>.
+#[derive(Serialize, Deserialize)]
+pub struct UserKeyPair {
+    pub ecdh_public_key: k256::PublicKey,
+    pub fhe_private_key_encrypted: Vec<u8>,
+}
+
+impl UserKeyPair {
+    // Record value for ENCRYPTED_FHE_KEY_<peer_id>. bincode keeps the encrypted key as raw bytes,
+    // where JSON wrote every byte out as a decimal number.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("bincode::serialize(UserKeyPair)")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<UserKeyPair, bincode::Error> {
+        bincode::deserialize(bytes)
+    }
+}
+
 pub struct User {
     // Sunscreen FHE keys
     pub fhe_public_key: PublicKey,
@@ -58,11 +541,32 @@ pub struct User {
     // Elliptic Curve Diffie-Hellman shared secret keys
     pub ecdh_public_key: k256::PublicKey,
     ecdh_private_key: EphemeralSecret,
+    // ECDH secrets for sharing FHE keys, derived once per peer id (and again if its ECDH key changes)
+    peer_shared_secrets: std::sync::Mutex<ecdh::SharedSecretCache<String>>,
+    // libp2p identity of the node, which signs every record it publishes (see records::sign_record)
+    pub identity: libp2p::identity::Keypair,
     // Name of the node, for convenience
     pub name: Option<String>,
     // encrypted FHE decryption keys from peers who shared it with this user
     pub peer_fhe_decryption_keys: std::collections::HashMap<String, UserKeyPair>,
+    // FHE public keys published by peers (FHE_PUBLIC_KEY records), to encrypt positions only that
+    // peer can decrypt, without it sharing its private key (see create_move_transaction_for_peer)
+    pub peer_fhe_public_keys: std::collections::HashMap<String, PublicKey>,
+    // Retired FHE keypairs, most recent first, so positions from before a key change still decrypt
+    fhe_key_ring: std::collections::VecDeque<(PublicKey, PrivateKey)>,
+    // FHE type moves are encrypted as, must match the AVS's coordinate_type
+    pub coordinate_type: CoordinateType,
+    // Moves with a coordinate beyond ±max_coordinate are rejected before encrypting (None: only
+    // representability is checked). The AVS can't check this itself, BFV has no comparisons.
+    pub max_coordinate: Option<f64>,
 }
+
+// Positions closer than this are the same spot for User::decrypt_collision
+pub const COLLISION_TOLERANCE: f64 = 1e-6;
+
+// Number of retired FHE keypairs a User keeps around
+pub const FHE_KEY_RING_SIZE: usize = 3;
+
 impl User {
 
     pub fn setup(params: &Params, name: &str) -> Result<User, Error> {
@@ -81,80 +585,290 @@ impl User {
             runtime: runtime,
             ecdh_public_key: ecdh_public_key,
             ecdh_private_key: ecdh_private_key,
+            peer_shared_secrets: std::sync::Mutex::new(ecdh::SharedSecretCache::new(PEER_FHE_KEY_INFO)),
+            identity: libp2p::identity::Keypair::generate_ed25519(),
             name: Some(name.to_string()),
             peer_fhe_decryption_keys: std::collections::HashMap::new(),
+            peer_fhe_public_keys: std::collections::HashMap::new(),
+            fhe_key_ring: std::collections::VecDeque::new(),
+            coordinate_type: CoordinateType::default(),
+            max_coordinate: None,
         })
     }
 
-    pub fn encrypt_fhe_key_for_peer(&self, bob_public_key: &k256::PublicKey) -> Vec<u8> {
+    // Swap in a new FHE keypair, retiring the current one into the key ring.
+    pub fn replace_fhe_keys(&mut self, public_key: PublicKey, private_key: PrivateKey) {
+        let retired_public_key = std::mem::replace(&mut self.fhe_public_key, public_key);
+        let retired_private_key = std::mem::replace(&mut self.fhe_private_key, private_key);
+        self.fhe_key_ring.push_front((retired_public_key, retired_private_key));
+        self.fhe_key_ring.truncate(FHE_KEY_RING_SIZE);
+    }
+
+    // Generate a fresh FHE keypair, e.g. after the private key was shared with SHARE_KEY or leaked.
+    // The old keypair is retired into the key ring so stored positions can still be re-encrypted
+    // (see reencrypt_positions); copies of the old key held by peers keep decrypting old positions only.
+    pub fn rotate_fhe_keys(&mut self) -> Result<(), Error> {
+        let (public_key, private_key) = self.runtime.generate_keys()?;
+        self.replace_fhe_keys(public_key, private_key);
+        Ok(())
+    }
 
-        let shared_secret_key = ecdh::compute_shared_secret(&self.ecdh_private_key, bob_public_key);
-        let alice_pkey = bincode::serialize(&self.fhe_private_key)
-            .expect("bincode::serialize(alice_pkey");
+    // Decrypts positions under the current or a retired key and re-encrypts them under the current key.
+    // Fresh encryptions also start with a full noise budget again.
+    pub fn reencrypt_positions(&self, old_positions: &[EncryptedPosition]) -> Result<Vec<EncryptedPosition>, Error> {
+        old_positions.iter()
+            .map(|position| self.create_move_transaction(self.decrypt_own_position(position.clone())?))
+            .collect()
+    }
 
-        ecdh::encrypt(&alice_pkey, &shared_secret_key)
+    // Undo the last key change (e.g. a botched rotation). The reverted-from keypair stays in the
+    // key ring so positions encrypted in the interim still decrypt. Returns false if there is nothing to revert to.
+    pub fn revert_fhe_keys(&mut self) -> bool {
+        match self.fhe_key_ring.pop_front() {
+            Some((public_key, private_key)) => {
+                self.replace_fhe_keys(public_key, private_key);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Encrypts the FHE private key under a password so it can be stored at rest.
+    // Layout: salt || nonce || ciphertext. Without the password the blob is useless.
+    pub fn seal_private_key(&self, password: &str) -> Vec<u8> {
+
+        let mut salt = [0u8; SEAL_SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let sealing_key = derive_sealing_key(password, &salt)
+            .expect("argon2 accepts a 16 byte salt and 32 byte output");
+
+        let private_key_bytes = Zeroizing::new(bincode::serialize(&self.fhe_private_key)
+            .expect("bincode::serialize(fhe_private_key)"));
+
+        let mut sealed = salt.to_vec();
+        sealed.extend(
+            ecdh::encrypt(&private_key_bytes, &sealing_key, &[], ecdh::AeadAlgorithm::ChaCha20Poly1305)
+                .expect("sealing key is 32 bytes and the serialized key is never empty")
+        );
+        sealed
+    }
+
+    pub fn unseal_private_key(sealed: &[u8], password: &str) -> Result<PrivateKey, SealError> {
+
+        if sealed.len() < SEAL_SALT_LEN {
+            return Err(SealError::Malformed);
+        }
+        let (salt, encrypted_private_key) = sealed.split_at(SEAL_SALT_LEN);
+        let sealing_key = derive_sealing_key(password, salt)?;
+
+        let private_key_bytes = ecdh::decrypt(encrypted_private_key, &sealing_key, &[])
+            .map(Zeroizing::new)
+            .map_err(|e| match e {
+                ecdh::EcdhError::Aead => SealError::WrongPassword,
+                _ => SealError::Malformed,
+            })?;
+
+        bincode::deserialize(&private_key_bytes).map_err(|_| SealError::Malformed)
+    }
+
+    // Fingerprint of this node's ECDH public key, for peers to check against their FINGERPRINT <name>
+    pub fn ecdh_public_key_fingerprint(&self) -> String {
+        public_key_fingerprint(&self.ecdh_public_key)
+    }
+
+    // Runs `f` with the ECDH secret shared with `peer_id`, deriving it only on first use
+    fn with_peer_shared_secret<T>(
+        &self,
+        peer_id: &str,
+        peer_public_key: &k256::PublicKey,
+        f: impl FnOnce(&ecdh::SharedSecret) -> T
+    ) -> T {
+        let mut secrets = self.peer_shared_secrets.lock().expect("peer shared secrets lock poisoned");
+        f(secrets.get_or_derive(peer_id.to_string(), &self.ecdh_private_key, peer_public_key))
+    }
+
+    // Number of ECDH computations for shared FHE keys so far, see ecdh::SharedSecretCache
+    pub fn peer_shared_secret_derivations(&self) -> u64 {
+        self.peer_shared_secrets.lock().expect("peer shared secrets lock poisoned").derivations()
+    }
+
+    // `record_key` is the Kademlia key the encrypted FHE key is stored under, bound in as AAD so the
+    // record can't be copied under another peer's key and still decrypt.
+    pub fn encrypt_fhe_key_for_peer(
+        &self,
+        bob_peer_id: &str,
+        bob_public_key: &k256::PublicKey,
+        record_key: &str
+    ) -> Result<Vec<u8>, ecdh::EcdhError> {
+
+        let alice_pkey = Zeroizing::new(bincode::serialize(&self.fhe_private_key)
+            .expect("bincode::serialize(alice_pkey"));
+
+        self.with_peer_shared_secret(bob_peer_id, bob_public_key, |shared_secret_key| {
+            ecdh::encrypt(&alice_pkey, shared_secret_key, record_key.as_bytes(), ecdh::AeadAlgorithm::ChaCha20Poly1305)
+        })
     }
 
     pub fn decrypt_fhe_key_from_peer(
         &self,
         encrypted_fhe_private_key: &[u8],
-        alice_public_key: &k256::PublicKey
-    ) -> PrivateKey {
+        alice_peer_id: &str,
+        alice_public_key: &k256::PublicKey,
+        record_key: &str
+    ) -> Result<PrivateKey, Error> {
 
         println!("Decrypting alice keys using Bob's shared secret...");
-        let shared_secret_key = ecdh::compute_shared_secret(&self.ecdh_private_key, alice_public_key);
-        let alice_private_key_bytes = ecdh::decrypt(&encrypted_fhe_private_key, &shared_secret_key);
+        let alice_private_key_bytes = self
+            .with_peer_shared_secret(alice_peer_id, alice_public_key, |shared_secret_key| {
+                ecdh::decrypt(encrypted_fhe_private_key, shared_secret_key, record_key.as_bytes())
+            })
+            .map(Zeroizing::new)
+            .map_err(|e| sunscreen::RuntimeError::fhe_type_error(&e.to_string()))?;
         let alice_private_key = bincode::deserialize(&alice_private_key_bytes)
-            .expect("bincode::deserialize(alice_pkey");
+            .map_err(|e| sunscreen::RuntimeError::BincodeError(Box::new(e.to_string())))?;
 
-        return alice_private_key
+        Ok(alice_private_key)
     }
 
     pub fn create_move_transaction(&self, position: Position) -> Result<EncryptedPosition, Error> {
+        self.encrypt_position_under(position, &self.fhe_public_key)
+    }
+
+    // Encrypts a position under a peer's FHE public key (from its FHE_PUBLIC_KEY record): only that
+    // peer can decrypt it, and neither side shares a private key. Not even this user can decrypt it.
+    pub fn create_move_transaction_for_peer(&self, position: Position, peer_id: &str) -> Result<EncryptedPosition, AppError> {
+        let public_key = self.peer_fhe_public_keys
+            .get(peer_id)
+            .ok_or_else(|| AppError::PeerNotFound(format!("no FHE public key from {peer_id}")))?;
+        Ok(self.encrypt_position_under(position, public_key)?)
+    }
+
+    fn encrypt_position_under(&self, position: Position, public_key: &PublicKey) -> Result<EncryptedPosition, Error> {
+        match self.max_coordinate {
+            Some(max_coordinate) => position.validate_within(max_coordinate)?,
+            None => position.validate()?,
+        }
+        let encrypt = |field, value| {
+            encrypt_coordinate(&self.runtime, self.coordinate_type, field, value, public_key)
+        };
         Ok(EncryptedPosition {
-            x: self.runtime.encrypt(Rational::try_from(position.x)?, &self.fhe_public_key)?,
-            y: self.runtime.encrypt(Rational::try_from(position.y)?, &self.fhe_public_key)?,
+            x: encrypt("x", position.x)?,
+            y: encrypt("y", position.y)?,
+            z: encrypt("z", position.z)?,
+            coordinate_type: self.coordinate_type,
+            hops: 0,
         })
     }
 
+    pub fn create_fixed_move_transaction(&self, position: Position) -> Result<EncryptedFixedPosition, Error> {
+        let position = FixedPosition::from_position(&position)?;
+        Ok(EncryptedFixedPosition {
+            x: self.runtime.encrypt(Signed::from(position.x as i64), &self.fhe_public_key)?,
+            y: self.runtime.encrypt(Signed::from(position.y as i64), &self.fhe_public_key)?,
+        })
+    }
+
+    // Encrypts a step relative to the current position, e.g. (-1, 3, 0), for AVS::run_relative_contract
+    pub fn create_relative_move_transaction(&self, delta: Position) -> Result<EncryptedRelativePosition, Error> {
+        let [dx, dy, dz] = delta.to_steps()?;
+        Ok(EncryptedRelativePosition {
+            x: self.runtime.encrypt(Signed::from(dx), &self.fhe_public_key)?,
+            y: self.runtime.encrypt(Signed::from(dy), &self.fhe_public_key)?,
+            z: self.runtime.encrypt(Signed::from(dz), &self.fhe_public_key)?,
+        })
+    }
+
+    pub fn decrypt_own_relative_position(&self, position: EncryptedRelativePosition) -> Result<Position, Error> {
+        let x: Signed = self.runtime.decrypt(&position.x, &self.fhe_private_key)?;
+        let y: Signed = self.runtime.decrypt(&position.y, &self.fhe_private_key)?;
+        let z: Signed = self.runtime.decrypt(&position.z, &self.fhe_private_key)?;
+        Ok(Position {
+            x: i64::from(x) as f64,
+            y: i64::from(y) as f64,
+            z: i64::from(z) as f64,
+        })
+    }
+
+    // Whether an AVS::check_proximity result means the two positions were within range
+    pub fn decrypt_proximity(&self, proximity: &Ciphertext) -> Result<bool, Error> {
+        let range_margin: Rational = self.runtime.decrypt(proximity, &self.fhe_private_key)?;
+        Ok(f64::from(range_margin) >= 0.0)
+    }
+
+    // Whether an AVS::check_collision result means the two positions were on the same spot.
+    // Rational arithmetic is exact, but f64 coordinates are only approximated when converted to Rational
+    // (0.1 + 0.2 and 0.3 come out a hair apart), so positions within COLLISION_TOLERANCE count as equal.
+    pub fn decrypt_collision(&self, collision: &Ciphertext) -> Result<bool, Error> {
+        let distance_squared: Rational = self.runtime.decrypt(collision, &self.fhe_private_key)?;
+        Ok(f64::from(distance_squared) <= COLLISION_TOLERANCE * COLLISION_TOLERANCE)
+    }
+
+    pub fn decrypt_own_fixed_position(&self, position: EncryptedFixedPosition) -> Result<Position, Error> {
+        let x: Signed = self.runtime.decrypt(&position.x, &self.fhe_private_key)?;
+        let y: Signed = self.runtime.decrypt(&position.y, &self.fhe_private_key)?;
+        let x = i32::try_from(i64::from(x))
+            .map_err(|_| sunscreen::RuntimeError::fhe_type_error("fixed-point x overflowed i32"))?;
+        let y = i32::try_from(i64::from(y))
+            .map_err(|_| sunscreen::RuntimeError::fhe_type_error("fixed-point y overflowed i32"))?;
+        Ok(FixedPosition { x, y }.to_position())
+    }
+
+    // Encrypts a known small position and measures its encoded record size under the current params
+    pub fn measure_ciphertext_expansion(&self) -> Result<CiphertextExpansion, Box<dyn std::error::Error>> {
+        let encrypted_position = self.create_move_transaction(Position { x: 1.0, y: 2.0, z: 0.0 })?;
+        Ok(CiphertextExpansion::measure(&encrypted_position)?)
+    }
+
+    // Tries the current FHE key, then each key in the key ring, returning the first successful decryption.
     pub fn decrypt_own_position(&self, position: EncryptedPosition) -> Result<Position, Error> {
 
-        let position_x: Rational = self.runtime
-            .decrypt(&position.x, &self.fhe_private_key)?;
+        let current_key_result = self.decrypt_position_with_key(&position, &self.fhe_private_key);
+        if current_key_result.is_ok() {
+            return current_key_result;
+        }
+
+        self.fhe_key_ring
+            .iter()
+            .find_map(|(_, private_key)| self.decrypt_position_with_key(&position, private_key).ok())
+            .map_or(current_key_result, Ok)
+    }
+
+    // Decrypts a path from AVS::get_position_history, in the same order
+    pub fn decrypt_history(&self, history: &[EncryptedPosition]) -> Result<Vec<Position>, Error> {
+        history.iter()
+            .map(|position| self.decrypt_own_position(position.clone()))
+            .collect()
+    }
+
+    fn decrypt_position_with_key(&self, position: &EncryptedPosition, private_key: &PrivateKey) -> Result<Position, Error> {
+
+        let decrypt = |ciphertext| {
+            decrypt_coordinate(&self.runtime, position.coordinate_type, ciphertext, private_key)
+        };
         // if Error with TooMuchNoise -> probably decrypting with wrong key.
         // or tried to decrypt ciphertexts that were chain-encrypted too many times.
-        let position_y: Rational = self.runtime
-            .decrypt(&position.y, &self.fhe_private_key)?;
-
-        let x: f64 = position_x.into();
-        let y: f64 = position_y.into();
+        let x = decrypt(&position.x)?;
+        let y = decrypt(&position.y)?;
+        let z = decrypt(&position.z)?;
 
-        Ok(Position { x, y })
+        Ok(Position { x, y, z })
     }
 
-    pub fn decrypt_peer_position(&self, position: EncryptedPosition, peer_id: &str) -> Result<Position, Error> {
+    pub fn decrypt_peer_position(&self, position: EncryptedPosition, peer_id: &str) -> Result<Position, AppError> {
 
+        // no UserKeyPair until the peer has shared their key with us
         let peer_keys = self.peer_fhe_decryption_keys.get(peer_id)
-            .expect(&format!("UserKeyPair not found for peer_id: {peer_id}"));
+            .ok_or_else(|| AppError::PeerNotFound(format!("no shared FHE key from {peer_id}")))?;
 
         // decrypt alice's FHE private key using shared secret
         let fhe_decryption_key = self.decrypt_fhe_key_from_peer(
             &peer_keys.fhe_private_key_encrypted, // alice's encrypted FHE key
-            &peer_keys.ecdh_public_key // alice's ECDH public key for Bob to compute shared secret
-        );
-
-        let position_x: Rational = self
-            .runtime
-            .decrypt(&position.x, &fhe_decryption_key)?;
-
-        let position_y: Rational = self
-            .runtime
-            .decrypt(&position.y, &fhe_decryption_key)?;
-
-        let x: f64 = position_x.into();
-        let y = position_y.into();
+            peer_id,
+            &peer_keys.ecdh_public_key, // alice's ECDH public key for Bob to compute shared secret
+            &form_encrypted_fhe_key(peer_id)
+        ).map_err(|e| AppError::DecryptionFailed(format!("{peer_id}'s shared FHE key: {e}")))?;
 
-        Ok(Position { x, y })
+        Ok(self.decrypt_position_with_key(&position, &fhe_decryption_key)?)
     }
 
 }
@@ -163,35 +877,191 @@ pub struct AVS {
     // FHE move program and runtime
     pub compiled_move_position: CompiledFheProgram,
     runtime: FheRuntime,
+    // Extra runtimes (same params) run_contract_concurrent spreads players' moves over, see set_concurrency
+    runtime_pool: Vec<FheRuntime>,
+    // FHE type of the coordinates compiled_move_position moves
+    pub coordinate_type: CoordinateType,
+    // How fast compiled_move_position uses up the noise budget, see estimate_noise_budget
+    pub noise_model: NoiseModel,
+    // run_contract warns once a position's estimated noise budget drops below this many bits
+    pub low_noise_budget: u32,
     // FHE encrypted positions
     pub encrypted_positions: std::collections::HashMap<String, EncryptedPosition>,
+    // Up to history_len most recent positions per player, oldest first: HashMap(public_key_hex -> positions)
+    position_history: std::collections::HashMap<String, std::collections::VecDeque<EncryptedPosition>>,
+    // Number of past positions kept per player, 0 disables the history
+    pub history_len: usize,
+    // Last encrypted position received from each peer: HashMap(peer_id -> position)
+    pub peer_positions: std::collections::HashMap<String, EncryptedPosition>,
     // Peer ECDH public keys: HashMap(name -> ECDH-PublickKey)
     pub peer_public_keys: std::collections::HashMap<String, k256::PublicKey>,
     // This AVS node's peerId
     pub peer_id: Option<libp2p::PeerId>,
     // HashMap<name -> PeerId>
     pub peer_ids: std::collections::HashMap<String, libp2p::PeerId>,
+    // Number of moves applied per player: HashMap(public_key_hex -> sequence number)
+    move_sequences: std::collections::HashMap<String, u64>,
+    // FHE programs run by name (run_named): the built-in ones, and any plugged in with register_program
+    programs: std::collections::HashMap<String, CompiledFheProgram>,
+    // FHE encrypted 16.16 fixed-point positions
+    pub encrypted_fixed_positions: std::collections::HashMap<String, EncryptedFixedPosition>,
+    // FHE encrypted grid positions moved by relative steps
+    pub encrypted_relative_positions: std::collections::HashMap<String, EncryptedRelativePosition>,
+}
+
+// One player's moves in a run_contract_concurrent batch, with their positions in the batch
+struct PlayerMoves {
+    pubkey_hex: String,
+    public_key: PublicKey,
+    moves: Vec<(usize, EncryptedPosition)>,
+}
+
+// Noise budget (in bits) of a fresh encryption, and how much each move_position run uses up.
+// Only the private key holder can measure a ciphertext's noise budget, so the AVS measures these
+// once on a throwaway keypair and estimates players' budgets from how many moves their position has taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoiseModel {
+    pub fresh_budget: u32,
+    pub cost_per_hop: u32,
+}
+
+// Default AVS::low_noise_budget: warn while a position can still take this many moves
+pub const LOW_NOISE_BUDGET_HOPS: u32 = 3;
+
+impl NoiseModel {
+
+    pub fn calibrate(
+        runtime: &FheRuntime,
+        move_program: &CompiledFheProgram,
+        coordinate_type: CoordinateType
+    ) -> Result<NoiseModel, Error> {
+        let (public_key, private_key) = runtime.generate_keys()?;
+        let fresh = encrypt_coordinate(runtime, coordinate_type, "x", 1.0, &public_key)?;
+        let fresh_budget = runtime.measure_noise_budget(&fresh, &private_key)?;
+
+        // the second hop adds a fresh move to an already moved position, like every later hop
+        let mut position = fresh.clone();
+        let mut budgets = vec![];
+        for _ in 0..2 {
+            let inputs = vec![position.clone(), fresh.clone(), fresh.clone(), fresh.clone(), fresh.clone(), fresh.clone()];
+            position = runtime.run(move_program, inputs, &public_key)?.remove(0);
+            budgets.push(runtime.measure_noise_budget(&position, &private_key)?);
+        }
+
+        Ok(NoiseModel {
+            fresh_budget,
+            cost_per_hop: budgets[0].saturating_sub(budgets[1]),
+        })
+    }
 }
+
+// Registered in every AVS. MOVE_POSITION is the move_position variant for the AVS's coordinate type.
+pub const MOVE_POSITION: &str = "move_position";
+pub const MOVE_FIXED_POSITION: &str = "move_fixed_position";
+pub const MOVE_RELATIVE: &str = "move_relative";
+pub const DISTANCE_WITHIN_RANGE: &str = "distance_within_range";
+pub const POSITIONS_EQUAL: &str = "positions_equal";
 impl AVS {
 
     pub fn setup() -> Result<AVS, Error> {
+        AVS::setup_with(CoordinateType::default())
+    }
+
+    // All move_position variants are compiled together so they share params,
+    // `coordinate_type` picks the one run_contract runs.
+    pub fn setup_with(coordinate_type: CoordinateType) -> Result<AVS, Error> {
 
         let app = Compiler::new()
             .fhe_program(move_position)
+            .fhe_program(move_position_signed)
+            .fhe_program(move_position_fractional)
+            .fhe_program(move_fixed_position)
+            .fhe_program(move_relative)
+            .fhe_program(distance_within_range)
+            .fhe_program(positions_equal)
             .compile()?;
 
         let runtime= FheRuntime::new(app.params())?;
 
+        let mut programs = std::collections::HashMap::new();
+        programs.insert(
+            MOVE_FIXED_POSITION.to_string(),
+            app.get_fhe_program(move_fixed_position).unwrap().clone()
+        );
+        programs.insert(
+            MOVE_RELATIVE.to_string(),
+            app.get_fhe_program(move_relative).unwrap().clone()
+        );
+        programs.insert(
+            DISTANCE_WITHIN_RANGE.to_string(),
+            app.get_fhe_program(distance_within_range).unwrap().clone()
+        );
+        programs.insert(
+            POSITIONS_EQUAL.to_string(),
+            app.get_fhe_program(positions_equal).unwrap().clone()
+        );
+
+        let compiled_move_position = match coordinate_type {
+            CoordinateType::Rational => app.get_fhe_program(move_position),
+            CoordinateType::Signed => app.get_fhe_program(move_position_signed),
+            CoordinateType::Fractional => app.get_fhe_program(move_position_fractional),
+        }.unwrap().clone();
+        programs.insert(MOVE_POSITION.to_string(), compiled_move_position.clone());
+        let noise_model = NoiseModel::calibrate(&runtime, &compiled_move_position, coordinate_type)?;
+
         Ok(AVS {
-            compiled_move_position: app.get_fhe_program(move_position).unwrap().clone(),
+            compiled_move_position,
+            coordinate_type,
+            low_noise_budget: noise_model.cost_per_hop * LOW_NOISE_BUDGET_HOPS,
+            noise_model,
             encrypted_positions: std::collections::HashMap::new(),
+            position_history: std::collections::HashMap::new(),
+            history_len: 0,
+            peer_positions: std::collections::HashMap::new(),
             runtime: runtime,
+            runtime_pool: Vec::new(),
             peer_public_keys: std::collections::HashMap::new(),
             peer_id: None,
             peer_ids: std::collections::HashMap::new(),
+            move_sequences: std::collections::HashMap::new(),
+            programs,
+            encrypted_fixed_positions: std::collections::HashMap::new(),
+            encrypted_relative_positions: std::collections::HashMap::new(),
         })
     }
 
+    // Plug in an externally compiled fhe_program. It must be compiled with the AVS's params
+    // (e.g. `Compiler::new().fhe_program(f).with_params(&avs.compiled_move_position.metadata.params)`)
+    // so it runs on the same runtime and ciphertexts as move_position.
+    pub fn register_program(&mut self, name: &str, compiled: CompiledFheProgram) -> Result<(), Error> {
+        if compiled.metadata.params != self.compiled_move_position.metadata.params {
+            return Err(sunscreen::RuntimeError::ParameterMismatch.into());
+        }
+        if self.programs.contains_key(name) {
+            return Err(Error::NameCollision);
+        }
+        self.programs.insert(name.to_string(), compiled);
+        Ok(())
+    }
+
+    // Run a registered program by name, e.g. MOVE_POSITION or one added with register_program
+    pub fn run_named(&self, name: &str, inputs: Vec<Ciphertext>, public_key: &PublicKey) -> Result<Vec<Ciphertext>, Error> {
+        self.run_named_on(&self.runtime, name, inputs, public_key)
+    }
+
+    // run_named on one of the AVS's runtimes, so run_contract_concurrent's workers don't share one
+    fn run_named_on(
+        &self,
+        runtime: &FheRuntime,
+        name: &str,
+        inputs: Vec<Ciphertext>,
+        public_key: &PublicKey
+    ) -> Result<Vec<Ciphertext>, Error> {
+        let program = self.programs.get(name)
+            .ok_or_else(|| Error::unsupported(&format!("no FHE program registered as {name}")))?;
+        Ok(runtime.run(program, inputs, public_key)?)
+    }
+
     pub fn set_peer_id(&mut self, peer_id: Option<libp2p::PeerId>) {
         self.peer_id = peer_id;
     }
@@ -207,58 +1077,410 @@ impl AVS {
         match self.encrypted_positions.get(&pubkey_str) {
             Some(p) => Ok(p.clone()),
             None => {
-                let x_encrypted  = self.runtime.encrypt(Rational::try_from(0.0)?, &public_key)?;
-                let y_encrypted  = self.runtime.encrypt(Rational::try_from(0.0)?, &public_key)?;
+                let origin = |field| encrypt_coordinate(&self.runtime, self.coordinate_type, field, 0.0, public_key);
                 Ok(EncryptedPosition {
-                    x: x_encrypted,
-                    y: y_encrypted
+                    x: origin("x")?,
+                    y: origin("y")?,
+                    z: origin("z")?,
+                    coordinate_type: self.coordinate_type,
+                    hops: 0,
                 })
             }
         }
     }
 
+    // Forget the player's current position and history, so their next move starts from the origin again.
+    pub fn clear_position(&mut self, public_key: &PublicKey) -> Option<EncryptedPosition> {
+        let pubkey_str = self.get_public_key_hex(public_key);
+        self.position_history.remove(&pubkey_str);
+        self.encrypted_positions.remove(&pubkey_str)
+    }
+
+    // The player's past positions, oldest first, ending with the current one. Empty unless history_len > 0.
+    pub fn get_position_history(&self, public_key: &PublicKey) -> Vec<EncryptedPosition> {
+        let pubkey_str = self.get_public_key_hex(public_key);
+        self.position_history.get(&pubkey_str)
+            .map(|history| history.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    // Move a player's position and move sequence over to their rotated FHE public key.
+    // `position` must already be re-encrypted under `new_public_key`.
+    pub fn rekey_position(&mut self, old_public_key: &PublicKey, new_public_key: &PublicKey, position: EncryptedPosition) {
+        let old_pubkey_str = self.get_public_key_hex(old_public_key);
+        let new_pubkey_str = self.get_public_key_hex(new_public_key);
+        self.encrypted_positions.remove(&old_pubkey_str);
+        self.encrypted_positions.insert(new_pubkey_str.clone(), position);
+        // past positions stay under the old key, User::decrypt_history reads them with the key ring
+        if let Some(history) = self.position_history.remove(&old_pubkey_str) {
+            self.position_history.insert(new_pubkey_str.clone(), history);
+        }
+        if let Some(sequence) = self.move_sequences.remove(&old_pubkey_str) {
+            self.move_sequences.insert(new_pubkey_str, sequence);
+        }
+    }
+
+    // Estimated noise budget left in `position`, in bits. Decryption fails with TooMuchNoise around 0.
+    // None if the position's coordinate type isn't the one this AVS's noise model was calibrated for.
+    pub fn estimate_noise_budget(&self, position: &EncryptedPosition) -> Option<u32> {
+        if position.coordinate_type != self.coordinate_type {
+            return None;
+        }
+        Some(self.noise_model.fresh_budget.saturating_sub(self.noise_model.cost_per_hop.saturating_mul(position.hops)))
+    }
+
+    pub fn is_noise_budget_low(&self, position: &EncryptedPosition) -> bool {
+        self.estimate_noise_budget(position).is_some_and(|budget| budget < self.low_noise_budget)
+    }
+
+    // Sequence number of the last move applied for this player (0 if they haven't moved yet)
+    pub fn move_sequence(&self, public_key: &PublicKey) -> u64 {
+        let pubkey_str = self.get_public_key_hex(public_key);
+        self.move_sequences.get(&pubkey_str).copied().unwrap_or(0)
+    }
+
+    // Reads the player's previous position and writes back the new one, so moves for the same
+    // player must be applied one at a time or an update is lost. `&mut self` enforces that here;
+    // callers sharing an AVS across tasks should go through `remote_avs::serve_avs`, which queues them.
     pub fn run_contract(
         &mut self,
         new_position: EncryptedPosition,
         public_key: &PublicKey
     ) -> Result<EncryptedPosition, Error> {
+        let prev_position: EncryptedPosition = self.get_prev_position(public_key)?;
+        let new_encrypted_position = self.apply_move(&self.runtime, prev_position, new_position, public_key)?;
+        self.record_move(public_key, &new_encrypted_position);
+        Ok(new_encrypted_position)
+    }
 
-        // get user's prev position
+    // run_contract without saving the result: the player's position, sequence and history are left as they were.
+    // For debugging, e.g. to see what a move would cost in noise budget before making it.
+    pub fn simulate_contract(
+        &self,
+        new_position: EncryptedPosition,
+        public_key: &PublicKey
+    ) -> Result<EncryptedPosition, Error> {
         let prev_position: EncryptedPosition = self.get_prev_position(public_key)?;
+        self.apply_move(&self.runtime, prev_position, new_position, public_key)
+    }
+
+    // Number of runtimes run_contract_concurrent uses (1 by default). Each extra runtime is built from the
+    // AVS's params up front, so concurrent moves don't all wait on one.
+    pub fn set_concurrency(&mut self, workers: usize) -> Result<(), Error> {
+        let extra = workers.saturating_sub(1);
+        self.runtime_pool.truncate(extra);
+        while self.runtime_pool.len() < extra {
+            self.runtime_pool.push(FheRuntime::new(&self.compiled_move_position.metadata.params)?);
+        }
+        Ok(())
+    }
+
+    // Applies a batch of moves, running different players' moves in parallel on the runtime pool.
+    // Each player's moves are applied in the order given, as if run_contract was called for each in turn,
+    // and results come back in the same order as `moves`. Every worker takes the next waiting player
+    // off a shared queue, so one slow player doesn't hold up the others.
+    pub fn run_contract_concurrent(
+        &mut self,
+        moves: Vec<(EncryptedPosition, PublicKey)>,
+    ) -> Vec<Result<EncryptedPosition, Error>> {
+        let mut players: Vec<PlayerMoves> = Vec::new();
+        for (index, (new_position, public_key)) in moves.into_iter().enumerate() {
+            let pubkey_hex = self.get_public_key_hex(&public_key);
+            match players.iter_mut().find(|player| player.pubkey_hex == pubkey_hex) {
+                Some(player) => player.moves.push((index, new_position)),
+                None => players.push(PlayerMoves { pubkey_hex, public_key, moves: vec![(index, new_position)] }),
+            }
+        }
+        let move_count = players.iter().map(|player| player.moves.len()).sum();
+
+        let next_player = std::sync::atomic::AtomicUsize::new(0);
+        let runtimes: Vec<&FheRuntime> = std::iter::once(&self.runtime).chain(&self.runtime_pool).collect();
+        let avs: &AVS = self;
+        let results: Vec<(usize, Result<EncryptedPosition, Error>)> = std::thread::scope(|scope| {
+            let workers: Vec<_> = runtimes.into_iter().map(|runtime| {
+                let (players, next_player) = (&players, &next_player);
+                scope.spawn(move || {
+                    let mut results = Vec::new();
+                    while let Some(player) = players.get(next_player.fetch_add(1, std::sync::atomic::Ordering::Relaxed)) {
+                        let mut position = avs.get_prev_position(&player.public_key);
+                        for (index, new_position) in &player.moves {
+                            let result = position.clone().and_then(|prev| {
+                                avs.apply_move(runtime, prev, new_position.clone(), &player.public_key)
+                            });
+                            // a failed move leaves the position as it was, like run_contract
+                            if let Ok(moved) = &result {
+                                position = Ok(moved.clone());
+                            }
+                            results.push((*index, result));
+                        }
+                    }
+                    results
+                })
+            }).collect();
+            workers.into_iter().flat_map(|worker| worker.join().expect("run_contract_concurrent worker panicked")).collect()
+        });
+
+        let mut ordered: Vec<Option<Result<EncryptedPosition, Error>>> = (0..move_count).map(|_| None).collect();
+        for (index, result) in results {
+            ordered[index] = Some(result);
+        }
+        // record each player's moves in order, so sequences and history match sequential run_contract calls
+        for player in &players {
+            for (index, _) in &player.moves {
+                if let Some(Ok(position)) = &ordered[*index] {
+                    self.record_move(&player.public_key, position);
+                }
+            }
+        }
+        ordered.into_iter().map(|result| result.expect("every move has a result")).collect()
+    }
+
+    // Runs move_position on `runtime`: prev_position + new_position, without touching any state
+    fn apply_move(
+        &self,
+        runtime: &FheRuntime,
+        prev_position: EncryptedPosition,
+        new_position: EncryptedPosition,
+        public_key: &PublicKey,
+    ) -> Result<EncryptedPosition, Error> {
+
+        if new_position.coordinate_type != self.coordinate_type {
+            return Err(Error::unsupported(&format!(
+                "move is encrypted as {} coordinates, this AVS moves {} coordinates",
+                new_position.coordinate_type, self.coordinate_type
+            )));
+        }
 
         // run movement function on encrypted position
-        let results = self.runtime.run(
-            &self.compiled_move_position,
-            vec![prev_position.x, prev_position.y, new_position.x, new_position.y],
+        let results = self.run_named_on(
+            runtime,
+            MOVE_POSITION,
+            vec![
+                prev_position.x.clone(), prev_position.y.clone(), prev_position.z.clone(),
+                new_position.x, new_position.y, new_position.z
+            ],
             public_key
         )?;
 
         let new_encrypted_position = EncryptedPosition {
+            x: results[0].clone(),
+            y: results[1].clone(),
+            z: results[2].clone(),
+            coordinate_type: self.coordinate_type,
+            hops: prev_position.hops.max(new_position.hops) + 1,
+        };
+        if self.is_noise_budget_low(&new_encrypted_position) {
+            eprintln!(
+                "[WARN] position is {} moves from a fresh encryption, estimated noise budget {:?} bits. \
+                Re-encrypt it (User::reencrypt_positions) before decryption fails with TooMuchNoise",
+                new_encrypted_position.hops, self.estimate_noise_budget(&new_encrypted_position)
+            );
+        }
+        println!("new encrypted position x: {}", WrapperCiphertext(&new_encrypted_position.x));
+        println!("new encrypted position y: {}", WrapperCiphertext(&new_encrypted_position.y));
+        println!("new encrypted position z: {}", WrapperCiphertext(&new_encrypted_position.z));
+        Ok(new_encrypted_position)
+    }
+
+    // save new encrypted position to state
+    fn record_move(&mut self, public_key: &PublicKey, new_encrypted_position: &EncryptedPosition) {
+        let pubkey_str = self.get_public_key_hex(public_key);
+        self.encrypted_positions.insert(pubkey_str.clone(), new_encrypted_position.clone());
+        if self.history_len > 0 {
+            let history = self.position_history.entry(pubkey_str.clone()).or_default();
+            history.push_back(new_encrypted_position.clone());
+            while history.len() > self.history_len {
+                history.pop_front();
+            }
+        }
+        *self.move_sequences.entry(pubkey_str).or_insert(0) += 1;
+    }
+
+    // Fixed-point counterpart of run_contract, with its own position state
+    pub fn run_fixed_contract(
+        &mut self,
+        new_position: EncryptedFixedPosition,
+        public_key: &PublicKey
+    ) -> Result<EncryptedFixedPosition, Error> {
+
+        let pubkey_str = self.get_public_key_hex(public_key);
+        let prev_position = match self.encrypted_fixed_positions.get(&pubkey_str) {
+            Some(p) => p.clone(),
+            None => EncryptedFixedPosition {
+                x: self.runtime.encrypt(Signed::from(0), public_key)?,
+                y: self.runtime.encrypt(Signed::from(0), public_key)?,
+            },
+        };
+
+        let results = self.run_named(
+            MOVE_FIXED_POSITION,
+            vec![prev_position.x, prev_position.y, new_position.x, new_position.y],
+            public_key
+        )?;
+
+        let new_encrypted_position = EncryptedFixedPosition {
             x: results[0].clone(),
             y: results[1].clone()
         };
-        println!("{}", WrapperCiphertext(&new_encrypted_position.x));
+        println!("new encrypted fixed-point position x: {}", WrapperCiphertext(&new_encrypted_position.x));
+
+        self.encrypted_fixed_positions.insert(pubkey_str.clone(), new_encrypted_position.clone());
+        *self.move_sequences.entry(pubkey_str).or_insert(0) += 1;
+
+        Ok(new_encrypted_position)
+    }
+
+    // Encrypted range² - distance² between two positions encrypted under `public_key`,
+    // see User::decrypt_proximity. Neither position is stored or moved.
+    pub fn check_proximity(
+        &self,
+        pos_a: &EncryptedPosition,
+        pos_b: &EncryptedPosition,
+        range: f64,
+        public_key: &PublicKey
+    ) -> Result<Ciphertext, Error> {
+
+        // distance_within_range is only compiled for Rational coordinates
+        if pos_a.coordinate_type != CoordinateType::Rational || pos_b.coordinate_type != CoordinateType::Rational {
+            return Err(Error::unsupported("proximity checks need rational coordinates"));
+        }
+
+        let program = self.programs.get(DISTANCE_WITHIN_RANGE)
+            .ok_or_else(|| Error::unsupported(&format!("no FHE program registered as {DISTANCE_WITHIN_RANGE}")))?;
+        let inputs: Vec<FheProgramInput> = vec![
+            pos_a.x.clone().into(),
+            pos_a.y.clone().into(),
+            pos_a.z.clone().into(),
+            pos_b.x.clone().into(),
+            pos_b.y.clone().into(),
+            pos_b.z.clone().into(),
+            Rational::try_from(range * range)?.into(),
+        ];
+        let mut results = self.runtime.run(program, inputs, public_key)?;
+        Ok(results.remove(0))
+    }
+
+    // check_proximity of `center` against every stored position, keyed like encrypted_positions, for the key
+    // holder to decrypt with User::decrypt_proximity. Nothing is decrypted here, so the AVS doesn't learn who's
+    // in range. FHE can't compare ciphertexts under different keys, so this only means anything for positions
+    // encrypted under `public_key` (e.g. players who all move under an operator's key); non-rational ones are skipped.
+    pub fn peers_within_range(
+        &self,
+        center: &EncryptedPosition,
+        range: f64,
+        public_key: &PublicKey
+    ) -> Result<Vec<(String, Ciphertext)>, Error> {
+        let mut results = Vec::new();
+        for (peer, position) in &self.encrypted_positions {
+            if position.coordinate_type != CoordinateType::Rational {
+                continue;
+            }
+            results.push((peer.clone(), self.check_proximity(center, position, range, public_key)?));
+        }
+        results.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(results)
+    }
+
+    // Encrypted squared distance between two positions encrypted under `public_key`, zero when they're
+    // on the same spot, see User::decrypt_collision. Neither position is stored or moved.
+    pub fn check_collision(
+        &self,
+        pos_a: &EncryptedPosition,
+        pos_b: &EncryptedPosition,
+        public_key: &PublicKey
+    ) -> Result<Ciphertext, Error> {
+
+        // positions_equal is only compiled for Rational coordinates
+        if pos_a.coordinate_type != CoordinateType::Rational || pos_b.coordinate_type != CoordinateType::Rational {
+            return Err(Error::unsupported("collision checks need rational coordinates"));
+        }
+
+        let program = self.programs.get(POSITIONS_EQUAL)
+            .ok_or_else(|| Error::unsupported(&format!("no FHE program registered as {POSITIONS_EQUAL}")))?;
+        let inputs: Vec<FheProgramInput> = vec![
+            pos_a.x.clone().into(),
+            pos_a.y.clone().into(),
+            pos_a.z.clone().into(),
+            pos_b.x.clone().into(),
+            pos_b.y.clone().into(),
+            pos_b.z.clone().into(),
+        ];
+        let mut results = self.runtime.run(program, inputs, public_key)?;
+        Ok(results.remove(0))
+    }
+
+    // Applies a relative step (see User::create_relative_move_transaction) to the player's grid
+    // position, starting from the origin. Shares the move sequence with absolute moves.
+    pub fn run_relative_contract(
+        &mut self,
+        delta: EncryptedRelativePosition,
+        public_key: &PublicKey
+    ) -> Result<EncryptedRelativePosition, Error> {
 
-        // save new encrypted position to state
         let pubkey_str = self.get_public_key_hex(public_key);
-        self.encrypted_positions.insert(pubkey_str, new_encrypted_position.clone());
+        let prev_position = match self.encrypted_relative_positions.get(&pubkey_str) {
+            Some(p) => p.clone(),
+            None => EncryptedRelativePosition {
+                x: self.runtime.encrypt(Signed::from(0), public_key)?,
+                y: self.runtime.encrypt(Signed::from(0), public_key)?,
+                z: self.runtime.encrypt(Signed::from(0), public_key)?,
+            },
+        };
+
+        let results = self.run_named(
+            MOVE_RELATIVE,
+            vec![prev_position.x, prev_position.y, prev_position.z, delta.x, delta.y, delta.z],
+            public_key
+        )?;
+
+        let new_encrypted_position = EncryptedRelativePosition {
+            x: results[0].clone(),
+            y: results[1].clone(),
+            z: results[2].clone()
+        };
+        println!("new encrypted relative position x: {}", WrapperCiphertext(&new_encrypted_position.x));
+
+        self.encrypted_relative_positions.insert(pubkey_str.clone(), new_encrypted_position.clone());
+        *self.move_sequences.entry(pubkey_str).or_insert(0) += 1;
 
         Ok(new_encrypted_position)
     }
 }
 
+// Short, stable fingerprint of a ciphertext for logs: the first 8 bytes of its SHA-256, hex encoded.
+// Use this wherever a ciphertext would be logged, full dumps are +870kb and end up in log files.
+pub fn ciphertext_digest(ciphertext: &Ciphertext) -> String {
+    let ciphertext_bytes = bincode::serialize(ciphertext)
+        .expect("bincode::serialize");
+    hex::encode(&Sha256::digest(&ciphertext_bytes)[..8])
+}
+
+// Short fingerprint of an ECDH public key: the first 8 bytes of the SHA-256 of its compressed SEC1 encoding,
+// in 4 hex digit groups so two operators can read them out to each other to rule out a MITM on the key exchange.
+pub fn public_key_fingerprint(public_key: &k256::PublicKey) -> String {
+    let digest = Sha256::digest(ecdh::public_key_to_sec1_compressed(public_key));
+    digest[..8].chunks(2).map(hex::encode).collect::<Vec<_>>().join(" ")
+}
+
 pub struct WrapperCiphertext<'a>(pub &'a Ciphertext);
 pub struct WrapperPrivateKey<'a>(pub &'a PrivateKey);
 
+impl <'a>WrapperCiphertext<'a> {
+    // Full hex dump of the ciphertext, for debugging only
+    pub fn full(&self) -> String {
+        let ciphertext_bytes = bincode::serialize(&self.0)
+            .expect("bincode::serialize");
+        hex::encode(&ciphertext_bytes)
+    }
+}
+
 impl <'a>Display for WrapperCiphertext<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> fmt::Result {
 
         let ciphertext_bytes = bincode::serialize(&self.0)
             .expect("bincode::serialize");
 
-        write!(f, "{}", hex::encode(&ciphertext_bytes))?;
-        write!(f, "\n>>> Encrypted Ciphertext length: {}", ciphertext_bytes.len())?;
-        Ok(())
+        write!(f, "sha256:{} ({} bytes)", ciphertext_digest(self.0), ciphertext_bytes.len())
     }
 }
 
@@ -273,3 +1495,605 @@ impl <'a>Display for WrapperPrivateKey<'a> {
     }
 }
 
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup_user(name: &str) -> (AVS, User) {
+        let avs = AVS::setup().unwrap();
+        let user = User::setup(&avs.compiled_move_position.metadata.params, name).unwrap();
+        (avs, user)
+    }
+
+    #[test]
+    fn position_validate_reports_bad_field() {
+        // NaN != NaN, so match on the variant instead
+        assert!(matches!(
+            Position { x: f64::NAN, y: 1.0, z: 0.0 }.validate(),
+            Err(PositionError::NotFinite { field: "x", .. })
+        ));
+        assert_eq!(
+            Position { x: 1.0, y: f64::INFINITY, z: 0.0 }.validate(),
+            Err(PositionError::NotFinite { field: "y", value: f64::INFINITY })
+        );
+        assert_eq!(
+            Position { x: 1.0, y: -1e300, z: 0.0 }.validate(),
+            Err(PositionError::OutOfRange { field: "y", value: -1e300 })
+        );
+        assert_eq!(Position { x: 3.5, y: -2.0, z: 0.0 }.validate(), Ok(()));
+
+        // a map range on top
+        assert!(matches!(
+            Position { x: f64::NAN, y: 1.0, z: 0.0 }.validate_within(100.0),
+            Err(PositionError::NotFinite { field: "x", .. })
+        ));
+        assert_eq!(
+            Position { x: 1.0, y: 2.0, z: -100.5 }.validate_within(100.0),
+            Err(PositionError::OutsideMap { field: "z", value: -100.5, max_coordinate: 100.0 })
+        );
+        assert_eq!(Position { x: 100.0, y: -100.0, z: 0.0 }.validate_within(100.0), Ok(()));
+    }
+
+    #[test]
+    fn ciphertext_expansion_matches_encoded_record() {
+        let (_avs, user) = setup_user("alice");
+        assert!(user.measure_ciphertext_expansion().unwrap().ratio() > 1.0);
+
+        let encrypted_position = user.create_move_transaction(Position { x: 1.0, y: 2.0, z: 0.0 }).unwrap();
+        let expansion = CiphertextExpansion::measure(&encrypted_position).unwrap();
+        let record = encode_position_record(&encrypted_position).unwrap();
+
+        assert_eq!(expansion.ciphertext_bytes, record.len());
+        assert_eq!(expansion.ratio(), record.len() as f64 / std::mem::size_of::<Position>() as f64);
+    }
+
+    #[test]
+    fn bincode_position_records_are_smaller_than_json() {
+        let (_avs, user) = setup_user("alice");
+        let encrypted_position = user.create_move_transaction(Position { x: 1.0, y: 2.0, z: 0.0 }).unwrap();
+
+        let record = encode_position_record(&encrypted_position).unwrap();
+        let legacy_record = serde_json::to_vec(&encrypted_position).unwrap();
+        println!(
+            "position record: {} bytes bincode vs {} bytes JSON ({:.1}% smaller)",
+            record.len(),
+            legacy_record.len(),
+            100.0 * (1.0 - record.len() as f64 / legacy_record.len() as f64)
+        );
+        assert!(record.len() < legacy_record.len());
+        assert_eq!(record[0], POSITION_RECORD_BINCODE);
+
+        // both formats decode to the same position
+        for record in [&record, &legacy_record] {
+            let position = user.decrypt_own_position(decode_position_record(record).unwrap()).unwrap();
+            assert_eq!((position.x, position.y), (1.0, 2.0));
+        }
+
+        assert!(matches!(decode_position_record(&[]), Err(PositionRecordError::Empty)));
+        assert!(matches!(decode_position_record(&[7, 0, 0]), Err(PositionRecordError::UnknownFormat(7))));
+        assert!(matches!(decode_position_record(&record[..100]), Err(PositionRecordError::Bincode(_))));
+    }
+
+    #[test]
+    fn compressed_position_records_round_trip_and_are_smaller() {
+        let (_, user) = setup_user("alice");
+        let encrypted_position = user.create_move_transaction(Position { x: 1.0, y: 2.0, z: 3.0 }).unwrap();
+        let encoded = encode_position_record(&encrypted_position).unwrap();
+
+        let compressed = compress_record(&encoded, DEFAULT_ZSTD_LEVEL).unwrap();
+        assert_eq!(compressed[0], RECORD_ZSTD);
+        assert!(compressed.len() < encoded.len(), "{} >= {}", compressed.len(), encoded.len());
+        assert!(decompress_record(&compressed).unwrap() == encoded);
+        // uncompressed records still load
+        assert!(decompress_record(&encoded).unwrap() == encoded);
+
+        for record in [&compressed, &encoded] {
+            let position = user.decrypt_own_position(decode_position_record(record).unwrap()).unwrap();
+            assert_eq!((position.x, position.y, position.z), (1.0, 2.0, 3.0));
+        }
+        assert!(matches!(
+            decode_position_record(&[RECORD_ZSTD, 1, 2, 3]),
+            Err(PositionRecordError::Zstd(_))
+        ));
+    }
+
+    #[test]
+    fn run_contract_applies_moves_cumulatively_and_in_sequence() {
+        let (mut avs, user) = setup_user("alice");
+        assert_eq!(avs.move_sequence(&user.fhe_public_key), 0);
+
+        let first = user.create_move_transaction(Position { x: 3.0, y: 2.0, z: 0.0 }).unwrap();
+        let second = user.create_move_transaction(Position { x: 1.0, y: 5.0, z: 0.0 }).unwrap();
+        avs.run_contract(first, &user.fhe_public_key).unwrap();
+        let new_position = avs.run_contract(second, &user.fhe_public_key).unwrap();
+
+        let position = user.decrypt_own_position(new_position).unwrap();
+        assert_eq!((position.x, position.y), (4.0, 7.0));
+        assert_eq!(avs.move_sequence(&user.fhe_public_key), 2);
+    }
+
+    #[test]
+    fn position_history_keeps_the_last_history_len_moves() {
+        let (mut avs, user) = setup_user("alice");
+        let moves = [(1.0, 0.0), (0.0, 2.0), (-3.0, 1.0)];
+
+        // disabled by default
+        let move_tx = user.create_move_transaction(Position { x: 1.0, y: 0.0, z: 0.0 }).unwrap();
+        avs.run_contract(move_tx, &user.fhe_public_key).unwrap();
+        assert!(avs.get_position_history(&user.fhe_public_key).is_empty());
+        avs.clear_position(&user.fhe_public_key);
+
+        avs.history_len = 3;
+        for (x, y) in moves {
+            let move_tx = user.create_move_transaction(Position { x, y, z: 0.0 }).unwrap();
+            avs.run_contract(move_tx, &user.fhe_public_key).unwrap();
+        }
+        let path: Vec<(f64, f64)> = user.decrypt_history(&avs.get_position_history(&user.fhe_public_key)).unwrap()
+            .into_iter()
+            .map(|p| (p.x, p.y))
+            .collect();
+        assert_eq!(path, vec![(1.0, 0.0), (1.0, 2.0), (-2.0, 3.0)]);
+
+        // the oldest position is dropped once the history is full
+        let move_tx = user.create_move_transaction(Position { x: 0.0, y: 1.0, z: 0.0 }).unwrap();
+        avs.run_contract(move_tx, &user.fhe_public_key).unwrap();
+        let history = avs.get_position_history(&user.fhe_public_key);
+        assert_eq!(history.len(), 3);
+        let first = user.decrypt_own_position(history[0].clone()).unwrap();
+        assert_eq!((first.x, first.y), (1.0, 2.0));
+
+        avs.clear_position(&user.fhe_public_key);
+        assert!(avs.get_position_history(&user.fhe_public_key).is_empty());
+    }
+
+    #[test]
+    fn noise_budget_warning_comes_before_decryption_fails() {
+        let (mut avs, user) = setup_user("alice");
+        assert!(avs.noise_model.cost_per_hop > 0, "{:?}", avs.noise_model);
+
+        let mut hops = 0;
+        let encrypted_position = loop {
+            let move_tx = user.create_move_transaction(Position { x: 1.0, y: 1.0, z: 0.0 }).unwrap();
+            let encrypted_position = avs.run_contract(move_tx, &user.fhe_public_key).unwrap();
+            hops += 1;
+            if avs.is_noise_budget_low(&encrypted_position) {
+                break encrypted_position;
+            }
+            assert!(hops < 100, "noise budget never ran low");
+        };
+        assert_eq!(encrypted_position.hops, hops);
+        let estimate = avs.estimate_noise_budget(&encrypted_position).unwrap();
+        let measured = user.runtime.measure_noise_budget(&encrypted_position.x, &user.fhe_private_key).unwrap();
+        assert!(measured > 0, "estimated {estimate} bits, measured {measured}");
+        let position = user.decrypt_own_position(encrypted_position.clone()).unwrap();
+        assert_eq!((position.x, position.y), (hops as f64, hops as f64));
+
+        // re-encrypting resets the estimate to a fresh budget
+        let fresh = user.reencrypt_positions(&[encrypted_position]).unwrap().remove(0);
+        assert_eq!(avs.estimate_noise_budget(&fresh), Some(avs.noise_model.fresh_budget));
+        avs.rekey_position(&user.fhe_public_key, &user.fhe_public_key, fresh);
+        let move_tx = user.create_move_transaction(Position { x: 1.0, y: 0.0, z: 0.0 }).unwrap();
+        let moved = avs.run_contract(move_tx, &user.fhe_public_key).unwrap();
+        assert_eq!(moved.hops, 1);
+        assert!(!avs.is_noise_budget_low(&moved));
+    }
+
+    #[test]
+    fn run_contract_sums_3d_moves() {
+        let (mut avs, user) = setup_user("alice");
+
+        let first = user.create_move_transaction(Position { x: 3.0, y: 2.0, z: 10.0 }).unwrap();
+        let second = user.create_move_transaction(Position { x: 1.0, y: -5.0, z: 2.5 }).unwrap();
+        avs.run_contract(first, &user.fhe_public_key).unwrap();
+        let new_position = avs.run_contract(second, &user.fhe_public_key).unwrap();
+
+        let position = user.decrypt_own_position(new_position).unwrap();
+        assert_eq!((position.x, position.y, position.z), (4.0, -3.0, 12.5));
+
+        // z is optional in MOVE, 2D moves stay at the same altitude
+        let position: Position = serde_json::from_str(r#"{"x":1,"y":2}"#).unwrap();
+        assert_eq!(position.z, 0.0);
+        let position: Position = serde_json::from_str(r#"{"x":1,"y":2,"z":3}"#).unwrap();
+        assert_eq!((position.x, position.y, position.z), (1.0, 2.0, 3.0));
+    }
+
+    #[test]
+    fn sealed_private_key_needs_the_right_password() {
+        let (_avs, user) = setup_user("alice");
+        let sealed = user.seal_private_key("correct horse battery staple");
+
+        assert!(matches!(
+            User::unseal_private_key(&sealed, "hunter2"),
+            Err(SealError::WrongPassword)
+        ));
+        assert!(matches!(
+            User::unseal_private_key(&sealed[..SEAL_SALT_LEN - 1], "correct horse battery staple"),
+            Err(SealError::Malformed)
+        ));
+
+        let private_key = User::unseal_private_key(&sealed, "correct horse battery staple").unwrap();
+        let encrypted_position = user.create_move_transaction(Position { x: 3.0, y: 2.0, z: 0.0 }).unwrap();
+        let position = user.decrypt_position_with_key(&encrypted_position, &private_key).unwrap();
+        assert_eq!((position.x, position.y), (3.0, 2.0));
+    }
+
+    #[fhe_program(scheme="bfv")]
+    fn subtract(a: Cipher<Rational>, b: Cipher<Rational>) -> Cipher<Rational> {
+        a - b
+    }
+
+    #[fhe_program(scheme="bfv")]
+    fn multiply(a: Cipher<Rational>, b: Cipher<Rational>) -> Cipher<Rational> {
+        a * b
+    }
+
+    #[test]
+    fn registered_program_runs_by_name() {
+        let (mut avs, user) = setup_user("alice");
+        let params = avs.compiled_move_position.metadata.params.clone();
+        let app = Compiler::new()
+            .fhe_program(subtract)
+            .fhe_program(multiply)
+            .with_params(&params)
+            .compile()
+            .unwrap();
+        let compiled = app.get_fhe_program(subtract).unwrap().clone();
+        avs.register_program("multiply", app.get_fhe_program(multiply).unwrap().clone()).unwrap();
+
+        let mut mismatched = compiled.clone();
+        mismatched.metadata.params.lattice_dimension *= 2;
+        assert!(avs.register_program("subtract", mismatched).is_err());
+
+        avs.register_program("subtract", compiled.clone()).unwrap();
+        assert!(matches!(avs.register_program("subtract", compiled.clone()), Err(Error::NameCollision)));
+
+        let a = user.runtime.encrypt(Rational::try_from(7.0).unwrap(), &user.fhe_public_key).unwrap();
+        let b = user.runtime.encrypt(Rational::try_from(2.5).unwrap(), &user.fhe_public_key).unwrap();
+        let result = avs.run_named("subtract", vec![a.clone(), b.clone()], &user.fhe_public_key).unwrap();
+        let difference: Rational = user.runtime.decrypt(&result[0], &user.fhe_private_key).unwrap();
+        assert_eq!(f64::from(difference), 4.5);
+        let result = avs.run_named("multiply", vec![a.clone(), b.clone()], &user.fhe_public_key).unwrap();
+        let product: Rational = user.runtime.decrypt(&result[0], &user.fhe_private_key).unwrap();
+        assert_eq!(f64::from(product), 17.5);
+        assert!(avs.run_named("divide", vec![a, b], &user.fhe_public_key).is_err());
+
+        // the built-in move program is registered too, and can't be replaced
+        assert!(matches!(avs.register_program(MOVE_POSITION, compiled), Err(Error::NameCollision)));
+        let move_tx = user.create_move_transaction(Position { x: 3.0, y: 2.0, z: 1.0 }).unwrap();
+        let start = avs.get_prev_position(&user.fhe_public_key).unwrap();
+        let inputs = vec![start.x, start.y, start.z, move_tx.x, move_tx.y, move_tx.z];
+        let moved = avs.run_named(MOVE_POSITION, inputs, &user.fhe_public_key).unwrap();
+        let x: Rational = user.runtime.decrypt(&moved[0], &user.fhe_private_key).unwrap();
+        assert_eq!(f64::from(x), 3.0);
+    }
+
+    #[test]
+    fn ciphertext_digest_is_stable_and_display_is_compact() {
+        let (_avs, user) = setup_user("alice");
+        let encrypted_position = user.create_move_transaction(Position { x: 3.0, y: 2.0, z: 0.0 }).unwrap();
+
+        let digest = ciphertext_digest(&encrypted_position.x);
+        assert_eq!(digest.len(), 16);
+        assert_eq!(digest, ciphertext_digest(&encrypted_position.x.clone()));
+        assert_ne!(digest, ciphertext_digest(&encrypted_position.y));
+
+        let wrapper = WrapperCiphertext(&encrypted_position.x);
+        let display = wrapper.to_string();
+        assert!(display.contains(&digest));
+        assert!(display.len() < 64, "{display}");
+        assert_eq!(wrapper.full().len(), 2 * bincode::serialize(&encrypted_position.x).unwrap().len());
+    }
+
+    #[test]
+    fn fixed_point_position_round_trips_and_is_smaller() {
+        let (mut avs, user) = setup_user("alice");
+
+        let position = Position { x: 3.3, y: -2.5, z: 0.0 };
+        let fixed = FixedPosition::from_position(&position).unwrap();
+        let round_tripped = fixed.to_position();
+        assert!((round_tripped.x - position.x).abs() <= 0.5 / FIXED_POINT_SCALE);
+        assert_eq!(round_tripped.y, position.y);
+        assert!(matches!(
+            FixedPosition::from_position(&Position { x: 40_000.0, y: 0.0, z: 0.0 }),
+            Err(PositionError::OutOfFixedPointRange { field: "x", .. })
+        ));
+
+        let move_tx = user.create_fixed_move_transaction(position.clone()).unwrap();
+        let fixed_bytes = bincode::serialize(&move_tx.x).unwrap().len();
+        let rational_tx = user.create_move_transaction(position.clone()).unwrap();
+        let rational_bytes = bincode::serialize(&rational_tx.x).unwrap().len();
+        assert!(fixed_bytes < rational_bytes, "{fixed_bytes} >= {rational_bytes}");
+
+        avs.run_fixed_contract(move_tx, &user.fhe_public_key).unwrap();
+        let second = user.create_fixed_move_transaction(Position { x: 1.0, y: 0.25, z: 0.0 }).unwrap();
+        let new_position = avs.run_fixed_contract(second, &user.fhe_public_key).unwrap();
+        let new_position = user.decrypt_own_fixed_position(new_position).unwrap();
+        assert!((new_position.x - 4.3).abs() <= 1.0 / FIXED_POINT_SCALE);
+        assert_eq!(new_position.y, -2.25);
+    }
+
+    #[test]
+    fn relative_moves_add_signed_steps() {
+        let (mut avs, user) = setup_user("alice");
+
+        let to_start = user.create_relative_move_transaction(Position { x: 2.0, y: 2.0, z: 0.0 }).unwrap();
+        avs.run_relative_contract(to_start, &user.fhe_public_key).unwrap();
+        let step = user.create_relative_move_transaction(Position { x: -1.0, y: 3.0, z: 0.0 }).unwrap();
+        let new_position = avs.run_relative_contract(step, &user.fhe_public_key).unwrap();
+
+        let position = user.decrypt_own_relative_position(new_position).unwrap();
+        assert_eq!((position.x, position.y, position.z), (1.0, 5.0, 0.0));
+        assert_eq!(avs.move_sequence(&user.fhe_public_key), 2);
+
+        assert!(matches!(
+            Position { x: 0.5, y: 0.0, z: 0.0 }.to_steps(),
+            Err(PositionError::NotWholeNumber { field: "x", .. })
+        ));
+    }
+
+    #[test]
+    fn signed_positions_stay_exact_and_keep_more_noise_budget_than_rational() {
+        const HOPS: usize = 8;
+        let mut noise_budgets = vec![];
+        for coordinate_type in [CoordinateType::Rational, CoordinateType::Signed] {
+            let mut avs = AVS::setup_with(coordinate_type).unwrap();
+            let mut user = User::setup(&avs.compiled_move_position.metadata.params, "alice").unwrap();
+            user.coordinate_type = coordinate_type;
+
+            let mut encrypted_position = None;
+            for _ in 0..HOPS {
+                let move_tx = user.create_move_transaction(Position { x: 1.0, y: -2.0, z: 3.0 }).unwrap();
+                encrypted_position = Some(avs.run_contract(move_tx, &user.fhe_public_key).unwrap());
+            }
+            let encrypted_position = encrypted_position.unwrap();
+            assert_eq!(encrypted_position.coordinate_type, coordinate_type);
+            noise_budgets.push(user.runtime.measure_noise_budget(&encrypted_position.x, &user.fhe_private_key).unwrap());
+
+            if coordinate_type == CoordinateType::Signed {
+                let position = user.decrypt_own_position(encrypted_position).unwrap();
+                assert_eq!((position.x, position.y, position.z), (8.0, -16.0, 24.0));
+            }
+        }
+        // Rational additions multiply numerators and denominators, Signed ones don't
+        assert!(noise_budgets[1] > noise_budgets[0], "noise budgets {noise_budgets:?}");
+    }
+
+    #[test]
+    fn twenty_signed_moves_still_decrypt_exactly() {
+        let mut avs = AVS::setup_with(CoordinateType::Signed).unwrap();
+        let mut user = User::setup(&avs.compiled_move_position.metadata.params, "alice").unwrap();
+        user.coordinate_type = CoordinateType::Signed;
+
+        let mut encrypted_position = None;
+        for i in 0..20 {
+            let step = Position { x: (i - 7) as f64, y: (3 * i + 1) as f64, z: -i as f64 };
+            let move_tx = user.create_move_transaction(step).unwrap();
+            encrypted_position = Some(avs.run_contract(move_tx, &user.fhe_public_key).unwrap());
+        }
+
+        let encrypted_position = encrypted_position.unwrap();
+        assert_eq!(encrypted_position.hops, 20);
+        let position = user.decrypt_own_position(encrypted_position).unwrap();
+        assert_eq!((position.x, position.y, position.z), (50.0, 590.0, -190.0));
+    }
+
+    #[test]
+    fn fractional_positions_move_and_coordinate_types_must_match() {
+        let mut avs = AVS::setup_with(CoordinateType::Fractional).unwrap();
+        let mut user = User::setup(&avs.compiled_move_position.metadata.params, "alice").unwrap();
+        user.coordinate_type = CoordinateType::Fractional;
+
+        let first = user.create_move_transaction(Position { x: 1.5, y: -0.25, z: 0.0 }).unwrap();
+        avs.run_contract(first, &user.fhe_public_key).unwrap();
+        let second = user.create_move_transaction(Position { x: 0.25, y: 1.0, z: 2.0 }).unwrap();
+        let encrypted_position = avs.run_contract(second, &user.fhe_public_key).unwrap();
+        let position = user.decrypt_own_position(encrypted_position).unwrap();
+        assert_eq!((position.x, position.y, position.z), (1.75, 0.75, 2.0));
+
+        // a Rational move is rejected rather than run through the Fractional program
+        user.coordinate_type = CoordinateType::Rational;
+        let rational_move = user.create_move_transaction(Position { x: 1.0, y: 0.0, z: 0.0 }).unwrap();
+        assert!(avs.run_contract(rational_move, &user.fhe_public_key).is_err());
+        assert_eq!(avs.move_sequence(&user.fhe_public_key), 2);
+
+        // Signed coordinates must be whole numbers
+        user.coordinate_type = CoordinateType::Signed;
+        assert!(user.create_move_transaction(Position { x: 0.5, y: 0.0, z: 0.0 }).is_err());
+
+        assert_eq!("Signed".parse::<CoordinateType>(), Ok(CoordinateType::Signed));
+        assert!("integer".parse::<CoordinateType>().is_err());
+    }
+
+    #[test]
+    fn check_collision_matches_identical_positions_only() {
+        let (mut avs, user) = setup_user("alice");
+        let tile = user.create_move_transaction(Position { x: 3.3, y: -2.0, z: 1.0 }).unwrap();
+
+        // identical, including a position reached in two steps that f64 can't represent exactly
+        let same = user.create_move_transaction(Position { x: 3.3, y: -2.0, z: 1.0 }).unwrap();
+        assert!(user.decrypt_collision(&avs.check_collision(&tile, &same, &user.fhe_public_key).unwrap()).unwrap());
+        // start from (1.1, -1, 0.5) rather than the origin, so the sum is a single move_position hop
+        let start = user.create_move_transaction(Position { x: 1.1, y: -1.0, z: 0.5 }).unwrap();
+        avs.encrypted_positions.insert(avs.get_public_key_hex(&user.fhe_public_key), start);
+        let two_steps = avs.run_contract(
+            user.create_move_transaction(Position { x: 2.2, y: -1.0, z: 0.5 }).unwrap(),
+            &user.fhe_public_key
+        ).unwrap();
+        assert!(user.decrypt_collision(&avs.check_collision(&tile, &two_steps, &user.fhe_public_key).unwrap()).unwrap());
+
+        // near-identical is a miss
+        let next_to = user.create_move_transaction(Position { x: 3.3, y: -2.0, z: 1.001 }).unwrap();
+        assert!(!user.decrypt_collision(&avs.check_collision(&tile, &next_to, &user.fhe_public_key).unwrap()).unwrap());
+    }
+
+    #[test]
+    fn check_proximity_compares_encrypted_distance_to_range() {
+        let (avs, user) = setup_user("alice");
+        let pos_a = user.create_move_transaction(Position { x: 1.0, y: 1.0, z: 0.0 }).unwrap();
+        let pos_b = user.create_move_transaction(Position { x: 1.0, y: 4.0, z: 0.0 }).unwrap();
+
+        let within_5 = avs.check_proximity(&pos_a, &pos_b, 5.0, &user.fhe_public_key).unwrap();
+        assert!(user.decrypt_proximity(&within_5).unwrap());
+        let within_2 = avs.check_proximity(&pos_a, &pos_b, 2.0, &user.fhe_public_key).unwrap();
+        assert!(!user.decrypt_proximity(&within_2).unwrap());
+    }
+
+    #[test]
+    fn peers_within_range_flags_positions_near_the_center() {
+        let (mut avs, operator) = setup_user("operator");
+        // every position under the operator's key, so they can be compared with one center
+        let positions = [("bob", (4.0, 1.0, 0.0)), ("carol", (0.0, -3.0, 4.0)), ("dave", (6.0, 0.0, 0.0)), ("erin", (-2.0, -2.0, -2.0))];
+        for (peer, (x, y, z)) in positions {
+            let position = operator.create_move_transaction(Position { x, y, z }).unwrap();
+            avs.encrypted_positions.insert(peer.to_string(), position);
+        }
+
+        let center = operator.create_move_transaction(Position { x: 1.0, y: 0.0, z: 0.0 }).unwrap();
+        let flagged: Vec<(String, bool)> = avs.peers_within_range(&center, 5.0, &operator.fhe_public_key).unwrap()
+            .into_iter()
+            .map(|(peer, proximity)| (peer, operator.decrypt_proximity(&proximity).unwrap()))
+            .collect();
+        // bob ~3.2, carol ~5.1, dave 5 (on the edge counts), erin ~4.1
+        assert_eq!(flagged, vec![
+            ("bob".to_string(), true),
+            ("carol".to_string(), false),
+            ("dave".to_string(), true),
+            ("erin".to_string(), true),
+        ]);
+    }
+
+    #[test]
+    fn corrupted_shared_fhe_key_is_an_error_not_a_panic() {
+        let (avs, alice) = setup_user("alice");
+        let bob = User::setup(&avs.compiled_move_position.metadata.params, "bob").unwrap();
+
+        let record_key = form_encrypted_fhe_key("alice-peer-id");
+        let mut encrypted_key = alice.encrypt_fhe_key_for_peer("bob-peer-id", &bob.ecdh_public_key, &record_key).unwrap();
+        assert!(bob.decrypt_fhe_key_from_peer(&encrypted_key, "alice-peer-id", &alice.ecdh_public_key, &record_key).is_ok());
+        // copied under another peer's record key
+        let other_record_key = form_encrypted_fhe_key("mallory-peer-id");
+        assert!(bob.decrypt_fhe_key_from_peer(&encrypted_key, "alice-peer-id", &alice.ecdh_public_key, &other_record_key).is_err());
+
+        encrypted_key[20] ^= 1;
+        assert!(bob.decrypt_fhe_key_from_peer(&encrypted_key, "alice-peer-id", &alice.ecdh_public_key, &record_key).is_err());
+    }
+
+    #[test]
+    fn position_encrypted_under_a_peers_public_key_only_that_peer_decrypts() {
+        let (avs, alice) = setup_user("alice");
+        let bob = User::setup(&avs.compiled_move_position.metadata.params, "bob").unwrap();
+        let position = Position { x: 4.0, y: -2.0, z: 1.0 };
+        assert!(matches!(
+            alice.create_move_transaction_for_peer(position.clone(), "bob-peer-id"),
+            Err(AppError::PeerNotFound(_))
+        ));
+
+        // bob's public key, as carried by his FHE_PUBLIC_KEY record
+        let mut alice = alice;
+        let bob_public_key = bincode::deserialize(&bincode::serialize(&bob.fhe_public_key).unwrap()).unwrap();
+        alice.peer_fhe_public_keys.insert("bob-peer-id".to_string(), bob_public_key);
+        let encrypted = alice.create_move_transaction_for_peer(position, "bob-peer-id").unwrap();
+
+        assert!(bob.peer_fhe_decryption_keys.is_empty() && alice.peer_fhe_decryption_keys.is_empty());
+        let decrypted = bob.decrypt_own_position(encrypted.clone()).unwrap();
+        assert_eq!((decrypted.x, decrypted.y, decrypted.z), (4.0, -2.0, 1.0));
+        assert!(alice.decrypt_own_position(encrypted).is_err());
+    }
+
+    #[test]
+    fn shared_fhe_keys_reuse_the_peers_cached_ecdh_secret() {
+        let (avs, alice) = setup_user("alice");
+        let bob = User::setup(&avs.compiled_move_position.metadata.params, "bob").unwrap();
+        let record_key = form_encrypted_fhe_key("alice-peer-id");
+
+        let first = alice.encrypt_fhe_key_for_peer("bob-peer-id", &bob.ecdh_public_key, &record_key).unwrap();
+        let second = alice.encrypt_fhe_key_for_peer("bob-peer-id", &bob.ecdh_public_key, &record_key).unwrap();
+        assert_eq!(alice.peer_shared_secret_derivations(), 1);
+        for encrypted_key in [first, second] {
+            assert!(bob.decrypt_fhe_key_from_peer(&encrypted_key, "alice-peer-id", &alice.ecdh_public_key, &record_key).is_ok());
+        }
+        assert_eq!(bob.peer_shared_secret_derivations(), 1);
+
+        // bob's node restarted with a new ECDH key under the same peer id
+        let new_bob = User::setup(&avs.compiled_move_position.metadata.params, "bob").unwrap();
+        let third = alice.encrypt_fhe_key_for_peer("bob-peer-id", &new_bob.ecdh_public_key, &record_key).unwrap();
+        assert_eq!(alice.peer_shared_secret_derivations(), 2);
+        assert!(new_bob.decrypt_fhe_key_from_peer(&third, "alice-peer-id", &alice.ecdh_public_key, &record_key).is_ok());
+    }
+
+    #[test]
+    fn user_key_pair_round_trips_through_bincode_and_is_smaller_than_json() {
+        let (avs, alice) = setup_user("alice");
+        let bob = User::setup(&avs.compiled_move_position.metadata.params, "bob").unwrap();
+        let record_key = form_encrypted_fhe_key("alice-peer-id");
+        let key_pair = UserKeyPair {
+            ecdh_public_key: alice.ecdh_public_key,
+            fhe_private_key_encrypted: alice.encrypt_fhe_key_for_peer("bob-peer-id", &bob.ecdh_public_key, &record_key).unwrap(),
+        };
+
+        let bytes = key_pair.to_bytes();
+        let decoded = UserKeyPair::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.ecdh_public_key, key_pair.ecdh_public_key);
+        assert_eq!(decoded.fhe_private_key_encrypted, key_pair.fhe_private_key_encrypted);
+        assert!(bob.decrypt_fhe_key_from_peer(&decoded.fhe_private_key_encrypted, "alice-peer-id", &decoded.ecdh_public_key, &record_key).is_ok());
+
+        let json = serde_json::to_vec(&key_pair).unwrap();
+        assert!(bytes.len() < json.len(), "{} bytes bincode vs {} bytes JSON", bytes.len(), json.len());
+        assert!(UserKeyPair::from_bytes(&bytes[..bytes.len() / 2]).is_err());
+    }
+
+    #[test]
+    fn public_key_fingerprints_match_only_for_the_same_key() {
+        let (_, alice) = ecdh::generate_ecdh_keys();
+        let (_, bob) = ecdh::generate_ecdh_keys();
+        let alice_copy = ecdh::public_key_from_sec1_compressed(&ecdh::public_key_to_sec1_compressed(&alice)).unwrap();
+
+        assert_eq!(public_key_fingerprint(&alice), public_key_fingerprint(&alice_copy));
+        assert_ne!(public_key_fingerprint(&alice), public_key_fingerprint(&bob));
+        assert_eq!(public_key_fingerprint(&alice).len(), "0123 4567 89ab cdef".len());
+    }
+
+    #[test]
+    fn rotated_keys_need_reencrypted_positions() {
+        let (mut avs, mut user) = setup_user("alice");
+        let move_tx = user.create_move_transaction(Position { x: 1.0, y: 2.0, z: 3.0 }).unwrap();
+        let under_old_key = avs.run_contract(move_tx, &user.fhe_public_key).unwrap();
+        let old_public_key = user.fhe_public_key.clone();
+
+        user.rotate_fhe_keys().unwrap();
+        assert!(user.decrypt_position_with_key(&under_old_key, &user.fhe_private_key).is_err());
+
+        let reencrypted = user.reencrypt_positions(&[under_old_key]).unwrap();
+        assert_eq!(reencrypted.len(), 1);
+        let position = user.decrypt_position_with_key(&reencrypted[0], &user.fhe_private_key).unwrap();
+        assert_eq!((position.x, position.y, position.z), (1.0, 2.0, 3.0));
+
+        // the AVS keeps moving the player from the re-encrypted position
+        avs.rekey_position(&old_public_key, &user.fhe_public_key, reencrypted[0].clone());
+        let move_tx = user.create_move_transaction(Position { x: 1.0, y: 0.0, z: 0.0 }).unwrap();
+        let new_position = avs.run_contract(move_tx, &user.fhe_public_key).unwrap();
+        let position = user.decrypt_position_with_key(&new_position, &user.fhe_private_key).unwrap();
+        assert_eq!((position.x, position.y, position.z), (2.0, 2.0, 3.0));
+        assert_eq!(avs.move_sequence(&user.fhe_public_key), 2);
+    }
+
+    #[test]
+    fn key_ring_decrypts_position_from_reverted_key() {
+        let (_avs, mut user) = setup_user("alice");
+        let under_original_key = user.create_move_transaction(Position { x: 1.0, y: 2.0, z: 0.0 }).unwrap();
+
+        // rotate to a new key, encrypt under it, then revert the rotation
+        let (public_key, private_key) = user.runtime.generate_keys().unwrap();
+        user.replace_fhe_keys(public_key, private_key);
+        let under_interim_key = user.create_move_transaction(Position { x: 3.0, y: 4.0, z: 0.0 }).unwrap();
+        assert!(user.revert_fhe_keys());
+
+        let position = user.decrypt_own_position(under_original_key).unwrap();
+        assert_eq!((position.x, position.y), (1.0, 2.0));
+        // the reverted-from key is retained in the key ring
+        let position = user.decrypt_own_position(under_interim_key).unwrap();
+        assert_eq!((position.x, position.y), (3.0, 4.0));
+    }
+}
@@ -0,0 +1,89 @@
+use std::error::Error;
+use std::fmt;
+use serde::{Deserialize, Serialize};
+
+// Bumped whenever record or message formats change in a way older nodes can't parse
+pub const PROTOCOL_VERSION: u32 = 3;
+
+// Ciphers a node can decrypt SHARE_KEY records with, as flags in Hello::ciphers
+pub const CIPHER_CHACHA20POLY1305: u32 = 1 << 0;
+pub const CIPHER_AES256GCM: u32 = 1 << 1;
+
+// What a node speaks, put as its HELLO_<peer_id> record when it connects to a peer.
+// Peers check it before decrypting anything the node publishes, so a format change shows up
+// as one clear warning instead of deserialization errors on every record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub ciphers: u32,
+}
+
+impl Default for Hello {
+    fn default() -> Self {
+        Hello {
+            protocol_version: PROTOCOL_VERSION,
+            ciphers: CIPHER_CHACHA20POLY1305,
+        }
+    }
+}
+
+impl Hello {
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("Hello always serializes")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Hello, bincode::Error> {
+        bincode::deserialize(bytes)
+    }
+
+    // Whether a peer that sent `theirs` can read our records and we theirs
+    pub fn check(&self, theirs: &Hello) -> Result<(), Incompatibility> {
+        if self.protocol_version != theirs.protocol_version {
+            return Err(Incompatibility::Version { ours: self.protocol_version, theirs: theirs.protocol_version });
+        }
+        if self.ciphers & theirs.ciphers == 0 {
+            return Err(Incompatibility::NoCommonCipher { ours: self.ciphers, theirs: theirs.ciphers });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Incompatibility {
+    Version { ours: u32, theirs: u32 },
+    NoCommonCipher { ours: u32, theirs: u32 },
+}
+
+impl fmt::Display for Incompatibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Incompatibility::Version { ours, theirs } => {
+                write!(f, "protocol version {theirs}, this node speaks version {ours}")
+            }
+            Incompatibility::NoCommonCipher { ours, theirs } => {
+                write!(f, "no common cipher (theirs {theirs:#b}, ours {ours:#b})")
+            }
+        }
+    }
+}
+
+impl Error for Incompatibility {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hellos_need_the_same_version_and_a_common_cipher() {
+        let ours = Hello::default();
+        assert_eq!(Hello::from_bytes(&ours.to_bytes()).unwrap(), ours);
+        assert!(ours.check(&Hello { ciphers: CIPHER_CHACHA20POLY1305 | CIPHER_AES256GCM, ..ours }).is_ok());
+
+        let newer = Hello { protocol_version: PROTOCOL_VERSION + 1, ..ours };
+        assert_eq!(ours.check(&newer), Err(Incompatibility::Version { ours: PROTOCOL_VERSION, theirs: PROTOCOL_VERSION + 1 }));
+        let aes_only = Hello { ciphers: CIPHER_AES256GCM, ..ours };
+        assert!(matches!(ours.check(&aes_only), Err(Incompatibility::NoCommonCipher { .. })));
+        assert!(Hello::from_bytes(&[1]).is_err());
+    }
+}
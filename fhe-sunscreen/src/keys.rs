@@ -0,0 +1,72 @@
+use regex::Regex;
+
+// Kademlia record keys are <KIND>_<peer_id>, e.g. AVS_PUBLIC_KEY_12D3KooW...
+pub const POSITION: &str = "POSITION";
+pub const AVS_PUBLIC_KEY: &str = "AVS_PUBLIC_KEY";
+pub const ENCRYPTED_FHE_KEY: &str = "ENCRYPTED_FHE_KEY";
+pub const FHE_PUBLIC_KEY: &str = "FHE_PUBLIC_KEY";
+pub const PEER_NAME: &str = "PEER_NAME";
+pub const HELLO: &str = "HELLO";
+
+/// Position records are owned by the subject player: POSITION_<subject_peer_id>.
+/// Positions are now exchanged directly (see position_exchange), the key only addresses older DHT records.
+///
+/// ```
+/// assert_eq!(fhe_sunscreen::form_position_key("12D3KooWabc"), "POSITION_12D3KooWabc");
+/// assert!(fhe_sunscreen::is_position_key("POSITION_12D3KooWabc"));
+/// ```
+pub fn form_position_key(peer_id: &str) -> String {
+    format!("{POSITION}_{peer_id}")
+}
+
+pub fn form_avs_public_key(peer_id: &str) -> String {
+    format!("{AVS_PUBLIC_KEY}_{peer_id}")
+}
+
+pub fn form_encrypted_fhe_key(peer_id: &str) -> String {
+    format!("{ENCRYPTED_FHE_KEY}_{peer_id}")
+}
+
+// FHE_PUBLIC_KEY_<peer_id> -> the peer's FHE public key, so others can encrypt positions only it can decrypt
+pub fn form_fhe_public_key(peer_id: &str) -> String {
+    format!("{FHE_PUBLIC_KEY}_{peer_id}")
+}
+
+// Small identify record: PEER_NAME_<peer_id> -> the node's --name, so peers can address it by name.
+pub fn form_peer_name_key(peer_id: &str) -> String {
+    format!("{PEER_NAME}_{peer_id}")
+}
+
+// HELLO_<peer_id> -> the node's protocol version and ciphers, see hello::Hello
+pub fn form_hello_key(peer_id: &str) -> String {
+    format!("{HELLO}_{peer_id}")
+}
+
+pub fn is_position_key(str: &str) -> bool {
+    let re = Regex::new(&format!(r"{}_(?<peer_id>\w*)", POSITION)).unwrap();
+    re.is_match(str)
+}
+
+pub fn get_peer_id_from_position_key(str: &str) -> String {
+    let results = str.split("_").collect::<Vec<&str>>();
+    results[1].to_string()
+}
+
+pub fn is_avs_public_key(str: &str) -> bool {
+    let re = Regex::new(&format!(r"{}_(?<peer_id>\w*)", AVS_PUBLIC_KEY)).unwrap();
+    re.is_match(str)
+}
+
+pub fn is_encrypted_fhe_key(str: &str) -> bool {
+    let re = Regex::new(&format!(r"{}_(?<peer_id>\w*)", ENCRYPTED_FHE_KEY)).unwrap();
+    let Some(capture) = re.captures(str) else {
+        return false;
+    };
+    println!("ENCRYPTED_FHE_KEY: Peer ID is: {}", &capture["peer_id"]);
+    true
+}
+
+pub fn is_fhe_public_key(str: &str) -> bool {
+    let re = Regex::new(&format!(r"{}_(?<peer_id>\w*)", FHE_PUBLIC_KEY)).unwrap();
+    re.is_match(str)
+}
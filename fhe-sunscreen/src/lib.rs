@@ -0,0 +1,35 @@
+//! FHE fog-of-war positions on sunscreen's BFV scheme.
+//!
+//! A [`User`] encrypts moves under their FHE key, an [`AVS`] applies them to the player's
+//! encrypted position without seeing it, and only the user (or peers they shared their key with)
+//! can decrypt the result. The `fhe-sunscreen` binary wraps this in a libp2p node.
+//!
+//! ```no_run
+//! use fhe_sunscreen::{Position, User, AVS};
+//!
+//! let mut avs = AVS::setup()?;
+//! let user = User::setup(&avs.compiled_move_position.metadata.params, "alice")?;
+//!
+//! let move_tx = user.create_move_transaction(Position { x: 3.0, y: 2.0, z: 0.0 })?;
+//! let encrypted_position = avs.run_contract(move_tx, &user.fhe_public_key)?;
+//! let position = user.decrypt_own_position(encrypted_position)?;
+//! assert_eq!((position.x, position.y, position.z), (3.0, 2.0, 0.0));
+//! # Ok::<(), sunscreen::Error>(())
+//! ```
+
+mod fhe_sunscreen;
+mod keys;
+pub mod chunks;
+pub mod error;
+pub mod hello;
+pub mod metrics;
+pub mod node;
+pub mod position_exchange;
+pub mod records;
+pub mod redial;
+pub mod remote_avs;
+pub mod store;
+
+pub use error::AppError;
+pub use fhe_sunscreen::*;
+pub use keys::*;
@@ -1,443 +1,201 @@
-
 use std::error::Error;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use futures::prelude::*;
-use futures::stream::StreamExt;
 use tokio::{
     io,
     io::AsyncBufReadExt,
-    select
-};
-use libp2p::{
-    kad::{self, store::{MemoryStore, MemoryStoreConfig}, Mode, Config},
-    mdns,
-    noise,
-    Swarm,
-    swarm::{NetworkBehaviour, SwarmEvent},
-    PeerId,
-    tcp,
-    yamux
 };
+use libp2p::Multiaddr;
+use clap::Parser;
 use tracing_subscriber::EnvFilter;
-use serde_json;
-use serde::{Deserialize, Serialize};
-use regex::Regex;
 
-mod fhe_sunscreen;
-use fhe_sunscreen::{EncryptedPosition, Position, User, AVS};
+use fhe_sunscreen::{
+    CoordinateType,
+    DEFAULT_ZSTD_LEVEL,
+    User,
+    AVS,
+};
+use fhe_sunscreen::node::{build_swarm, parse_name, Node};
+use fhe_sunscreen::records::{RecordRepublisher, DEFAULT_REPUBLISH_INTERVAL, KADEMLIA_RECORD_TTL};
+use fhe_sunscreen::position_exchange::PositionStore;
+use fhe_sunscreen::metrics::NodeMetrics;
+#[cfg(feature = "metrics")]
+use fhe_sunscreen::metrics;
+use fhe_sunscreen::redial::{RedialPolicy, DEFAULT_MAX_REDIAL_ATTEMPTS, DEFAULT_REDIAL_BASE_DELAY};
+
+#[derive(Parser)]
+#[command(version, about = "FHE fog-of-war node on a Kademlia DHT", long_about = None)]
+struct Cli {
+    /// Name of this node's player, e.g. alice
+    #[arg(short, long, value_parser = parse_name)]
+    name: String,
+
+    /// Address to listen on
+    #[arg(long, default_value = "/ip4/0.0.0.0/tcp/0")]
+    listen_addr: Multiaddr,
+
+    /// Known peer to dial on startup
+    #[arg(long)]
+    bootstrap: Option<Multiaddr>,
+
+    /// Seconds between re-puts of this node's own records
+    #[arg(long, default_value_t = DEFAULT_REPUBLISH_INTERVAL.as_secs())]
+    republish_interval: u64,
+
+    /// Seconds shared FHE keys and served positions stay valid, by default they never expire
+    #[arg(long)]
+    record_ttl: Option<u64>,
+
+    /// Serve Prometheus metrics on this address, e.g. 127.0.0.1:9090
+    #[cfg(feature = "metrics")]
+    #[arg(long)]
+    metrics_addr: Option<std::net::SocketAddr>,
+
+    /// zstd level encrypted positions are compressed at (1-22)
+    #[arg(long, default_value_t = DEFAULT_ZSTD_LEVEL, value_parser = clap::value_parser!(i32).range(1..=22))]
+    zstd_level: i32,
+
+    /// FHE type positions are encrypted as: rational, signed (whole numbers) or fractional
+    #[arg(long, default_value_t = CoordinateType::default())]
+    coordinates: CoordinateType,
+
+    /// Keep this many past positions per player (0 keeps only the current one)
+    #[arg(long, default_value_t = 0)]
+    history_len: usize,
+
+    /// Reject MOVEs with any coordinate beyond ±this value
+    #[arg(long, value_parser = parse_max_coordinate)]
+    max_coordinate: Option<f64>,
+
+    /// Re-put this node's records one last time when it's stopped with Ctrl-C
+    #[arg(long)]
+    republish_on_exit: bool,
+
+    /// Times to redial a dialed peer whose connection dropped, backing off exponentially (0 disables)
+    #[arg(long, default_value_t = DEFAULT_MAX_REDIAL_ATTEMPTS)]
+    max_redial_attempts: u32,
+}
 
-// Create a custom network behaviour that combines Kademlia and mDNS.
-#[derive(NetworkBehaviour)]
-struct Behaviour {
-    kademlia: kad::Behaviour<MemoryStore>,
-    mdns: mdns::tokio::Behaviour,
+fn parse_max_coordinate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(max) if max.is_finite() && max > 0.0 => Ok(max),
+        _ => Err(format!("{s} is not a positive, finite number")),
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
 
     tracing_subscriber::fmt().with_env_filter(EnvFilter::from_default_env()).init();
-    println!("\nSetting up IPFS node with Kademlia DHT...");
-
-    let mut swarm = libp2p::SwarmBuilder::with_new_identity()
-        .with_tokio()
-        .with_tcp(
-            tcp::Config::default(),
-            noise::Config::new,
-            yamux::Config::default,
-        )?
-        .with_behaviour(|key| {
-
-            let memory_store_config = MemoryStore::with_config(
-                key.public().to_peer_id(),
-                MemoryStoreConfig {
-                    // The maximum number of records.
-                    max_records: 1024,
-                    // The maximum size of record values, in bytes.
-                    // Note: make this big as FHE ciphertexts are very large (+6mil characters)
-                    max_value_bytes: 4_294_967_296, // max uint32 -> 4,294,967,296 bytes
-                    // The maximum number of providers stored for a key.
-                    // This should match up with the chosen replication factor.
-                    max_providers_per_key: 1024,
-                    // The maximum number of provider records for which the
-                    // local node is the provider.
-                    max_provided_keys: MemoryStoreConfig::default().max_provided_keys,
-                }
-            );
-
-            let mut config: Config = Default::default();
-            // ciphertexts are +865kb, increase packet size to 1048576 = 1024**2
-            config.set_max_packet_size(4_294_967_296);
-
-            let kad_behaviour = kad::Behaviour::with_config(
-                key.public().to_peer_id(),
-                memory_store_config,
-                config
-            );
-
-            Ok(Behaviour {
-                kademlia: kad_behaviour,
-                mdns: mdns::tokio::Behaviour::new(
-                    mdns::Config::default(),
-                    key.public().to_peer_id(),
-                )?,
-            })
-        })?
-        .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(u64::MAX)))
-        .build();
 
-    // use clap or something better for cmd ling args
-    let cmd_args = std::env::args().collect::<Vec<String>>();
-    let user_name = cmd_args.get(1).expect("\n[ERROR] Missing name, run: cargo run -- <alice/bob>");
+    let Cli {
+        name: user_name,
+        listen_addr,
+        bootstrap,
+        republish_interval,
+        record_ttl,
+        #[cfg(feature = "metrics")]
+        metrics_addr,
+        zstd_level,
+        coordinates,
+        history_len,
+        max_coordinate,
+        republish_on_exit,
+        max_redial_attempts,
+    } = Cli::parse();
+    let republish_interval = Duration::from_secs(republish_interval);
+
+    let metrics = Arc::new(NodeMetrics::default());
+    #[cfg(feature = "metrics")]
+    if let Some(addr) = metrics_addr {
+        let listener = metrics::bind_metrics(addr).await?;
+        println!("Serving metrics on http://{}/metrics", listener.local_addr()?);
+        tokio::spawn(metrics::serve_metrics(listener, metrics.clone()));
+    }
 
-    println!("Setting up AVS with FHE program...");
-    let mut avs = AVS::setup()?;
+    println!("Kademlia record TTL: {KADEMLIA_RECORD_TTL:?}, republishing own records every {republish_interval:?}");
+    if republish_interval >= KADEMLIA_RECORD_TTL {
+        eprintln!("[WARN] --republish-interval is longer than the record TTL, records will expire before being republished");
+    }
+    let record_ttl = record_ttl.map(Duration::from_secs);
+    let mut republisher = RecordRepublisher::new(republish_interval, Instant::now());
+    republisher.record_ttl = record_ttl;
+    let mut served_positions = PositionStore::new(zstd_level);
+    served_positions.ttl = record_ttl;
+
+    println!("Setting up AVS with FHE program for {coordinates} coordinates...");
+    let mut avs = AVS::setup_with(coordinates)?;
+    avs.history_len = history_len;
     // FHE scheme parameters are public to the protocol, so Alice has them.
     println!("Setting up keys for user...\n");
-    let mut user = User::setup(&avs.compiled_move_position.metadata.params, user_name)?;
+    let mut user = User::setup(&avs.compiled_move_position.metadata.params, &user_name)?;
+    user.coordinate_type = coordinates;
+    user.max_coordinate = max_coordinate;
 
-    swarm.behaviour_mut().kademlia.set_mode(Some(Mode::Server));
+    println!("Setting up IPFS node with Kademlia DHT...");
+    // the node's peer id is the user's identity key, which signs the records it publishes
+    let swarm = build_swarm(&user.identity)?;
+    let mut node = Node::new(swarm, user, avs, republisher, served_positions, metrics);
+    node.redial = RedialPolicy::new(max_redial_attempts, DEFAULT_REDIAL_BASE_DELAY);
 
     // read full lines from stdin
-    let mut stdin = io::BufReader::new(io::stdin()).lines();
-    // Tell the swarm to listen on all interfaces and a random, OS-assigned port
-    swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
-
-    loop {
-        select! {
-            Ok(Some(line)) = stdin.next_line() => handle_input_line(
-                swarm.local_peer_id().clone(),
-                &mut swarm.behaviour_mut().kademlia,
-                line,
-                &mut user,
-                &mut avs
-            ),
-            event = swarm.select_next_some() => match event {
-                SwarmEvent::NewListenAddr { address, .. } => {
-                    let local_peer_id = swarm.local_peer_id().clone();
-                    println!("[Local Peer]: <{user_name}> {local_peer_id} listening on {address:?}\n");
-                    avs.set_peer_id(Some(local_peer_id));
-                },
-                SwarmEvent::ConnectionClosed { cause, peer_id, ..} => {
-                    println!("ConnectionClosed for peer {peer_id}: {cause:?}");
-                },
-                SwarmEvent::ConnectionEstablished { peer_id, ..} => {
-                    handle_connection_established(peer_id, &mut user, &mut avs, &mut swarm)?;
-                },
-                SwarmEvent::Behaviour(
-                    BehaviourEvent::Mdns(mdns::Event::Discovered(list))
-                ) => {
-                    for (peer_id, multiaddr) in list {
-                        let kademlia = &mut swarm.behaviour_mut().kademlia;
-                        kademlia.add_address(&peer_id, multiaddr);
-                    }
-                },
-                SwarmEvent::Behaviour(
-                    BehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed { result, ..})
-                ) => {
-                    match result {
-                        kad::QueryResult::GetRecord(Ok(
-                            kad::GetRecordOk::FoundRecord(kad::PeerRecord { record, .. })
-                        )) => {
-                            handle_get_record_result(record, &mut user, &mut avs)?;
-                        }
-                        kad::QueryResult::GetRecord(Err(err)) => {
-                            eprintln!("Failed to get record: {err:?}");
-                        }
-                        kad::QueryResult::PutRecord(Ok(kad::PutRecordOk { key })) => {
-                            println!(
-                                "Successfully put record {:?}",
-                                std::str::from_utf8(key.as_ref()).unwrap()
-                            );
-                        }
-                        kad::QueryResult::PutRecord(Err(err)) => {
-                            eprintln!("Failed to put record: {err:?}");
-                        }
-                        _ => {}
-                    }
-                },
-                _ => {
-                    println!("...")
-                },
-            }
+    let stdin = io::BufReader::new(io::stdin()).lines();
+    // Defaults to all interfaces and a random, OS-assigned port
+    node.swarm.listen_on(listen_addr)?;
+    if let Some(addr) = bootstrap {
+        println!("Dialing bootstrap peer {addr}...");
+        // keep running on mDNS and DIAL if the bootstrap peer is unreachable
+        if let Err(e) = node.swarm.dial(addr) {
+            eprintln!("Failed to dial bootstrap peer: {e}");
         }
     }
-}
-
-
-
-fn handle_connection_established(
-    peer_id: libp2p::PeerId,
-    user: &mut User,
-    avs: &mut AVS,
-    swarm: &mut Swarm<Behaviour>,
-) -> Result<(), Box<dyn Error>> {
-
-    println!("[Remote Peer]: {peer_id}: ConnectionEstablished!");
-    let local_peer_id = swarm.local_peer_id().clone();
-    let key = form_avs_public_key(&local_peer_id.to_string());
-    let kademlia = &mut swarm.behaviour_mut().kademlia;
-    let avs_public_key_value: Vec<u8> = user.ecdh_public_key.to_sec1_bytes().to_vec();
-
-    kademlia.put_record(
-        kad::Record {
-            key: kad::RecordKey::new(&key),
-            value: avs_public_key_value,
-            publisher: None,
-            expires: None,
-        },
-        kad::Quorum::One
-    )?;
-
-    if user.name == Some("alice".to_string()) {
-        avs.peer_ids.insert("bob".to_string(), peer_id);
-        avs.peer_ids.insert("alice".to_string(), local_peer_id);
-    }
-    if user.name == Some("bob".to_string()) {
-        avs.peer_ids.insert("alice".to_string(), peer_id);
-        avs.peer_ids.insert("bob".to_string(), local_peer_id);
-    }
-
-    kademlia.get_record(kad::RecordKey::new(&form_avs_public_key(&peer_id.to_string())));
-    Ok(())
-}
-
-fn handle_get_record_result(
-    record: kad::Record,
-    user: &mut User,
-    avs: &mut AVS
-) -> Result<(), Box<dyn Error>> {
-
-    let kad::Record { key, value, publisher, ..  } = record;
-    let key_str = std::str::from_utf8(key.as_ref()).expect("key.as_ref() missing?");
-
-    if is_encrypted_fhe_key(key_str) {
-        // replicate alice decryption key for testing purposes
-
-        let peer_id = publisher.expect("there should be a publisher").to_string();
-
-        println!("Getting alice keys for Bob...");
-        let peer_keys: UserKeyPair = serde_json::from_slice(&value)
-            .expect("serde_json::from_utf8() failed");
-
-        user.peer_fhe_decryption_keys.insert(peer_id, peer_keys);
-        println!("saved alice's encrypted FHE keys and ECDH public key in AVS node");
-
-    } else if is_position_key(key_str) {
-        // encrypted position
-        println!("read encrypted position from IPFS kademlia...");
-        println!("unpacking encrypted positions (ciphertexts are +870 kb)...");
-        let encrypted_position: EncryptedPosition = serde_json::from_slice(&value)
-            .expect("from_slice failed");
-
-        let peer_id = get_peer_id_from_position_key(&key_str);
-
-        println!("Decoding encrypted positions...");
-        println!("publisher: {:?}", publisher);
-        println!("avs.peer_id: {:?}", avs.peer_id);
-
-        let position = match publisher == avs.peer_id {
-            true  => user.decrypt_own_position(encrypted_position)?,
-            false => user.decrypt_peer_position(encrypted_position, &peer_id)?,
-        };
-
-        println!("Decrypted position for {key_str}: {position:?}");
-
-    } else if is_avs_public_key(key_str) {
-
-        let avs_public_key: k256::PublicKey = k256::PublicKey::from_sec1_bytes(&value)
-            .expect("deserialize avs_public_key");
-
-        avs.peer_public_keys.insert(key_str.to_string(), avs_public_key);
 
-        let check_avs_pubkey = avs.peer_public_keys.get(key_str)
-            .expect("should have saved peer_avs_public_key")
-            .as_affine();
-
-        println!("\nSaved {}: {:?} of length: {}", key_str, check_avs_pubkey, value.len());
-        // use this public_key to encrypt alice's FHE key intended for Bob
+    node.run_until(stdin, async {
+        // without a signal handler the node runs until it's killed, as before
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            eprintln!("Failed to listen for Ctrl-C: {e}");
+            std::future::pending::<()>().await;
+        }
+    }).await;
 
-    } else {
-        println!("Unhandled key")
-    }
+    println!("\nShutting down...");
+    let summary = node.shutdown(republish_on_exit).await;
+    println!("{summary}");
     Ok(())
 }
 
-fn handle_input_line(
-    local_peer_id: PeerId,
-    kademlia: &mut kad::Behaviour<MemoryStore>,
-    line: String,
-    user: &mut User,
-    avs: &mut AVS
-) {
-    let mut args = line.split(' ');
-
-    match (args.next(), args.next()) {
-        (None, _) => {
-            eprintln!("expected GET, PUT, MOVE or SHARE_KEY");
-        }
-        (Some(_), None) => {
-            eprintln!("Expected key in 2nd argument");
-        }
-        (Some("GET"), Some(cmd)) => {
-
-            let name = args.next().expect("expected alice or bob for 3rd argument");
-            let peer_id = avs.peer_ids.get(name)
-                .expect(&format!("{} missing in avs.peer_ids", name))
-                .to_string();
-
-            match cmd {
-                AVS_PUBLIC_KEY => {
-                    kademlia.get_record(kad::RecordKey::new(&form_avs_public_key(&peer_id)));
-                }
-                POSITION => {
-                    kademlia.get_record(kad::RecordKey::new(&form_position_key(&peer_id)));
-                }
-                ENCRYPTED_FHE_KEY => {
-                    kademlia.get_record(kad::RecordKey::new(&form_encrypted_fhe_key(&peer_id)));
-                }
-                _ => {
-                    eprintln!("Unrecognised GET command: choose AVS_PUBLICKEY, POSITION, or ENCRYPTED_FHE_KEY");
-                }
-            }
-        }
-        (Some("SHARE_KEY"), Some(_name)) => {
-            // Encrypt Alice's FHE private key and share it with Bob using Elliptic-curve Diffie–Hellman (ECDH).
-            // This is for testing only. Alice should not be sharing private keys.
-            let peer_id = match user.name.clone().unwrap().as_str() {
-                "alice" => avs.peer_ids.get("bob").expect("bob peer_id missing").clone().to_string(),
-                "bob" => avs.peer_ids.get("alice").expect("alice peer_id missing").clone().to_string(),
-                _ => {
-                    eprintln!("unexpected name");
-                    return;
-                }
-            };
-
-            // Get Bob's ECDH public key
-            let avs_peer_ecdh_public_key = match avs.peer_public_keys
-                .get(&form_avs_public_key(&peer_id)) {
-                Some(pkey) => pkey,
-                None => {
-                    eprintln!("avs_peer_ecdh_public_key for {peer_id} missing");
-                    return;
-                }
-            };
-
-            // ECDH encrypt so Bob can decrypt using his shared secret
-            println!("encrypting {}'s private_key for bob...", user.name.as_ref().expect("user.name missing"));
-            let alice_fhe_private_key_encrypted = user.encrypt_fhe_key_for_peer(avs_peer_ecdh_public_key);
-
-            let encrypted_fhe_keys_str = serde_json::to_string(&(UserKeyPair {
-                ecdh_public_key: user.ecdh_public_key,
-                fhe_private_key_encrypted: alice_fhe_private_key_encrypted
-            })).expect("serde_json::to_string(UserKeyPair) failed");
-
-            match kademlia.put_record(
-                kad::Record {
-                    key: kad::RecordKey::new(&form_encrypted_fhe_key(&local_peer_id.to_string())),
-                    value: encrypted_fhe_keys_str.as_bytes().to_vec(),
-                    publisher: Some(local_peer_id),
-                    expires: None,
-                },
-                kad::Quorum::One
-            ) {
-                Ok(query_id) => println!("stored {local_peer_id}_private_key queryId: {query_id}"),
-                Err(e) => println!("{:?}", e),
-            }
-        }
-        (Some("MOVE"), Some(name)) => match args.next() {
-            None => eprintln!(r#"Expected a position value like {{"x":1,"y":2}}"#),
-            Some(value) => {
-
-                let position = serde_json::from_str::<Position>(&value).unwrap();
-                println!("Moving to: ({}, {})", position.x, position.y);
-
-                let move_tx = user.create_move_transaction(position.clone())
-                    .expect("alice.create_move_transaction");
-
-                let new_encrypted_position = avs.run_contract(
-                    move_tx,
-                    &user.fhe_public_key // can use peer AVS's public key. Then peer can decrypt Alice's position
-                ).expect("AVS.run_contract");
-
-                let peer_id = avs.peer_ids.get(name)
-                    .expect(&format!("{} missing in avs.peer_ids", name))
-                    .to_string();
-
-                let key_str = form_position_key(&peer_id);
-
-                println!("saving encrypted position...");
-                // save encrypted position to Kademlia
-                let record = kad::Record {
-                    key: kad::RecordKey::new(&key_str),
-                    value: serde_json::to_vec(&new_encrypted_position).expect("serde_json::to_vec(new_encrypted_position) failed"),
-                    // DEFAULT_MAX_PACKET_SIZE = 16 * 1024; = 16,384
-                    // Configure Kademlia packet size to accomodate +900kb ciphertexts (Vec<u8>)
-                    publisher: Some(local_peer_id),
-                    expires: None,
-                };
-
-                match kademlia.put_record(record, kad::Quorum::One) {
-                    Ok(query_id) => println!("stored with queryId: {query_id}"),
-                    Err(e) => println!("{:?}", e),
-                }
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cli_parses_node_options_and_rejects_empty_names() {
+        let cli = Cli::try_parse_from([
+            "fhe-sunscreen", "--name", "bob", "--bootstrap", "/ip4/127.0.0.1/tcp/4001",
+        ]).unwrap();
+        assert_eq!(cli.name, "bob");
+        assert_eq!(cli.listen_addr, "/ip4/0.0.0.0/tcp/0".parse::<Multiaddr>().unwrap());
+        assert_eq!(cli.bootstrap, Some("/ip4/127.0.0.1/tcp/4001".parse().unwrap()));
+        assert_eq!(cli.republish_interval, DEFAULT_REPUBLISH_INTERVAL.as_secs());
+        assert_eq!(cli.zstd_level, DEFAULT_ZSTD_LEVEL);
+        assert_eq!(cli.coordinates, CoordinateType::Rational);
+        assert_eq!(cli.history_len, 0);
+        assert_eq!(cli.record_ttl, None);
+        assert!(!cli.republish_on_exit);
+        assert_eq!(cli.max_redial_attempts, DEFAULT_MAX_REDIAL_ATTEMPTS);
+
+        assert!(Cli::try_parse_from(["fhe-sunscreen"]).is_err());
+        assert!(Cli::try_parse_from(["fhe-sunscreen", "--name", " "]).is_err());
+        assert!(Cli::try_parse_from(["fhe-sunscreen", "--name", "alice", "--bootstrap", "not-a-multiaddr"]).is_err());
+        assert!(Cli::try_parse_from(["fhe-sunscreen", "--name", "alice", "--zstd-level", "23"]).is_err());
+        let cli = Cli::try_parse_from(["fhe-sunscreen", "--name", "alice", "--coordinates", "signed"]).unwrap();
+        assert_eq!(cli.coordinates, CoordinateType::Signed);
+        assert!(Cli::try_parse_from(["fhe-sunscreen", "--name", "alice", "--coordinates", "integer"]).is_err());
+        let cli = Cli::try_parse_from(["fhe-sunscreen", "--name", "alice", "--max-coordinate", "500"]).unwrap();
+        assert_eq!(cli.max_coordinate, Some(500.0));
+        for bad in ["NaN", "inf", "-5", "0", "far"] {
+            assert!(Cli::try_parse_from(["fhe-sunscreen", "--name", "alice", "--max-coordinate", bad]).is_err(), "{bad}");
         }
-        (Some(s), _) => eprintln!("Unrecognised command: {s}")
     }
 }
-
-
-#[derive(Serialize, Deserialize)]
-struct UserKeyPair {
-    ecdh_public_key: k256::PublicKey,
-    fhe_private_key_encrypted: Vec<u8>,
-}
-
-const POSITION: &str = "POSITION";
-const AVS_PUBLIC_KEY: &str = "AVS_PUBLIC_KEY";
-const ENCRYPTED_FHE_KEY: &str = "ENCRYPTED_FHE_KEY";
-
-pub fn form_position_key(peer_id: &str) -> String {
-    format!("{POSITION}_{peer_id}")
-}
-
-pub fn form_avs_public_key(peer_id: &str) -> String {
-    format!("{AVS_PUBLIC_KEY}_{peer_id}")
-}
-
-pub fn form_encrypted_fhe_key(peer_id: &str) -> String {
-    format!("{ENCRYPTED_FHE_KEY}_{peer_id}")
-}
-
-pub fn is_position_key(str: &str) -> bool {
-    let re = Regex::new(&format!(r"{}_(?<peer_id>\w*)", POSITION)).unwrap();
-    let Some(_capture) = re.captures(str) else {
-        return false;
-    };
-    return true;
-}
-
-pub fn get_peer_id_from_position_key(str: &str) -> String {
-    let results = str.split("_").collect::<Vec<&str>>();
-    let peer_id = results[1].to_string();
-    return peer_id
-}
-
-pub fn is_avs_public_key(str: &str) -> bool {
-    let re = Regex::new(&format!(r"{}_(?<peer_id>\w*)", AVS_PUBLIC_KEY)).unwrap();
-    let Some(_capture) = re.captures(str) else {
-        return false;
-    };
-    return true;
-}
-
-pub fn is_encrypted_fhe_key(str: &str) -> bool {
-    let re = Regex::new(&format!(r"{}_(?<peer_id>\w*)", ENCRYPTED_FHE_KEY)).unwrap();
-    let Some(capture) = re.captures(str) else {
-        return false;
-    };
-    println!("ENCRYPTED_FHE_KEY: Peer ID is: {}", &capture["peer_id"]);
-    return true;
-}
\ No newline at end of file
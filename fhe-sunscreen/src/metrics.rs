@@ -0,0 +1,261 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use libp2p::kad::store::RecordStore;
+#[cfg(feature = "metrics")]
+pub use server::{bind_metrics, serve_metrics};
+
+// Upper bounds (seconds) of the FHE timing histogram buckets. BFV ops run from milliseconds
+// (encrypting a move) to seconds (run_contract on large parameters).
+const DURATION_BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0];
+
+// Cumulative Prometheus histogram of durations over DURATION_BUCKETS
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; DURATION_BUCKETS.len()],
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+
+    pub fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        for (bucket, le) in self.buckets.iter().zip(DURATION_BUCKETS) {
+            if seconds <= le {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.sum_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn render(&self, name: &str, help: &str) -> String {
+        let mut text = format!("# HELP {name} {help}\n# TYPE {name} histogram\n");
+        for (bucket, le) in self.buckets.iter().zip(DURATION_BUCKETS) {
+            text += &format!("{name}_bucket{{le=\"{le}\"}} {}\n", bucket.load(Ordering::Relaxed));
+        }
+        let count = self.count();
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        text += &format!("{name}_bucket{{le=\"+Inf\"}} {count}\n{name}_sum {sum}\n{name}_count {count}\n");
+        text
+    }
+}
+
+// FHE operations timed by NodeMetrics::time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FheOp {
+    // User::create_move_transaction
+    Encrypt,
+    // AVS::run_contract
+    RunContract,
+    // decrypting an own or peer position
+    Decrypt,
+}
+
+// Node stats for operators running headless, served as Prometheus text on `--metrics-addr`.
+// Counters are updated from the swarm event handlers and read by the metrics server task.
+#[derive(Default)]
+pub struct NodeMetrics {
+    peers_connected: AtomicU64,
+    records_stored: AtomicU64,
+    bytes_stored: AtomicU64,
+    moves_applied: AtomicU64,
+    decryptions: AtomicU64,
+    faults: AtomicU64,
+    records_put: AtomicU64,
+    records_got: AtomicU64,
+    refused: AtomicU64,
+    pub encrypt_seconds: Histogram,
+    pub run_contract_seconds: Histogram,
+    pub decrypt_seconds: Histogram,
+}
+
+impl NodeMetrics {
+
+    pub fn connection_established(&self) {
+        self.peers_connected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        // saturate at 0 in case a close is seen for a connection opened before we started counting
+        let _ = self.peers_connected.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+    }
+
+    pub fn move_applied(&self) {
+        self.moves_applied.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn decryption_performed(&self) {
+        self.decryptions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn fault(&self) {
+        self.faults.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // a put query that reached its quorum
+    pub fn record_put(&self) {
+        self.records_put.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // a record found by a get query
+    pub fn record_got(&self) {
+        self.records_got.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // a record or position from a peer with an incompatible protocol (see hello::Hello), or a position a peer
+    // served for someone other than itself
+    pub fn refused(&self) {
+        self.refused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Runs `f`, recording how long it took in `op`'s histogram
+    pub fn time<T>(&self, op: FheOp, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.histogram(op).observe(start.elapsed());
+        result
+    }
+
+    pub fn histogram(&self, op: FheOp) -> &Histogram {
+        match op {
+            FheOp::Encrypt => &self.encrypt_seconds,
+            FheOp::RunContract => &self.run_contract_seconds,
+            FheOp::Decrypt => &self.decrypt_seconds,
+        }
+    }
+
+    // Refresh the record gauges from the local Kademlia store
+    pub fn observe_store(&self, store: &impl RecordStore) {
+        let (records, bytes) = store.records()
+            .fold((0, 0), |(records, bytes), record| (records + 1, bytes + record.value.len() as u64));
+        self.records_stored.store(records, Ordering::Relaxed);
+        self.bytes_stored.store(bytes, Ordering::Relaxed);
+    }
+
+    pub fn render_prometheus(&self) -> String {
+        let metrics = [
+            ("fhe_sunscreen_peers_connected", "gauge", "Peers currently connected", &self.peers_connected),
+            ("fhe_sunscreen_records_stored", "gauge", "Records held in the local Kademlia store", &self.records_stored),
+            ("fhe_sunscreen_bytes_stored", "gauge", "Bytes of record values held in the local Kademlia store", &self.bytes_stored),
+            ("fhe_sunscreen_moves_applied_total", "counter", "Moves run through the AVS", &self.moves_applied),
+            ("fhe_sunscreen_decryptions_total", "counter", "Positions decrypted", &self.decryptions),
+            ("fhe_sunscreen_faults_total", "counter", "Failed DHT queries and decryptions", &self.faults),
+            ("fhe_sunscreen_records_put_total", "counter", "Records put to the DHT", &self.records_put),
+            ("fhe_sunscreen_records_got_total", "counter", "Records found in the DHT", &self.records_got),
+            ("fhe_sunscreen_refused_total", "counter", "Records and positions refused from peers with an incompatible protocol, or served for another peer", &self.refused),
+        ];
+        let histograms = [
+            ("fhe_sunscreen_encrypt_seconds", "Time to encrypt a move", &self.encrypt_seconds),
+            ("fhe_sunscreen_run_contract_seconds", "Time to run a move through the AVS", &self.run_contract_seconds),
+            ("fhe_sunscreen_decrypt_seconds", "Time to decrypt a position", &self.decrypt_seconds),
+        ];
+
+        let counters = metrics.iter()
+            .map(|(name, kind, help, value)| {
+                format!("# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {}\n", value.load(Ordering::Relaxed))
+            });
+        counters
+            .chain(histograms.iter().map(|(name, help, histogram)| histogram.render(name, help)))
+            .collect()
+    }
+}
+
+// The HTTP endpoint, behind the `metrics` feature. Without it the counters are still kept for tests.
+#[cfg(feature = "metrics")]
+mod server {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::NodeMetrics;
+
+    pub async fn bind_metrics(addr: SocketAddr) -> std::io::Result<TcpListener> {
+        TcpListener::bind(addr).await
+    }
+
+    // Minimal HTTP server: answers every request with the current metrics, then closes the connection.
+    pub async fn serve_metrics(listener: TcpListener, metrics: Arc<NodeMetrics>) {
+        loop {
+            let (mut stream, _) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    eprintln!("metrics: failed to accept connection: {e:?}");
+                    continue;
+                }
+            };
+
+            let metrics = metrics.clone();
+            tokio::spawn(async move {
+                // only the request line matters, the path is ignored
+                let mut request = [0u8; 1024];
+                if stream.read(&mut request).await.is_err() {
+                    return;
+                }
+                let body = metrics.render_prometheus();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            });
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histograms_render_cumulative_buckets() {
+        let metrics = NodeMetrics::default();
+        metrics.time(FheOp::RunContract, || ());
+        metrics.histogram(FheOp::RunContract).observe(Duration::from_millis(300));
+        metrics.histogram(FheOp::RunContract).observe(Duration::from_secs(60));
+
+        let text = metrics.render_prometheus();
+        assert!(text.contains("# TYPE fhe_sunscreen_run_contract_seconds histogram\n"));
+        assert!(text.contains("\nfhe_sunscreen_run_contract_seconds_bucket{le=\"0.001\"} 1\n"));
+        assert!(text.contains("\nfhe_sunscreen_run_contract_seconds_bucket{le=\"0.5\"} 2\n"));
+        assert!(text.contains("\nfhe_sunscreen_run_contract_seconds_bucket{le=\"10\"} 2\n"));
+        assert!(text.contains("\nfhe_sunscreen_run_contract_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("\nfhe_sunscreen_run_contract_seconds_count 3\n"));
+        assert!(text.contains("\nfhe_sunscreen_encrypt_seconds_count 0\n"));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn metrics_endpoint_serves_counters() {
+        use std::sync::Arc;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpStream;
+
+        let metrics = Arc::new(NodeMetrics::default());
+        let listener = bind_metrics("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_metrics(listener, metrics.clone()));
+
+        // simulated connection and move
+        metrics.connection_established();
+        metrics.move_applied();
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("\nfhe_sunscreen_peers_connected 1\n"));
+        assert!(response.contains("\nfhe_sunscreen_moves_applied_total 1\n"));
+        assert!(response.contains("\nfhe_sunscreen_faults_total 0\n"));
+    }
+}
@@ -0,0 +1,1753 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::stream::StreamExt;
+use tokio::io::{AsyncBufRead, Lines};
+
+use libp2p::{
+    core::{transport::{MemoryTransport, Transport}, upgrade},
+    gossipsub,
+    identity,
+    kad::{self, store::{MemoryStoreConfig, RecordStore}, Mode, Config},
+    mdns,
+    noise,
+    request_response,
+    Swarm,
+    swarm::{dial_opts::DialOpts, ConnectionError, NetworkBehaviour, SwarmEvent},
+    Multiaddr,
+    PeerId,
+    tcp,
+    yamux
+};
+
+use crate::{
+    AppError,
+    ciphertext_digest,
+    compress_record,
+    decode_position_record,
+    encode_position_record,
+    EncryptedPosition,
+    Position,
+    User,
+    WrapperCiphertext,
+    AVS,
+    form_avs_public_key,
+    form_encrypted_fhe_key,
+    form_fhe_public_key,
+    form_hello_key,
+    form_peer_name_key,
+    form_position_key,
+    get_peer_id_from_position_key,
+    public_key_fingerprint,
+    is_avs_public_key,
+    is_encrypted_fhe_key,
+    is_fhe_public_key,
+    is_position_key,
+    UserKeyPair,
+    AVS_PUBLIC_KEY,
+    ENCRYPTED_FHE_KEY,
+    FHE_PUBLIC_KEY,
+    HELLO,
+    PEER_NAME,
+    POSITION,
+};
+use crate::store::BoundedStore;
+use crate::records::{
+    record_owner, sign_record, verify_record, verify_record_sequenced, RecordRepublisher, RecordSequences,
+    KADEMLIA_RECORD_TTL,
+};
+use crate::hello::Hello;
+use crate::chunks::{
+    self,
+    ChunkAssembler,
+    ChunkManifest,
+    MAX_KADEMLIA_PACKET_SIZE,
+    MAX_RECORD_VALUE_BYTES,
+    RECORD_CHUNK_SIZE,
+};
+use crate::position_exchange::{self, PositionCodec, PositionRequest, PositionStore};
+use crate::metrics::{FheOp, NodeMetrics};
+use crate::redial::RedialPolicy;
+
+// Gossipsub topic PUBLISH broadcasts position updates on. Every node subscribes to it.
+pub const POSITION_TOPIC: &str = "fhe-sunscreen-positions";
+// Gossipsub's default 64kb limit is far below a position record: an uncompressed one is ~870kb,
+// plus the message signature and protobuf framing.
+pub const MAX_GOSSIP_MESSAGE_SIZE: usize = 2 * 1024 * 1024;
+
+// Create a custom network behaviour that combines Kademlia and mDNS.
+#[derive(NetworkBehaviour)]
+pub struct Behaviour {
+    pub kademlia: kad::Behaviour<BoundedStore>,
+    pub mdns: mdns::tokio::Behaviour,
+    pub position_exchange: request_response::Behaviour<PositionCodec>,
+    pub gossipsub: gossipsub::Behaviour,
+}
+
+pub fn position_topic() -> gossipsub::IdentTopic {
+    gossipsub::IdentTopic::new(POSITION_TOPIC)
+}
+
+// Node on TCP, for the fhe-sunscreen binary. `identity` should be the User's, which signs its records.
+pub fn build_swarm(identity: &identity::Keypair) -> Result<Swarm<Behaviour>, Box<dyn Error>> {
+
+    let mut swarm = libp2p::SwarmBuilder::with_existing_identity(identity.clone())
+        .with_tokio()
+        .with_tcp(
+            tcp::Config::default(),
+            noise::Config::new,
+            yamux::Config::default,
+        )?
+        .with_behaviour(|key| Ok(new_behaviour(key)?))?
+        .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(u64::MAX)))
+        .build();
+
+    swarm.behaviour_mut().kademlia.set_mode(Some(Mode::Server));
+    Ok(swarm)
+}
+
+// Node on an in-process memory transport (listen on /memory/0), for tests driving several nodes at once
+pub fn build_memory_swarm(identity: &identity::Keypair) -> Result<Swarm<Behaviour>, Box<dyn Error>> {
+
+    let mut swarm = libp2p::SwarmBuilder::with_existing_identity(identity.clone())
+        .with_tokio()
+        .with_other_transport(|key| {
+            Ok(MemoryTransport::default()
+                .upgrade(upgrade::Version::V1)
+                .authenticate(noise::Config::new(key)?)
+                .multiplex(yamux::Config::default()))
+        })?
+        .with_behaviour(|key| Ok(new_behaviour(key)?))?
+        .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(u64::MAX)))
+        .build();
+
+    swarm.behaviour_mut().kademlia.set_mode(Some(Mode::Server));
+    Ok(swarm)
+}
+
+fn new_behaviour(key: &identity::Keypair) -> Result<Behaviour, std::io::Error> {
+
+        let memory_store_config = BoundedStore::with_config(
+            key.public().to_peer_id(),
+            MemoryStoreConfig {
+                // The maximum number of records.
+                // FHE records are stored as RECORD_CHUNK_SIZE chunks, ~15 per position.
+                max_records: 4096,
+                // The maximum size of record values, in bytes.
+                max_value_bytes: MAX_RECORD_VALUE_BYTES,
+                // The maximum number of providers stored for a key.
+                // This should match up with the chosen replication factor.
+                max_providers_per_key: 1024,
+                // The maximum number of provider records for which the
+                // local node is the provider.
+                max_provided_keys: MemoryStoreConfig::default().max_provided_keys,
+            }
+        );
+
+        let mut config: Config = Default::default();
+        // ciphertexts are +865kb, so they're split into chunks that each fit in one packet
+        config.set_max_packet_size(MAX_KADEMLIA_PACKET_SIZE);
+        // Remote peers drop our records after this TTL, even if we put them with `expires: None`.
+        // We re-put originated records every `republish_interval` to stay ahead of it.
+        config.set_record_ttl(Some(KADEMLIA_RECORD_TTL));
+
+        let kad_behaviour = kad::Behaviour::with_config(
+            key.public().to_peer_id(),
+            memory_store_config,
+            config
+        );
+
+        // signed, so a message's source is the peer whose position it is
+        let gossipsub_config = gossipsub::ConfigBuilder::default()
+            .max_transmit_size(MAX_GOSSIP_MESSAGE_SIZE)
+            .build()
+            .map_err(std::io::Error::other)?;
+        let mut gossipsub = gossipsub::Behaviour::new(
+            gossipsub::MessageAuthenticity::Signed(key.clone()),
+            gossipsub_config
+        ).map_err(std::io::Error::other)?;
+        gossipsub.subscribe(&position_topic()).map_err(std::io::Error::other)?;
+
+        Ok(Behaviour {
+            kademlia: kad_behaviour,
+            mdns: mdns::tokio::Behaviour::new(
+                mdns::Config::default(),
+                key.public().to_peer_id(),
+            )?,
+            position_exchange: position_exchange::new_behaviour(),
+            gossipsub,
+        })
+}
+
+// A node's swarm and the state its commands and swarm events act on.
+// The binary drives it from stdin and the swarm; tests can drive several nodes in-process.
+pub struct Node {
+    pub swarm: Swarm<Behaviour>,
+    pub user: User,
+    pub avs: AVS,
+    pub republisher: RecordRepublisher,
+    pub assembler: ChunkAssembler,
+    // newest sequence seen per record key, so replayed older records are rejected
+    pub record_sequences: RecordSequences,
+    // this node's latest encrypted position, served to peers over the position exchange protocol
+    pub served_positions: PositionStore,
+    pub metrics: Arc<NodeMetrics>,
+    pub redial: RedialPolicy,
+    // the protocol version and ciphers this node advertises, and those its peers advertised
+    pub hello: Hello,
+    pub peer_hellos: HashMap<PeerId, Hello>,
+}
+
+impl Node {
+
+    pub fn new(
+        swarm: Swarm<Behaviour>,
+        user: User,
+        avs: AVS,
+        republisher: RecordRepublisher,
+        served_positions: PositionStore,
+        metrics: Arc<NodeMetrics>,
+    ) -> Node {
+        Node {
+            swarm,
+            user,
+            avs,
+            republisher,
+            assembler: ChunkAssembler::default(),
+            record_sequences: RecordSequences::default(),
+            served_positions,
+            metrics,
+            redial: RedialPolicy::default(),
+            hello: Hello::default(),
+            peer_hellos: HashMap::new(),
+        }
+    }
+
+    // One command line, e.g. `MOVE bob {"x":1,"y":2}`
+    pub fn handle_input_line(&mut self, line: String) -> Result<(), AppError> {
+        let result = handle_input_line(
+            &mut self.swarm,
+            &mut self.republisher,
+            &mut self.served_positions,
+            &self.metrics,
+            line,
+            &mut self.user,
+            &mut self.avs
+        );
+        self.metrics.observe_store(self.swarm.behaviour_mut().kademlia.store_mut());
+        result
+    }
+
+    // Re-put this node's records if they're due (see RecordRepublisher)
+    pub fn republish_due(&mut self, now: Instant) {
+        if let Some(records) = self.republisher.poll_due(now) {
+            println!("Republishing {} records ahead of the {KADEMLIA_RECORD_TTL:?} record TTL...", records.len());
+            let kademlia = &mut self.swarm.behaviour_mut().kademlia;
+            for record in records {
+                if let Err(e) = kademlia.put_record(record, kad::Quorum::One) {
+                    eprintln!("Failed to republish record: {e:?}");
+                    self.metrics.fault();
+                }
+            }
+        }
+    }
+
+    // Whether a peer advertised a HELLO this node can't work with. Peers that haven't sent one yet aren't refused.
+    pub fn refuses(&self, peer_id: &PeerId) -> bool {
+        self.peer_hellos.get(peer_id).is_some_and(|hello| self.hello.check(hello).is_err())
+    }
+
+    // Records a peer's HELLO. Positions are only requested from a peer once it's known to be compatible.
+    fn learn_hello(&mut self, peer_id: PeerId, hello: Hello) {
+        if self.peer_hellos.insert(peer_id, hello) == Some(hello) {
+            return;
+        }
+        match self.hello.check(&hello) {
+            Ok(()) => {
+                println!("{peer_id} speaks protocol version {}", hello.protocol_version);
+                if self.swarm.is_connected(&peer_id) {
+                    self.swarm.behaviour_mut().position_exchange.send_request(&peer_id, PositionRequest::new(peer_id));
+                }
+            }
+            Err(e) => eprintln!("[WARN] {peer_id} speaks an incompatible protocol ({e}), refusing its records and positions"),
+        }
+    }
+
+    // Redial peers whose connection dropped, if their backoff has elapsed (see RedialPolicy)
+    pub fn redial_due(&mut self, now: Instant) {
+        for (peer_id, address) in self.redial.poll_due(now) {
+            let attempt = self.redial.attempts(&peer_id).unwrap_or_default();
+            println!("Redialing {peer_id} at {address} (attempt {attempt}/{})...", self.redial.max_attempts);
+            let opts = DialOpts::peer_id(peer_id).addresses(vec![address]).build();
+            if let Err(e) = self.swarm.dial(opts) {
+                eprintln!("Failed to redial {peer_id}: {e}");
+                self.redial.dial_failed(peer_id, now);
+            }
+        }
+    }
+
+    // The node's event loop: handles command lines from `input`, swarm events and republishing until
+    // `shutdown` resolves (Ctrl-C in the binary). Call shutdown() afterwards to close it down.
+    pub async fn run_until<R: AsyncBufRead + Unpin>(&mut self, mut input: Lines<R>, shutdown: impl Future<Output = ()>) {
+        let interval = self.republisher.interval;
+        let mut republish_timer = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+        let mut redial_timer = tokio::time::interval(REDIAL_POLL_INTERVAL);
+        tokio::pin!(shutdown);
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => break,
+                Ok(Some(line)) = input.next_line() => {
+                    if let Err(e) = self.handle_input_line(line) {
+                        eprintln!("{e}");
+                    }
+                },
+                _ = republish_timer.tick() => self.republish_due(Instant::now()),
+                _ = redial_timer.tick() => self.redial_due(Instant::now()),
+                event = self.swarm.select_next_some() => self.handle_swarm_event(event),
+            }
+        }
+    }
+
+    // Optionally re-puts this node's records one last time (waiting up to SHUTDOWN_TIMEOUT for the puts),
+    // then closes every connection so peers see it leave instead of timing out on it.
+    pub async fn shutdown(&mut self, republish: bool) -> ShutdownSummary {
+        let records = self.republisher.records(Instant::now());
+        let mut republished = 0;
+
+        if republish {
+            let kademlia = &mut self.swarm.behaviour_mut().kademlia;
+            let mut pending: HashSet<kad::QueryId> = records.iter()
+                .filter_map(|record| kademlia.put_record(record.clone(), kad::Quorum::One).ok())
+                .collect();
+            let _ = tokio::time::timeout(SHUTDOWN_TIMEOUT, async {
+                while !pending.is_empty() {
+                    let event = self.swarm.select_next_some().await;
+                    if let SwarmEvent::Behaviour(BehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed {
+                        id, result: kad::QueryResult::PutRecord(result), step, ..
+                    })) = &event {
+                        if step.last && pending.remove(id) && result.is_ok() {
+                            republished += 1;
+                        }
+                    }
+                    self.handle_swarm_event(event);
+                }
+            }).await;
+        }
+
+        let peers: Vec<PeerId> = self.swarm.connected_peers().copied().collect();
+        for peer_id in peers {
+            let _ = self.swarm.disconnect_peer_id(peer_id);
+        }
+        let _ = tokio::time::timeout(SHUTDOWN_TIMEOUT, async {
+            while self.swarm.connected_peers().next().is_some() {
+                let event = self.swarm.select_next_some().await;
+                self.handle_swarm_event(event);
+            }
+        }).await;
+
+        let (chunks, published): (Vec<kad::Record>, Vec<kad::Record>) = records.into_iter()
+            .partition(|record| chunks::parse_chunk_key(&record.key).is_some());
+        let mut published: Vec<String> = published.iter()
+            .map(|record| String::from_utf8_lossy(record.key.as_ref()).into_owned())
+            .collect();
+        published.sort();
+        ShutdownSummary { published, chunks: chunks.len(), republished }
+    }
+
+    pub fn handle_swarm_event(&mut self, event: SwarmEvent<BehaviourEvent>) {
+        match event {
+            SwarmEvent::NewListenAddr { address, .. } => {
+                let local_peer_id = *self.swarm.local_peer_id();
+                let user_name = self.user.name.as_deref().unwrap_or("");
+                println!("[Local Peer]: <{user_name}> {local_peer_id} listening on {address:?}\n");
+                self.avs.set_peer_id(Some(local_peer_id));
+            },
+            SwarmEvent::ConnectionClosed { cause, peer_id, num_established, ..} => {
+                println!("ConnectionClosed for peer {peer_id}: {cause:?}");
+                self.metrics.connection_closed();
+                // no cause means we closed it ourselves (e.g. on shutdown), and idle timeouts are ours too
+                let deliberate = matches!(cause, None | Some(ConnectionError::KeepAliveTimeout));
+                if num_established == 0 {
+                    if let Some(delay) = self.redial.connection_closed(peer_id, deliberate, Instant::now()) {
+                        println!("Redialing {peer_id} in {delay:?}");
+                    }
+                }
+            },
+            SwarmEvent::ConnectionEstablished { peer_id, endpoint, ..} => {
+                self.metrics.connection_established();
+                // a peer we dialed (DIAL or --bootstrap) is routable at the address we reached it on,
+                // which is the only way peers on other networks get into the routing table without mDNS
+                if endpoint.is_dialer() {
+                    let address = endpoint.get_remote_address().clone();
+                    self.swarm.behaviour_mut().kademlia.add_address(&peer_id, address.clone());
+                    self.redial.connected(peer_id, Some(address));
+                } else {
+                    self.redial.connected(peer_id, None);
+                }
+                if let Err(e) = handle_connection_established(peer_id, &self.hello, &mut self.user, &mut self.avs, &mut self.swarm, &mut self.republisher) {
+                    eprintln!("Failed to set up connection with {peer_id}: {e}");
+                }
+                // on a reconnection the peer's HELLO is already known, otherwise this waits for it (see learn_hello)
+                if self.peer_hellos.get(&peer_id).is_some_and(|hello| self.hello.check(hello).is_ok()) {
+                    self.swarm.behaviour_mut().position_exchange.send_request(&peer_id, PositionRequest::new(peer_id));
+                }
+            },
+            SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+                let peer = peer_id.map(|p| p.to_string()).unwrap_or("unknown peer".to_string());
+                eprintln!("Failed to dial {peer}: {error}");
+                if let Some(peer_id) = peer_id.filter(|peer_id| self.redial.attempts(peer_id).is_some()) {
+                    match self.redial.dial_failed(peer_id, Instant::now()) {
+                        Some(delay) => println!("Redialing {peer_id} in {delay:?}"),
+                        None => eprintln!("Giving up on {peer_id} after {} redials", self.redial.max_attempts),
+                    }
+                }
+            },
+            SwarmEvent::Behaviour(
+                BehaviourEvent::Mdns(mdns::Event::Discovered(list))
+            ) => {
+                for (peer_id, multiaddr) in list {
+                    let kademlia = &mut self.swarm.behaviour_mut().kademlia;
+                    kademlia.add_address(&peer_id, multiaddr);
+                }
+            },
+            SwarmEvent::Behaviour(
+                BehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed { result, ..})
+            ) => {
+                match result {
+                    kad::QueryResult::GetRecord(Ok(
+                        kad::GetRecordOk::FoundRecord(kad::PeerRecord { record, .. })
+                    )) => {
+                        self.metrics.record_got();
+                        let key = String::from_utf8_lossy(record.key.as_ref()).into_owned();
+                        if key.starts_with(&format!("{HELLO}_")) {
+                            match verify_record(record).ok().and_then(|record| hello_from_record(&record)) {
+                                Some((peer_id, hello)) => self.learn_hello(peer_id, hello),
+                                None => eprintln!("Ignoring invalid HELLO record {key}"),
+                            }
+                        } else if record_owner(&record.key).is_some_and(|owner| self.refuses(&owner)) {
+                            eprintln!("Refusing record {key} from a peer with an incompatible protocol");
+                            self.metrics.refused();
+                        } else if let Err(e) = handle_get_record_result(
+                            record,
+                            &mut self.swarm.behaviour_mut().kademlia,
+                            &mut self.assembler,
+                            &mut self.record_sequences,
+                            &mut self.user,
+                            &mut self.avs,
+                            &self.metrics
+                        ) {
+                            eprintln!("Failed to handle record {key}: {e}");
+                        }
+                    }
+                    kad::QueryResult::GetRecord(Err(err)) => {
+                        eprintln!("Failed to get record: {err:?}");
+                        self.metrics.fault();
+                    }
+                    kad::QueryResult::PutRecord(Ok(kad::PutRecordOk { key })) => {
+                        self.metrics.record_put();
+                        println!(
+                            "Successfully put record {:?}",
+                            String::from_utf8_lossy(key.as_ref())
+                        );
+                    }
+                    kad::QueryResult::PutRecord(Err(err)) => {
+                        eprintln!("Failed to put record: {err:?}");
+                        self.metrics.fault();
+                    }
+                    _ => {}
+                }
+            },
+            SwarmEvent::Behaviour(BehaviourEvent::PositionExchange(request_response::Event::Message {
+                peer,
+                message: request_response::Message::Request { request, channel, .. },
+            })) => {
+                let response = self.served_positions.respond(&request);
+                println!("Serving position for {:?} to {peer} (found: {})", request.subject(), response.position.is_some());
+                if self.swarm.behaviour_mut().position_exchange.send_response(channel, response).is_err() {
+                    eprintln!("Failed to send position to {peer}: connection closed");
+                }
+            },
+            SwarmEvent::Behaviour(BehaviourEvent::PositionExchange(request_response::Event::Message {
+                peer,
+                message: request_response::Message::Response { response, .. },
+            })) => {
+                match (response.subject(), response.position) {
+                    (Some(subject), Some(_)) if self.refuses(&subject) || self.refuses(&peer) => {
+                        eprintln!("Refusing position for {subject} from {peer}: incompatible protocol");
+                        self.metrics.refused();
+                    }
+                    // positions are only requested from the player who moved, and nodes only serve their own:
+                    // a response for anyone else is a peer passing off (or replaying) another player's ciphertext
+                    (Some(subject), Some(_)) if subject != peer => {
+                        eprintln!("Refusing position for {subject} from {peer}: peers only serve their own position");
+                        self.metrics.refused();
+                    }
+                    (Some(subject), Some(position)) => {
+                        println!("read encrypted position for {subject} directly from {peer}...");
+                        if let Err(e) = handle_encrypted_position(&position, subject, &mut self.user, &mut self.avs, &self.metrics) {
+                            eprintln!("Failed to decrypt position for {subject}: {e}");
+                        }
+                    }
+                    (subject, None) => println!("{peer} has no position for {subject:?}"),
+                    (None, Some(_)) => eprintln!("{peer} sent a position for an invalid peer id"),
+                }
+            },
+            SwarmEvent::Behaviour(BehaviourEvent::PositionExchange(
+                request_response::Event::OutboundFailure { peer, error, .. }
+            )) => {
+                eprintln!("Failed to request position from {peer}: {error}");
+                self.metrics.fault();
+            },
+            SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Message { message, .. })) => {
+                match message.source {
+                    Some(subject) if message.topic == position_topic().hash() && self.refuses(&subject) => {
+                        eprintln!("Refusing gossiped position from {subject}: incompatible protocol");
+                        self.metrics.refused();
+                    }
+                    Some(subject) if message.topic == position_topic().hash() => {
+                        println!("received encrypted position for {subject} over gossipsub...");
+                        if let Err(e) = handle_encrypted_position(&message.data, subject, &mut self.user, &mut self.avs, &self.metrics) {
+                            eprintln!("Failed to decrypt position for {subject}: {e}");
+                        }
+                    }
+                    _ => eprintln!("Ignoring gossipsub message on {} from {:?}", message.topic, message.source),
+                }
+            },
+            SwarmEvent::Behaviour(
+                BehaviourEvent::Kademlia(kad::Event::InboundRequest { request: kad::InboundRequest::PutRecord { .. } })
+            ) => {
+                // remote peers storing records with us
+                let local_peer_id = *self.swarm.local_peer_id();
+                let local = self.user.name.as_deref().map(|name| (name, local_peer_id));
+                let store = self.swarm.behaviour_mut().kademlia.store_mut();
+                for (name, peer_id) in learn_peer_names(store, &mut self.avs.peer_ids, local) {
+                    println!("Learned peer name {name}: {peer_id}");
+                }
+                self.metrics.observe_store(store);
+                let hellos: Vec<(PeerId, Hello)> = store.records()
+                    .filter(|record| has_key_kind(record, HELLO))
+                    .filter_map(|record| verify_record(record.into_owned()).ok())
+                    .filter_map(|record| hello_from_record(&record))
+                    .collect();
+                for (peer_id, hello) in hellos {
+                    self.learn_hello(peer_id, hello);
+                }
+            },
+            _ => {
+                println!("...")
+            },
+        }
+    }
+}
+
+// How long shutdown() waits for the last republish, and for connections to close
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+// how often the event loop checks for redials that are due
+const REDIAL_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// Records this node originated, as of shutdown
+#[derive(Debug)]
+pub struct ShutdownSummary {
+    // record keys, without the chunks of chunked records
+    pub published: Vec<String>,
+    pub chunks: usize,
+    // puts (records and chunks) that succeeded in the last republish
+    pub republished: usize,
+}
+
+impl std::fmt::Display for ShutdownSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "published {} records ({} chunks), {} republished on shutdown",
+            self.published.len(), self.chunks, self.republished
+        )?;
+        for key in &self.published {
+            write!(f, "\n\t{key}")?;
+        }
+        Ok(())
+    }
+}
+
+fn handle_connection_established(
+    peer_id: libp2p::PeerId,
+    hello: &Hello,
+    user: &mut User,
+    avs: &mut AVS,
+    swarm: &mut Swarm<Behaviour>,
+    republisher: &mut RecordRepublisher,
+) -> Result<(), AppError> {
+
+    println!("[Remote Peer]: {peer_id}: ConnectionEstablished!");
+    let local_peer_id = *swarm.local_peer_id();
+    let key = form_avs_public_key(&local_peer_id.to_string());
+    let kademlia = &mut swarm.behaviour_mut().kademlia;
+    let avs_public_key_value: Vec<u8> = ecdh::public_key_to_sec1_compressed(&user.ecdh_public_key);
+
+    let record = sign_record(kad::Record {
+        key: kad::RecordKey::new(&key),
+        value: avs_public_key_value,
+        publisher: None,
+        expires: None,
+    }, &user.identity);
+    republisher.track(record.clone());
+    kademlia.put_record(record, kad::Quorum::One)?;
+
+    register_peer_names(&mut avs.peer_ids, user.name.as_deref(), local_peer_id, peer_id);
+    // advertise our name, directly to the new peer as well since it may not be routable yet
+    if let Some(name) = &user.name {
+        let record = form_peer_name_record(&user.identity, name);
+        republisher.track(record.clone());
+        kademlia.put_record(record.clone(), kad::Quorum::One)?;
+        kademlia.put_record_to(record, std::iter::once(peer_id), kad::Quorum::One);
+    }
+
+    // advertise what we speak, directly to the new peer too, and fetch its HELLO in case it put one already
+    let record = sign_record(kad::Record {
+        key: kad::RecordKey::new(&form_hello_key(&local_peer_id.to_string())),
+        value: hello.to_bytes(),
+        publisher: Some(local_peer_id),
+        expires: None,
+    }, &user.identity);
+    republisher.track(record.clone());
+    kademlia.put_record(record.clone(), kad::Quorum::One)?;
+    kademlia.put_record_to(record, std::iter::once(peer_id), kad::Quorum::One);
+    kademlia.get_record(kad::RecordKey::new(&form_hello_key(&peer_id.to_string())));
+
+    // catch up on every known peer's records, e.g. after a restart or partition
+    let mut known_peers: Vec<PeerId> = avs.peer_ids.values().copied().collect();
+    known_peers.push(peer_id);
+    known_peers.retain(|p| *p != local_peer_id);
+    let resync_keys = resync_record_keys(&known_peers, &avs.peer_public_keys, &user.peer_fhe_decryption_keys);
+    println!("Resyncing {} records from {} known peers...", resync_keys.len(), known_peers.len());
+    for key in resync_keys {
+        kademlia.get_record(key);
+    }
+    // positions aren't in the DHT: the new peer's latest one is requested directly once its HELLO checks out
+    Ok(())
+}
+
+// Commands address peers by name: this node under `--name`, every peer under its peer id, and remote
+// peers under the name they advertise in their PEER_NAME record (see learn_peer_names).
+fn register_peer_names(
+    peer_ids: &mut std::collections::HashMap<String, PeerId>,
+    local_name: Option<&str>,
+    local_peer_id: PeerId,
+    peer_id: PeerId,
+) {
+    if let Some(name) = local_name {
+        peer_ids.insert(name.to_string(), local_peer_id);
+    }
+    peer_ids.insert(peer_id.to_string(), peer_id);
+}
+
+// Registers every advertised name in the local store, returning the newly learned ones.
+fn learn_peer_names(
+    store: &mut BoundedStore,
+    peer_ids: &mut std::collections::HashMap<String, PeerId>,
+    local: Option<(&str, PeerId)>,
+) -> Vec<(String, PeerId)> {
+    let advertised: Vec<(String, PeerId)> = store.records()
+        .filter(|record| has_key_kind(record, PEER_NAME))
+        .filter_map(|record| verify_record(record.into_owned()).ok())
+        .filter_map(|record| peer_name_from_record(&record))
+        .collect();
+
+    advertised.into_iter()
+        .filter(|(name, peer_id)| bind_peer_name(peer_ids, local, name, *peer_id))
+        .collect()
+}
+
+// Names are first come, first served: a name already bound (or this node's own `--name`) is never
+// rebound, so a peer can't redirect commands meant for another by advertising its name.
+// Returns whether the name was newly bound.
+fn bind_peer_name(
+    peer_ids: &mut std::collections::HashMap<String, PeerId>,
+    local: Option<(&str, PeerId)>,
+    name: &str,
+    peer_id: PeerId,
+) -> bool {
+    let bound = match local {
+        Some((local_name, local_peer_id)) if local_name == name => Some(local_peer_id),
+        _ => peer_ids.get(name).copied(),
+    };
+    match bound {
+        Some(bound) if bound == peer_id => false,
+        Some(bound) => {
+            eprintln!("[WARN] Ignoring claim of name {name} by {peer_id}: already bound to {bound}");
+            false
+        }
+        None => {
+            peer_ids.insert(name.to_string(), peer_id);
+            true
+        }
+    }
+}
+
+// Records to fetch on (re)connection so a rejoining node rebuilds its state without manual GETs.
+// Keys are skipped if already held; positions are requested from the peer directly, not from the DHT.
+// ENCRYPTED_FHE_KEY only exists for peers who shared their key with us, for others the GET just fails.
+fn resync_record_keys(
+    peers: &[PeerId],
+    held_avs_public_keys: &std::collections::HashMap<String, k256::PublicKey>,
+    held_fhe_keys: &std::collections::HashMap<String, UserKeyPair>,
+) -> Vec<kad::RecordKey> {
+
+    let mut peers = peers.to_vec();
+    peers.sort();
+    peers.dedup();
+
+    peers.iter().flat_map(|peer_id| {
+        let peer_id = peer_id.to_string();
+        let avs_public_key = form_avs_public_key(&peer_id);
+
+        let mut keys = vec![];
+        if !held_avs_public_keys.contains_key(&avs_public_key) {
+            keys.push(avs_public_key);
+        }
+        if !held_fhe_keys.contains_key(&peer_id) {
+            keys.push(form_encrypted_fhe_key(&peer_id));
+        }
+        keys
+    })
+    .map(|key| kad::RecordKey::new(&key))
+    .collect()
+}
+
+fn handle_get_record_result(
+    record: kad::Record,
+    kademlia: &mut kad::Behaviour<BoundedStore>,
+    assembler: &mut ChunkAssembler,
+    sequences: &mut RecordSequences,
+    user: &mut User,
+    avs: &mut AVS,
+    metrics: &NodeMetrics,
+) -> Result<(), AppError> {
+
+    // e.g. a shared key put with --record-ttl, or a chunk of one. Treated as absent.
+    if record.is_expired(Instant::now()) {
+        println!("record {} expired, ignoring it", String::from_utf8_lossy(record.key.as_ref()));
+        return Ok(());
+    }
+
+    // chunked records: fetch every chunk listed in the manifest, and only handle the record once all have arrived.
+    // A chunk's value can start with any byte, so chunks are recognised by their key before looking for a manifest.
+    let record = match chunks::parse_chunk_key(&record.key) {
+        Some(_) => match assembler.add_chunk(&record.key, record.value).inspect_err(|_| metrics.fault())? {
+            Some(record) => record,
+            None => return Ok(()),
+        },
+        None => record,
+    };
+    if let Some(manifest) = ChunkManifest::decode(&record.value) {
+        let manifest = manifest.inspect_err(|_| metrics.fault())?;
+        let key = String::from_utf8_lossy(record.key.as_ref()).into_owned();
+        let chunk_keys = assembler.start(record.key, record.publisher, manifest).inspect_err(|_| metrics.fault())?;
+        println!("Fetching {} chunks of {key}...", chunk_keys.len());
+        for chunk_key in chunk_keys {
+            kademlia.get_record(chunk_key);
+        }
+        return Ok(());
+    }
+
+    // only records signed by the peer whose key they're stored under are trusted
+    let key = String::from_utf8_lossy(record.key.as_ref()).into_owned();
+    let publisher = record.publisher;
+    let (record, sequence) = verify_record_sequenced(record).inspect_err(|e| {
+        metrics.fault();
+        eprintln!("[WARN] Rejected {key} from {publisher:?}: {e}");
+    })?;
+    // and only if they're not older than one we've already seen under the same key (a replay)
+    sequences.check(&record.key, sequence).inspect_err(|e| {
+        metrics.fault();
+        eprintln!("[WARN] Rejected stale {key} from {publisher:?}: {e}");
+    })?;
+
+    if let Some((name, peer_id)) = peer_name_from_record(&record) {
+        if bind_peer_name(&mut avs.peer_ids, user.name.as_deref().zip(avs.peer_id), &name, peer_id) {
+            println!("Learned peer name {name}: {peer_id}");
+        }
+        return Ok(());
+    }
+
+    let kad::Record { key, value, publisher, ..  } = record;
+    let key_str = std::str::from_utf8(key.as_ref()).inspect_err(|_| metrics.fault())?;
+
+    if is_encrypted_fhe_key(key_str) {
+        // replicate alice decryption key for testing purposes
+
+        let peer_id = publisher
+            .ok_or_else(|| format!("{key_str} has no publisher"))
+            .inspect_err(|_| metrics.fault())?
+            .to_string();
+
+        println!("Getting alice keys for Bob...");
+        let peer_keys = UserKeyPair::from_bytes(&value)
+            .inspect_err(|_| metrics.fault())?;
+
+        user.peer_fhe_decryption_keys.insert(peer_id, peer_keys);
+        println!("saved alice's encrypted FHE keys and ECDH public key in AVS node");
+
+    } else if is_position_key(key_str) {
+        // positions published to the DHT before the position exchange protocol
+        println!("read encrypted position from IPFS kademlia...");
+        let subject: PeerId = get_peer_id_from_position_key(key_str).parse()?;
+        println!("publisher: {:?}", publisher);
+        handle_encrypted_position(&value, subject, user, avs, metrics)?;
+
+    } else if is_fhe_public_key(key_str) {
+        // a peer's FHE public key: encrypts positions only that peer can decrypt (User::create_move_transaction_for_peer)
+        let peer_id = publisher
+            .ok_or_else(|| format!("{key_str} has no publisher"))
+            .inspect_err(|_| metrics.fault())?
+            .to_string();
+        let fhe_public_key: sunscreen::PublicKey = bincode::deserialize(&value)
+            .inspect_err(|_| metrics.fault())?;
+
+        println!("saved {peer_id}'s FHE public key");
+        user.peer_fhe_public_keys.insert(peer_id, fhe_public_key);
+
+    } else if is_avs_public_key(key_str) {
+
+        let avs_public_key: k256::PublicKey = ecdh::public_key_from_sec1_compressed(&value)
+            .inspect_err(|e| {
+                metrics.fault();
+                println!("Rejected {key_str} from {publisher:?}: {e}");
+            })?;
+
+        println!("\nSaved {}: {:?} of length: {}", key_str, avs_public_key.as_affine(), value.len());
+        avs.peer_public_keys.insert(key_str.to_string(), avs_public_key);
+        // use this public_key to encrypt alice's FHE key intended for Bob
+
+    } else {
+        println!("Unhandled key")
+    }
+    Ok(())
+}
+
+// Decodes and decrypts a player's position record, from the position exchange or the DHT
+fn handle_encrypted_position(
+    encoded_position: &[u8],
+    subject: PeerId,
+    user: &mut User,
+    avs: &mut AVS,
+    metrics: &NodeMetrics,
+) -> Result<(), AppError> {
+
+    println!("unpacking encrypted positions (ciphertexts are +870 kb)...");
+    let encrypted_position: EncryptedPosition = decode_position_record(encoded_position)
+        .inspect_err(|_| metrics.fault())?;
+    println!(
+        "encrypted position x: {}, y: {}, z: {}",
+        ciphertext_digest(&encrypted_position.x),
+        ciphertext_digest(&encrypted_position.y),
+        ciphertext_digest(&encrypted_position.z)
+    );
+
+    println!("Decoding encrypted positions...");
+    // `subject` is always a concrete peer (the position exchange subject, or the peer id in a POSITION_<peer_id> key),
+    // never the record's optional publisher. Before our own peer id is known we can't tell which positions are ours.
+    let local_peer_id = avs.peer_id
+        .ok_or("local peer id isn't known yet (no listen address), can't tell own positions from peers'")
+        .inspect_err(|_| metrics.fault())?;
+
+    let position = match subject == local_peer_id {
+        true  => metrics.time(FheOp::Decrypt, || user.decrypt_own_position(encrypted_position).map_err(AppError::from)),
+        false => {
+            // kept for LIST, even if we can't decrypt it
+            avs.peer_positions.insert(subject.to_string(), encrypted_position.clone());
+            metrics.time(FheOp::Decrypt, || user.decrypt_peer_position(encrypted_position, &subject.to_string()))
+        }
+    }.inspect_err(|_| metrics.fault())?;
+    metrics.decryption_performed();
+
+    println!("Decrypted position for {subject}: {position:?}");
+    Ok(())
+}
+
+fn handle_input_line(
+    swarm: &mut Swarm<Behaviour>,
+    republisher: &mut RecordRepublisher,
+    served_positions: &mut PositionStore,
+    metrics: &NodeMetrics,
+    line: String,
+    user: &mut User,
+    avs: &mut AVS
+) -> Result<(), AppError> {
+    let local_peer_id = *swarm.local_peer_id();
+    let Behaviour { kademlia, position_exchange, gossipsub, .. } = swarm.behaviour_mut();
+    let mut args = line.split(' ');
+
+    match (args.next(), args.next()) {
+        (None, _) => {
+            return Err("expected GET, PUT, MOVE, MOVE_SIM, PUBLISH, DELETE, SHARE_KEY, SHARE_PUBLIC_KEY, ROTATE_KEYS, LIST, STATS, FINGERPRINT, DIAL or EXPANSION".into());
+        }
+        (Some("ROTATE_KEYS"), _) => {
+            let old_public_key = user.fhe_public_key.clone();
+            user.rotate_fhe_keys()?;
+
+            // carry the current position over to the new key, and serve the re-encrypted copy
+            if let Some(position) = avs.encrypted_positions.get(&avs.get_public_key_hex(&old_public_key)).cloned() {
+                let position = user.reencrypt_positions(&[position])?.remove(0);
+                let encoded_position = encode_position_record(&position)?;
+                served_positions.insert(local_peer_id, compress_record(&encoded_position, served_positions.zstd_level)?);
+                avs.rekey_position(&old_public_key, &user.fhe_public_key, position);
+            }
+
+            // a published public key is harmless to replace: peers would otherwise encrypt under the old one
+            let key = kad::RecordKey::new(&form_fhe_public_key(&local_peer_id.to_string()));
+            if delete_record(kademlia, republisher, &key) {
+                let chunk_count = put_fhe_public_key(kademlia, republisher, local_peer_id, user)?;
+                println!("republished FHE public key in {chunk_count} chunks");
+            }
+
+            // stop handing out the old key: drop our shared copy and its DHT record
+            let local_peer_id = local_peer_id.to_string();
+            user.peer_fhe_decryption_keys.remove(&local_peer_id);
+            let key = kad::RecordKey::new(&form_encrypted_fhe_key(&local_peer_id));
+            if delete_record(kademlia, republisher, &key) {
+                println!("deleted shared FHE key, run SHARE_KEY again for peers that should keep access");
+            }
+            println!("rotated FHE keys");
+        }
+        (Some("LIST"), _) => {
+            let listings = list_known_peers(local_peer_id, user, avs);
+            println!("{} known peers:", listings.len());
+            for listing in listings {
+                println!("{listing}");
+            }
+        }
+        (Some("STATS"), _) => {
+            let store = kademlia.store_mut();
+            println!(
+                "record store: {}/{} records, {} puts rejected while full",
+                store.len(), store.max_records(), store.rejected()
+            );
+            println!("republishing {} records", republisher.records(Instant::now()).len());
+        }
+        (Some("SHARE_PUBLIC_KEY"), _) => {
+            // unlike SHARE_KEY, only lets peers encrypt positions for this node, not decrypt its own
+            let chunk_count = put_fhe_public_key(kademlia, republisher, local_peer_id, user)?;
+            println!("stored {} in {chunk_count} chunks", form_fhe_public_key(&local_peer_id.to_string()));
+        }
+        (Some("EXPANSION"), _) => {
+            let expansion = user.measure_ciphertext_expansion()
+                .map_err(|e| format!("Failed to measure ciphertext expansion: {e:?}"))?;
+            println!("Position ciphertext size: {expansion}");
+        }
+        (Some("PUBLISH"), _) => {
+            // broadcast the latest MOVE to every subscribed peer, instead of waiting for each to request it
+            let position = served_positions.respond(&PositionRequest::new(local_peer_id)).position
+                .ok_or("no position yet, MOVE first")?;
+            let message_id = gossipsub.publish(position_topic(), position)
+                .map_err(|e| format!("Failed to publish position: {e}"))?;
+            println!("published encrypted position ({message_id})");
+        }
+        (Some(_), None) => {
+            return Err("Expected key in 2nd argument".into());
+        }
+        (Some("DIAL"), Some(addr)) => {
+            let addr: Multiaddr = addr.parse().map_err(|e| format!("invalid multiaddr {addr}: {e}"))?;
+            println!("Dialing {addr}...");
+            // failures to connect come back later as an OutgoingConnectionError
+            swarm.dial(addr)?;
+        }
+        (Some("GET"), Some(cmd)) => {
+
+            let name = args.next().ok_or("expected a peer name or peer id for 3rd argument")?;
+            let subject = lookup_peer_id(avs, name)?;
+            let peer_id = subject.to_string();
+
+            match cmd {
+                AVS_PUBLIC_KEY => {
+                    kademlia.get_record(kad::RecordKey::new(&form_avs_public_key(&peer_id)));
+                }
+                POSITION => {
+                    if subject == local_peer_id {
+                        let response = served_positions.respond(&PositionRequest::new(subject));
+                        match response.position {
+                            Some(position) => handle_encrypted_position(&position, subject, user, avs, metrics)
+                                .map_err(|e| format!("Failed to decrypt own position: {e}"))?,
+                            None => println!("no position yet, MOVE first"),
+                        }
+                    } else {
+                        position_exchange.send_request(&subject, PositionRequest::new(subject));
+                    }
+                }
+                ENCRYPTED_FHE_KEY => {
+                    kademlia.get_record(kad::RecordKey::new(&form_encrypted_fhe_key(&peer_id)));
+                }
+                FHE_PUBLIC_KEY => {
+                    kademlia.get_record(kad::RecordKey::new(&form_fhe_public_key(&peer_id)));
+                }
+                _ => {
+                    return Err("Unrecognised GET command: choose AVS_PUBLICKEY, POSITION, ENCRYPTED_FHE_KEY or FHE_PUBLIC_KEY".into());
+                }
+            }
+        }
+        (Some("DELETE"), Some(cmd)) => {
+
+            let name = args.next().ok_or("expected a peer name or peer id for 3rd argument")?;
+            let peer_id = lookup_peer_id(avs, name)?;
+
+            let key = match cmd {
+                POSITION => {
+                    // only this node's own position is held in AVS state and served to peers
+                    if peer_id == local_peer_id {
+                        avs.clear_position(&user.fhe_public_key);
+                        served_positions.remove(&local_peer_id);
+                    }
+                    avs.peer_positions.remove(&peer_id.to_string());
+                    // and any copy left in the DHT from before the position exchange protocol
+                    form_position_key(&peer_id.to_string())
+                }
+                ENCRYPTED_FHE_KEY => {
+                    user.peer_fhe_decryption_keys.remove(&peer_id.to_string());
+                    form_encrypted_fhe_key(&peer_id.to_string())
+                }
+                _ => {
+                    return Err("Unrecognised DELETE command: choose POSITION or ENCRYPTED_FHE_KEY".into());
+                }
+            };
+
+            match delete_record(kademlia, republisher, &kad::RecordKey::new(&key)) {
+                true => println!("deleted {key}"),
+                false => println!("no record for {key}, nothing to delete"),
+            }
+        }
+        (Some("SHARE_KEY"), Some(name)) => {
+            // Encrypt Alice's FHE private key and share it with Bob using Elliptic-curve Diffie–Hellman (ECDH).
+            // This is for testing only. Alice should not be sharing private keys.
+            let peer_id = lookup_peer_id(avs, name)?.to_string();
+
+            // Get Bob's ECDH public key
+            let avs_peer_ecdh_public_key = avs.peer_public_keys
+                .get(&form_avs_public_key(&peer_id))
+                .ok_or_else(|| format!("avs_peer_ecdh_public_key for {peer_id} missing"))?;
+
+            // ECDH encrypt so Bob can decrypt using his shared secret
+            println!("encrypting {}'s private_key for {name}...", user.name.as_deref().unwrap_or("this node"));
+            let record_key = form_encrypted_fhe_key(&local_peer_id.to_string());
+            let alice_fhe_private_key_encrypted = user.encrypt_fhe_key_for_peer(&peer_id, avs_peer_ecdh_public_key, &record_key)
+                .map_err(|e| format!("Failed to encrypt FHE key for {peer_id}: {e}"))?;
+
+            let encrypted_fhe_keys = UserKeyPair {
+                ecdh_public_key: user.ecdh_public_key,
+                fhe_private_key_encrypted: alice_fhe_private_key_encrypted
+            }.to_bytes();
+
+            let record = sign_record(kad::Record {
+                key: kad::RecordKey::new(&record_key),
+                value: encrypted_fhe_keys,
+                publisher: Some(local_peer_id),
+                expires: republisher.expires_at(Instant::now()),
+            }, &user.identity);
+            match put_chunked_record(kademlia, republisher, record) {
+                Ok(chunk_count) => println!("stored {local_peer_id}_private_key in {chunk_count} chunks"),
+                Err(e) => println!("{:?}", e),
+            }
+        }
+        (Some("FINGERPRINT"), Some(name)) => {
+            // the AVS_PUBLIC_KEY we'd share our FHE key under, to compare with the peer's own out-of-band
+            let peer_id = lookup_peer_id(avs, name)?;
+            let fingerprint = match peer_id == local_peer_id {
+                true => user.ecdh_public_key_fingerprint(),
+                false => avs.peer_public_keys.get(&form_avs_public_key(&peer_id.to_string()))
+                    .map(public_key_fingerprint)
+                    .ok_or_else(|| AppError::PeerNotFound(format!("no AVS_PUBLIC_KEY cached for {name}, GET AVS_PUBLIC_KEY {name} first")))?,
+            };
+            println!("{name} ({peer_id}) AVS_PUBLIC_KEY fingerprint: {fingerprint}");
+        }
+        (Some("MOVE_SIM"), Some(name)) => {
+            // a dry run of MOVE: runs the contract but keeps, serves and puts nothing
+            let position = parse_position_arg(args.next())?;
+            lookup_peer_id(avs, name)?;
+            println!("Simulating move to: ({}, {}, {})", position.x, position.y, position.z);
+
+            let move_tx = user.create_move_transaction(position)?;
+            let simulated_position = avs.simulate_contract(move_tx, &user.fhe_public_key)?;
+            println!(
+                "simulated move #{} for {name}: {} hops, estimated noise budget {:?} bits, ciphertext {}",
+                avs.move_sequence(&user.fhe_public_key) + 1,
+                simulated_position.hops,
+                avs.estimate_noise_budget(&simulated_position),
+                WrapperCiphertext(&simulated_position.x)
+            );
+            let decrypted = user.decrypt_own_position(simulated_position)?;
+            println!("would move to: ({}, {}, {}) (not applied)", decrypted.x, decrypted.y, decrypted.z);
+        }
+        (Some("MOVE"), Some(name)) => {
+            let position = parse_position_arg(args.next())?;
+
+            // `name` is the recipient the move is meant for; the position is always served
+            // keyed by the subject (this node), and the recipient requests it directly.
+            // Looked up before the move is applied, so a typo doesn't move the player.
+            let recipient_peer_id = lookup_peer_id(avs, name)?;
+            println!("Moving to: ({}, {}, {})", position.x, position.y, position.z);
+
+            let move_tx = metrics.time(FheOp::Encrypt, || user.create_move_transaction(position))?;
+
+            let new_encrypted_position = metrics.time(FheOp::RunContract, || avs.run_contract(
+                move_tx,
+                &user.fhe_public_key // can use peer AVS's public key. Then peer can decrypt Alice's position
+            ))?;
+            metrics.move_applied();
+            println!("applied move #{} for {name}", avs.move_sequence(&user.fhe_public_key));
+            println!("serving encrypted position to recipient {name} ({recipient_peer_id})...");
+
+            let encoded_position = encode_position_record(&new_encrypted_position)?;
+            let compressed_position = compress_record(&encoded_position, served_positions.zstd_level)?;
+            println!("compressed position from {} to {} bytes", encoded_position.len(), compressed_position.len());
+            served_positions.insert(local_peer_id, compressed_position);
+        }
+        (Some(s), _) => return Err(format!("Unrecognised command: {s}").into())
+    }
+    Ok(())
+}
+
+fn parse_position_arg(value: Option<&str>) -> Result<Position, AppError> {
+    let value = value.ok_or(r#"Expected a position value like {"x":1,"y":2,"z":3}"#)?;
+    Ok(serde_json::from_str::<Position>(value).map_err(|e| format!("Invalid position {value}: {e}"))?)
+}
+
+#[derive(Debug, PartialEq)]
+enum ListedPosition {
+    Unknown,
+    Decrypted(f64, f64, f64),
+    // cached, but no FHE key was shared to decrypt it
+    Encrypted,
+}
+
+#[derive(Debug)]
+struct PeerListing {
+    name: String,
+    peer_id: PeerId,
+    is_local: bool,
+    has_avs_public_key: bool,
+    position: ListedPosition,
+}
+
+impl std::fmt::Display for PeerListing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let local = if self.is_local { " (this node)" } else { "" };
+        let avs_public_key = if self.has_avs_public_key { "cached" } else { "missing" };
+        let position = match self.position {
+            ListedPosition::Unknown => "unknown".to_string(),
+            ListedPosition::Decrypted(x, y, z) => format!("({x}, {y}, {z})"),
+            ListedPosition::Encrypted => "encrypted (no key)".to_string(),
+        };
+        write!(f, "{}: {}{local}, AVS_PUBLIC_KEY {avs_public_key}, position {position}", self.name, self.peer_id)
+    }
+}
+
+// What this node knows about each entry in avs.peer_ids, sorted by name. Read-only.
+fn list_known_peers(local_peer_id: PeerId, user: &User, avs: &AVS) -> Vec<PeerListing> {
+    let mut listings: Vec<PeerListing> = avs.peer_ids.iter().map(|(name, &peer_id)| {
+        let is_local = peer_id == local_peer_id;
+        let cached_position = match is_local {
+            true => avs.encrypted_positions.get(&avs.get_public_key_hex(&user.fhe_public_key)),
+            false => avs.peer_positions.get(&peer_id.to_string()),
+        };
+        let position = match cached_position {
+            None => ListedPosition::Unknown,
+            Some(encrypted_position) => {
+                let decrypted = match is_local {
+                    true => user.decrypt_own_position(encrypted_position.clone()).map_err(AppError::from),
+                    false => user.decrypt_peer_position(encrypted_position.clone(), &peer_id.to_string()),
+                };
+                match decrypted {
+                    Ok(p) => ListedPosition::Decrypted(p.x, p.y, p.z),
+                    Err(_) => ListedPosition::Encrypted,
+                }
+            }
+        };
+        PeerListing {
+            name: name.clone(),
+            peer_id,
+            is_local,
+            has_avs_public_key: avs.peer_public_keys.contains_key(&form_avs_public_key(&peer_id.to_string())),
+            position,
+        }
+    }).collect();
+    listings.sort_by(|a, b| a.name.cmp(&b.name));
+    listings
+}
+
+fn lookup_peer_id(avs: &AVS, name: &str) -> Result<PeerId, AppError> {
+    avs.peer_ids.get(name).copied().ok_or_else(|| AppError::PeerNotFound(format!("{name} missing in avs.peer_ids")))
+}
+
+
+// Puts a large record as a manifest plus RECORD_CHUNK_SIZE chunks (see chunks::split_record),
+// tracking each for republishing. Returns the number of chunks.
+fn put_chunked_record(
+    kademlia: &mut kad::Behaviour<BoundedStore>,
+    republisher: &mut RecordRepublisher,
+    record: kad::Record,
+) -> Result<usize, kad::store::Error> {
+    let records = chunks::split_record(record, RECORD_CHUNK_SIZE);
+    let chunk_count = records.len() - 1;
+    for record in records {
+        republisher.track(record.clone());
+        kademlia.put_record(record, kad::Quorum::One)?;
+    }
+    Ok(chunk_count)
+}
+
+// Publishes this node's FHE public key as a signed FHE_PUBLIC_KEY record (~tens of kb, so chunked)
+fn put_fhe_public_key(
+    kademlia: &mut kad::Behaviour<BoundedStore>,
+    republisher: &mut RecordRepublisher,
+    local_peer_id: PeerId,
+    user: &User,
+) -> Result<usize, AppError> {
+    let record = sign_record(kad::Record {
+        key: kad::RecordKey::new(&form_fhe_public_key(&local_peer_id.to_string())),
+        value: bincode::serialize(&user.fhe_public_key)?,
+        publisher: Some(local_peer_id),
+        expires: republisher.expires_at(Instant::now()),
+    }, &user.identity);
+    Ok(put_chunked_record(kademlia, republisher, record)?)
+}
+
+// Removes a record (and its chunks, if chunked) from the local store and stops republishing it.
+// Copies already replicated to other peers stay until they expire (KADEMLIA_RECORD_TTL).
+// Returns false if the record wasn't held.
+fn delete_record(
+    kademlia: &mut kad::Behaviour<BoundedStore>,
+    republisher: &mut RecordRepublisher,
+    key: &kad::RecordKey,
+) -> bool {
+    let held = kademlia.store_mut().get(key).map(|record| record.into_owned());
+    if let Some(Ok(manifest)) = held.as_ref().and_then(|record| ChunkManifest::decode(&record.value)) {
+        for chunk_index in 0..manifest.chunk_count {
+            let chunk_key = chunks::form_chunk_key(key, chunk_index);
+            kademlia.remove_record(&chunk_key);
+            republisher.untrack(&chunk_key);
+        }
+    }
+    kademlia.remove_record(key);
+    let tracked = republisher.untrack(key);
+    held.is_some() || tracked
+}
+
+fn form_peer_name_record(identity: &identity::Keypair, name: &str) -> kad::Record {
+    let peer_id = identity.public().to_peer_id();
+    sign_record(kad::Record {
+        key: kad::RecordKey::new(&form_peer_name_key(&peer_id.to_string())),
+        value: name.as_bytes().to_vec(),
+        publisher: Some(peer_id),
+        expires: None,
+    }, identity)
+}
+
+// Whether the record's key is <kind>_..., checked before the (much slower) signature verification
+fn has_key_kind(record: &kad::Record, kind: &str) -> bool {
+    record.key.as_ref().strip_prefix(kind.as_bytes()).is_some_and(|rest| rest.starts_with(b"_"))
+}
+
+// (peer_id, hello) from a verified HELLO record, only if the peer published its own
+fn hello_from_record(record: &kad::Record) -> Option<(PeerId, Hello)> {
+    let key_str = std::str::from_utf8(record.key.as_ref()).ok()?;
+    let peer_id: PeerId = key_str.strip_prefix(&format!("{HELLO}_"))?.parse().ok()?;
+    if record.publisher != Some(peer_id) {
+        return None;
+    }
+    Some((peer_id, Hello::from_bytes(&record.value).ok()?))
+}
+
+// (name, peer_id) from a verified PEER_NAME record. Records published by anyone other than the named peer are ignored.
+fn peer_name_from_record(record: &kad::Record) -> Option<(String, PeerId)> {
+    let key_str = std::str::from_utf8(record.key.as_ref()).ok()?;
+    let peer_id: PeerId = key_str.strip_prefix(&format!("{PEER_NAME}_"))?.parse().ok()?;
+    if record.publisher != Some(peer_id) {
+        return None;
+    }
+    let name = parse_name(std::str::from_utf8(&record.value).ok()?).ok()?;
+    Some((name, peer_id))
+}
+
+pub fn parse_name(name: &str) -> Result<String, String> {
+    match name.trim() {
+        "" => Err("name must not be empty".to_string()),
+        name => Ok(name.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::prelude::*;
+    use crate::DEFAULT_ZSTD_LEVEL;
+    use crate::records::DEFAULT_REPUBLISH_INTERVAL;
+
+    #[test]
+    fn deleted_records_are_no_longer_returned_or_republished() {
+        let alice = PeerId::random();
+        let mut kademlia = kad::Behaviour::new(alice, BoundedStore::new(alice));
+        let start = Instant::now();
+        let mut republisher = RecordRepublisher::new(Duration::from_secs(10), start);
+
+        let key = kad::RecordKey::new(&form_encrypted_fhe_key(&alice.to_string()));
+        let record = kad::Record::new(key.clone(), vec![1u8; 3 * RECORD_CHUNK_SIZE]);
+        assert_eq!(put_chunked_record(&mut kademlia, &mut republisher, record).unwrap(), 3);
+        assert!(kademlia.store_mut().get(&key).is_some());
+        assert!(kademlia.store_mut().get(&chunks::form_chunk_key(&key, 2)).is_some());
+
+        // the manifest and every chunk are gone
+        assert!(delete_record(&mut kademlia, &mut republisher, &key));
+        assert!(kademlia.store_mut().get(&key).is_none());
+        assert_eq!(kademlia.store_mut().records().count(), 0);
+        assert_eq!(republisher.poll_due(start + Duration::from_secs(10)), Some(vec![]));
+
+        // deleting a missing record is a no-op
+        assert!(!delete_record(&mut kademlia, &mut republisher, &key));
+        let missing = kad::RecordKey::new(&form_position_key(&alice.to_string()));
+        assert!(!delete_record(&mut kademlia, &mut republisher, &missing));
+    }
+
+    #[test]
+    fn peer_name_records_are_only_trusted_from_the_named_peer() {
+        let alice_identity = identity::Keypair::generate_ed25519();
+        let mallory_identity = identity::Keypair::generate_ed25519();
+        let alice = alice_identity.public().to_peer_id();
+
+        let record = verify_record(form_peer_name_record(&alice_identity, "alice")).unwrap();
+        assert_eq!(peer_name_from_record(&record), Some(("alice".to_string(), alice)));
+
+        let mut forged = record.clone();
+        forged.publisher = Some(mallory_identity.public().to_peer_id());
+        assert_eq!(peer_name_from_record(&forged), None);
+        // mallory can't sign a name record under alice's key either
+        let mut signed_by_mallory = form_peer_name_record(&mallory_identity, "alice");
+        signed_by_mallory.key = record.key.clone();
+        assert!(verify_record(signed_by_mallory).is_err());
+        let blank = verify_record(form_peer_name_record(&alice_identity, " ")).unwrap();
+        assert_eq!(peer_name_from_record(&blank), None);
+        let not_a_name = kad::Record::new(kad::RecordKey::new(&form_position_key(&alice.to_string())), b"alice".to_vec());
+        assert_eq!(peer_name_from_record(&not_a_name), None);
+    }
+
+    #[test]
+    fn a_second_claim_on_a_name_is_ignored() {
+        let alice_identity = identity::Keypair::generate_ed25519();
+        let mallory_identity = identity::Keypair::generate_ed25519();
+        let alice = alice_identity.public().to_peer_id();
+        let local_peer_id = PeerId::random();
+        let mut store = BoundedStore::new(local_peer_id);
+        let mut peer_ids = std::collections::HashMap::new();
+
+        store.put(form_peer_name_record(&alice_identity, "alice")).unwrap();
+        let learned = learn_peer_names(&mut store, &mut peer_ids, Some(("bob", local_peer_id)));
+        assert_eq!(learned, vec![("alice".to_string(), alice)]);
+
+        // mallory's records are validly signed by mallory, but the names are taken: one by alice, one by us
+        store.put(form_peer_name_record(&mallory_identity, "alice")).unwrap();
+        let learned = learn_peer_names(&mut store, &mut peer_ids, Some(("bob", local_peer_id)));
+        assert!(learned.is_empty());
+        store.remove(&form_peer_name_record(&mallory_identity, "alice").key);
+        store.put(form_peer_name_record(&mallory_identity, "bob")).unwrap();
+        let learned = learn_peer_names(&mut store, &mut peer_ids, Some(("bob", local_peer_id)));
+        assert!(learned.is_empty());
+        assert_eq!(peer_ids, std::collections::HashMap::from([("alice".to_string(), alice)]));
+
+        // our own record doesn't conflict with our own name
+        assert!(!bind_peer_name(&mut peer_ids, Some(("bob", local_peer_id)), "bob", local_peer_id));
+    }
+
+    #[test]
+    fn replayed_older_records_are_ignored() {
+        let alice_identity = identity::Keypair::generate_ed25519();
+        let local_peer_id = PeerId::random();
+        let mut kademlia = kad::Behaviour::new(local_peer_id, BoundedStore::new(local_peer_id));
+        let mut assembler = ChunkAssembler::default();
+        let mut sequences = RecordSequences::default();
+        let metrics = NodeMetrics::default();
+        let mut avs = AVS::setup().unwrap();
+        let mut user = User::setup(&avs.compiled_move_position.metadata.params, "bob").unwrap();
+
+        let old = form_peer_name_record(&alice_identity, "alice");
+        let new = form_peer_name_record(&alice_identity, "alice2");
+        handle_get_record_result(new.clone(), &mut kademlia, &mut assembler, &mut sequences, &mut user, &mut avs, &metrics).unwrap();
+        // re-fetching the same record is fine
+        handle_get_record_result(new, &mut kademlia, &mut assembler, &mut sequences, &mut user, &mut avs, &metrics).unwrap();
+
+        // someone re-puts alice's older, validly signed record
+        let result = handle_get_record_result(old, &mut kademlia, &mut assembler, &mut sequences, &mut user, &mut avs, &metrics);
+        assert!(result.unwrap_err().to_string().contains("older than the last seen"));
+        assert!(avs.peer_ids.contains_key("alice2"));
+        assert!(!avs.peer_ids.contains_key("alice"));
+    }
+
+    #[tokio::test]
+    async fn three_nodes_learn_each_others_peer_ids() {
+        let names = ["alice", "bob", "carol"];
+        let identities: Vec<identity::Keypair> = names.iter().map(|_| identity::Keypair::generate_ed25519()).collect();
+        let mut swarms: Vec<Swarm<Behaviour>> = identities.iter().map(|identity| build_swarm(identity).unwrap()).collect();
+        let mut peer_ids = vec![std::collections::HashMap::new(); names.len()];
+
+        let mut addrs = Vec::new();
+        for swarm in swarms.iter_mut() {
+            swarm.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+            loop {
+                if let SwarmEvent::NewListenAddr { address, .. } = swarm.select_next_some().await {
+                    addrs.push(address);
+                    break;
+                }
+            }
+        }
+        // bob bootstraps from alice, carol from both
+        swarms[1].dial(addrs[0].clone()).unwrap();
+        swarms[2].dial(addrs[0].clone()).unwrap();
+        swarms[2].dial(addrs[1].clone()).unwrap();
+
+        let expected: Vec<(String, PeerId)> = names.iter()
+            .zip(swarms.iter())
+            .map(|(name, swarm)| (name.to_string(), *swarm.local_peer_id()))
+            .collect();
+        let all_learned = |peer_ids: &[std::collections::HashMap<String, PeerId>]| {
+            peer_ids.iter().all(|ids| expected.iter().all(|(name, peer_id)| ids.get(name) == Some(peer_id)))
+        };
+
+        tokio::time::timeout(Duration::from_secs(30), async {
+            while !all_learned(&peer_ids) {
+                let ((i, event), _, pending) = future::select_all(
+                    swarms.iter_mut().enumerate().map(|(i, swarm)| swarm.select_next_some().map(move |e| (i, e)))
+                ).await;
+                drop(pending);
+
+                // same handling as the main event loop
+                let local_peer_id = *swarms[i].local_peer_id();
+                let kademlia = &mut swarms[i].behaviour_mut().kademlia;
+                match event {
+                    SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                        register_peer_names(&mut peer_ids[i], Some(names[i]), local_peer_id, peer_id);
+                        let record = form_peer_name_record(&identities[i], names[i]);
+                        kademlia.put_record_to(record, std::iter::once(peer_id), kad::Quorum::One);
+                    }
+                    SwarmEvent::Behaviour(BehaviourEvent::Kademlia(
+                        kad::Event::InboundRequest { request: kad::InboundRequest::PutRecord { .. } }
+                    )) => {
+                        learn_peer_names(kademlia.store_mut(), &mut peer_ids[i], Some((names[i], local_peer_id)));
+                    }
+                    _ => {}
+                }
+            }
+        }).await.expect("nodes should learn each other's names");
+    }
+
+    #[tokio::test]
+    async fn positions_are_exchanged_directly_between_two_nodes() {
+        let mut alice = build_swarm(&identity::Keypair::generate_ed25519()).unwrap();
+        let mut bob = build_swarm(&identity::Keypair::generate_ed25519()).unwrap();
+        let alice_peer_id = *alice.local_peer_id();
+        let bob_peer_id = *bob.local_peer_id();
+
+        // alice moves: her position is only served, never put into the DHT
+        let mut alice_positions = PositionStore::new(DEFAULT_ZSTD_LEVEL);
+        alice_positions.insert(alice_peer_id, b"alice position".to_vec());
+
+        alice.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let alice_addr = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = alice.select_next_some().await {
+                break address;
+            }
+        };
+        bob.dial(alice_addr).unwrap();
+
+        let response = tokio::time::timeout(Duration::from_secs(30), async {
+            loop {
+                tokio::select! {
+                    event = alice.select_next_some() => {
+                        if let SwarmEvent::Behaviour(BehaviourEvent::PositionExchange(request_response::Event::Message {
+                            message: request_response::Message::Request { request, channel, .. }, ..
+                        })) = event {
+                            let response = alice_positions.respond(&request);
+                            alice.behaviour_mut().position_exchange.send_response(channel, response).unwrap();
+                        }
+                    }
+                    event = bob.select_next_some() => match event {
+                        SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                            assert_eq!(peer_id, alice_peer_id);
+                            bob.behaviour_mut().position_exchange.send_request(&peer_id, PositionRequest::new(peer_id));
+                            // bob has no position for himself
+                            bob.behaviour_mut().position_exchange.send_request(&peer_id, PositionRequest::new(bob_peer_id));
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::PositionExchange(request_response::Event::Message {
+                            message: request_response::Message::Response { response, .. }, ..
+                        })) if response.subject() == Some(alice_peer_id) => break response,
+                        _ => {}
+                    }
+                }
+            }
+        }).await.expect("bob should receive alice's position");
+
+        assert_eq!(response.position.as_deref(), Some(&b"alice position"[..]));
+        assert_eq!(alice_positions.respond(&PositionRequest::new(bob_peer_id)).position, None);
+        assert_eq!(alice.behaviour_mut().kademlia.store_mut().records().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn simulated_moves_change_nothing() {
+        let mut swarm = build_swarm(&identity::Keypair::generate_ed25519()).unwrap();
+        let local_peer_id = *swarm.local_peer_id();
+        let mut republisher = RecordRepublisher::new(DEFAULT_REPUBLISH_INTERVAL, Instant::now());
+        let mut served_positions = PositionStore::new(DEFAULT_ZSTD_LEVEL);
+        let metrics = NodeMetrics::default();
+        let mut avs = AVS::setup().unwrap();
+        let mut user = User::setup(&avs.compiled_move_position.metadata.params, "alice").unwrap();
+        avs.peer_ids.insert("alice".to_string(), local_peer_id);
+
+        handle_input_line(
+            &mut swarm, &mut republisher, &mut served_positions, &metrics,
+            r#"MOVE alice {"x":1,"y":2}"#.to_string(), &mut user, &mut avs
+        ).unwrap();
+        let position = avs.get_prev_position(&user.fhe_public_key).unwrap();
+        let served = served_positions.respond(&PositionRequest::new(local_peer_id)).position;
+
+        handle_input_line(
+            &mut swarm, &mut republisher, &mut served_positions, &metrics,
+            r#"MOVE_SIM alice {"x":5,"y":5}"#.to_string(), &mut user, &mut avs
+        ).unwrap();
+        assert_eq!(avs.move_sequence(&user.fhe_public_key), 1);
+        assert_eq!(avs.encrypted_positions.len(), 1);
+        let unchanged = user.decrypt_own_position(avs.get_prev_position(&user.fhe_public_key).unwrap()).unwrap();
+        let before = user.decrypt_own_position(position).unwrap();
+        assert_eq!((unchanged.x, unchanged.y), (before.x, before.y));
+        assert_eq!(served_positions.respond(&PositionRequest::new(local_peer_id)).position, served);
+        assert_eq!(swarm.behaviour_mut().kademlia.store_mut().records().count(), 0);
+        assert!(republisher.records(Instant::now()).is_empty());
+
+        // a simulated move is checked like a real one
+        assert!(handle_input_line(
+            &mut swarm, &mut republisher, &mut served_positions, &metrics,
+            r#"MOVE_SIM bob {"x":5,"y":5}"#.to_string(), &mut user, &mut avs
+        ).is_err());
+    }
+
+    #[tokio::test]
+    async fn malformed_input_and_records_are_errors_not_panics() {
+        let mut swarm = build_swarm(&identity::Keypair::generate_ed25519()).unwrap();
+        let local_peer_id = *swarm.local_peer_id();
+        let mut republisher = RecordRepublisher::new(DEFAULT_REPUBLISH_INTERVAL, Instant::now());
+        let mut served_positions = PositionStore::new(DEFAULT_ZSTD_LEVEL);
+        let mut assembler = ChunkAssembler::default();
+        let metrics = NodeMetrics::default();
+        let mut avs = AVS::setup().unwrap();
+        let mut user = User::setup(&avs.compiled_move_position.metadata.params, "alice").unwrap();
+        avs.peer_ids.insert("alice".to_string(), local_peer_id);
+
+        for line in [
+            "",
+            "MOVE",
+            "MOVE alice",
+            r#"MOVE alice {"x":1,"y""#,
+            r#"MOVE alice {"x":"one","y":2}"#,
+            r#"MOVE alice {"x":1e400,"y":2}"#,
+            r#"MOVE bob {"x":1,"y":2}"#,
+            "GET POSITION",
+            "GET POSITION bob",
+            "DELETE FOO alice",
+            "SHARE_KEY bob",
+            // nothing to publish before the first MOVE
+            "PUBLISH",
+        ] {
+            let result = handle_input_line(
+                &mut swarm, &mut republisher, &mut served_positions, &metrics, line.to_string(), &mut user, &mut avs
+            );
+            assert!(result.is_err(), "{line:?} should be rejected");
+        }
+        // moves off the map
+        user.max_coordinate = Some(1000.0);
+        let result = handle_input_line(
+            &mut swarm, &mut republisher, &mut served_positions, &metrics,
+            r#"MOVE alice {"x":1,"y":1e308}"#.to_string(), &mut user, &mut avs
+        );
+        assert!(result.unwrap_err().to_string().contains("outside the map range"));
+
+        // nothing was moved by the rejected lines, and the node still handles a valid move
+        assert_eq!(avs.move_sequence(&user.fhe_public_key), 0);
+        handle_input_line(
+            &mut swarm, &mut republisher, &mut served_positions, &metrics,
+            r#"MOVE alice {"x":1,"y":2}"#.to_string(), &mut user, &mut avs
+        ).unwrap();
+        assert!(served_positions.respond(&PositionRequest::new(local_peer_id)).position.is_some());
+
+        // truncated or unattributed records from peers
+        let fhe_key = kad::RecordKey::new(&form_encrypted_fhe_key(&local_peer_id.to_string()));
+        for record in [
+            kad::Record::new(fhe_key.clone(), b"{\"ecdh_public_key\"".to_vec()),
+            kad::Record { publisher: Some(local_peer_id), ..kad::Record::new(fhe_key, b"{".to_vec()) },
+            kad::Record::new(kad::RecordKey::new(&form_avs_public_key(&local_peer_id.to_string())), vec![4, 1, 2]),
+            kad::Record::new(kad::RecordKey::new(&form_position_key(&local_peer_id.to_string())), vec![1, 0]),
+            kad::Record::new(kad::RecordKey::new(&[0xff, 0xfe]), vec![0]),
+        ] {
+            let result = handle_get_record_result(
+                record, &mut swarm.behaviour_mut().kademlia, &mut assembler, &mut RecordSequences::default(), &mut user, &mut avs, &metrics
+            );
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    fn positions_are_only_own_once_the_local_peer_id_is_known() {
+        let local_peer_id = PeerId::random();
+        let bob_identity = identity::Keypair::generate_ed25519();
+        let bob = bob_identity.public().to_peer_id();
+        let mut kademlia = kad::Behaviour::new(local_peer_id, BoundedStore::new(local_peer_id));
+        let mut assembler = ChunkAssembler::default();
+        let mut sequences = RecordSequences::default();
+        let metrics = NodeMetrics::default();
+        let mut avs = AVS::setup().unwrap();
+        let mut user = User::setup(&avs.compiled_move_position.metadata.params, "alice").unwrap();
+
+        let move_tx = user.create_move_transaction(Position { x: 1.0, y: 2.0, z: 0.0 }).unwrap();
+        let encoded_position = encode_position_record(&avs.run_contract(move_tx, &user.fhe_public_key).unwrap()).unwrap();
+
+        // before the first NewListenAddr even our own position isn't decrypted as ours
+        assert!(avs.peer_id.is_none());
+        assert!(handle_encrypted_position(&encoded_position, local_peer_id, &mut user, &mut avs, &metrics).is_err());
+        assert!(avs.peer_positions.is_empty());
+
+        avs.set_peer_id(Some(local_peer_id));
+        handle_encrypted_position(&encoded_position, local_peer_id, &mut user, &mut avs, &metrics).unwrap();
+        assert!(avs.peer_positions.is_empty());
+
+        // a DHT record is routed by the peer id in its key: bob's, whose key we don't hold
+        let record = kad::Record::new(kad::RecordKey::new(&form_position_key(&bob.to_string())), encoded_position);
+        let result = handle_get_record_result(record.clone(), &mut kademlia, &mut assembler, &mut sequences, &mut user, &mut avs, &metrics);
+        assert!(result.unwrap_err().to_string().contains("isn't signed"));
+        assert!(avs.peer_positions.is_empty());
+
+        let record = sign_record(record, &bob_identity);
+        let result = handle_get_record_result(record, &mut kademlia, &mut assembler, &mut sequences, &mut user, &mut avs, &metrics);
+        assert!(result.is_err());
+        assert!(avs.peer_positions.contains_key(&bob.to_string()));
+    }
+
+    #[test]
+    fn expired_records_are_treated_as_absent() {
+        let alice = PeerId::random();
+        let local_peer_id = PeerId::random();
+        let mut kademlia = kad::Behaviour::new(local_peer_id, BoundedStore::new(local_peer_id));
+        let mut assembler = ChunkAssembler::default();
+        let mut sequences = RecordSequences::default();
+        let metrics = NodeMetrics::default();
+        let mut avs = AVS::setup().unwrap();
+        let mut user = User::setup(&avs.compiled_move_position.metadata.params, "bob").unwrap();
+
+        // alice shared her key with a 1ms TTL
+        let (_, alice_ecdh_public_key) = ecdh::generate_ecdh_keys();
+        let key_pair = UserKeyPair { ecdh_public_key: alice_ecdh_public_key, fhe_private_key_encrypted: vec![1, 2, 3] };
+        let mut republisher = RecordRepublisher::new(DEFAULT_REPUBLISH_INTERVAL, Instant::now());
+        republisher.record_ttl = Some(Duration::from_millis(1));
+        let record = kad::Record {
+            key: kad::RecordKey::new(&form_encrypted_fhe_key(&alice.to_string())),
+            value: key_pair.to_bytes(),
+            publisher: Some(alice),
+            expires: republisher.expires_at(Instant::now()),
+        };
+        std::thread::sleep(Duration::from_millis(5));
+
+        // the manifest and its chunks are all ignored
+        for record in chunks::split_record(record, RECORD_CHUNK_SIZE) {
+            handle_get_record_result(record, &mut kademlia, &mut assembler, &mut sequences, &mut user, &mut avs, &metrics).unwrap();
+        }
+        assert!(user.peer_fhe_decryption_keys.is_empty());
+    }
+
+    #[tokio::test]
+    async fn list_reports_known_peers_without_changing_state() {
+        let mut swarm = build_swarm(&identity::Keypair::generate_ed25519()).unwrap();
+        let local_peer_id = *swarm.local_peer_id();
+        let bob = PeerId::random();
+        let mut republisher = RecordRepublisher::new(DEFAULT_REPUBLISH_INTERVAL, Instant::now());
+        let mut served_positions = PositionStore::new(DEFAULT_ZSTD_LEVEL);
+        let metrics = NodeMetrics::default();
+        let mut avs = AVS::setup().unwrap();
+        let mut user = User::setup(&avs.compiled_move_position.metadata.params, "alice").unwrap();
+
+        // bob is discovered, then alice moves
+        register_peer_names(&mut avs.peer_ids, Some("alice"), local_peer_id, bob);
+        avs.peer_ids.insert("bob".to_string(), bob);
+        let (_, bob_ecdh_public_key) = ecdh::generate_ecdh_keys();
+        avs.peer_public_keys.insert(form_avs_public_key(&bob.to_string()), bob_ecdh_public_key);
+        handle_input_line(
+            &mut swarm, &mut republisher, &mut served_positions, &metrics,
+            r#"MOVE bob {"x":1,"y":2}"#.to_string(), &mut user, &mut avs
+        ).unwrap();
+
+        for line in ["FINGERPRINT alice", "FINGERPRINT bob", "STATS"] {
+            handle_input_line(&mut swarm, &mut republisher, &mut served_positions, &metrics, line.to_string(), &mut user, &mut avs).unwrap();
+        }
+        let result = handle_input_line(
+            &mut swarm, &mut republisher, &mut served_positions, &metrics, "FINGERPRINT carol".to_string(), &mut user, &mut avs
+        );
+        assert!(matches!(result, Err(AppError::PeerNotFound(_))));
+
+        let listings = list_known_peers(local_peer_id, &user, &avs);
+        let summary: Vec<(&str, bool, bool, &ListedPosition)> = listings.iter()
+            .map(|l| (l.name.as_str(), l.is_local, l.has_avs_public_key, &l.position))
+            .collect();
+        // peer ids ("12D3Koo...") sort before names
+        let bob_peer_id = bob.to_string();
+        assert_eq!(summary, vec![
+            (bob_peer_id.as_str(), false, true, &ListedPosition::Unknown),
+            ("alice", true, false, &ListedPosition::Decrypted(1.0, 2.0, 0.0)),
+            ("bob", false, true, &ListedPosition::Unknown),
+        ]);
+
+        // a position from bob without his FHE key stays encrypted
+        let bob_position = user.create_move_transaction(Position { x: 5.0, y: 5.0, z: 0.0 }).unwrap();
+        avs.peer_positions.insert(bob.to_string(), bob_position);
+        let bob_listing = list_known_peers(local_peer_id, &user, &avs).into_iter().find(|l| l.name == "bob").unwrap();
+        assert_eq!(bob_listing.position, ListedPosition::Encrypted);
+        assert!(bob_listing.to_string().contains("encrypted (no key)"));
+
+        // LIST only reads
+        assert_eq!(avs.move_sequence(&user.fhe_public_key), 1);
+        handle_input_line(
+            &mut swarm, &mut republisher, &mut served_positions, &metrics, "LIST".to_string(), &mut user, &mut avs
+        ).unwrap();
+        assert_eq!(avs.move_sequence(&user.fhe_public_key), 1);
+        assert_eq!(avs.peer_ids.len(), 3);
+    }
+
+    #[test]
+    fn resync_fetches_peer_records_missing_from_local_state() {
+        let alice = PeerId::random();
+        let (_, alice_ecdh_public_key) = ecdh::generate_ecdh_keys();
+
+        // alice's published records
+        let mut alice_store = BoundedStore::new(alice);
+        for (key, value) in [
+            (form_avs_public_key(&alice.to_string()), b"avs public key".to_vec()),
+            (form_encrypted_fhe_key(&alice.to_string()), b"encrypted fhe key".to_vec()),
+        ] {
+            alice_store.put(kad::Record::new(kad::RecordKey::new(&key), value)).unwrap();
+        }
+
+        // bob rejoins with empty state, and the same peer reported twice
+        let mut held_avs_public_keys = std::collections::HashMap::new();
+        let mut held_fhe_keys = std::collections::HashMap::new();
+        let keys = resync_record_keys(&[alice, alice], &held_avs_public_keys, &held_fhe_keys);
+
+        let mut published: Vec<kad::RecordKey> = alice_store.records().map(|r| r.key.clone()).collect();
+        let mut fetched = keys.clone();
+        published.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+        fetched.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+        assert_eq!(fetched, published);
+
+        // once the keys are held nothing is refetched, positions are requested from alice directly
+        held_avs_public_keys.insert(form_avs_public_key(&alice.to_string()), alice_ecdh_public_key);
+        held_fhe_keys.insert(alice.to_string(), UserKeyPair {
+            ecdh_public_key: alice_ecdh_public_key,
+            fhe_private_key_encrypted: vec![],
+        });
+        let keys = resync_record_keys(&[alice], &held_avs_public_keys, &held_fhe_keys);
+        assert!(keys.is_empty());
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::io;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use libp2p::{request_response, PeerId, StreamProtocol};
+use serde::{Deserialize, Serialize};
+
+// Direct peer-to-peer transfer of encrypted positions. Positions are only ever read by the peers a
+// player moves for, so they're served on request instead of being replicated through the Kademlia DHT,
+// which is kept for public key discovery.
+pub const POSITION_EXCHANGE_PROTOCOL: StreamProtocol = StreamProtocol::new("/fhe-sunscreen/position-exchange/1");
+
+// Largest request or response accepted, a bincode position record is ~870kb
+const MAX_MESSAGE_BYTES: u64 = 4 * 1024 * 1024;
+
+// Asks a peer for the latest position it holds for `subject` (usually the peer itself)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PositionRequest {
+    subject: Vec<u8>,
+}
+
+impl PositionRequest {
+
+    pub fn new(subject: PeerId) -> Self {
+        PositionRequest { subject: subject.to_bytes() }
+    }
+
+    pub fn subject(&self) -> Option<PeerId> {
+        PeerId::from_bytes(&self.subject).ok()
+    }
+}
+
+// `position` is an encoded position record (see encode_position_record), None if the peer has none
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PositionResponse {
+    pub subject: Vec<u8>,
+    pub position: Option<Vec<u8>>,
+}
+
+impl PositionResponse {
+
+    pub fn subject(&self) -> Option<PeerId> {
+        PeerId::from_bytes(&self.subject).ok()
+    }
+}
+
+// Latest encoded position this node serves, per subject, with when it stops being served
+pub struct PositionStore {
+    positions: HashMap<PeerId, (Vec<u8>, Option<Instant>)>,
+    // zstd level positions are compressed at before they're stored (see compress_record)
+    pub zstd_level: i32,
+    // How long a position is served after a MOVE (`--record-ttl`), None serves it until the next one
+    pub ttl: Option<Duration>,
+}
+
+impl PositionStore {
+
+    pub fn new(zstd_level: i32) -> Self {
+        PositionStore { positions: HashMap::new(), zstd_level, ttl: None }
+    }
+
+    pub fn insert(&mut self, subject: PeerId, encoded_position: Vec<u8>) {
+        let expires = self.ttl.map(|ttl| Instant::now() + ttl);
+        self.positions.insert(subject, (encoded_position, expires));
+    }
+
+    pub fn remove(&mut self, subject: &PeerId) -> bool {
+        self.positions.remove(subject).is_some()
+    }
+
+    pub fn respond(&self, request: &PositionRequest) -> PositionResponse {
+        PositionResponse {
+            subject: request.subject.clone(),
+            // an expired position is answered like a missing one, so peers never decrypt stale ciphertexts
+            position: request.subject()
+                .and_then(|subject| self.positions.get(&subject))
+                .filter(|(_, expires)| expires.is_none_or(|expires| Instant::now() < expires))
+                .map(|(position, _)| position.clone()),
+        }
+    }
+}
+
+pub fn new_behaviour() -> request_response::Behaviour<PositionCodec> {
+    request_response::Behaviour::new(
+        [(POSITION_EXCHANGE_PROTOCOL, request_response::ProtocolSupport::Full)],
+        request_response::Config::default(),
+    )
+}
+
+// Length-prefixed (u32 BE) bincode messages
+#[derive(Debug, Clone, Default)]
+pub struct PositionCodec;
+
+pub(crate) async fn read_message<T, M>(io: &mut T) -> io::Result<M>
+where
+    T: AsyncRead + Unpin + Send,
+    M: for<'de> Deserialize<'de>,
+{
+    let mut len = [0u8; 4];
+    io.read_exact(&mut len).await?;
+    let len = u32::from_be_bytes(len) as u64;
+    if len > MAX_MESSAGE_BYTES {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("message of {len} bytes is too large")));
+    }
+    let mut message = Vec::with_capacity(len as usize);
+    io.take(len).read_to_end(&mut message).await?;
+    bincode::deserialize(&message).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub(crate) async fn write_message<T, M>(io: &mut T, message: &M) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+    M: Serialize,
+{
+    let message = bincode::serialize(message).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    io.write_all(&(message.len() as u32).to_be_bytes()).await?;
+    io.write_all(&message).await?;
+    io.close().await
+}
+
+#[async_trait]
+impl request_response::Codec for PositionCodec {
+    type Protocol = StreamProtocol;
+    type Request = PositionRequest;
+    type Response = PositionResponse;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<PositionRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_message(io).await
+    }
+
+    async fn read_response<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<PositionResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_message(io).await
+    }
+
+    async fn write_request<T>(&mut self, _: &StreamProtocol, io: &mut T, request: PositionRequest) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_message(io, &request).await
+    }
+
+    async fn write_response<T>(&mut self, _: &StreamProtocol, io: &mut T, response: PositionResponse) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_message(io, &response).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expired_positions_are_not_served() {
+        let subject = PeerId::random();
+        let request = PositionRequest::new(subject);
+        let mut store = PositionStore::new(3);
+        store.insert(subject, b"position".to_vec());
+        assert_eq!(store.respond(&request).position.as_deref(), Some(&b"position"[..]));
+
+        store.ttl = Some(Duration::from_millis(20));
+        store.insert(subject, b"short-lived position".to_vec());
+        assert_eq!(store.respond(&request).position.as_deref(), Some(&b"short-lived position"[..]));
+        std::thread::sleep(Duration::from_millis(40));
+        assert_eq!(store.respond(&request).position, None);
+    }
+}
@@ -0,0 +1,349 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use libp2p::{identity, kad, PeerId};
+use serde::{Deserialize, Serialize};
+
+// Kademlia expires records stored on remote peers after its record TTL, even when the
+// record was put with `expires: None`. We set the TTL explicitly so it can be logged and
+// compared against the republish interval.
+pub const KADEMLIA_RECORD_TTL: Duration = Duration::from_secs(48 * 60 * 60);
+pub const DEFAULT_REPUBLISH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Keeps a copy of every record this node originated and periodically hands them back
+// to be re-put, so positions and keys are refreshed before the DHT drops them.
+pub struct RecordRepublisher {
+    pub interval: Duration,
+    // Lifetime of shared keys this node puts (`--record-ttl`), None never expires them locally.
+    // Republishing doesn't extend it, expired records are dropped instead.
+    pub record_ttl: Option<Duration>,
+    next_due: Instant,
+    records: HashMap<kad::RecordKey, kad::Record>,
+}
+
+impl RecordRepublisher {
+
+    pub fn new(interval: Duration, now: Instant) -> Self {
+        RecordRepublisher {
+            interval,
+            record_ttl: None,
+            next_due: now + interval,
+            records: HashMap::new(),
+        }
+    }
+
+    // Remember an originated record. Re-putting the same key replaces the older value.
+    pub fn track(&mut self, record: kad::Record) {
+        self.records.insert(record.key.clone(), record);
+    }
+
+    // `expires` for a record put at `now`
+    pub fn expires_at(&self, now: Instant) -> Option<Instant> {
+        self.record_ttl.map(|ttl| now + ttl)
+    }
+
+    // Stop republishing a record (e.g. after it was deleted). Returns false if it wasn't tracked.
+    pub fn untrack(&mut self, key: &kad::RecordKey) -> bool {
+        self.records.remove(key).is_some()
+    }
+
+    // Every originated record that hasn't expired, e.g. for a last republish on shutdown
+    pub fn records(&self, now: Instant) -> Vec<kad::Record> {
+        self.records.values().filter(|record| !record.is_expired(now)).cloned().collect()
+    }
+
+    // Returns the records to re-put if the republish interval has elapsed, and schedules the next run.
+    pub fn poll_due(&mut self, now: Instant) -> Option<Vec<kad::Record>> {
+        if now < self.next_due {
+            return None;
+        }
+        self.next_due = now + self.interval;
+        self.records.retain(|_, record| !record.is_expired(now));
+        Some(self.records.values().cloned().collect())
+    }
+}
+
+// Domain separation for record signatures, so they can't be replayed as signatures over anything else
+const RECORD_SIGNATURE_CONTEXT: &[u8] = b"fhe-sunscreen-record-v2";
+
+// First byte of a signed record value, ahead of the bincode SignedRecord. Manifests are stored under the same
+// keys, unsigned and starting with chunks::CHUNK_MANIFEST_TAG, and are told apart from signed records by this
+// byte before any signature is checked. Bincode alone would start with the low byte of the payload length.
+pub const SIGNED_RECORD_TAG: u8 = 0xfe;
+
+// Record value as it's stored in Kademlia (after SIGNED_RECORD_TAG): the payload, with a signature over
+// (key, payload, sequence, publisher) by the publisher's libp2p identity key. Kademlia doesn't authenticate `publisher`, so without this
+// any peer could put a record under POSITION_<someone_else> and have it trusted.
+#[derive(Serialize, Deserialize)]
+pub struct SignedRecord {
+    pub value: Vec<u8>,
+    // when it was signed, in microseconds since the epoch and strictly increasing per node (see next_sequence).
+    // Signed, so a captured old record can't be re-put as new: RecordSequences rejects it.
+    pub sequence: u64,
+    // protobuf encoded libp2p public key, whose peer id must be the record's publisher
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RecordSignatureError {
+    Unsigned,
+    NoPublisher,
+    // the signing key isn't the publisher's
+    WrongPublisher,
+    // the publisher isn't the peer whose id is in the record key
+    WrongOwner,
+    InvalidSignature,
+}
+
+impl std::fmt::Display for RecordSignatureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecordSignatureError::Unsigned => write!(f, "record isn't signed"),
+            RecordSignatureError::NoPublisher => write!(f, "signed record has no publisher"),
+            RecordSignatureError::WrongPublisher => write!(f, "record is signed by a key other than its publisher's"),
+            RecordSignatureError::WrongOwner => write!(f, "record was published under another peer's key"),
+            RecordSignatureError::InvalidSignature => write!(f, "record signature is invalid"),
+        }
+    }
+}
+
+impl std::error::Error for RecordSignatureError {}
+
+// Wraps an originated record's value in a SignedRecord. Kademlia sets `publisher` to the local
+// peer id on put_record, so it's set (and signed) the same way here.
+pub fn sign_record(record: kad::Record, identity: &identity::Keypair) -> kad::Record {
+    let publisher = identity.public().to_peer_id();
+    let sequence = next_sequence();
+    let signature = identity.sign(&signed_bytes(&record.key, &record.value, sequence, &publisher))
+        .expect("libp2p identity keys can sign");
+    let signed = SignedRecord {
+        value: record.value,
+        sequence,
+        public_key: identity.public().encode_protobuf(),
+        signature,
+    };
+    kad::Record {
+        value: signed.encode(),
+        publisher: Some(publisher),
+        ..record
+    }
+}
+
+impl SignedRecord {
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut value = vec![SIGNED_RECORD_TAG];
+        bincode::serialize_into(&mut value, self).expect("bincode::serialize(SignedRecord)");
+        value
+    }
+
+    pub fn decode(value: &[u8]) -> Result<SignedRecord, RecordSignatureError> {
+        match value.split_first() {
+            Some((&SIGNED_RECORD_TAG, signed)) => bincode::deserialize(signed).map_err(|_| RecordSignatureError::Unsigned),
+            _ => Err(RecordSignatureError::Unsigned),
+        }
+    }
+}
+
+// Checks a record's signature and that its publisher owns the key it's stored under (the peer id
+// at the end of e.g. POSITION_<peer_id>), returning the record with its payload unwrapped.
+pub fn verify_record(record: kad::Record) -> Result<kad::Record, RecordSignatureError> {
+    verify_record_sequenced(record).map(|(record, _)| record)
+}
+
+// verify_record, also returning the signed sequence number for RecordSequences::check
+pub fn verify_record_sequenced(record: kad::Record) -> Result<(kad::Record, u64), RecordSignatureError> {
+    let signed = SignedRecord::decode(&record.value)?;
+    let publisher = record.publisher.ok_or(RecordSignatureError::NoPublisher)?;
+    let public_key = identity::PublicKey::try_decode_protobuf(&signed.public_key)
+        .map_err(|_| RecordSignatureError::WrongPublisher)?;
+    if public_key.to_peer_id() != publisher {
+        return Err(RecordSignatureError::WrongPublisher);
+    }
+    if !public_key.verify(&signed_bytes(&record.key, &signed.value, signed.sequence, &publisher), &signed.signature) {
+        return Err(RecordSignatureError::InvalidSignature);
+    }
+    if record_owner(&record.key) != Some(publisher) {
+        return Err(RecordSignatureError::WrongOwner);
+    }
+    Ok((kad::Record { value: signed.value, ..record }, signed.sequence))
+}
+
+fn signed_bytes(key: &kad::RecordKey, value: &[u8], sequence: u64, publisher: &PeerId) -> Vec<u8> {
+    bincode::serialize(&(RECORD_SIGNATURE_CONTEXT, key.as_ref(), value, sequence, publisher.to_bytes()))
+        .expect("bincode::serialize(record signature payload)")
+}
+
+// Wall clock microseconds, bumped past the last sequence handed out, so sequences keep increasing
+// across restarts (unlike a counter) and within the same microsecond.
+fn next_sequence() -> u64 {
+    static LAST_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or(0);
+    let last = LAST_SEQUENCE.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| Some(now.max(last + 1)))
+        .expect("fetch_update closure always returns Some");
+    now.max(last + 1)
+}
+
+// The newest sequence number seen for each record key, to reject replays of older records.
+// The same sequence is accepted again: that's the latest record re-fetched (e.g. on resync), not a replay.
+#[derive(Default)]
+pub struct RecordSequences {
+    seen: HashMap<kad::RecordKey, u64>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct StaleRecord {
+    pub sequence: u64,
+    pub last_seen: u64,
+}
+
+impl std::fmt::Display for StaleRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "record sequence {} is older than the last seen {}, possibly replayed", self.sequence, self.last_seen)
+    }
+}
+
+impl std::error::Error for StaleRecord {}
+
+impl RecordSequences {
+
+    pub fn check(&mut self, key: &kad::RecordKey, sequence: u64) -> Result<(), StaleRecord> {
+        match self.seen.get(key) {
+            Some(&last_seen) if sequence < last_seen => Err(StaleRecord { sequence, last_seen }),
+            _ => {
+                self.seen.insert(key.clone(), sequence);
+                Ok(())
+            }
+        }
+    }
+}
+
+// Record keys end in the owning peer's id, e.g. AVS_PUBLIC_KEY_<peer_id>. Peer ids never contain '_'.
+pub(crate) fn record_owner(key: &kad::RecordKey) -> Option<PeerId> {
+    let (_, peer_id) = std::str::from_utf8(key.as_ref()).ok()?.rsplit_once('_')?;
+    peer_id.parse().ok()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunks::{ChunkManifest, CHUNK_MANIFEST_TAG};
+
+    fn record(key: &str, value: &[u8]) -> kad::Record {
+        kad::Record {
+            key: kad::RecordKey::new(&key),
+            value: value.to_vec(),
+            publisher: None,
+            expires: None,
+        }
+    }
+
+    #[test]
+    fn older_record_sequences_are_rejected() {
+        let identity = identity::Keypair::generate_ed25519();
+        let key = format!("POSITION_{}", identity.public().to_peer_id());
+        let old = verify_record_sequenced(sign_record(record(&key, b"old"), &identity)).unwrap();
+        let new = verify_record_sequenced(sign_record(record(&key, b"new"), &identity)).unwrap();
+        assert!(new.1 > old.1);
+
+        let mut sequences = RecordSequences::default();
+        sequences.check(&new.0.key, new.1).unwrap();
+        // re-fetching the latest record is fine, re-putting the older one isn't
+        sequences.check(&new.0.key, new.1).unwrap();
+        assert_eq!(sequences.check(&old.0.key, old.1), Err(StaleRecord { sequence: old.1, last_seen: new.1 }));
+        // other keys are tracked separately
+        sequences.check(&kad::RecordKey::new(&"PEER_NAME_x"), old.1).unwrap();
+    }
+
+    #[test]
+    fn republish_triggers_once_per_interval() {
+        let start = Instant::now();
+        let mut republisher = RecordRepublisher::new(Duration::from_secs(10), start);
+        republisher.track(record("POSITION_a", b"1"));
+        republisher.track(record("AVS_PUBLIC_KEY_a", b"2"));
+        // same key replaces the older value
+        republisher.track(record("POSITION_a", b"3"));
+
+        assert!(republisher.poll_due(start + Duration::from_secs(5)).is_none());
+
+        let due = republisher.poll_due(start + Duration::from_secs(10))
+            .expect("records should be due after one interval");
+        assert_eq!(due.len(), 2);
+        assert!(due.iter().any(|r| r.value == b"3"));
+
+        // next run is scheduled a full interval later
+        assert!(republisher.poll_due(start + Duration::from_secs(11)).is_none());
+        assert!(republisher.poll_due(start + Duration::from_secs(20)).is_some());
+    }
+
+    #[test]
+    fn expired_records_are_not_republished() {
+        let start = Instant::now();
+        let mut republisher = RecordRepublisher::new(Duration::from_secs(10), start);
+        assert_eq!(republisher.expires_at(start), None);
+
+        republisher.record_ttl = Some(Duration::from_secs(15));
+        let expires = republisher.expires_at(start);
+        assert_eq!(expires, Some(start + Duration::from_secs(15)));
+        republisher.track(kad::Record { expires, ..record("ENCRYPTED_FHE_KEY_a", b"1") });
+        republisher.track(record("AVS_PUBLIC_KEY_a", b"2"));
+
+        assert_eq!(republisher.poll_due(start + Duration::from_secs(10)).unwrap().len(), 2);
+        let due = republisher.poll_due(start + Duration::from_secs(20)).unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].value, b"2");
+    }
+
+    #[test]
+    fn records_signed_by_the_wrong_key_are_rejected() {
+        let alice = identity::Keypair::generate_ed25519();
+        let mallory = identity::Keypair::generate_ed25519();
+        let alice_position_key = format!("POSITION_{}", alice.public().to_peer_id());
+
+        let signed = sign_record(record(&alice_position_key, b"position"), &alice);
+        assert_eq!(signed.publisher, Some(alice.public().to_peer_id()));
+        assert_eq!(verify_record(signed.clone()).unwrap().value, b"position");
+
+        // mallory's own signature, under alice's key
+        let spoofed = sign_record(record(&alice_position_key, b"position"), &mallory);
+        assert_eq!(verify_record(spoofed).unwrap_err(), RecordSignatureError::WrongOwner);
+
+        // alice's signature, claimed by mallory
+        let relabelled = kad::Record { publisher: Some(mallory.public().to_peer_id()), ..signed.clone() };
+        assert_eq!(verify_record(relabelled).unwrap_err(), RecordSignatureError::WrongPublisher);
+
+        // mallory's key substituted for alice's, or the value changed after signing
+        let mut wrapped = SignedRecord::decode(&signed.value).unwrap();
+        wrapped.public_key = mallory.public().encode_protobuf();
+        let resigned = kad::Record { value: wrapped.encode(), ..signed.clone() };
+        assert_eq!(verify_record(resigned).unwrap_err(), RecordSignatureError::WrongPublisher);
+        let mut wrapped = SignedRecord::decode(&signed.value).unwrap();
+        wrapped.value = b"elsewhere".to_vec();
+        let tampered = kad::Record { value: wrapped.encode(), ..signed.clone() };
+        assert_eq!(verify_record(tampered).unwrap_err(), RecordSignatureError::InvalidSignature);
+
+        assert_eq!(verify_record(record(&alice_position_key, b"position")).unwrap_err(), RecordSignatureError::Unsigned);
+        let unpublished = kad::Record { publisher: None, ..signed };
+        assert_eq!(verify_record(unpublished).unwrap_err(), RecordSignatureError::NoPublisher);
+    }
+
+    #[test]
+    fn signed_records_are_never_mistaken_for_manifests() {
+        let identity = identity::Keypair::generate_ed25519();
+        let key = format!("PEER_NAME_{}", identity.public().to_peer_id());
+        // e.g. a 255 byte --name: untagged, its bincode would start with the manifest tag
+        let name = [b'a'; 255];
+        let signed = sign_record(record(&key, &name), &identity);
+        let untagged = bincode::serialize(&SignedRecord::decode(&signed.value).unwrap()).unwrap();
+        assert_eq!(untagged[0], CHUNK_MANIFEST_TAG);
+
+        assert_eq!(signed.value[0], SIGNED_RECORD_TAG);
+        assert!(ChunkManifest::decode(&signed.value).is_none());
+        assert_eq!(verify_record(signed).unwrap().value, name);
+        // a signed record without its tag isn't accepted either
+        let untagged = kad::Record { value: untagged, ..sign_record(record(&key, &name), &identity) };
+        assert_eq!(verify_record(untagged).unwrap_err(), RecordSignatureError::Unsigned);
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use libp2p::{Multiaddr, PeerId};
+
+pub const DEFAULT_MAX_REDIAL_ATTEMPTS: u32 = 5;
+pub const DEFAULT_REDIAL_BASE_DELAY: Duration = Duration::from_secs(1);
+pub const MAX_REDIAL_DELAY: Duration = Duration::from_secs(60);
+
+struct PendingRedial {
+    address: Multiaddr,
+    // redials made so far, the next one waits base_delay * 2^attempts
+    attempts: u32,
+    due: Instant,
+}
+
+// Redials peers whose connection dropped, at the last address we dialed them on, with exponential
+// backoff up to `max_attempts`. Connections we closed ourselves (cause None) aren't retried.
+pub struct RedialPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    addresses: HashMap<PeerId, Multiaddr>,
+    pending: HashMap<PeerId, PendingRedial>,
+}
+
+impl Default for RedialPolicy {
+    fn default() -> Self {
+        RedialPolicy::new(DEFAULT_MAX_REDIAL_ATTEMPTS, DEFAULT_REDIAL_BASE_DELAY)
+    }
+}
+
+impl RedialPolicy {
+
+    pub fn new(max_attempts: u32, base_delay: Duration) -> Self {
+        RedialPolicy {
+            max_attempts,
+            base_delay,
+            addresses: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    // A connection we dialed came up: remember where the peer is and stop retrying it
+    pub fn connected(&mut self, peer_id: PeerId, address: Option<Multiaddr>) {
+        if let Some(address) = address {
+            self.addresses.insert(peer_id, address);
+        }
+        self.pending.remove(&peer_id);
+    }
+
+    // The last connection to a peer closed. `deliberate` is a local close, which isn't retried.
+    // Returns the delay before the first redial, if one was scheduled.
+    pub fn connection_closed(&mut self, peer_id: PeerId, deliberate: bool, now: Instant) -> Option<Duration> {
+        if deliberate || self.max_attempts == 0 {
+            self.pending.remove(&peer_id);
+            return None;
+        }
+        let address = self.addresses.get(&peer_id)?.clone();
+        let delay = self.backoff(0);
+        self.pending.insert(peer_id, PendingRedial { address, attempts: 0, due: now + delay });
+        Some(delay)
+    }
+
+    // A redial failed: back off further, or give up after max_attempts. Returns the next delay.
+    pub fn dial_failed(&mut self, peer_id: PeerId, now: Instant) -> Option<Duration> {
+        let attempts = self.pending.get(&peer_id)?.attempts;
+        if attempts >= self.max_attempts {
+            self.pending.remove(&peer_id);
+            return None;
+        }
+        let delay = self.backoff(attempts);
+        self.pending.get_mut(&peer_id)?.due = now + delay;
+        Some(delay)
+    }
+
+    // Redials to make now. Each counts as an attempt, and stays pending until connected() or dial_failed().
+    pub fn poll_due(&mut self, now: Instant) -> Vec<(PeerId, Multiaddr)> {
+        let mut due = Vec::new();
+        for (peer_id, redial) in self.pending.iter_mut() {
+            if redial.due <= now && redial.attempts < self.max_attempts {
+                redial.attempts += 1;
+                // not due again until dial_failed() reschedules it
+                redial.due = now + MAX_REDIAL_DELAY;
+                due.push((*peer_id, redial.address.clone()));
+            }
+        }
+        due
+    }
+
+    // Redials made to a peer since its connection dropped
+    pub fn attempts(&self, peer_id: &PeerId) -> Option<u32> {
+        self.pending.get(peer_id).map(|redial| redial.attempts)
+    }
+
+    fn backoff(&self, attempts: u32) -> Duration {
+        self.base_delay.saturating_mul(2u32.saturating_pow(attempts)).min(MAX_REDIAL_DELAY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address() -> Multiaddr {
+        "/ip4/127.0.0.1/tcp/4001".parse().unwrap()
+    }
+
+    #[test]
+    fn redials_back_off_exponentially_until_max_attempts() {
+        let start = Instant::now();
+        let peer_id = PeerId::random();
+        let mut redial = RedialPolicy::new(3, Duration::from_secs(1));
+        redial.connected(peer_id, Some(address()));
+
+        assert_eq!(redial.connection_closed(peer_id, false, start), Some(Duration::from_secs(1)));
+        assert!(redial.poll_due(start).is_empty());
+        assert_eq!(redial.poll_due(start + Duration::from_secs(1)), vec![(peer_id, address())]);
+        assert_eq!(redial.attempts(&peer_id), Some(1));
+
+        let now = start + Duration::from_secs(2);
+        assert_eq!(redial.dial_failed(peer_id, now), Some(Duration::from_secs(2)));
+        assert_eq!(redial.poll_due(now + Duration::from_secs(2)).len(), 1);
+        assert_eq!(redial.dial_failed(peer_id, now), Some(Duration::from_secs(4)));
+        assert_eq!(redial.poll_due(now + Duration::from_secs(4)).len(), 1);
+
+        // third attempt failed, give up
+        assert_eq!(redial.dial_failed(peer_id, now), None);
+        assert_eq!(redial.attempts(&peer_id), None);
+        assert!(redial.poll_due(now + MAX_REDIAL_DELAY).is_empty());
+    }
+
+    #[test]
+    fn deliberate_and_unknown_disconnects_are_not_redialed() {
+        let now = Instant::now();
+        let dialed = PeerId::random();
+        let mut redial = RedialPolicy::default();
+        redial.connected(dialed, Some(address()));
+
+        assert_eq!(redial.connection_closed(dialed, true, now), None);
+        // a peer that dialed us has no address we could reach it on
+        assert_eq!(redial.connection_closed(PeerId::random(), false, now), None);
+        assert!(redial.poll_due(now + MAX_REDIAL_DELAY).is_empty());
+
+        // reconnecting cancels a pending redial
+        redial.connection_closed(dialed, false, now);
+        redial.connected(dialed, None);
+        assert!(redial.poll_due(now + MAX_REDIAL_DELAY).is_empty());
+    }
+}
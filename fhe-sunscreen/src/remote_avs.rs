@@ -0,0 +1,438 @@
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use libp2p::{
+    request_response::{self, OutboundRequestId, ResponseChannel},
+    swarm::SwarmEvent,
+    Multiaddr,
+    PeerId,
+    StreamProtocol,
+    Swarm,
+};
+use serde::{Deserialize, Serialize};
+use sunscreen::PublicKey;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::fhe_sunscreen::{EncryptedPosition, AVS};
+use crate::position_exchange::{read_message, write_message};
+
+// Remote AVS calls. The AVS runs as a separate libp2p service and clients send it moves over
+// this request-response protocol. A client Submits a move, which is answered as soon as it's queued,
+// then long-polls with Poll for each following event until the new position comes back.
+pub const AVS_PROTOCOL: StreamProtocol = StreamProtocol::new("/fhe-sunscreen/avs/1");
+
+// A Poll is only answered once its move has started or finished, so it may wait out every move queued ahead of it
+const AVS_REQUEST_TIMEOUT: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AvsProgress {
+    // `ahead` is how many moves were waiting in front of this one when it was accepted
+    Queued { ahead: usize },
+    Computing,
+    // set by the client once the result arrives, never sent over the wire
+    Done,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum AvsRequest {
+    // `id` is picked by the client and only has to be unique per peer
+    Submit { id: u64, position: EncryptedPosition, public_key: PublicKey },
+    // Answered with the next event for move `id`, once there is one
+    Poll { id: u64 },
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum AvsResponse {
+    Progress { id: u64, progress: AvsProgress },
+    // boxed, an EncryptedPosition is three ciphertexts and would dwarf the Progress variant
+    Result { id: u64, result: Result<Box<EncryptedPosition>, String> },
+}
+
+pub fn new_behaviour() -> request_response::Behaviour<AvsCodec> {
+    request_response::Behaviour::new(
+        [(AVS_PROTOCOL, request_response::ProtocolSupport::Full)],
+        request_response::Config::default().with_request_timeout(AVS_REQUEST_TIMEOUT),
+    )
+}
+
+// Length-prefixed (u32 BE) bincode messages, like PositionCodec
+#[derive(Debug, Clone, Default)]
+pub struct AvsCodec;
+
+#[async_trait]
+impl request_response::Codec for AvsCodec {
+    type Protocol = StreamProtocol;
+    type Request = AvsRequest;
+    type Response = AvsResponse;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<AvsRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_message(io).await
+    }
+
+    async fn read_response<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<AvsResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_message(io).await
+    }
+
+    async fn write_request<T>(&mut self, _: &StreamProtocol, io: &mut T, request: AvsRequest) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_message(io, &request).await
+    }
+
+    async fn write_response<T>(&mut self, _: &StreamProtocol, io: &mut T, response: AvsResponse) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_message(io, &response).await
+    }
+}
+
+// A move is identified by the peer that submitted it and the id that peer gave it
+type MoveKey = (PeerId, u64);
+
+// Events for a move that haven't been delivered yet, and the Poll waiting for the next one (if any)
+#[derive(Default)]
+struct QueuedMove {
+    events: VecDeque<AvsResponse>,
+    waiting: Option<ResponseChannel<AvsResponse>>,
+}
+
+// Serves run_contract requests arriving on `swarm` in the order they arrive, reporting progress for each one.
+// Requests from every client share this one queue, so concurrent moves for the same player
+// are applied in order against the previous result and none are lost.
+// A client that disconnects loses the events of its queued moves, though the moves are still applied.
+pub async fn serve_avs(mut swarm: Swarm<request_response::Behaviour<AvsCodec>>, avs: AVS) {
+
+    let (move_tx, move_rx) = mpsc::unbounded_channel();
+    let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+    tokio::spawn(run_moves(avs, move_rx, event_tx));
+
+    let mut moves: HashMap<MoveKey, QueuedMove> = HashMap::new();
+    // moves accepted that haven't started computing yet
+    let mut waiting_to_start = 0;
+
+    loop {
+        tokio::select! {
+            event = swarm.select_next_some() => match event {
+                SwarmEvent::Behaviour(request_response::Event::Message {
+                    peer,
+                    message: request_response::Message::Request { request, channel, .. },
+                }) => match request {
+                    AvsRequest::Submit { id, position, public_key } => {
+                        if moves.contains_key(&(peer, id)) {
+                            let result = Err(format!("move {id} is already queued"));
+                            let _ = swarm.behaviour_mut().send_response(channel, AvsResponse::Result { id, result });
+                        } else {
+                            moves.insert((peer, id), QueuedMove::default());
+                            let queued = AvsProgress::Queued { ahead: waiting_to_start };
+                            let _ = swarm.behaviour_mut().send_response(channel, AvsResponse::Progress { id, progress: queued });
+                            waiting_to_start += 1;
+                            let _ = move_tx.send(((peer, id), position, public_key));
+                        }
+                    }
+                    AvsRequest::Poll { id } => match moves.get_mut(&(peer, id)) {
+                        Some(queued_move) => match queued_move.events.pop_front() {
+                            Some(response) => respond(&mut swarm, &mut moves, (peer, id), channel, response),
+                            None => queued_move.waiting = Some(channel),
+                        },
+                        None => {
+                            let result = Err(format!("no move {id} is queued"));
+                            let _ = swarm.behaviour_mut().send_response(channel, AvsResponse::Result { id, result });
+                        }
+                    },
+                },
+                SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                    moves.retain(|(peer, _), _| *peer != peer_id);
+                }
+                _ => {}
+            },
+            Some((key, response)) = event_rx.recv() => {
+                if let AvsResponse::Progress { progress: AvsProgress::Computing, .. } = response {
+                    waiting_to_start -= 1;
+                }
+                if let Some(queued_move) = moves.get_mut(&key) {
+                    match queued_move.waiting.take() {
+                        Some(channel) => respond(&mut swarm, &mut moves, key, channel, response),
+                        None => queued_move.events.push_back(response),
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Answers a Poll, forgetting the move once its result is delivered
+fn respond(
+    swarm: &mut Swarm<request_response::Behaviour<AvsCodec>>,
+    moves: &mut HashMap<MoveKey, QueuedMove>,
+    key: MoveKey,
+    channel: ResponseChannel<AvsResponse>,
+    response: AvsResponse
+) {
+    if let AvsResponse::Result { .. } = response {
+        moves.remove(&key);
+    }
+    // only fails once the client has hung up, in which case nobody is waiting on the event
+    let _ = swarm.behaviour_mut().send_response(channel, response);
+}
+
+// Runs queued moves one at a time. run_contract is blocking and takes seconds of CPU,
+// so it runs on the blocking pool to keep the swarm answering requests meanwhile.
+async fn run_moves(
+    mut avs: AVS,
+    mut moves: mpsc::UnboundedReceiver<(MoveKey, EncryptedPosition, PublicKey)>,
+    events: mpsc::UnboundedSender<(MoveKey, AvsResponse)>
+) {
+    while let Some(((peer, id), position, public_key)) = moves.recv().await {
+        let _ = events.send(((peer, id), AvsResponse::Progress { id, progress: AvsProgress::Computing }));
+
+        let (returned_avs, result) = tokio::task::spawn_blocking(move || {
+            let result = avs.run_contract(position, &public_key);
+            (avs, result)
+        }).await.expect("run_contract panicked");
+        avs = returned_avs;
+
+        let result = result.map(Box::new).map_err(|e| e.to_string());
+        let _ = events.send(((peer, id), AvsResponse::Result { id, result }));
+    }
+}
+
+struct Call {
+    id: u64,
+    position: EncryptedPosition,
+    public_key: PublicKey,
+    progress: mpsc::UnboundedSender<AvsProgress>,
+    result: oneshot::Sender<Result<EncryptedPosition, String>>,
+}
+
+struct PendingCall {
+    progress: mpsc::UnboundedSender<AvsProgress>,
+    result: oneshot::Sender<Result<EncryptedPosition, String>>,
+}
+
+// Cloning shares the connection, so e.g. a script runner and stdin can issue moves side by side.
+#[derive(Clone)]
+pub struct AvsClient {
+    next_id: Arc<AtomicU64>,
+    calls: mpsc::UnboundedSender<Call>,
+}
+
+impl AvsClient {
+
+    // Spawns a task driving `swarm`, which sends each call to the AVS service `server` listening on `addr`
+    // and routes its events back to the matching in-flight call.
+    pub fn connect(
+        mut swarm: Swarm<request_response::Behaviour<AvsCodec>>,
+        server: PeerId,
+        addr: Multiaddr
+    ) -> AvsClient {
+        swarm.add_peer_address(server, addr);
+        let (calls_tx, calls_rx) = mpsc::unbounded_channel();
+        tokio::spawn(drive_client(swarm, server, calls_rx));
+
+        AvsClient {
+            next_id: Arc::new(AtomicU64::new(0)),
+            calls: calls_tx,
+        }
+    }
+
+    pub fn run_contract(&self, new_position: EncryptedPosition, public_key: &PublicKey) -> ProgressFuture {
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (progress_tx, progress_rx) = mpsc::unbounded_channel();
+        let (result_tx, result_rx) = oneshot::channel();
+
+        // if the client task is gone, dropping the call resolves the future with an error
+        let _ = self.calls.send(Call {
+            id,
+            position: new_position,
+            public_key: public_key.clone(),
+            progress: progress_tx,
+            result: result_tx,
+        });
+
+        ProgressFuture {
+            id,
+            progress: progress_rx,
+            result: result_rx,
+        }
+    }
+}
+
+// Runs until every AvsClient handle is dropped and no call is left waiting on the service
+async fn drive_client(
+    mut swarm: Swarm<request_response::Behaviour<AvsCodec>>,
+    server: PeerId,
+    mut calls: mpsc::UnboundedReceiver<Call>
+) {
+    let mut in_flight: HashMap<u64, PendingCall> = HashMap::new();
+    // the call each outstanding Submit or Poll belongs to, to fail it if the request does
+    let mut requests: HashMap<OutboundRequestId, u64> = HashMap::new();
+    let mut clients_dropped = false;
+
+    while !(clients_dropped && in_flight.is_empty()) {
+        tokio::select! {
+            call = calls.recv(), if !clients_dropped => match call {
+                Some(Call { id, position, public_key, progress, result }) => {
+                    let request_id = swarm.behaviour_mut().send_request(&server, AvsRequest::Submit { id, position, public_key });
+                    requests.insert(request_id, id);
+                    in_flight.insert(id, PendingCall { progress, result });
+                }
+                None => clients_dropped = true,
+            },
+            event = swarm.select_next_some() => match event {
+                SwarmEvent::Behaviour(request_response::Event::Message {
+                    message: request_response::Message::Response { request_id, response },
+                    ..
+                }) => {
+                    requests.remove(&request_id);
+                    match response {
+                        AvsResponse::Progress { id, progress } => {
+                            if let Some(call) = in_flight.get(&id) {
+                                let _ = call.progress.send(progress);
+                                let request_id = swarm.behaviour_mut().send_request(&server, AvsRequest::Poll { id });
+                                requests.insert(request_id, id);
+                            }
+                        }
+                        AvsResponse::Result { id, result } => {
+                            if let Some(call) = in_flight.remove(&id) {
+                                let _ = call.progress.send(AvsProgress::Done);
+                                let _ = call.result.send(result.map(|position| *position));
+                            }
+                        }
+                    }
+                }
+                SwarmEvent::Behaviour(request_response::Event::OutboundFailure { request_id, error, .. }) => {
+                    if let Some(call) = requests.remove(&request_id).and_then(|id| in_flight.remove(&id)) {
+                        let _ = call.result.send(Err(format!("remote AVS request failed: {error}")));
+                    }
+                }
+                _ => {}
+            },
+        }
+    }
+}
+
+// Resolves to the new encrypted position once the remote AVS is done.
+// Intermediate progress events can be read with `progress()` while waiting.
+pub struct ProgressFuture {
+    pub id: u64,
+    progress: mpsc::UnboundedReceiver<AvsProgress>,
+    result: oneshot::Receiver<Result<EncryptedPosition, String>>,
+}
+
+impl ProgressFuture {
+    pub async fn progress(&mut self) -> Option<AvsProgress> {
+        self.progress.recv().await
+    }
+}
+
+impl Future for ProgressFuture {
+    type Output = Result<EncryptedPosition, Box<dyn Error + Send + Sync>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.result).poll(cx).map(|result| match result {
+            Ok(Ok(position)) => Ok(position),
+            Ok(Err(e)) => Err(e.into()),
+            Err(_) => Err("remote AVS disconnected before responding".into()),
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fhe_sunscreen::{Position, User};
+    use libp2p::{
+        core::{transport::{MemoryTransport, Transport}, upgrade},
+        noise,
+        yamux,
+    };
+
+    fn memory_swarm() -> Swarm<request_response::Behaviour<AvsCodec>> {
+        libp2p::SwarmBuilder::with_new_identity()
+            .with_tokio()
+            .with_other_transport(|key| {
+                Ok(MemoryTransport::default()
+                    .upgrade(upgrade::Version::V1)
+                    .authenticate(noise::Config::new(key)?)
+                    .multiplex(yamux::Config::default()))
+            }).unwrap()
+            .with_behaviour(|_| new_behaviour()).unwrap()
+            .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(u64::MAX)))
+            .build()
+    }
+
+    // Starts an AVS service on the memory transport and connects a client to it
+    async fn connect_to_avs(avs: AVS) -> AvsClient {
+        let mut server = memory_swarm();
+        server.listen_on("/memory/0".parse().unwrap()).unwrap();
+        let addr = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = server.select_next_some().await {
+                break address;
+            }
+        };
+        let server_peer_id = *server.local_peer_id();
+        tokio::spawn(serve_avs(server, avs));
+        AvsClient::connect(memory_swarm(), server_peer_id, addr)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn remote_run_contract_resolves_to_new_position() {
+        let avs = AVS::setup().unwrap();
+        let user = User::setup(&avs.compiled_move_position.metadata.params, "alice").unwrap();
+        let client = connect_to_avs(avs).await;
+
+        let move_tx = user.create_move_transaction(Position { x: 3.0, y: 2.0, z: 0.0 }).unwrap();
+        let mut call = client.run_contract(move_tx, &user.fhe_public_key);
+
+        assert_eq!(call.progress().await, Some(AvsProgress::Queued { ahead: 0 }));
+        assert_eq!(call.progress().await, Some(AvsProgress::Computing));
+
+        let new_position = call.await.unwrap();
+        let position = user.decrypt_own_position(new_position).unwrap();
+        assert_eq!((position.x, position.y), (3.0, 2.0));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_moves_for_same_player_are_applied_cumulatively() {
+        let avs = AVS::setup().unwrap();
+        let user = User::setup(&avs.compiled_move_position.metadata.params, "alice").unwrap();
+        let client = connect_to_avs(avs).await;
+
+        // issue both moves before awaiting either, from two handles on the same client
+        let first = client.run_contract(
+            user.create_move_transaction(Position { x: 3.0, y: 2.0, z: 0.0 }).unwrap(),
+            &user.fhe_public_key
+        );
+        let second = client.clone().run_contract(
+            user.create_move_transaction(Position { x: 1.0, y: 5.0, z: 0.0 }).unwrap(),
+            &user.fhe_public_key
+        );
+        // calls already made still complete after every handle is dropped
+        drop(client);
+
+        let (first, second) = tokio::join!(first, second);
+        let position = user.decrypt_own_position(first.unwrap()).unwrap();
+        assert_eq!((position.x, position.y), (3.0, 2.0));
+        let position = user.decrypt_own_position(second.unwrap()).unwrap();
+        assert_eq!((position.x, position.y), (4.0, 7.0));
+    }
+}
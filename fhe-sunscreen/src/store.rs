@@ -0,0 +1,112 @@
+use std::borrow::Cow;
+use libp2p::kad::store::{self, MemoryStore, MemoryStoreConfig, RecordStore};
+use libp2p::kad::{ProviderRecord, Record, RecordKey};
+use libp2p::PeerId;
+
+// MemoryStore that reports puts it turns away for being full, instead of failing them silently.
+// Kademlia stores records put by remote peers without telling the node, so a full store otherwise
+// looks like a MOVE that worked locally but never reached anyone. Every rejection is logged with
+// its key and counted (STATS shows the count).
+pub struct BoundedStore {
+    inner: MemoryStore,
+    max_records: usize,
+    rejected: u64,
+}
+
+impl BoundedStore {
+
+    pub fn new(local_peer_id: PeerId) -> Self {
+        BoundedStore::with_config(local_peer_id, MemoryStoreConfig::default())
+    }
+
+    pub fn with_config(local_peer_id: PeerId, config: MemoryStoreConfig) -> Self {
+        BoundedStore {
+            max_records: config.max_records,
+            inner: MemoryStore::with_config(local_peer_id, config),
+            rejected: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.records().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn max_records(&self) -> usize {
+        self.max_records
+    }
+
+    // Puts refused because the store was at max_records
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+}
+
+impl RecordStore for BoundedStore {
+    type RecordsIter<'a> = <MemoryStore as RecordStore>::RecordsIter<'a>;
+    type ProvidedIter<'a> = <MemoryStore as RecordStore>::ProvidedIter<'a>;
+
+    fn get(&self, k: &RecordKey) -> Option<Cow<'_, Record>> {
+        self.inner.get(k)
+    }
+
+    fn put(&mut self, r: Record) -> store::Result<()> {
+        let key = String::from_utf8_lossy(r.key.as_ref()).into_owned();
+        self.inner.put(r).inspect_err(|e| {
+            if matches!(e, store::Error::MaxRecords) {
+                self.rejected += 1;
+                eprintln!("[WARN] Record store is full ({} records), rejected {key}", self.max_records);
+            }
+        })
+    }
+
+    fn remove(&mut self, k: &RecordKey) {
+        self.inner.remove(k)
+    }
+
+    fn records(&self) -> Self::RecordsIter<'_> {
+        self.inner.records()
+    }
+
+    fn add_provider(&mut self, record: ProviderRecord) -> store::Result<()> {
+        self.inner.add_provider(record)
+    }
+
+    fn providers(&self, key: &RecordKey) -> Vec<ProviderRecord> {
+        self.inner.providers(key)
+    }
+
+    fn provided(&self) -> Self::ProvidedIter<'_> {
+        self.inner.provided()
+    }
+
+    fn remove_provider(&mut self, k: &RecordKey, p: &PeerId) {
+        self.inner.remove_provider(k, p)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn puts_past_capacity_are_counted_not_silent() {
+        let config = MemoryStoreConfig { max_records: 2, ..MemoryStoreConfig::default() };
+        let mut store = BoundedStore::with_config(PeerId::random(), config);
+        let record = |key: &str| Record::new(RecordKey::new(&key), b"value".to_vec());
+
+        store.put(record("POSITION_a")).unwrap();
+        store.put(record("POSITION_b")).unwrap();
+        assert!(matches!(store.put(record("POSITION_c")), Err(store::Error::MaxRecords)));
+        assert_eq!((store.len(), store.max_records(), store.rejected()), (2, 2, 1));
+
+        // replacing a stored record isn't a new one, and isn't rejected
+        store.put(record("POSITION_a")).unwrap();
+        store.remove(&RecordKey::new(&"POSITION_b"));
+        store.put(record("POSITION_c")).unwrap();
+        assert_eq!((store.len(), store.rejected()), (2, 1));
+    }
+}
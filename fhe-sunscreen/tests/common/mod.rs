@@ -0,0 +1,68 @@
+// Fixtures shared by the integration tests. Each test crate only uses some of them.
+#![allow(dead_code)]
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{future, FutureExt};
+use futures::stream::StreamExt;
+
+use fhe_sunscreen::{User, AVS, DEFAULT_ZSTD_LEVEL};
+use fhe_sunscreen::metrics::NodeMetrics;
+use fhe_sunscreen::node::{build_memory_swarm, Node};
+use fhe_sunscreen::position_exchange::PositionStore;
+use fhe_sunscreen::records::{RecordRepublisher, DEFAULT_REPUBLISH_INTERVAL};
+
+pub fn memory_node(name: &str) -> Node {
+    memory_node_with_metrics(name, Arc::new(NodeMetrics::default()))
+}
+
+// A node on the in-process memory transport, recording into `metrics` (e.g. one also served on --metrics-addr)
+pub fn memory_node_with_metrics(name: &str, metrics: Arc<NodeMetrics>) -> Node {
+    let avs = AVS::setup().unwrap();
+    let user = User::setup(&avs.compiled_move_position.metadata.params, name).unwrap();
+    Node::new(
+        build_memory_swarm(&user.identity).unwrap(),
+        user,
+        avs,
+        RecordRepublisher::new(DEFAULT_REPUBLISH_INTERVAL, Instant::now()),
+        PositionStore::new(DEFAULT_ZSTD_LEVEL),
+        metrics,
+    )
+}
+
+// Handles every node's swarm events, as the binary's event loop would, until `done` holds
+pub async fn drive_until(nodes: &mut [Node], what: &str, done: impl Fn(&[Node]) -> bool) {
+    tokio::time::timeout(Duration::from_secs(60), async {
+        while !done(nodes) {
+            let ((i, event), _, pending) = future::select_all(
+                nodes.iter_mut().enumerate().map(|(i, node)| node.swarm.select_next_some().map(move |e| (i, e)))
+            ).await;
+            drop(pending);
+            nodes[i].handle_swarm_event(event);
+        }
+    }).await.unwrap_or_else(|_| panic!("timed out waiting until {what}"));
+}
+
+// drive_until for two nodes held separately
+pub async fn drive_pair_until(alice: &mut Node, bob: &mut Node, what: &str, done: impl Fn(&Node, &Node) -> bool) {
+    tokio::time::timeout(Duration::from_secs(60), async {
+        while !done(alice, bob) {
+            tokio::select! {
+                event = alice.swarm.select_next_some() => alice.handle_swarm_event(event),
+                event = bob.swarm.select_next_some() => bob.handle_swarm_event(event),
+            }
+        }
+    }).await.unwrap_or_else(|_| panic!("timed out waiting until {what}"));
+}
+
+// A counter's current value, as a metrics scrape would report it
+pub fn counter(node: &Node, name: &str) -> u64 {
+    let metrics = node.metrics.render_prometheus();
+    let line = metrics.lines().find(|line| line.starts_with(&format!("{name} "))).unwrap();
+    line.rsplit(' ').next().unwrap().parse().unwrap()
+}
+
+pub fn decryptions(node: &Node) -> u64 {
+    counter(node, "fhe_sunscreen_decryptions_total")
+}
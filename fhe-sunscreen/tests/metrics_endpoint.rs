@@ -0,0 +1,45 @@
+#![cfg(feature = "metrics")]
+
+mod common;
+
+use std::sync::Arc;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use fhe_sunscreen::metrics::{bind_metrics, serve_metrics, NodeMetrics};
+
+use common::{drive_until, memory_node_with_metrics};
+
+async fn scrape(addr: std::net::SocketAddr) -> String {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+// A MOVE typed into a node shows up in the run_contract and encrypt histograms on --metrics-addr
+#[tokio::test]
+async fn move_is_timed_in_the_run_contract_histogram() {
+    let metrics = Arc::new(NodeMetrics::default());
+    let mut node = memory_node_with_metrics("alice", metrics.clone());
+
+    let listener = bind_metrics("127.0.0.1:0".parse().unwrap()).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(serve_metrics(listener, metrics));
+
+    let before = scrape(addr).await;
+    assert!(before.contains("\nfhe_sunscreen_run_contract_seconds_count 0\n"));
+
+    // MOVE needs the node's own peer id, which it learns once it listens
+    node.swarm.listen_on("/memory/0".parse().unwrap()).unwrap();
+    drive_until(std::slice::from_mut(&mut node), "the node listens", |nodes| nodes[0].avs.peer_id.is_some()).await;
+    node.handle_input_line(r#"MOVE alice {"x":3,"y":-4,"z":5}"#.to_string()).unwrap();
+
+    let after = scrape(addr).await;
+    assert!(after.contains("\nfhe_sunscreen_run_contract_seconds_count 1\n"), "{after}");
+    assert!(after.contains("\nfhe_sunscreen_run_contract_seconds_bucket{le=\"+Inf\"} 1\n"));
+    assert!(after.contains("\nfhe_sunscreen_encrypt_seconds_count 1\n"));
+    assert!(after.contains("\nfhe_sunscreen_moves_applied_total 1\n"));
+}
@@ -0,0 +1,58 @@
+use fhe_sunscreen::{
+    decode_position_record,
+    encode_position_record,
+    Position,
+    User,
+    AVS,
+};
+
+// Drives a player's moves through the library API only: encrypt -> run_contract -> decrypt.
+#[test]
+fn encrypt_run_contract_decrypt_cycle() {
+    let mut avs = AVS::setup().unwrap();
+    let user = User::setup(&avs.compiled_move_position.metadata.params, "alice").unwrap();
+
+    let first = user.create_move_transaction(Position { x: 3.0, y: 2.0, z: 1.0 }).unwrap();
+    avs.run_contract(first, &user.fhe_public_key).unwrap();
+    let second = user.create_move_transaction(Position { x: -1.0, y: 0.5, z: 0.0 }).unwrap();
+    let encrypted_position = avs.run_contract(second, &user.fhe_public_key).unwrap();
+    assert_eq!(avs.move_sequence(&user.fhe_public_key), 2);
+
+    // positions survive the record encoding peers exchange them in
+    let record = encode_position_record(&encrypted_position).unwrap();
+    let position = user.decrypt_own_position(decode_position_record(&record).unwrap()).unwrap();
+    assert_eq!((position.x, position.y, position.z), (2.0, 2.5, 1.0));
+}
+
+// Several players' moves in one batch, spread over three runtimes: each player's moves still apply in order
+#[test]
+fn concurrent_moves_update_every_position() {
+    let mut avs = AVS::setup().unwrap();
+    avs.set_concurrency(3).unwrap();
+    let params = avs.compiled_move_position.metadata.params.clone();
+    let users: Vec<User> = ["alice", "bob", "carol"].iter()
+        .map(|name| User::setup(&params, name).unwrap())
+        .collect();
+
+    let steps = [(0, (1.0, 2.0, 0.0)), (1, (-3.0, 1.0, 2.0)), (0, (2.0, -1.0, 1.0)), (2, (0.5, 0.0, -4.0)), (1, (1.0, 1.0, 1.0))];
+    let moves = steps.iter()
+        .map(|&(user, (x, y, z))| {
+            let move_tx = users[user].create_move_transaction(Position { x, y, z }).unwrap();
+            (move_tx, users[user].fhe_public_key.clone())
+        })
+        .collect();
+    let results = avs.run_contract_concurrent(moves);
+    assert_eq!(results.len(), steps.len());
+
+    // results come back in batch order: alice's second move is her running total
+    let alice_second = users[0].decrypt_own_position(results[2].as_ref().unwrap().clone()).unwrap();
+    assert_eq!((alice_second.x, alice_second.y, alice_second.z), (3.0, 1.0, 1.0));
+
+    let expected = [((3.0, 1.0, 1.0), 2), ((-2.0, 2.0, 3.0), 2), ((0.5, 0.0, -4.0), 1)];
+    for (user, (position, sequence)) in users.iter().zip(expected) {
+        let encrypted_position = avs.get_prev_position(&user.fhe_public_key).unwrap();
+        let decrypted = user.decrypt_own_position(encrypted_position).unwrap();
+        assert_eq!((decrypted.x, decrypted.y, decrypted.z), position);
+        assert_eq!(avs.move_sequence(&user.fhe_public_key), sequence);
+    }
+}
@@ -0,0 +1,57 @@
+mod common;
+
+use libp2p::{Multiaddr, PeerId};
+
+use fhe_sunscreen::{form_encrypted_fhe_key, UserKeyPair};
+use fhe_sunscreen::node::{position_topic, Node};
+
+use common::{decryptions, drive_until, memory_node};
+
+// Alice publishes one move, and every subscriber decrypts it without requesting it
+#[tokio::test]
+async fn one_publish_reaches_three_subscribers() {
+    let mut nodes: Vec<Node> = ["alice", "bob", "carol", "dave"].into_iter().map(memory_node).collect();
+    let alice_peer_id: PeerId = *nodes[0].swarm.local_peer_id();
+
+    for node in nodes.iter_mut() {
+        node.swarm.listen_on("/memory/0".parse().unwrap()).unwrap();
+    }
+    drive_until(&mut nodes, "every node listens", |nodes| nodes.iter().all(|node| node.avs.peer_id.is_some())).await;
+
+    // alice shares her FHE key with each subscriber, as SHARE_KEY + GET ENCRYPTED_FHE_KEY would
+    let record_key = form_encrypted_fhe_key(&alice_peer_id.to_string());
+    let (alice, subscribers) = nodes.split_first_mut().unwrap();
+    for subscriber in subscribers.iter_mut() {
+        let subscriber_peer_id = subscriber.swarm.local_peer_id().to_string();
+        let fhe_private_key_encrypted = alice.user
+            .encrypt_fhe_key_for_peer(&subscriber_peer_id, &subscriber.user.ecdh_public_key, &record_key)
+            .unwrap();
+        subscriber.user.peer_fhe_decryption_keys.insert(alice_peer_id.to_string(), UserKeyPair {
+            ecdh_public_key: alice.user.ecdh_public_key,
+            fhe_private_key_encrypted,
+        });
+    }
+
+    let alice_addr: Multiaddr = alice.swarm.listeners().next().unwrap().clone();
+    for subscriber in subscribers.iter_mut() {
+        subscriber.handle_input_line(format!("DIAL {alice_addr}")).unwrap();
+    }
+    let topic = position_topic().hash();
+    drive_until(&mut nodes, "alice sees three subscribers", |nodes| {
+        nodes[0].swarm.behaviour().gossipsub.all_peers()
+            .filter(|(_, topics)| topics.contains(&&topic))
+            .count() == 3
+    }).await;
+
+    nodes[0].handle_input_line(r#"MOVE alice {"x":3,"y":-4,"z":5}"#.to_string()).unwrap();
+    nodes[0].handle_input_line("PUBLISH".to_string()).unwrap();
+    drive_until(&mut nodes, "every subscriber decrypts alice's position", |nodes| {
+        nodes[1..].iter().all(|node| decryptions(node) == 1)
+    }).await;
+
+    for subscriber in &nodes[1..] {
+        let encrypted_position = subscriber.avs.peer_positions.get(&alice_peer_id.to_string()).unwrap().clone();
+        let position = subscriber.user.decrypt_peer_position(encrypted_position, &alice_peer_id.to_string()).unwrap();
+        assert_eq!((position.x, position.y, position.z), (3.0, -4.0, 5.0));
+    }
+}
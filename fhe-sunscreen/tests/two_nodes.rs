@@ -0,0 +1,254 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use futures::stream::StreamExt;
+use futures::channel::oneshot;
+use tokio::io::AsyncBufReadExt;
+use libp2p::{request_response, swarm::SwarmEvent, Multiaddr, PeerId};
+
+use fhe_sunscreen::{form_avs_public_key, form_hello_key, form_peer_name_key, AppError};
+use fhe_sunscreen::hello::PROTOCOL_VERSION;
+use fhe_sunscreen::node::BehaviourEvent;
+use fhe_sunscreen::position_exchange::{PositionRequest, PositionResponse};
+
+use common::{counter, decryptions, drive_pair_until, memory_node};
+
+// Bob only knows alice's address: no mDNS, no routing table entries added by hand
+#[tokio::test]
+async fn dial_connects_to_an_explicit_address() {
+    let mut alice = memory_node("alice");
+    let mut bob = memory_node("bob");
+    let alice_peer_id: PeerId = *alice.swarm.local_peer_id();
+
+    alice.swarm.listen_on("/memory/0".parse().unwrap()).unwrap();
+    bob.swarm.listen_on("/memory/0".parse().unwrap()).unwrap();
+    drive_pair_until(&mut alice, &mut bob, "both nodes listen", |alice, bob| {
+        alice.avs.peer_id.is_some() && bob.avs.peer_id.is_some()
+    }).await;
+    let alice_addr: Multiaddr = alice.swarm.listeners().next().unwrap().clone();
+
+    assert!(bob.handle_input_line("DIAL not-a-multiaddr".to_string()).is_err());
+    bob.handle_input_line(format!("DIAL {alice_addr}")).unwrap();
+    drive_pair_until(&mut alice, &mut bob, "bob learns alice's name", |_, bob| {
+        bob.avs.peer_ids.get("alice") == Some(&alice_peer_id)
+    }).await;
+
+    let routes: Vec<PeerId> = bob.swarm.behaviour_mut().kademlia.kbuckets()
+        .flat_map(|bucket| bucket.iter().map(|entry| *entry.node.key.preimage()).collect::<Vec<_>>())
+        .collect();
+    assert_eq!(routes, vec![alice_peer_id]);
+}
+
+// A shutdown channel stands in for Ctrl-C: the loop exits, republishes alice's records and disconnects
+#[tokio::test]
+async fn shutdown_exits_the_loop_and_republishes() {
+    let mut alice = memory_node("alice");
+    let mut bob = memory_node("bob");
+    let alice_peer_id: PeerId = *alice.swarm.local_peer_id();
+
+    alice.swarm.listen_on("/memory/0".parse().unwrap()).unwrap();
+    bob.swarm.listen_on("/memory/0".parse().unwrap()).unwrap();
+    drive_pair_until(&mut alice, &mut bob, "both nodes listen", |alice, bob| {
+        alice.avs.peer_id.is_some() && bob.avs.peer_id.is_some()
+    }).await;
+    let alice_addr: Multiaddr = alice.swarm.listeners().next().unwrap().clone();
+    bob.handle_input_line(format!("DIAL {alice_addr}")).unwrap();
+    drive_pair_until(&mut alice, &mut bob, "bob learns alice's name", |_, bob| {
+        bob.avs.peer_ids.get("alice") == Some(&alice_peer_id)
+    }).await;
+
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    let input = tokio::io::BufReader::new(&b"LIST\n"[..]).lines();
+    shutdown_tx.send(()).unwrap();
+    tokio::time::timeout(Duration::from_secs(10), alice.run_until(input, async { shutdown_rx.await.unwrap() }))
+        .await
+        .expect("the loop should exit once shutdown fires");
+
+    // bob keeps handling events while alice republishes and disconnects
+    let summary = tokio::time::timeout(Duration::from_secs(60), async {
+        let shutdown = alice.shutdown(true);
+        tokio::pin!(shutdown);
+        loop {
+            tokio::select! {
+                summary = &mut shutdown => break summary,
+                event = bob.swarm.select_next_some() => bob.handle_swarm_event(event),
+            }
+        }
+    }).await.expect("shutdown should finish");
+
+    assert_eq!(summary.published, vec![
+        form_avs_public_key(&alice_peer_id.to_string()),
+        form_hello_key(&alice_peer_id.to_string()),
+        form_peer_name_key(&alice_peer_id.to_string()),
+    ]);
+    assert_eq!(summary.chunks, 0);
+    assert_eq!(summary.republished, 3);
+    assert_eq!(alice.swarm.connected_peers().count(), 0);
+}
+
+// Alice drops bob's connection, which bob didn't close himself: bob redials alice's address and reconnects
+#[tokio::test]
+async fn dropped_connection_is_redialed() {
+    let mut alice = memory_node("alice");
+    let mut bob = memory_node("bob");
+    let alice_peer_id: PeerId = *alice.swarm.local_peer_id();
+    let bob_peer_id: PeerId = *bob.swarm.local_peer_id();
+
+    alice.swarm.listen_on("/memory/0".parse().unwrap()).unwrap();
+    bob.swarm.listen_on("/memory/0".parse().unwrap()).unwrap();
+    drive_pair_until(&mut alice, &mut bob, "both nodes listen", |alice, bob| {
+        alice.avs.peer_id.is_some() && bob.avs.peer_id.is_some()
+    }).await;
+    let alice_addr: Multiaddr = alice.swarm.listeners().next().unwrap().clone();
+    bob.handle_input_line(format!("DIAL {alice_addr}")).unwrap();
+    drive_pair_until(&mut alice, &mut bob, "bob connects to alice", |_, bob| bob.swarm.is_connected(&alice_peer_id)).await;
+
+    alice.swarm.disconnect_peer_id(bob_peer_id).unwrap();
+    drive_pair_until(&mut alice, &mut bob, "bob schedules a redial", |_, bob| bob.redial.attempts(&alice_peer_id).is_some()).await;
+    // alice closed the connection herself, and only has bob's ephemeral dialing address anyway
+    assert_eq!(alice.redial.attempts(&bob_peer_id), None);
+
+    bob.redial_due(Instant::now() + bob.redial.base_delay);
+    assert_eq!(bob.redial.attempts(&alice_peer_id), Some(1));
+    drive_pair_until(&mut alice, &mut bob, "bob reconnects to alice", |_, bob| bob.swarm.is_connected(&alice_peer_id)).await;
+    assert_eq!(bob.redial.attempts(&alice_peer_id), None);
+}
+
+// Two nodes on the in-process memory transport, driven through the same commands a user types:
+// SHARE_KEY -> MOVE -> GET POSITION, checking the receiving node decrypts the mover's position.
+#[tokio::test]
+async fn shared_key_lets_a_peer_decrypt_a_move() {
+    let mut alice = memory_node("alice");
+    let mut bob = memory_node("bob");
+    let alice_peer_id: PeerId = *alice.swarm.local_peer_id();
+    let bob_peer_id: PeerId = *bob.swarm.local_peer_id();
+
+    alice.swarm.listen_on("/memory/0".parse().unwrap()).unwrap();
+    bob.swarm.listen_on("/memory/0".parse().unwrap()).unwrap();
+    drive_pair_until(&mut alice, &mut bob, "both nodes listen", |alice, bob| {
+        alice.avs.peer_id.is_some() && bob.avs.peer_id.is_some()
+    }).await;
+    let alice_addr: Multiaddr = alice.swarm.listeners().next().unwrap().clone();
+    let bob_addr: Multiaddr = bob.swarm.listeners().next().unwrap().clone();
+    // what mDNS does on a LAN, so both routing tables hold the other node
+    alice.swarm.behaviour_mut().kademlia.add_address(&bob_peer_id, bob_addr);
+    bob.swarm.behaviour_mut().kademlia.add_address(&alice_peer_id, alice_addr.clone());
+    bob.swarm.dial(alice_addr).unwrap();
+
+    // discovery: each node learns the other's name from its PEER_NAME record
+    drive_pair_until(&mut alice, &mut bob, "the nodes learn each other's names", |alice, bob| {
+        alice.avs.peer_ids.get("bob") == Some(&bob_peer_id) && bob.avs.peer_ids.get("alice") == Some(&alice_peer_id)
+    }).await;
+
+    // names nobody has announced are PeerNotFound, not a generic failure
+    let result = alice.handle_input_line(r#"MOVE carol {"x":1,"y":2}"#.to_string());
+    assert!(matches!(result, Err(AppError::PeerNotFound(_))), "{result:?}");
+
+    // alice needs bob's ECDH public key to share her FHE key with him
+    alice.handle_input_line("GET AVS_PUBLIC_KEY bob".to_string()).unwrap();
+    let bob_avs_public_key = form_avs_public_key(&bob_peer_id.to_string());
+    drive_pair_until(&mut alice, &mut bob, "alice has bob's AVS_PUBLIC_KEY", |alice, _| {
+        alice.avs.peer_public_keys.contains_key(&bob_avs_public_key)
+    }).await;
+
+    alice.handle_input_line("SHARE_KEY bob".to_string()).unwrap();
+    bob.handle_input_line("GET ENCRYPTED_FHE_KEY alice".to_string()).unwrap();
+    drive_pair_until(&mut alice, &mut bob, "bob holds alice's shared FHE key", |_, bob| {
+        bob.user.peer_fhe_decryption_keys.contains_key(&alice_peer_id.to_string())
+    }).await;
+
+    alice.handle_input_line(r#"MOVE bob {"x":3,"y":-4,"z":5}"#.to_string()).unwrap();
+    bob.handle_input_line("GET POSITION alice".to_string()).unwrap();
+    drive_pair_until(&mut alice, &mut bob, "bob decrypts alice's position", |_, bob| decryptions(bob) == 1).await;
+
+    // bob decrypted it as a peer's position, with alice's shared key
+    let encrypted_position = bob.avs.peer_positions.get(&alice_peer_id.to_string()).unwrap().clone();
+    let position = bob.user.decrypt_peer_position(encrypted_position, &alice_peer_id.to_string()).unwrap();
+    assert_eq!((position.x, position.y, position.z), (3.0, -4.0, 5.0));
+    // bob never shared his key with alice
+    let result = alice.user.decrypt_peer_position(
+        bob.avs.peer_positions[&alice_peer_id.to_string()].clone(), &bob_peer_id.to_string()
+    );
+    assert!(matches!(result, Err(AppError::PeerNotFound(_))));
+
+    // alice reads her own position with her own key
+    alice.handle_input_line("GET POSITION alice".to_string()).unwrap();
+    assert_eq!(decryptions(&alice), 1);
+    assert!(alice.avs.peer_positions.is_empty());
+}
+
+// Bob runs a newer protocol: after exchanging HELLOs, alice refuses bob's position instead of failing to parse it
+#[tokio::test]
+async fn mismatched_protocol_versions_refuse_each_other() {
+    let mut alice = memory_node("alice");
+    let mut bob = memory_node("bob");
+    bob.hello.protocol_version = PROTOCOL_VERSION + 1;
+    let alice_peer_id: PeerId = *alice.swarm.local_peer_id();
+    let bob_peer_id: PeerId = *bob.swarm.local_peer_id();
+
+    alice.swarm.listen_on("/memory/0".parse().unwrap()).unwrap();
+    bob.swarm.listen_on("/memory/0".parse().unwrap()).unwrap();
+    drive_pair_until(&mut alice, &mut bob, "both nodes listen", |alice, bob| {
+        alice.avs.peer_id.is_some() && bob.avs.peer_id.is_some()
+    }).await;
+    let alice_addr: Multiaddr = alice.swarm.listeners().next().unwrap().clone();
+    bob.handle_input_line(format!("DIAL {alice_addr}")).unwrap();
+    drive_pair_until(&mut alice, &mut bob, "the nodes exchange HELLOs", |alice, bob| {
+        alice.peer_hellos.contains_key(&bob_peer_id) && bob.peer_hellos.contains_key(&alice_peer_id)
+    }).await;
+    assert!(alice.refuses(&bob_peer_id));
+    assert!(bob.refuses(&alice_peer_id));
+
+    bob.handle_input_line(r#"MOVE bob {"x":1,"y":2,"z":3}"#.to_string()).unwrap();
+    alice.handle_input_line(format!("GET POSITION {bob_peer_id}")).unwrap();
+    drive_pair_until(&mut alice, &mut bob, "alice refuses bob's position", |alice, _| {
+        counter(alice, "fhe_sunscreen_refused_total") == 1
+    }).await;
+    assert_eq!(decryptions(&alice), 0);
+    assert!(alice.avs.peer_positions.is_empty());
+}
+
+// Alice asks bob for his position and bob answers with it under another player's peer id: alice refuses it
+// instead of storing and decrypting it as that player's position
+#[tokio::test]
+async fn positions_served_for_another_peer_are_refused() {
+    let mut alice = memory_node("alice");
+    let mut bob = memory_node("bob");
+    let alice_peer_id: PeerId = *alice.swarm.local_peer_id();
+    let bob_peer_id: PeerId = *bob.swarm.local_peer_id();
+    let carol_peer_id = PeerId::random();
+
+    alice.swarm.listen_on("/memory/0".parse().unwrap()).unwrap();
+    bob.swarm.listen_on("/memory/0".parse().unwrap()).unwrap();
+    drive_pair_until(&mut alice, &mut bob, "both nodes listen", |alice, bob| {
+        alice.avs.peer_id.is_some() && bob.avs.peer_id.is_some()
+    }).await;
+    let alice_addr: Multiaddr = alice.swarm.listeners().next().unwrap().clone();
+    bob.handle_input_line(format!("DIAL {alice_addr}")).unwrap();
+    drive_pair_until(&mut alice, &mut bob, "bob learns alice's name", |_, bob| {
+        bob.avs.peer_ids.get("alice") == Some(&alice_peer_id)
+    }).await;
+
+    bob.handle_input_line(r#"MOVE bob {"x":1,"y":2,"z":3}"#.to_string()).unwrap();
+    let bob_position = bob.served_positions.respond(&PositionRequest::new(bob_peer_id)).position.unwrap();
+    alice.handle_input_line(format!("GET POSITION {bob_peer_id}")).unwrap();
+    tokio::time::timeout(Duration::from_secs(60), async {
+        while counter(&alice, "fhe_sunscreen_refused_total") == 0 {
+            tokio::select! {
+                event = alice.swarm.select_next_some() => alice.handle_swarm_event(event),
+                event = bob.swarm.select_next_some() => match event {
+                    SwarmEvent::Behaviour(BehaviourEvent::PositionExchange(request_response::Event::Message {
+                        message: request_response::Message::Request { channel, .. }, ..
+                    })) => {
+                        let spoofed = PositionResponse { subject: carol_peer_id.to_bytes(), position: Some(bob_position.clone()) };
+                        bob.swarm.behaviour_mut().position_exchange.send_response(channel, spoofed).unwrap();
+                    }
+                    event => bob.handle_swarm_event(event),
+                },
+            }
+        }
+    }).await.expect("alice should refuse the position bob served for carol");
+    assert_eq!(decryptions(&alice), 0);
+    assert!(alice.avs.peer_positions.is_empty());
+}
@@ -0,0 +1,94 @@
+use std::time::{Duration, Instant};
+use tfhe::{ConfigBuilder, generate_keys, FheUint32};
+use tfhe::prelude::*;
+
+use crate::fhe_distance::{fhe_distance_calc, set_server_key};
+
+
+// FHE operations `fhe-zama bench` can time, on FheUint32
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum BenchOp {
+    Add,
+    Mul,
+    DivRem,
+    Le,
+    // fhe_distance_calc, including its sqrt iterations
+    Distance,
+}
+
+#[derive(Debug)]
+pub struct BenchResult {
+    pub op: BenchOp,
+    pub min: Duration,
+    pub median: Duration,
+    pub max: Duration,
+}
+
+impl std::fmt::Display for BenchResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: min {:?}, median {:?}, max {:?}", self.op, self.min, self.median, self.max)
+    }
+}
+
+// Times each op `repetitions` times on fresh encryptions of the same inputs.
+// Keys are generated and the server key set once, up front, so only the operations themselves are timed.
+pub fn run_bench(ops: &[BenchOp], repetitions: usize, sqrt_iterations: usize) -> Vec<BenchResult> {
+    let config = ConfigBuilder::default().build();
+    let (client_key, server_key) = generate_keys(config);
+    set_server_key(server_key);
+
+    let (x1, y1) = (FheUint32::encrypt(3_u32, &client_key), FheUint32::encrypt(2_u32, &client_key));
+    let (x2, y2) = (FheUint32::encrypt(12_u32, &client_key), FheUint32::encrypt(10_u32, &client_key));
+
+    ops.iter().map(|&op| {
+        let timings = (0..repetitions.max(1)).map(|_| {
+            let start = Instant::now();
+            match op {
+                BenchOp::Add => { let _ = &x1 + &x2; }
+                BenchOp::Mul => { let _ = &x1 * &x2; }
+                BenchOp::DivRem => { let _ = (&x2).div_rem(&x1); }
+                BenchOp::Le => { let _ = x1.le(&x2); }
+                BenchOp::Distance => {
+                    let _ = fhe_distance_calc(&x1, &y1, &x2, &y2, sqrt_iterations)
+                        .expect("server key is set and inputs are FheUint32");
+                }
+            }
+            start.elapsed()
+        }).collect();
+        summarize(op, timings)
+    }).collect()
+}
+
+fn summarize(op: BenchOp, mut timings: Vec<Duration>) -> BenchResult {
+    timings.sort();
+    BenchResult {
+        op,
+        min: timings[0],
+        median: timings[timings.len() / 2],
+        max: timings[timings.len() - 1],
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarize_sorts_timings() {
+        let timings = [5, 1, 3].map(Duration::from_millis).to_vec();
+        let result = summarize(BenchOp::Add, timings);
+        assert_eq!(
+            (result.min, result.median, result.max),
+            (Duration::from_millis(1), Duration::from_millis(3), Duration::from_millis(5))
+        );
+    }
+
+    #[test]
+    fn bench_runs_a_tiny_iteration_count() {
+        let results = run_bench(&[BenchOp::Add, BenchOp::Le], 2, 0);
+        let ops: Vec<BenchOp> = results.iter().map(|result| result.op).collect();
+        assert_eq!(ops, vec![BenchOp::Add, BenchOp::Le]);
+        assert!(results.iter().all(|result| result.min <= result.median && result.median <= result.max));
+    }
+}
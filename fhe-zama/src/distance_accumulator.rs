@@ -0,0 +1,106 @@
+use std::time::{Duration, Instant};
+use tfhe::{FheBool, FheUint32};
+use tfhe::prelude::*;
+use crate::fhe_distance::fhe_abs_diff;
+
+// Anti-cheat speed cap: the most distance a player may cover within one window.
+// Distances are Manhattan (|dx| + |dy| tiles), which is exact on FheUint32 and needs no sqrt.
+pub const MAX_DISTANCE_PER_WINDOW: u32 = 20;
+pub const DISTANCE_WINDOW: Duration = Duration::from_secs(10);
+
+// Server-side running sum of the distance a player travelled in the current window.
+// Individual moves are never decrypted: only the violation flag is handed to the MPC network
+// to decrypt, the same way it decrypts `should_reveal_bob`.
+pub struct DistanceAccumulator {
+    window: Duration,
+    window_start: Instant,
+    last_position: (FheUint32, FheUint32),
+    total: FheUint32,
+}
+
+impl DistanceAccumulator {
+
+    pub fn new(x: FheUint32, y: FheUint32, window: Duration, now: Instant) -> Self {
+        DistanceAccumulator {
+            window,
+            window_start: now,
+            last_position: (x, y),
+            total: FheUint32::encrypt_trivial(0_u32),
+        }
+    }
+
+    // Adds the distance from the last position to (x, y), starting a new window first if the
+    // current one has elapsed. Returns the encrypted violation flag for the current window.
+    pub fn record_move(&mut self, x: FheUint32, y: FheUint32, now: Instant) -> FheBool {
+        if now.duration_since(self.window_start) >= self.window {
+            self.window_start = now;
+            self.total = FheUint32::encrypt_trivial(0_u32);
+        }
+
+        let (last_x, last_y) = &self.last_position;
+        self.total = &self.total + fhe_capped_manhattan_distance(last_x, last_y, &x, &y);
+        self.last_position = (x, y);
+
+        self.violation()
+    }
+
+    // Encrypted flag: has the player exceeded MAX_DISTANCE_PER_WINDOW in the current window?
+    pub fn violation(&self) -> FheBool {
+        self.total.gt(MAX_DISTANCE_PER_WINDOW)
+    }
+}
+
+// |dx| + |dy|, with each axis capped just above the window limit. A single capped axis is already a
+// violation, and capping stops a huge (wrapped) move from overflowing the running sum back under the cap.
+fn fhe_capped_manhattan_distance(
+    x1: &FheUint32, y1: &FheUint32,
+    x2: &FheUint32, y2: &FheUint32,
+) -> FheUint32 {
+    let cap = MAX_DISTANCE_PER_WINDOW + 1;
+    let dx = fhe_abs_diff(x1, x2).min(cap);
+    let dy = fhe_abs_diff(y1, y2).min(cap);
+    dx + dy
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tfhe::{ConfigBuilder, generate_keys};
+    use crate::fhe_distance::set_server_key;
+
+    #[test]
+    fn flags_bursts_over_the_cap_and_resets_each_window() {
+        let config = ConfigBuilder::default().build();
+        let (client_key, server_key) = generate_keys(config);
+        set_server_key(server_key);
+
+        let encrypt = |x: u32, y: u32| (FheUint32::encrypt(x, &client_key), FheUint32::encrypt(y, &client_key));
+        let start = Instant::now();
+        let (x, y) = encrypt(50, 50);
+        let mut accumulator = DistanceAccumulator::new(x, y, DISTANCE_WINDOW, start);
+
+        // legal movement: 3 + 4 + 5 + 6 = 18 tiles within one window, in both directions
+        let legal_moves = [(53, 50), (53, 46), (50, 48), (44, 48)];
+        for (i, (x, y)) in legal_moves.into_iter().enumerate() {
+            let (x, y) = encrypt(x, y);
+            let violation = accumulator.record_move(x, y, start + Duration::from_secs(i as u64));
+            assert!(!violation.decrypt(&client_key));
+        }
+
+        // a burst of 4 more tiles in the same window takes the total to 22
+        let (x, y) = encrypt(48, 48);
+        let violation = accumulator.record_move(x, y, start + Duration::from_secs(5));
+        assert!(violation.decrypt(&client_key));
+
+        // the next window starts from zero
+        let (x, y) = encrypt(50, 50);
+        let violation = accumulator.record_move(x, y, start + DISTANCE_WINDOW + Duration::from_secs(1));
+        assert!(!violation.decrypt(&client_key));
+
+        // a single huge jump is flagged, and can't wrap the sum back under the cap
+        let (x, y) = encrypt(u32::MAX, 50);
+        let violation = accumulator.record_move(x, y, start + DISTANCE_WINDOW + Duration::from_secs(2));
+        assert!(violation.decrypt(&client_key));
+    }
+}
@@ -1,11 +1,164 @@
+use std::cell::RefCell;
 use std::ops::Mul;
+use anyhow::{anyhow, Result};
 use serde::{Serialize, Deserialize};
-use tfhe::{ConfigBuilder, generate_keys, set_server_key, FheUint32};
+use tfhe::{FheBool, FheInt64, FheUint8, FheUint32, FheUint64};
 use tfhe::prelude::*;
+use rayon::prelude::*;
 
 
+// Defaults for --precision, --view-range and --sqrt-iterations
 pub const PRECISION: u32 = 100;
 pub const FOW_VIEW_RANGE: u32 = 11;
+pub const SQRT_ITERATIONS: usize = 1;
+// Newton's method starting point for fhe_distance_calc: a distance of ~10
+pub const INITIAL_SQRT_GUESS: u32 = 10;
+
+thread_local! {
+    // tfhe keeps the server key in a thread local it doesn't let us read, so it's mirrored here.
+    // tfhe::ServerKey is an Arc, so the copy is cheap.
+    static SERVER_KEY: RefCell<Option<tfhe::ServerKey>> = const { RefCell::new(None) };
+}
+
+// Use this instead of tfhe::set_server_key, so fhe_distance_calc can report a missing server key
+// as a DistanceError instead of tfhe panicking halfway through. Like tfhe's, it's per thread.
+pub fn set_server_key(server_key: tfhe::ServerKey) {
+    tfhe::set_server_key(server_key.clone());
+    SERVER_KEY.with(|key| *key.borrow_mut() = Some(server_key));
+}
+
+fn current_server_key() -> Option<tfhe::ServerKey> {
+    SERVER_KEY.with(|key| key.borrow().clone())
+}
+
+// rayon may run `f` on another worker thread, which needs this thread's server key set first
+fn with_server_key<T>(server_key: &tfhe::ServerKey, f: impl FnOnce() -> T) -> T {
+    set_server_key(server_key.clone());
+    f()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DistanceError {
+    // set_server_key wasn't called on this thread
+    ServerKeyNotSet,
+    // fhe_distance_calc_auto's points were encrypted at different widths
+    InputWidthMismatch { expected_bits: usize, found_bits: usize },
+}
+
+impl std::fmt::Display for DistanceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DistanceError::ServerKeyNotSet => write!(f, "no FHE server key set on this thread, call set_server_key first"),
+            DistanceError::InputWidthMismatch { expected_bits, found_bits } => {
+                write!(f, "expected a {expected_bits}-bit encrypted input, got {found_bits} bits")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DistanceError {}
+
+// Checks fhe_distance_calc's server key up front, before any FHE operation can panic without it.
+// The input widths need no check, FheUint32 and FheUint64 already fix them.
+fn check_server_key() -> Result<(), DistanceError> {
+    match SERVER_KEY.with(|key| key.borrow().is_some()) {
+        true => Ok(()),
+        false => Err(DistanceError::ServerKeyNotSet),
+    }
+}
+
+// Resolution of the view range threshold: reveal decisions match comparing a distance kept to 1 / precision,
+// e.g. 100 for 2 decimals. Only the threshold is scaled (see ScaledThreshold::scaled_squared), never the
+// encrypted distance, so larger precisions cost nothing. Capped at 10_000.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Precision(u32);
+
+impl Precision {
+    pub const MAX: u32 = 10_000;
+
+    pub fn new(precision: u32) -> Result<Precision> {
+        let mut p = precision;
+        while p > 1 && p.is_multiple_of(10) {
+            p /= 10;
+        }
+        if p != 1 || precision > Self::MAX {
+            return Err(anyhow!("precision {precision} must be a power of ten between 1 and {}", Self::MAX));
+        }
+        Ok(Precision(precision))
+    }
+
+    pub fn get(&self) -> u32 {
+        self.0
+    }
+}
+
+impl Default for Precision {
+    fn default() -> Self {
+        Precision(PRECISION)
+    }
+}
+
+// A view range, converted into thresholds for the unscaled dx² + dy² from fhe_distance_sq:
+// - squared() is range², the exact straight-line check.
+// - scaled_squared() is the range * precision check on a distance kept to 1 / precision, moved onto dx² + dy²
+//   so the encrypted distance is never multiplied by precision².
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScaledThreshold {
+    range: u32,
+    precision: Precision,
+    metric: DistanceMetric,
+}
+
+// How fhe_within_view measures distance for the reveal decision
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum DistanceMetric {
+    // straight-line distance, compared as dx² + dy² <= range²
+    #[default]
+    Euclidean,
+    // |dx| + |dy| <= range: grid distance, no multiplications at all
+    Manhattan,
+}
+
+impl ScaledThreshold {
+    // fhe_visibility_level scales range² by 255 in a u32
+    pub const MAX_FADE_RANGE: u32 = 4104;
+
+    pub fn new(range: u32, precision: Precision) -> Result<ScaledThreshold> {
+        if range == 0 || range > Self::MAX_FADE_RANGE {
+            return Err(anyhow!("view range {range} must be between 1 and {}", Self::MAX_FADE_RANGE));
+        }
+        Ok(ScaledThreshold { range, precision, metric: DistanceMetric::default() })
+    }
+
+    pub fn with_metric(self, metric: DistanceMetric) -> ScaledThreshold {
+        ScaledThreshold { metric, ..self }
+    }
+
+    pub fn metric(&self) -> DistanceMetric {
+        self.metric
+    }
+
+    pub fn range(&self) -> u32 {
+        self.range
+    }
+
+    pub fn precision(&self) -> Precision {
+        self.precision
+    }
+
+    // Threshold for squared distances from fhe_distance_sq
+    pub fn squared(&self) -> u32 {
+        self.range.pow(2)
+    }
+
+    // Threshold on dx² + dy² giving the same decision as an exact integer sqrt of the precision²-scaled
+    // distance compared with range * precision: isqrt(d * p²) <= r * p  <=>  d * p² < (r * p + 1)²  <=>  d <= r² + 2r / p.
+    // The 2r / p is the rounding slack of a distance kept to 1 / p: none at the default precision for
+    // ranges under 50, but a whole-unit distance (precision 1) counts anything under range + 1 as within.
+    pub fn scaled_squared(&self) -> u32 {
+        self.squared() + 2 * self.range / self.precision.get()
+    }
+}
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Position {
@@ -13,10 +166,22 @@ pub struct Position {
     pub y: u32
 }
 
+// A publicly committed region (e.g. the playable area), inclusive on all sides
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+pub struct Bounds {
+    pub min_x: u32,
+    pub min_y: u32,
+    pub max_x: u32,
+    pub max_y: u32,
+}
+
 pub fn fhe_distance_example(
     alice_key: tfhe::ClientKey,
-    server_key_alice: tfhe::ServerKey
-) {
+    server_key_alice: tfhe::ServerKey,
+    view_range: &ScaledThreshold,
+    sqrt_iterations: usize,
+    max_coord: Option<u32>,
+) -> Result<DistanceReport, DistanceError> {
 
     // Client-side
     println!("\nAlice:");
@@ -37,13 +202,23 @@ pub fn fhe_distance_example(
     // new position:
     let x2 = FheUint32::encrypt(p1.x + m.x, &alice_key);
     let y2 = FheUint32::encrypt(p1.y + m.y, &alice_key);
+    // the distance is computed wide enough for the declared map size, or at least these coordinates
+    let p2 = Position { x: p1.x + m.x, y: p1.y + m.y };
+    let max_coord = max_coord.unwrap_or(0).max(p2.x).max(p2.y);
+    let width = required_width(max_coord);
+    let point1 = EncryptedPoint::encrypt(&p1, width, &alice_key);
+    let point2 = EncryptedPoint::encrypt(&p2, width, &alice_key);
 
     // Server-side
     set_server_key(server_key_alice);
     println!("\nServer:");
     println!("\tPerforming FHE operations to calculate distance to new position");
-    let (g, rem) = fhe_distance_calc(&x1, &y1, &x2, &y2);
-    let reveal_position = g.le(FOW_VIEW_RANGE * PRECISION.pow(2));
+    println!("\tsqrt iterations: {sqrt_iterations}");
+    println!("\tdistance width for coordinates up to {max_coord}: {width}");
+    let distance = fhe_distance_calc_auto(&point1, &point2, sqrt_iterations, max_coord)?;
+    let reveal_position = fhe_within_view(&x1, &y1, &x2, &y2, view_range);
+    let within_distance = fhe_distance_within(&fhe_distance_sq(&x1, &y1, &x2, &y2), view_range);
+    let visibility_level = fhe_visibility_level(&x1, &y1, &x2, &y2, view_range);
 
     //Client-side
     println!("\nAlice:");
@@ -52,16 +227,79 @@ pub fn fhe_distance_example(
         y: y2.decrypt(&alice_key),
     };
     println!("\tDecypted new position: {new_position:?}");
-    println!("\tReveal position?: {}", reveal_position.decrypt(&alice_key));
-    let distance_decrypted: u32 = g.decrypt(&alice_key);
-    let rem: u32 = rem.decrypt(&alice_key);
-    let distance_final = (distance_decrypted + rem/2) as f32 / PRECISION as f32;
+    println!("\tView range: {} ({:?}) at precision {}", view_range.range(), view_range.metric(), view_range.precision().get());
+    let reveal: bool = reveal_position.decrypt(&alice_key);
+    println!("\tReveal position?: {reveal}");
+    let within_distance: bool = within_distance.decrypt(&alice_key);
+    println!("\tWithin view range (from distance)?: {within_distance}");
+    let visibility_level: u8 = visibility_level.decrypt(&alice_key);
+    println!("\tVisibility level: {visibility_level}/255");
+    // whole units: the sqrt runs on the unscaled dx² + dy²
+    let (distance_decrypted, _rem) = distance.decrypt(&alice_key);
+    let distance_final = distance_decrypted as f32;
     println!("\tDistance: {:?}", distance_final);
 
     assert_eq!(new_position.x, m.x + p1.x);
     assert_eq!(new_position.y, m.y + p1.y);
 
     println!("\tAssert distance: f32::sqrt(9.powf(2) + 8.powf(2)) = {}", check_distance(9.0, 8.0));
+    Ok(DistanceReport { reveal, distance: distance_final })
+}
+
+// What Alice decrypts from fhe_distance_example
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DistanceReport {
+    pub reveal: bool,
+    pub distance: f32,
+}
+
+// `basic --alice-pos x,y --bob-pos x,y`: the reveal decision and distance between any two signed
+// positions. The server computes dx² + dy² exactly, Alice takes its sqrt after decrypting it, so unlike
+// fhe_distance_example there's no Newton approximation to drift for far apart points.
+pub fn fhe_signed_distance_example(
+    alice_key: tfhe::ClientKey,
+    server_key: tfhe::ServerKey,
+    alice: SignedPosition,
+    bob: SignedPosition,
+    view_range: &ScaledThreshold,
+) -> SignedDistanceReport {
+
+    // Client-side
+    println!("\nAlice:");
+    println!("\tEncrypting Alice's position {alice} and Bob's position {bob}");
+    let encrypt = |p: &SignedPosition| (FheInt64::encrypt(p.x, &alice_key), FheInt64::encrypt(p.y, &alice_key));
+    let (x1, y1) = encrypt(&alice);
+    let (x2, y2) = encrypt(&bob);
+
+    // Server-side
+    set_server_key(server_key);
+    println!("\nServer:");
+    println!("\tPerforming FHE operations on FheInt64 coordinates");
+    let distance_sq = fhe_distance_sq_signed(&x1, &y1, &x2, &y2);
+    let reveal = fhe_within_view_signed(&x1, &y1, &x2, &y2, view_range);
+
+    // Client-side
+    let report = SignedDistanceReport {
+        reveal: reveal.decrypt(&alice_key),
+        distance_sq: distance_sq.decrypt(&alice_key),
+    };
+    println!("\nAlice:");
+    println!("\tView range: {} ({:?})", view_range.range(), view_range.metric());
+    println!("\tReveal Bob's position?: {}", report.reveal);
+    println!("\tDistance: ~{:.2}", report.distance());
+    report
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignedDistanceReport {
+    pub reveal: bool,
+    pub distance_sq: u64,
+}
+
+impl SignedDistanceReport {
+    pub fn distance(&self) -> f64 {
+        (self.distance_sq as f64).sqrt()
+    }
 }
 
 fn check_distance(dx: f32, dy: f32) -> f32 {
@@ -69,34 +307,176 @@ fn check_distance(dx: f32, dy: f32) -> f32 {
     f32::sqrt(distance_sq)
 }
 
-// Server-side calculation of distance
+// Server-side calculation of distance: g ≈ sqrt(dx² + dy²), in whole units.
+// The sqrt runs on the unscaled dx² + dy², so the largest intermediate only overflows ~46_000 apart.
+// Scaling it by precision² first would give the distance decimals, but overflow ~655 apart at the default
+// precision. Decisions don't need the decimals: fhe_distance_within puts the precision into the threshold.
 pub fn fhe_distance_calc(
     x1: &FheUint32, y1: &FheUint32,
     x2: &FheUint32, y2: &FheUint32,
-) -> (FheUint32, FheUint32) {
+    sqrt_iterations: usize,
+) -> Result<(FheUint32, FheUint32), DistanceError> {
+    check_server_key()?;
+    let distance_sq = fhe_distance_sq(x1, y1, x2, y2);
+    Ok(sqrt_newtowns_approx(&distance_sq, INITIAL_SQRT_GUESS, sqrt_iterations))
+}
 
-    let dx = x2 - x1;
-    let dy = y2 - y1;
+// Server-side squared distance dx² + dy² (no sqrt).
+// dx and dy are absolute differences, so peers in the negative direction (x2 < x1) don't wrap.
+// The two axes are independent, so they're computed in parallel: on two cores the multiplications,
+// the bulk of the cost, take about as long as one. That needs the key from this crate's set_server_key
+// to hand to rayon's workers: after a bare tfhe::set_server_key the axes are computed one after the other.
+pub fn fhe_distance_sq(
+    x1: &FheUint32, y1: &FheUint32,
+    x2: &FheUint32, y2: &FheUint32,
+) -> FheUint32 {
+    let axis_sq = |a: &FheUint32, b: &FheUint32| {
+        let d = fhe_abs_diff(a, b);
+        d.clone().mul(d)
+    };
 
-    let dx_sq = dx.clone().mul(dx);
-    let dy_sq = dy.clone().mul(dy);
+    let (dx_sq, dy_sq) = match current_server_key() {
+        Some(server_key) => rayon::join(
+            || with_server_key(&server_key, || axis_sq(x1, x2)),
+            || with_server_key(&server_key, || axis_sq(y1, y2)),
+        ),
+        None => (axis_sq(x1, x2), axis_sq(y1, y2)),
+    };
+
+    dx_sq + dy_sq
+}
+
+// Range check without any sqrt: dx² + dy² <= range².
+// Far cheaper than computing the distance with fhe_distance_calc and then comparing it.
+pub fn fhe_within_range(
+    x1: &FheUint32, y1: &FheUint32,
+    x2: &FheUint32, y2: &FheUint32,
+    range: &ScaledThreshold,
+) -> FheBool {
+    fhe_distance_sq(x1, y1, x2, y2).le(range.squared())
+}
 
-    // multiply by 10_000 (then divide by sqrt(10k) = 100) to calculate sqrt on integers with 2-decimal precision
-    let distance_sq = (dx_sq + dy_sq) * PRECISION.pow(2);
+// |dx| + |dy|, exact on FheUint32: no multiplication and no sqrt approximation
+pub fn fhe_manhattan_distance(
+    x1: &FheUint32, y1: &FheUint32,
+    x2: &FheUint32, y2: &FheUint32,
+) -> FheUint32 {
+    fhe_abs_diff(x1, x2) + fhe_abs_diff(y1, y2)
+}
+
+// |a - b| by comparing first, so the subtraction never wraps
+pub fn fhe_abs_diff(a: &FheUint32, b: &FheUint32) -> FheUint32 {
+    a.gt(b).if_then_else(&(a - b), &(b - a))
+}
+
+// The reveal decision: is (x2, y2) within range of (x1, y1), measured with range.metric()?
+pub fn fhe_within_view(
+    x1: &FheUint32, y1: &FheUint32,
+    x2: &FheUint32, y2: &FheUint32,
+    range: &ScaledThreshold,
+) -> FheBool {
+    match range.metric() {
+        DistanceMetric::Euclidean => fhe_within_range(x1, y1, x2, y2, range),
+        DistanceMetric::Manhattan => fhe_manhattan_distance(x1, y1, x2, y2).le(range.range()),
+    }
+}
+
+// Squared distances dx² + dy² from Alice to each peer, in peer order.
+// The peers are independent, so they're computed in parallel with rayon. tfhe's server key is per thread,
+// so each rayon job sets its own copy of `server_key` before its first FHE operation.
+pub fn fhe_distances_batch(
+    server_key: &tfhe::ServerKey,
+    alice: (&FheUint32, &FheUint32),
+    peers: &[(FheUint32, FheUint32)],
+) -> Vec<FheUint32> {
+    let (x1, y1) = alice;
+    par_map_peers(server_key, peers, |(x2, y2)| fhe_distance_sq(x1, y1, x2, y2))
+}
 
-    let initial_sqrt_guess = 1000_u32;
-    fhe_sqrt_newtons_approx(&distance_sq, initial_sqrt_guess)
+// fhe_within_view for each peer: the encrypted reveal decision, in peer order
+pub fn fhe_within_view_batch(
+    server_key: &tfhe::ServerKey,
+    alice: (&FheUint32, &FheUint32),
+    peers: &[(FheUint32, FheUint32)],
+    range: &ScaledThreshold,
+) -> Vec<FheBool> {
+    let (x1, y1) = alice;
+    par_map_peers(server_key, peers, |(x2, y2)| fhe_within_view(x1, y1, x2, y2, range))
 }
 
-pub fn fhe_sqrt_newtons_approx(n: &FheUint32, initial_guess: u32) -> (FheUint32, FheUint32) {
+fn par_map_peers<T: Send>(
+    server_key: &tfhe::ServerKey,
+    peers: &[(FheUint32, FheUint32)],
+    f: impl Fn(&(FheUint32, FheUint32)) -> T + Sync,
+) -> Vec<T> {
+    peers.par_iter()
+        .map_init(|| set_server_key(server_key.clone()), |_, peer| f(peer))
+        .collect()
+}
+
+// Range check at range.precision() on dx² + dy² from fhe_distance_sq: the same decision as comparing the
+// distance * precision with range * precision, without inflating the encrypted distance by precision².
+// See ScaledThreshold::scaled_squared, and fhe_within_range for the check without rounding slack.
+pub fn fhe_distance_within(distance_sq: &FheUint32, range: &ScaledThreshold) -> FheBool {
+    distance_sq.le(range.scaled_squared())
+}
 
-    let (g, _rem) = sqrt_newtowns_approx_initial_step(
-        &n,
-        initial_guess
-    );
-    // run ~2 iterations for the square root approximation
-    // number of iterations depends on how close your initial_sqrt_guess is
-    let (g, rem) = sqrt_newtowns_approx_iteration(&n, &g);
+// Friendly fire off: teammates are always visible, opponents only within range.
+// Public team ids can be passed in with FheUint8::encrypt_trivial.
+pub fn fhe_team_visible(
+    me: (&FheUint32, &FheUint32),
+    my_team: &FheUint8,
+    peer: (&FheUint32, &FheUint32),
+    peer_team: &FheUint8,
+    range: &ScaledThreshold,
+) -> FheBool {
+    let same_team = my_team.eq(peer_team);
+    let within_range = fhe_within_range(me.0, me.1, peer.0, peer.1, range);
+    same_team | within_range
+}
+
+// Encrypted flag: is the position inside the committed bounds? Only this boolean is ever decrypted,
+// so a player can prove they're in the playable area without revealing where.
+pub fn fhe_within_bounds(x: &FheUint32, y: &FheUint32, bounds: &Bounds) -> FheBool {
+    x.ge(bounds.min_x) & x.le(bounds.max_x) & y.ge(bounds.min_y) & y.le(bounds.max_y)
+}
+
+// Encrypted fog density: a visibility level from 255 (same tile) fading linearly in the squared
+// distance down to 0 at the edge of the range, and 0 beyond it. The client decrypts it to render a fade.
+// Works on dx² + dy² so no sqrt is needed. ScaledThreshold::MAX_FADE_RANGE keeps (range² * 255) within a u32.
+pub fn fhe_visibility_level(
+    x1: &FheUint32, y1: &FheUint32,
+    x2: &FheUint32, y2: &FheUint32,
+    range: &ScaledThreshold,
+) -> FheUint8 {
+    let range_sq = range.squared();
+    let distance_sq = fhe_distance_sq(x1, y1, x2, y2).min(range_sq);
+    let level: FheUint32 = (range_sq - distance_sq) * u8::MAX as u32 / range_sq;
+    level.cast_into()
+}
+
+// Encrypted flag: is peer `a` strictly closer to `me` than peer `b`?
+// Compares squared distances, so neither distance is revealed and no sqrt is needed.
+pub fn fhe_nearer(
+    me: (&FheUint32, &FheUint32),
+    a: (&FheUint32, &FheUint32),
+    b: (&FheUint32, &FheUint32),
+) -> FheBool {
+    let distance_sq_a = fhe_distance_sq(me.0, me.1, a.0, a.1);
+    let distance_sq_b = fhe_distance_sq(me.0, me.1, b.0, b.1);
+    distance_sq_a.lt(&distance_sq_b)
+}
+
+// Integer sqrt of n by Newton's method: an initial step from `initial_guess`, then `iterations` more.
+// FHE can't branch on an encrypted value, so there is no early exit on convergence: the iteration
+// count is fixed at call time and every iteration costs a ciphertext division, converged or not.
+// Each iteration roughly doubles the correct digits once the guess is within ~2x of sqrt(n),
+// so pick the count from how far the initial guess can be from the answer.
+pub fn sqrt_newtowns_approx(n: &FheUint32, initial_guess: u32, iterations: usize) -> (FheUint32, FheUint32) {
+    let (mut g, mut rem) = sqrt_newtowns_approx_initial_step(n, initial_guess);
+    for _ in 0..iterations {
+        (g, rem) = sqrt_newtowns_approx_iteration(n, &g);
+    }
     (g, rem)
 }
 
@@ -107,4 +487,752 @@ fn sqrt_newtowns_approx_initial_step(n: &FheUint32, g: u32) -> (FheUint32, FheUi
 
 fn sqrt_newtowns_approx_iteration(n: &FheUint32, g: &FheUint32) -> (FheUint32, FheUint32) {
     (g.clone() + (n/g)).div_rem(2)
-}
\ No newline at end of file
+}
+
+// 64-bit versions of the distance functions, for maps too large for FheUint32.
+// The u32 path overflows silently: dx² + dy² wraps past ~46_000 apart.
+// In 64 bits coordinates up to 2^31 keep dx² + dy² exact.
+
+// Newton iterations for fhe_sqrt_newtons_approx_u64 after the initial step, enough from a guess within ~1%
+pub const SQRT_ITERATIONS_U64: usize = 2;
+
+pub fn fhe_distance_sq_u64(
+    x1: &FheUint64, y1: &FheUint64,
+    x2: &FheUint64, y2: &FheUint64,
+) -> FheUint64 {
+
+    let dx = fhe_abs_diff_u64(x1, x2);
+    let dy = fhe_abs_diff_u64(y1, y2);
+
+    let dx_sq = dx.clone().mul(dx);
+    let dy_sq = dy.clone().mul(dy);
+
+    dx_sq + dy_sq
+}
+
+pub fn fhe_abs_diff_u64(a: &FheUint64, b: &FheUint64) -> FheUint64 {
+    a.gt(b).if_then_else(&(a - b), &(b - a))
+}
+
+// Like fhe_distance_calc, g ≈ distance in whole units. Each of the `sqrt_iterations` costs a ciphertext division,
+// so the closer `initial_sqrt_guess` is to the expected distance (e.g. the last one computed), the fewer are needed.
+pub fn fhe_distance_calc_u64(
+    x1: &FheUint64, y1: &FheUint64,
+    x2: &FheUint64, y2: &FheUint64,
+    initial_sqrt_guess: u64,
+    sqrt_iterations: usize,
+) -> Result<(FheUint64, FheUint64), DistanceError> {
+    check_server_key()?;
+    let distance_sq = fhe_distance_sq_u64(x1, y1, x2, y2);
+    Ok(fhe_sqrt_newtons_approx_u64(&distance_sq, initial_sqrt_guess, sqrt_iterations))
+}
+
+pub fn fhe_within_range_u64(
+    x1: &FheUint64, y1: &FheUint64,
+    x2: &FheUint64, y2: &FheUint64,
+    range: &ScaledThreshold,
+) -> FheBool {
+    fhe_distance_sq_u64(x1, y1, x2, y2).le(range.squared() as u64)
+}
+
+pub fn fhe_distance_within_u64(distance_sq: &FheUint64, range: &ScaledThreshold) -> FheBool {
+    distance_sq.le(range.scaled_squared() as u64)
+}
+
+// sqrt_newtowns_approx on FheUint64, with the same fixed `iterations` after the initial step
+pub fn fhe_sqrt_newtons_approx_u64(n: &FheUint64, initial_guess: u64, iterations: usize) -> (FheUint64, FheUint64) {
+    let (mut g, mut rem) = (initial_guess + (n / initial_guess)).div_rem(2);
+    for _ in 0..iterations {
+        (g, rem) = (g.clone() + (n / &g)).div_rem(2);
+    }
+    (g, rem)
+}
+
+// Signed positions for `basic --alice-pos/--bob-pos`: any coordinates within ±MAX_SIGNED_COORD, negatives
+// included, encrypted as FheInt64. Coordinates that far apart still differ by less than 2^31, so
+// |dx|² + |dy|² < 2^63 is exact in an FheUint64, with no precision² scaling to overflow it.
+pub const MAX_SIGNED_COORD: i64 = 1 << 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignedPosition {
+    pub x: i64,
+    pub y: i64,
+}
+
+// "x,y", e.g. "-3,4"
+impl std::str::FromStr for SignedPosition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (x, y) = s.split_once(',').ok_or_else(|| format!("expected x,y, got {s}"))?;
+        let coordinate = |c: &str| -> Result<i64, String> {
+            let c = c.trim().parse::<i64>().map_err(|e| format!("invalid coordinate {c}: {e}"))?;
+            match c.abs() <= MAX_SIGNED_COORD {
+                true => Ok(c),
+                false => Err(format!("coordinate {c} is outside ±{MAX_SIGNED_COORD}")),
+            }
+        };
+        Ok(SignedPosition { x: coordinate(x)?, y: coordinate(y)? })
+    }
+}
+
+impl std::fmt::Display for SignedPosition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "({}, {})", self.x, self.y)
+    }
+}
+
+// |a - b| for FheInt64 coordinates within ±MAX_SIGNED_COORD, so the subtraction can't overflow
+pub fn fhe_abs_diff_signed(a: &FheInt64, b: &FheInt64) -> FheUint64 {
+    FheUint64::cast_from((a - b).abs())
+}
+
+// Exact dx² + dy² between two signed positions, see MAX_SIGNED_COORD
+pub fn fhe_distance_sq_signed(
+    x1: &FheInt64, y1: &FheInt64,
+    x2: &FheInt64, y2: &FheInt64,
+) -> FheUint64 {
+    let dx = fhe_abs_diff_signed(x1, x2);
+    let dy = fhe_abs_diff_signed(y1, y2);
+    dx.clone().mul(dx) + dy.clone().mul(dy)
+}
+
+// fhe_within_view for signed positions
+pub fn fhe_within_view_signed(
+    x1: &FheInt64, y1: &FheInt64,
+    x2: &FheInt64, y2: &FheInt64,
+    range: &ScaledThreshold,
+) -> FheBool {
+    match range.metric() {
+        DistanceMetric::Euclidean => fhe_distance_sq_signed(x1, y1, x2, y2).le(range.squared() as u64),
+        DistanceMetric::Manhattan => {
+            (fhe_abs_diff_signed(x1, x2) + fhe_abs_diff_signed(y1, y2)).le(range.range() as u64)
+        }
+    }
+}
+
+// Integer width fhe_distance_calc_auto computes distances in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FheWidth {
+    U32,
+    U64,
+}
+
+impl std::fmt::Display for FheWidth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FheWidth::U32 => write!(f, "FheUint32"),
+            FheWidth::U64 => write!(f, "FheUint64"),
+        }
+    }
+}
+
+// The narrowest width whose fhe_distance_calc can't overflow for coordinates up to `max_coord`:
+// its largest intermediate is dx² + dy², at most (2 * max_coord)².
+// FheUint32 is several times faster, so it's used whenever that fits.
+pub fn required_width(max_coord: u32) -> FheWidth {
+    let largest = (2 * max_coord as u64).pow(2);
+    match largest <= u32::MAX as u64 {
+        true => FheWidth::U32,
+        false => FheWidth::U64,
+    }
+}
+
+// A position encrypted at the width required_width picked
+pub enum EncryptedPoint {
+    U32(FheUint32, FheUint32),
+    U64(FheUint64, FheUint64),
+}
+
+impl EncryptedPoint {
+    pub fn encrypt(p: &Position, width: FheWidth, key: &tfhe::ClientKey) -> EncryptedPoint {
+        match width {
+            FheWidth::U32 => EncryptedPoint::U32(FheUint32::encrypt(p.x, key), FheUint32::encrypt(p.y, key)),
+            FheWidth::U64 => EncryptedPoint::U64(FheUint64::encrypt(p.x as u64, key), FheUint64::encrypt(p.y as u64, key)),
+        }
+    }
+
+    pub fn width(&self) -> FheWidth {
+        match self {
+            EncryptedPoint::U32(..) => FheWidth::U32,
+            EncryptedPoint::U64(..) => FheWidth::U64,
+        }
+    }
+}
+
+// (g, rem) from fhe_distance_calc or fhe_distance_calc_u64, g ≈ distance in whole units
+pub enum EncryptedDistance {
+    U32(FheUint32, FheUint32),
+    U64(FheUint64, FheUint64),
+}
+
+impl EncryptedDistance {
+    pub fn decrypt(&self, key: &tfhe::ClientKey) -> (u64, u64) {
+        match self {
+            EncryptedDistance::U32(g, rem) => {
+                let (g, rem): (u32, u32) = (g.decrypt(key), rem.decrypt(key));
+                (g as u64, rem as u64)
+            }
+            EncryptedDistance::U64(g, rem) => (g.decrypt(key), rem.decrypt(key)),
+        }
+    }
+}
+
+// Newton iterations fhe_distance_calc_auto's FheUint64 branch needs after its initial step. From a guess of
+// max_coord, each step only about halves a guess far above the distance, so points one unit apart
+// take ~log2(max_coord) steps to reach, and two more to converge within 1.
+pub fn min_sqrt_iterations_u64(max_coord: u32) -> usize {
+    (u32::BITS - max_coord.leading_zeros()) as usize + 2
+}
+
+// fhe_distance_calc at the points' width: FheUint32 points go through fhe_distance_calc with `sqrt_iterations`,
+// FheUint64 ones through fhe_distance_calc_u64 with at least min_sqrt_iterations_u64(max_coord).
+// Both points must be the same width, pick it once with required_width from the same `max_coord`.
+pub fn fhe_distance_calc_auto(
+    p1: &EncryptedPoint,
+    p2: &EncryptedPoint,
+    sqrt_iterations: usize,
+    max_coord: u32,
+) -> Result<EncryptedDistance, DistanceError> {
+    match (p1, p2) {
+        (EncryptedPoint::U32(x1, y1), EncryptedPoint::U32(x2, y2)) => {
+            let (g, rem) = fhe_distance_calc(x1, y1, x2, y2, sqrt_iterations)?;
+            Ok(EncryptedDistance::U32(g, rem))
+        }
+        (EncryptedPoint::U64(x1, y1), EncryptedPoint::U64(x2, y2)) => {
+            // FheUint64 is only picked for large maps, where the fixed ~10 unit guess is orders of magnitude off:
+            // start from the middle of the possible distances, 0 to 2 * max_coord
+            let initial_guess = (max_coord as u64).max(1);
+            let sqrt_iterations = sqrt_iterations.max(min_sqrt_iterations_u64(max_coord));
+            let (g, rem) = fhe_distance_calc_u64(x1, y1, x2, y2, initial_guess, sqrt_iterations)?;
+            Ok(EncryptedDistance::U64(g, rem))
+        }
+        _ => {
+            let bits = |p: &EncryptedPoint| match p.width() {
+                FheWidth::U32 => FheUint32::num_bits(),
+                FheWidth::U64 => FheUint64::num_bits(),
+            };
+            Err(DistanceError::InputWidthMismatch { expected_bits: bits(p1), found_bits: bits(p2) })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tfhe::{ConfigBuilder, generate_keys};
+
+    fn encrypt_position(p: &Position, key: &tfhe::ClientKey) -> (FheUint32, FheUint32) {
+        (FheUint32::encrypt(p.x, key), FheUint32::encrypt(p.y, key))
+    }
+
+    #[test]
+    fn signed_positions_parse_negatives_and_reject_out_of_range() {
+        assert_eq!("-3,4".parse(), Ok(SignedPosition { x: -3, y: 4 }));
+        assert_eq!(" 7 , -12".parse(), Ok(SignedPosition { x: 7, y: -12 }));
+        assert_eq!(format!("{}", SignedPosition { x: -1, y: 0 }), "(-1, 0)");
+        assert!("3".parse::<SignedPosition>().is_err());
+        assert!("a,1".parse::<SignedPosition>().is_err());
+        assert!(format!("{},0", MAX_SIGNED_COORD + 1).parse::<SignedPosition>().is_err());
+        assert!(format!("{},0", -MAX_SIGNED_COORD).parse::<SignedPosition>().is_ok());
+    }
+
+    #[test]
+    fn signed_distance_example_handles_negative_and_far_apart_points() {
+        let config = ConfigBuilder::default().build();
+        let (client_key, server_key) = generate_keys(config);
+        let view_range = ScaledThreshold::new(FOW_VIEW_RANGE, Precision::default()).unwrap();
+        let pair = |alice: &str, bob: &str| (alice.parse().unwrap(), bob.parse().unwrap());
+
+        for ((alice, bob), reveal, distance_sq) in [
+            (pair("-3,-4", "3,4"), true, 100),              // through the origin, 10 apart
+            (pair("-20,5", "-8,0"), false, 169),            // both negative x, 13 apart
+            (pair("-1073741824,0", "1073741824,0"), false, 1 << 62), // opposite edges of the map
+        ] {
+            let report = fhe_signed_distance_example(client_key.clone(), server_key.clone(), alice, bob, &view_range);
+            assert_eq!(report, SignedDistanceReport { reveal, distance_sq }, "{alice} to {bob}");
+        }
+        assert_eq!(SignedDistanceReport { reveal: false, distance_sq: 169 }.distance(), 13.0);
+    }
+
+    #[test]
+    fn distance_to_a_peer_in_the_negative_direction() {
+        let config = ConfigBuilder::default().build();
+        let (client_key, server_key) = generate_keys(config);
+        set_server_key(server_key);
+
+        // Bob is to the lower-left of Alice: dx = -9, dy = -8
+        let alice = Position { x: 12, y: 10 };
+        let bob = Position { x: 3, y: 2 };
+        let (x1, y1) = encrypt_position(&alice, &client_key);
+        let (x2, y2) = encrypt_position(&bob, &client_key);
+
+        let abs_dx: u32 = fhe_abs_diff(&x2, &x1).decrypt(&client_key);
+        assert_eq!(abs_dx, 9);
+        let distance_sq: u32 = fhe_distance_sq(&x1, &y1, &x2, &y2).decrypt(&client_key);
+        assert_eq!(distance_sq, 145);
+        // same distance either way round
+        let distance_sq: u32 = fhe_distance_sq(&x2, &y2, &x1, &y1).decrypt(&client_key);
+        assert_eq!(distance_sq, 145);
+
+        let (g, _rem) = fhe_distance_calc(&x1, &y1, &x2, &y2, SQRT_ITERATIONS + 2).unwrap();
+        let g: u32 = g.decrypt(&client_key);
+        assert_eq!(g, 145_f64.sqrt() as u32);
+    }
+
+    #[test]
+    fn parallel_distance_sq_matches_sequential() {
+        let config = ConfigBuilder::default().build();
+        let (client_key, server_key) = generate_keys(config);
+        // only the pool's own threads, none of which has a server key until fhe_distance_sq sets it
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+
+        let pairs = [
+            (Position { x: 3, y: 2 }, Position { x: 12, y: 10 }),
+            (Position { x: 12, y: 10 }, Position { x: 3, y: 2 }),
+            (Position { x: 7, y: 7 }, Position { x: 7, y: 7 }),
+            (Position { x: 0, y: 300 }, Position { x: 400, y: 0 }),
+        ];
+        for (p1, p2) in pairs {
+            let (x1, y1) = encrypt_position(&p1, &client_key);
+            let (x2, y2) = encrypt_position(&p2, &client_key);
+
+            let parallel: u32 = pool.install(|| {
+                set_server_key(server_key.clone());
+                fhe_distance_sq(&x1, &y1, &x2, &y2)
+            }).decrypt(&client_key);
+
+            set_server_key(server_key.clone());
+            let (dx, dy) = (fhe_abs_diff(&x1, &x2), fhe_abs_diff(&y1, &y2));
+            let sequential: u32 = (&dx * &dx + &dy * &dy).decrypt(&client_key);
+
+            assert_eq!(parallel, sequential, "{p1:?} -> {p2:?}");
+            assert_eq!(parallel, p1.x.abs_diff(p2.x).pow(2) + p1.y.abs_diff(p2.y).pow(2));
+        }
+    }
+
+    #[test]
+    fn distance_sq_works_after_tfhe_set_server_key() {
+        let config = ConfigBuilder::default().build();
+        let (client_key, server_key) = generate_keys(config);
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+
+        let (x1, y1) = encrypt_position(&Position { x: 3, y: 2 }, &client_key);
+        let (x2, y2) = encrypt_position(&Position { x: 12, y: 10 }, &client_key);
+        // tfhe's own setter leaves nothing to hand the pool's other thread, which has no server key
+        let distance_sq: u32 = pool.install(|| {
+            tfhe::set_server_key(server_key.clone());
+            fhe_distance_sq(&x1, &y1, &x2, &y2)
+        }).decrypt(&client_key);
+        assert_eq!(distance_sq, 9 * 9 + 8 * 8);
+    }
+
+    #[test]
+    fn batch_flags_the_peers_within_range() {
+        let config = ConfigBuilder::default().build();
+        let (client_key, server_key) = generate_keys(config);
+        set_server_key(server_key.clone());
+
+        let range = ScaledThreshold::new(FOW_VIEW_RANGE, Precision::default()).unwrap();
+        let alice = Position { x: 20, y: 20 };
+        let (x1, y1) = encrypt_position(&alice, &client_key);
+        let peers = [
+            Position { x: 23, y: 24 },  // 5 away
+            Position { x: 40, y: 20 },  // 20 away
+            Position { x: 12, y: 13 },  // ~10.6 away, to the lower-left
+            Position { x: 20, y: 8 },   // 12 away
+        ];
+        let encrypted_peers: Vec<(FheUint32, FheUint32)> = peers.iter()
+            .map(|peer| encrypt_position(peer, &client_key))
+            .collect();
+
+        let distances: Vec<u32> = fhe_distances_batch(&server_key, (&x1, &y1), &encrypted_peers).iter()
+            .map(|d| d.decrypt(&client_key))
+            .collect();
+        assert_eq!(distances, vec![25, 400, 113, 144]);
+
+        let reveals: Vec<bool> = fhe_within_view_batch(&server_key, (&x1, &y1), &encrypted_peers, &range).iter()
+            .map(|r| r.decrypt(&client_key))
+            .collect();
+        assert_eq!(reveals, vec![true, false, true, false]);
+    }
+
+    #[test]
+    fn fhe_within_range_matches_sqrt_decision_near_boundary() {
+        let config = ConfigBuilder::default().build();
+        let (client_key, server_key) = generate_keys(config);
+        set_server_key(server_key);
+
+        let range = ScaledThreshold::new(5, Precision::default()).unwrap();
+        let me = Position { x: 10, y: 10 };
+        let (x1, y1) = encrypt_position(&me, &client_key);
+        let peers = [
+            Position { x: 13, y: 14 }, // exactly on the boundary
+            Position { x: 6, y: 7 },   // on the boundary, negative direction
+            Position { x: 14, y: 12 }, // just inside
+            Position { x: 15, y: 11 }, // just outside
+            Position { x: 5, y: 9 },   // just outside, negative direction
+        ];
+
+        for peer in peers {
+            let (x2, y2) = encrypt_position(&peer, &client_key);
+            let within_range: bool = fhe_within_range(&x1, &y1, &x2, &y2, &range).decrypt(&client_key);
+
+            let dx = peer.x as f32 - me.x as f32;
+            let dy = peer.y as f32 - me.y as f32;
+            assert_eq!(within_range, check_distance(dx, dy) <= range.range() as f32, "{peer:?}");
+        }
+    }
+
+    #[test]
+    fn precision_must_be_a_power_of_ten() {
+        for precision in [1, 10, 100, 1_000, 10_000] {
+            assert_eq!(Precision::new(precision).unwrap().get(), precision);
+        }
+        for precision in [0, 2, 50, 101, 110, 100_000] {
+            assert!(Precision::new(precision).is_err(), "{precision}");
+        }
+    }
+
+    #[test]
+    fn scaled_threshold_agrees_between_squared_and_scaled_squared() {
+        for precision in [1, 100, 10_000] {
+            let precision = Precision::new(precision).unwrap();
+            let max_range = ScaledThreshold::MAX_FADE_RANGE;
+            assert!(ScaledThreshold::new(0, precision).is_err());
+            assert!(ScaledThreshold::new(max_range + 1, precision).is_err());
+            let max = ScaledThreshold::new(max_range, precision).unwrap();
+            assert!(max.squared().checked_mul(u8::MAX as u32).is_some());
+            assert!(max.scaled_squared() >= max.squared());
+        }
+
+        let range = ScaledThreshold::new(FOW_VIEW_RANGE, Precision::default()).unwrap();
+        for dx in 0..=20_u32 {
+            for dy in 0..=20_u32 {
+                let distance_sq = dx.pow(2) + dy.pow(2);
+                let expected = check_distance(dx as f32, dy as f32) <= FOW_VIEW_RANGE as f32;
+
+                assert_eq!(distance_sq <= range.squared(), expected, "({dx}, {dy})");
+                assert_eq!(distance_sq <= range.scaled_squared(), expected, "({dx}, {dy})");
+            }
+        }
+    }
+
+    #[test]
+    fn scaled_squared_threshold_matches_the_scaled_sqrt_decision() {
+        for precision in [1, 10, 100, 1_000, 10_000] {
+            let precision = Precision::new(precision).unwrap();
+            for range in [1, 5, 11, 49, 50, 120, 655, ScaledThreshold::MAX_FADE_RANGE] {
+                let range = ScaledThreshold::new(range, precision).unwrap();
+                let reach = range.range() + 3;
+                for dx in 0..=reach {
+                    for dy in (0..=reach).step_by(1 + reach as usize / 40) {
+                        let distance_sq = (dx * dx + dy * dy) as u64;
+                        // before: the exact integer sqrt of the precision²-scaled distance, vs range * precision
+                        let scaled = distance_sq * (precision.get() as u64).pow(2);
+                        let before = scaled.isqrt() <= (range.range() * precision.get()) as u64;
+                        // after: the unscaled distance vs the scaled threshold, with no larger intermediate
+                        let after = distance_sq <= range.scaled_squared() as u64;
+                        assert_eq!(before, after, "({dx}, {dy}) range {} precision {}", range.range(), precision.get());
+                    }
+                }
+            }
+        }
+        // no rounding slack at the default precision for the default view range
+        let range = ScaledThreshold::new(FOW_VIEW_RANGE, Precision::default()).unwrap();
+        assert_eq!(range.scaled_squared(), range.squared());
+    }
+
+    #[test]
+    fn fhe_reveal_decision_matches_cleartext_range_check() {
+        let config = ConfigBuilder::default().build();
+        let (client_key, server_key) = generate_keys(config);
+        set_server_key(server_key);
+
+        let range = ScaledThreshold::new(FOW_VIEW_RANGE, Precision::default()).unwrap();
+        let me = Position { x: 50, y: 50 };
+        let (x1, y1) = encrypt_position(&me, &client_key);
+        let peers = [
+            Position { x: 50, y: 50 },
+            Position { x: 61, y: 50 }, // on the boundary
+            Position { x: 62, y: 50 },
+            Position { x: 57, y: 58 }, // 7² + 8² = 113 <= 121
+            Position { x: 58, y: 58 }, // 8² + 8² = 128 > 121
+            Position { x: 43, y: 41 },
+            Position { x: 41, y: 43 },
+            Position { x: 20, y: 90 },
+        ];
+
+        for peer in peers {
+            let (x2, y2) = encrypt_position(&peer, &client_key);
+            let reveal: bool = fhe_within_range(&x1, &y1, &x2, &y2, &range).decrypt(&client_key);
+
+            let dx = peer.x as f32 - me.x as f32;
+            let dy = peer.y as f32 - me.y as f32;
+            assert_eq!(reveal, check_distance(dx, dy) <= FOW_VIEW_RANGE as f32, "{peer:?}");
+        }
+    }
+
+    #[test]
+    fn fhe_team_visible_ignores_range_for_teammates() {
+        let config = ConfigBuilder::default().build();
+        let (client_key, server_key) = generate_keys(config);
+        set_server_key(server_key);
+
+        let view_range = ScaledThreshold::new(FOW_VIEW_RANGE, Precision::default()).unwrap();
+        let me = encrypt_position(&Position { x: 10, y: 10 }, &client_key);
+        let my_team = FheUint8::encrypt(1_u8, &client_key);
+        let cases = [
+            // teammate far away
+            (Position { x: 90, y: 90 }, 1_u8, true),
+            // enemy far away
+            (Position { x: 90, y: 90 }, 2_u8, false),
+            // enemy in range
+            (Position { x: 12, y: 13 }, 2_u8, true),
+        ];
+
+        for (peer, peer_team, expected) in cases {
+            let peer = encrypt_position(&peer, &client_key);
+            let peer_team = FheUint8::encrypt(peer_team, &client_key);
+            let visible = fhe_team_visible((&me.0, &me.1), &my_team, (&peer.0, &peer.1), &peer_team, &view_range);
+            let visible: bool = visible.decrypt(&client_key);
+            assert_eq!(visible, expected);
+        }
+    }
+
+    #[test]
+    fn fhe_visibility_level_fades_with_distance() {
+        let config = ConfigBuilder::default().build();
+        let (client_key, server_key) = generate_keys(config);
+        set_server_key(server_key);
+
+        let view_range = ScaledThreshold::new(FOW_VIEW_RANGE, Precision::default()).unwrap();
+        let me = encrypt_position(&Position { x: 20, y: 20 }, &client_key);
+        let separations = [0, 2, 4, 6, 8, 10];
+        let mut levels = Vec::new();
+
+        for dx in separations {
+            let peer = encrypt_position(&Position { x: 20 + dx, y: 20 }, &client_key);
+            let level: u8 = fhe_visibility_level(&me.0, &me.1, &peer.0, &peer.1, &view_range).decrypt(&client_key);
+            levels.push(level);
+        }
+
+        assert_eq!(levels[0], u8::MAX);
+        assert!(levels.windows(2).all(|pair| pair[0] > pair[1]), "{levels:?}");
+
+        // at and beyond the range, in either direction
+        for peer in [Position { x: 31, y: 20 }, Position { x: 32, y: 20 }, Position { x: 5, y: 5 }] {
+            let peer = encrypt_position(&peer, &client_key);
+            let level: u8 = fhe_visibility_level(&me.0, &me.1, &peer.0, &peer.1, &view_range).decrypt(&client_key);
+            assert_eq!(level, 0);
+        }
+    }
+
+    #[test]
+    fn u64_distance_is_exact_where_u32_overflows() {
+        let config = ConfigBuilder::default().build();
+        let (client_key, server_key) = generate_keys(config);
+        set_server_key(server_key);
+
+        let (me, peer) = (Position { x: 0, y: 0 }, Position { x: 50_000, y: 50_000 });
+        let expected_sq = 2 * 50_000_u64.pow(2);
+        assert!(expected_sq > u32::MAX as u64);
+
+        // the u32 path wraps around
+        let (x1, y1) = encrypt_position(&me, &client_key);
+        let (x2, y2) = encrypt_position(&peer, &client_key);
+        let distance_sq: u32 = fhe_distance_sq(&x1, &y1, &x2, &y2).decrypt(&client_key);
+        assert_eq!(distance_sq as u64, expected_sq % (1 << 32));
+
+        let encrypt = |p: &Position| (FheUint64::encrypt(p.x as u64, &client_key), FheUint64::encrypt(p.y as u64, &client_key));
+        let (x1, y1) = encrypt(&me);
+        let (x2, y2) = encrypt(&peer);
+        let distance_sq: u64 = fhe_distance_sq_u64(&x1, &y1, &x2, &y2).decrypt(&client_key);
+        assert_eq!(distance_sq, expected_sq);
+
+        // distance = 70_710.6..., from a guess within ~1%
+        let (g, _rem) = fhe_distance_calc_u64(&x1, &y1, &x2, &y2, 70_000, SQRT_ITERATIONS_U64).unwrap();
+        let g: u64 = g.decrypt(&client_key);
+        let exact = (expected_sq as f64).sqrt();
+        assert!((g as f64 - exact).abs() <= 1.0, "{g} vs {exact}");
+
+        let range = ScaledThreshold::new(FOW_VIEW_RANGE, Precision::default()).unwrap();
+        let within_range: bool = fhe_within_range_u64(&x1, &y1, &x2, &y2, &range).decrypt(&client_key);
+        assert!(!within_range);
+        let distance_sq = fhe_distance_sq_u64(&x1, &y1, &x2, &y2);
+        let within_distance: bool = fhe_distance_within_u64(&distance_sq, &range).decrypt(&client_key);
+        assert!(!within_distance);
+    }
+
+    #[test]
+    fn width_is_u64_once_the_squared_distance_overflows_u32() {
+        // (2 * 32_767)² = 2^32 - 2^18 + 4 fits, (2 * 32_768)² = 2^32 doesn't
+        assert_eq!(required_width(32_767), FheWidth::U32);
+        assert_eq!(required_width(32_768), FheWidth::U64);
+        assert_eq!(required_width(0), FheWidth::U32);
+        assert_eq!(required_width(u32::MAX), FheWidth::U64);
+    }
+
+    #[test]
+    fn auto_width_distance_matches_the_fixed_width_paths() {
+        let config = ConfigBuilder::default().build();
+        let (client_key, server_key) = generate_keys(config);
+        set_server_key(server_key);
+
+        let (me, peer) = (Position { x: 3, y: 2 }, Position { x: 12, y: 10 });
+        for width in [FheWidth::U32, FheWidth::U64] {
+            let p1 = EncryptedPoint::encrypt(&me, width, &client_key);
+            let p2 = EncryptedPoint::encrypt(&peer, width, &client_key);
+            let (g, _rem) = fhe_distance_calc_auto(&p1, &p2, SQRT_ITERATIONS_U64, 12).unwrap().decrypt(&client_key);
+            // sqrt(9² + 8²) = 12.04...
+            assert_eq!(g, 12, "{width}");
+        }
+
+        // a map large enough that only FheUint64 fits, at the default iterations: the guess of 40_000 is near
+        // sqrt(36_000² + 15_000²) = 39_000, but ~3000 times the 12 of the example's points
+        let max_coord = 40_000;
+        assert_eq!(required_width(max_coord), FheWidth::U64);
+        for (me, peer, expected) in [
+            (Position { x: 0, y: 0 }, Position { x: 36_000, y: 15_000 }, 39_000),
+            (Position { x: 3, y: 2 }, Position { x: 12, y: 10 }, 12),
+        ] {
+            let p1 = EncryptedPoint::encrypt(&me, FheWidth::U64, &client_key);
+            let p2 = EncryptedPoint::encrypt(&peer, FheWidth::U64, &client_key);
+            let (g, _rem) = fhe_distance_calc_auto(&p1, &p2, SQRT_ITERATIONS, max_coord).unwrap().decrypt(&client_key);
+            assert!(g.abs_diff(expected) <= 1, "{g}, expected {expected}");
+        }
+
+        let p1 = EncryptedPoint::encrypt(&me, FheWidth::U32, &client_key);
+        let p2 = EncryptedPoint::encrypt(&peer, FheWidth::U64, &client_key);
+        assert_eq!(
+            fhe_distance_calc_auto(&p1, &p2, 1, 12).err(),
+            Some(DistanceError::InputWidthMismatch { expected_bits: 32, found_bits: 64 })
+        );
+    }
+
+    #[test]
+    fn larger_view_range_reveals_peer_hidden_at_default_range() {
+        let config = ConfigBuilder::default().build();
+        let (client_key, server_key) = generate_keys(config);
+        set_server_key(server_key);
+
+        let precision = Precision::new(10).unwrap();
+        let default_range = ScaledThreshold::new(FOW_VIEW_RANGE, precision).unwrap();
+        let wide_range = ScaledThreshold::new(20, precision).unwrap();
+        // 12² + 9² = 225, 15 apart
+        let me = encrypt_position(&Position { x: 10, y: 10 }, &client_key);
+        let peer = encrypt_position(&Position { x: 22, y: 19 }, &client_key);
+
+        let (g, _rem) = fhe_distance_calc(&me.0, &me.1, &peer.0, &peer.1, SQRT_ITERATIONS).unwrap();
+        let distance: u32 = g.decrypt(&client_key);
+        assert_eq!(distance, 15);
+
+        let distance_sq = fhe_distance_sq(&me.0, &me.1, &peer.0, &peer.1);
+        for (range, expected) in [(default_range, false), (wide_range, true)] {
+            let within_range: bool = fhe_within_range(&me.0, &me.1, &peer.0, &peer.1, &range).decrypt(&client_key);
+            let within_distance: bool = fhe_distance_within(&distance_sq, &range).decrypt(&client_key);
+            assert_eq!(within_range, expected, "{range:?}");
+            assert_eq!(within_distance, expected, "{range:?}");
+        }
+    }
+
+    #[test]
+    fn sqrt_error_shrinks_with_more_iterations() {
+        let config = ConfigBuilder::default().build();
+        let (client_key, server_key) = generate_keys(config);
+        set_server_key(server_key);
+
+        // distances 12.04, 30 and 50, from a guess of 10
+        for n in [145_u32, 30_u32.pow(2), 50_u32.pow(2)] {
+            let exact = (n as f64).sqrt().floor() as i64;
+            let encrypted_n = FheUint32::encrypt(n, &client_key);
+
+            let errors: Vec<i64> = (0..=4).map(|iterations| {
+                let (g, _rem) = sqrt_newtowns_approx(&encrypted_n, INITIAL_SQRT_GUESS, iterations);
+                let g: u32 = g.decrypt(&client_key);
+                (g as i64 - exact).abs()
+            }).collect();
+
+            assert!(errors.windows(2).all(|pair| pair[0] >= pair[1]), "{n}: {errors:?}");
+            assert!(errors[4] <= 1, "{n}: {errors:?}");
+        }
+    }
+
+    #[test]
+    fn distance_calc_reports_a_missing_server_key() {
+        let config = ConfigBuilder::default().build();
+        let (client_key, server_key) = generate_keys(config);
+        let (x, y) = encrypt_position(&Position { x: 3, y: 4 }, &client_key);
+
+        // a fresh thread has no server key yet
+        let no_key = std::thread::scope(|scope| {
+            scope.spawn(|| fhe_distance_calc(&x, &y, &x, &y, SQRT_ITERATIONS).err()).join().unwrap()
+        });
+        assert_eq!(no_key, Some(DistanceError::ServerKeyNotSet));
+        assert_eq!(no_key.unwrap().to_string(), "no FHE server key set on this thread, call set_server_key first");
+
+        set_server_key(server_key);
+        assert!(fhe_distance_calc(&x, &y, &x, &y, SQRT_ITERATIONS).is_ok());
+    }
+
+    #[test]
+    fn manhattan_metric_is_exact_without_sqrt_iterations() {
+        let config = ConfigBuilder::default().build();
+        let (client_key, server_key) = generate_keys(config);
+        set_server_key(server_key);
+
+        let euclidean = ScaledThreshold::new(FOW_VIEW_RANGE, Precision::default()).unwrap();
+        let manhattan = euclidean.with_metric(DistanceMetric::Manhattan);
+        let me = Position { x: 10, y: 10 };
+        let (x1, y1) = encrypt_position(&me, &client_key);
+        // (peer, euclidean reveal, manhattan reveal)
+        let cases = [
+            (Position { x: 21, y: 10 }, true, true),   // 11 along one axis, on both boundaries
+            (Position { x: 18, y: 17 }, true, false),  // ~10.6 in a straight line, 15 on the grid
+            (Position { x: 2, y: 7 }, true, true),     // negative direction: ~8.5, and 11
+            (Position { x: 1, y: 0 }, false, false),   // ~13.5, and 19
+        ];
+
+        for (peer, euclidean_reveal, manhattan_reveal) in cases {
+            let (x2, y2) = encrypt_position(&peer, &client_key);
+            let distance: u32 = fhe_manhattan_distance(&x1, &y1, &x2, &y2).decrypt(&client_key);
+            assert_eq!(distance, peer.x.abs_diff(me.x) + peer.y.abs_diff(me.y), "{peer:?}");
+
+            let reveal: bool = fhe_within_view(&x1, &y1, &x2, &y2, &euclidean).decrypt(&client_key);
+            assert_eq!(reveal, euclidean_reveal, "{peer:?}");
+            let reveal: bool = fhe_within_view(&x1, &y1, &x2, &y2, &manhattan).decrypt(&client_key);
+            assert_eq!(reveal, manhattan_reveal, "{peer:?}");
+        }
+
+        // the same euclidean distance depends on how many sqrt iterations it gets
+        let (x2, y2) = encrypt_position(&Position { x: 1, y: 0 }, &client_key);
+        let (rough, _rem) = fhe_distance_calc(&x1, &y1, &x2, &y2, 0).unwrap();
+        let rough: u32 = rough.decrypt(&client_key);
+        assert_ne!(rough, 181_f64.sqrt() as u32);
+    }
+
+    #[test]
+    fn fhe_nearer_flags_the_closer_peer() {
+        let config = ConfigBuilder::default().build();
+        let (client_key, server_key) = generate_keys(config);
+        set_server_key(server_key);
+
+        let me = encrypt_position(&Position { x: 10, y: 10 }, &client_key);
+        let cases = [
+            // A clearly closer
+            (Position { x: 11, y: 10 }, Position { x: 30, y: 30 }, true),
+            // B clearly closer
+            (Position { x: 40, y: 2 }, Position { x: 9, y: 12 }, false),
+            // near-tie: A is 4² + 4² = 32 away, B is 3² + 5² = 34 away
+            (Position { x: 14, y: 14 }, Position { x: 7, y: 15 }, true),
+        ];
+
+        for (a, b, expected) in cases {
+            let a = encrypt_position(&a, &client_key);
+            let b = encrypt_position(&b, &client_key);
+            let a_is_nearer = fhe_nearer((&me.0, &me.1), (&a.0, &a.1), (&b.0, &b.1));
+            let a_is_nearer: bool = a_is_nearer.decrypt(&client_key);
+            assert_eq!(a_is_nearer, expected);
+        }
+    }
+}
@@ -0,0 +1,10 @@
+//! Encrypted fog-of-war distance checks on tfhe-rs, with a blsttc threshold network to reveal them.
+//!
+//! The `fhe-zama` binary runs the examples and benchmarks; the pieces it doesn't drive from the CLI
+//! (e.g. [`distance_accumulator`], batched and team visibility checks) are used from here.
+
+pub mod bench;
+pub mod distance_accumulator;
+pub mod fhe_distance;
+pub mod mpc_network;
+pub mod persistence;
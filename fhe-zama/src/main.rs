@@ -1,22 +1,23 @@
 
+use std::path::PathBuf;
 use std::time::Instant;
 use anyhow::Result;
 
-use tfhe::{ConfigBuilder, generate_keys, set_server_key, FheUint32};
+use tfhe::{ConfigBuilder, generate_keys, FheUint32};
 use tfhe::prelude::*;
-use clap::{Parser, Subcommand};
-use ecdh;
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use ecdh::k256;
 
-mod mpc_network;
-use mpc_network::MpcNetwork;
-
-mod fhe_distance;
-use fhe_distance::{
-    FOW_VIEW_RANGE, PRECISION, Position,
+use fhe_zama::mpc_network::{Axis, MpcNetwork, MPC_REVEAL_KEY_INFO};
+use fhe_zama::fhe_distance::{
+    DistanceMetric, FOW_VIEW_RANGE, PRECISION, SQRT_ITERATIONS, Position, Precision, ScaledThreshold,
+    SignedPosition,
     fhe_distance_example,
-    fhe_distance_calc
+    fhe_signed_distance_example,
+    set_server_key,
 };
+use fhe_zama::persistence::load_or_generate_keys;
+use fhe_zama::bench::{BenchOp, run_bench};
 
 
 #[derive(Parser)]
@@ -29,17 +30,90 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Basic FHE example
-    Basic { },
+    Basic {
+        #[command(flatten)]
+        distance: DistanceArgs,
+
+        /// Newton iterations for the FHE sqrt, fixed up front since FHE can't stop early on convergence
+        #[arg(long, default_value_t = SQRT_ITERATIONS)]
+        sqrt_iterations: usize,
+
+        /// Reuse the FHE keys saved in this directory, or generate and save them there on the first run
+        #[arg(long)]
+        keys_dir: Option<PathBuf>,
+
+        /// Largest coordinate on the map, to compute distances in FheUint64 when FheUint32 would overflow
+        #[arg(long)]
+        max_coord: Option<u32>,
+
+        /// Alice's position as x,y (negatives allowed, e.g. -3,4), instead of the fixed example.
+        /// Computed on FheInt64, so any coordinates within ±2^30 work, and takes no sqrt in FHE:
+        /// --max-coord and --sqrt-iterations don't apply.
+        #[arg(long, allow_hyphen_values = true, requires = "bob_pos", conflicts_with_all = ["sqrt_iterations", "max_coord"])]
+        alice_pos: Option<SignedPosition>,
+
+        /// Bob's position as x,y, see --alice-pos
+        #[arg(long, allow_hyphen_values = true, requires = "alice_pos", conflicts_with_all = ["sqrt_iterations", "max_coord"])]
+        bob_pos: Option<SignedPosition>,
+    },
     /// FHE with MPC for keygen
     Mpc {
         #[arg(short, long)]
         threshold: usize,
 
         #[arg(short, long)]
-        number_of_parties: usize
+        number_of_parties: usize,
+
+        /// Only reveal one coordinate of Bob's position (e.g. a radar sweep)
+        #[arg(long, value_enum)]
+        reveal_axis: Option<Axis>,
+
+        #[command(flatten)]
+        distance: DistanceArgs,
+    },
+    /// Time individual FHE operations, printing min/median/max per operation
+    Bench {
+        /// Operations to time, e.g. --operations add,distance (all of them by default)
+        #[arg(long, value_enum, value_delimiter = ',')]
+        operations: Vec<BenchOp>,
+
+        /// How many times to run each operation
+        #[arg(short, long, default_value_t = 5)]
+        repetitions: usize,
+
+        /// Newton iterations for the distance operation's sqrt
+        #[arg(long, default_value_t = SQRT_ITERATIONS)]
+        sqrt_iterations: usize,
+    },
+    /// Export reference test vectors for the MPC scheme as JSON
+    #[cfg(feature = "test-vectors")]
+    TestVectors {
+        #[arg(short, long, default_value_t = 0)]
+        seed: u64
     },
 }
 
+#[derive(Args)]
+struct DistanceArgs {
+    /// Resolution of the view range check, a power of ten (100 = distances to 2 decimal places)
+    #[arg(long, default_value_t = PRECISION)]
+    precision: u32,
+
+    /// Fog-of-war view range, in map units
+    #[arg(long, default_value_t = FOW_VIEW_RANGE)]
+    view_range: u32,
+
+    /// How distance is measured for the reveal decision (manhattan needs no multiplication or sqrt)
+    #[arg(long, value_enum, default_value_t = DistanceMetric::Euclidean)]
+    metric: DistanceMetric,
+}
+
+impl DistanceArgs {
+    fn view_range(&self) -> Result<ScaledThreshold> {
+        Ok(ScaledThreshold::new(self.view_range, Precision::new(self.precision)?)?.with_metric(self.metric))
+    }
+}
+
 struct User {
     name: String,
     pub ecdh_pubkey: k256::PublicKey,
@@ -55,9 +129,9 @@ impl User {
         }
     }
 
-    pub fn decrypt_ecdh_message(&self, msg: &[u8], pubkey: &k256::PublicKey) -> Vec<u8> {
-        let shared_secret = ecdh::compute_shared_secret(&self.ecdh_skey, pubkey);
-        ecdh::decrypt(msg, &shared_secret)
+    pub fn decrypt_ecdh_message(&self, msg: &[u8], pubkey: &k256::PublicKey, aad: &[u8]) -> Result<Vec<u8>> {
+        let shared_secret_key = ecdh::SharedSecret::derive(&self.ecdh_skey, pubkey, MPC_REVEAL_KEY_INFO);
+        Ok(ecdh::decrypt(msg, &shared_secret_key, aad)?)
     }
 }
 
@@ -69,15 +143,29 @@ async fn main() -> Result<()> {
     let args: Cli = Cli::parse();
 
     match args.command {
-        Commands::Basic {} => {
+        Commands::Basic { distance, sqrt_iterations, keys_dir, max_coord, alice_pos, bob_pos } => {
+            let view_range = distance.view_range()?;
             let config = ConfigBuilder::default().build();
-            let (client_key, server_key) = generate_keys(config);
-            fhe_distance_example(client_key, server_key);
+            let (client_key, server_key) = match keys_dir {
+                Some(keys_dir) => load_or_generate_keys(keys_dir, config)?,
+                None => generate_keys(config),
+            };
+            match alice_pos.zip(bob_pos) {
+                Some((alice, bob)) => {
+                    fhe_signed_distance_example(client_key, server_key, alice, bob, &view_range);
+                }
+                None => {
+                    fhe_distance_example(client_key, server_key, &view_range, sqrt_iterations, max_coord)?;
+                }
+            }
         },
         Commands::Mpc {
             threshold,
-            number_of_parties
+            number_of_parties,
+            reveal_axis,
+            distance,
         } => {
+            let view_range = distance.view_range()?;
 
             // Server Side:
             // Setup FHE keys
@@ -85,13 +173,13 @@ async fn main() -> Result<()> {
             let (fhe_client_key, fhe_server_key) = generate_keys(config);
             // Setup MPC network
             let (
-                mut mpc_network,
+                mpc_network,
                 mpc_pub_key
             ) = setup_mpc_network(threshold, number_of_parties, fhe_server_key);
 
             // Client-side
             println!("\nAlice:");
-            let alice = User::new("alice");
+            let alice = User::new("Alice");
             let p1 = Position {
                 x: 2,
                 y: 2
@@ -111,7 +199,7 @@ async fn main() -> Result<()> {
             println!("\nBob:");
             let p2 = Position {
                 x: 4,
-                y: 4
+                y: 7
             };
             println!("\tEncrypting Bob's Position {{ x: ?, y: ? }} with FHE client_key");
             let x2 = FheUint32::encrypt(p2.x, &fhe_client_key);
@@ -127,10 +215,10 @@ async fn main() -> Result<()> {
             // Server-side
             println!("\nMPC_Network:");
             println!("\tFetching MPC shares and decrypting for FHE ciphertexts...");
-            let result_x1 = mpc_network.mpc_decrypt(ciphertext_x1)?;
-            let result_y1 = mpc_network.mpc_decrypt(ciphertext_y1)?;
-            let result_x2 = mpc_network.mpc_decrypt(ciphertext_x2)?;
-            let result_y2 = mpc_network.mpc_decrypt(ciphertext_y2)?;
+            let result_x1 = mpc_network.mpc_decrypt(ciphertext_x1).await?;
+            let result_y1 = mpc_network.mpc_decrypt(ciphertext_y1).await?;
+            let result_x2 = mpc_network.mpc_decrypt(ciphertext_x2).await?;
+            let result_y2 = mpc_network.mpc_decrypt(ciphertext_y2).await?;
             // MPC network will also perform FHE operations after MPC decrypting the msg
             set_server_key(mpc_network.fhe_server_key.clone());
             println!("\tRunning FHE operations on Position ciphertexts...");
@@ -139,17 +227,6 @@ async fn main() -> Result<()> {
             let fhe_x2: FheUint32 = bincode::deserialize(&result_x2)?;
             let fhe_y2: FheUint32 = bincode::deserialize(&result_y2)?;
 
-            let (distance, rem) = fhe_distance_calc(
-                &fhe_x1,
-                &fhe_y1,
-                &fhe_x2,
-                &fhe_y2,
-            );
-            let reveal_position = distance.le(FOW_VIEW_RANGE * PRECISION.pow(2));
-            let should_reveal_bob: bool = reveal_position.decrypt(&fhe_client_key);
-            println!("\tAlice's fog-of-war view range: {}", FOW_VIEW_RANGE);
-            println!("\tshould_reveal_bob?: {}", should_reveal_bob);
-
             // Here you can compare Alice and Bob's positions as they as encrypted under the same FHE key
             // The MPC network can decrypt comparison results (alice.x > bob.x) without revealing the
             // positions of Alice and Bob (with some trust assumptions on the MPC network).
@@ -158,31 +235,59 @@ async fn main() -> Result<()> {
             //
             // MPC network then needs to encrypt the FHE ciphertext response using Alice's pubkey (ECDH)
             // or it can be intercepted and decrypted by anyone with the fhe_client_key
+            let axes = match reveal_axis {
+                Some(axis) => vec![axis],
+                None => vec![Axis::X, Axis::Y],
+            };
+            let revealed = mpc_network.reveal_within_range(
+                (&fhe_x1, &fhe_y1),
+                (&fhe_x2, &fhe_y2),
+                &view_range,
+                &axes,
+                &fhe_client_key,
+                &alice.ecdh_pubkey,
+            )?;
+            println!("\tAlice's fog-of-war view range: {} ({:?})", view_range.range(), view_range.metric());
+            println!("\tshould_reveal_bob?: {}", revealed.is_some());
 
-            if (should_reveal_bob) {
-                println!("\n\tBob is within Alice's FOW view range, decrypting Bob's position...");
-                let revealed_x2: u32 = fhe_x2.decrypt(&fhe_client_key);
-                let revealed_y2: u32 = fhe_x2.decrypt(&fhe_client_key);
-                // println!("\nBob's position: ({}, {})", revealed_x2, revealed_y2);
+            if let Some(revealed) = revealed {
+                match reveal_axis {
+                    Some(axis) => println!("\n\tBob is within Alice's FOW view range, decrypting only Bob's {axis:?} coordinate..."),
+                    None => println!("\n\tBob is within Alice's FOW view range, decrypting Bob's position..."),
+                }
                 println!("\tEncrypting response and sending to Alice...");
 
-                let x2_for_alice = mpc_network.ecdh_encrypt(&revealed_x2.to_string().as_bytes(), &alice.ecdh_pubkey);
-                let y2_for_alice = mpc_network.ecdh_encrypt(&revealed_y2.to_string().as_bytes(), &alice.ecdh_pubkey);
-
-                println!("\nAlice:");
-                let x2_result = alice.decrypt_ecdh_message(&x2_for_alice, &mpc_network.ecdh_pub_key);
-                let x2_result = std::str::from_utf8(&x2_result)?.parse::<u32>()?;
-
-                let y2_result = alice.decrypt_ecdh_message(&y2_for_alice, &mpc_network.ecdh_pub_key);
-                let y2_result = std::str::from_utf8(&y2_result)?.parse::<u32>()?;
-
-                println!("\tAlice received and decrypted Bob's Position {{ x: {}, y: {} }}", x2_result, y2_result);
-                assert!(x2_result == 4);
-                assert!(y2_result == 4);
+                println!("\n{}:", alice.name);
+                for (axis, axis_for_alice) in revealed {
+                    let axis_result = alice.decrypt_ecdh_message(&axis_for_alice, &mpc_network.ecdh_pub_key, axis.aad())?;
+                    let axis_result = std::str::from_utf8(&axis_result)?.parse::<u32>()?;
+                    println!("\tAlice received and decrypted Bob's {axis:?} coordinate: {}", axis_result);
+                    let expected = match axis {
+                        Axis::X => p2.x,
+                        Axis::Y => p2.y,
+                    };
+                    assert!(axis_result == expected);
+                }
             } else {
                 println!("Alice wasn't close enough to Bob to reveal his position");
             }
         },
+        Commands::Bench { operations, repetitions, sqrt_iterations } => {
+            let operations = match operations.is_empty() {
+                true => BenchOp::value_variants().to_vec(),
+                false => operations,
+            };
+            println!("Timing {operations:?}, {repetitions} repetitions each (sqrt iterations: {sqrt_iterations})");
+            for result in run_bench(&operations, repetitions, sqrt_iterations) {
+                println!("\t{result}");
+            }
+        },
+        #[cfg(feature = "test-vectors")]
+        Commands::TestVectors { seed } => {
+            println!("{}", MpcNetwork::test_vectors(seed)?);
+            // nothing else on stdout, so it can be redirected into test-vectors/
+            return Ok(());
+        },
     }
 
     let duration = start.elapsed();
@@ -205,3 +310,46 @@ fn setup_mpc_network(
     (mpc_network, mpc_pubkey)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn basic_takes_negative_positions_from_the_cli() {
+        let parse = |args: &[&str]| Cli::try_parse_from([&["fhe-zama", "basic"], args].concat());
+
+        for (alice, bob) in [("-3,4", "5,-2"), ("0,0", "-1073741824,1073741824"), ("-7,-7", "-7,-7")] {
+            let Commands::Basic { alice_pos, bob_pos, .. } = parse(&["--alice-pos", alice, "--bob-pos", bob]).unwrap().command else {
+                panic!("expected the basic subcommand");
+            };
+            assert_eq!(alice_pos, Some(alice.parse().unwrap()));
+            assert_eq!(bob_pos, Some(bob.parse().unwrap()));
+        }
+
+        // one position alone has nothing to measure against
+        assert!(parse(&["--alice-pos", "-3,4"]).is_err());
+        assert!(parse(&["--alice-pos", "-3,4", "--bob-pos", "2000000000,0"]).is_err());
+        // the signed path would silently ignore these
+        for ignored in [["--sqrt-iterations", "3"], ["--max-coord", "1000"]] {
+            let result = parse(&[&["--alice-pos", "-3,4", "--bob-pos", "5,-2"][..], &ignored[..]].concat());
+            assert_eq!(result.err().map(|e| e.kind()), Some(clap::error::ErrorKind::ArgumentConflict));
+        }
+        assert!(parse(&["--sqrt-iterations", "3", "--max-coord", "1000"]).is_ok());
+    }
+
+    #[test]
+    fn basic_reveals_with_the_chosen_metric() {
+        let (client_key, server_key) = generate_keys(ConfigBuilder::default().build());
+
+        // the example moves from (3, 2) to (12, 10): ~12.04 apart, but 17 in Manhattan distance
+        for (metric, reveal) in [("euclidean", true), ("manhattan", false)] {
+            let cli = Cli::try_parse_from(["fhe-zama", "basic", "--view-range", "15", "--metric", metric]).unwrap();
+            let Commands::Basic { distance, sqrt_iterations, max_coord, .. } = cli.command else {
+                panic!("expected the basic subcommand");
+            };
+            let view_range = distance.view_range().unwrap();
+            let report = fhe_distance_example(client_key.clone(), server_key.clone(), &view_range, sqrt_iterations, max_coord).unwrap();
+            assert_eq!(report.reveal, reveal, "{metric}");
+        }
+    }
+}
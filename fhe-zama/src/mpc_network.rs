@@ -1,17 +1,57 @@
 
 use std::collections::BTreeMap;
+use std::time::Duration;
 use blsttc::{
     Ciphertext,
     DecryptionShare,
+    Fr,
     PublicKey,
     PublicKeySet,
     PublicKeyShare,
     SecretKeySet,
     SecretKeyShare
 };
+use blsttc::poly::{Commitment, Poly};
 use anyhow::{anyhow, Context, Result};
+use tfhe::{FheBool, FheUint32};
+use tfhe::prelude::*;
 use ecdh;
+use crate::fhe_distance::{ScaledThreshold, fhe_within_view};
 use ecdh::k256;
+use ecdh::zeroize::Zeroizing;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+// A coordinate revealed by the MPC network, ECDH-encrypted for the observer under axis.aad()
+pub type RevealedAxis = (Axis, Vec<u8>);
+
+// How long a decryption meeting waits for shares by default, slower actors are left out
+pub const DEFAULT_SHARE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A decryption, and the actors caught sending an invalid decryption share along the way
+#[derive(Debug)]
+pub struct DecryptionReport {
+    pub plaintext: Vec<u8>,
+    // sorted actor ids. Absent or slow actors aren't in here, only ones whose share failed to verify.
+    pub cheating_actors: Vec<usize>,
+}
+
+// A decryption share that doesn't verify against the actor's public-key share
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidDecryptionShare {
+    pub actor: usize,
+}
+
+impl std::fmt::Display for InvalidDecryptionShare {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid decryption share from actor {}", self.actor)
+    }
+}
+
+impl std::error::Error for InvalidDecryptionShare {}
+
+// HKDF context for the ECDH key the MPC network uses to send reveals to players
+pub const MPC_REVEAL_KEY_INFO: &[u8] = b"fhe-mpc-reveal-v1";
 
 // Mock MPC Network source:
 // https://github.com/maidsafe/blsttc/tree/master/examples
@@ -21,17 +61,22 @@ use ecdh::k256;
 // encrypt a message to the society's members; the society is also responsible for giving each
 // actor their respective share of the secret-key.
 pub struct MpcNetwork {
-    actors: Vec<Actor>,
+    actors: Vec<ActorHandle>,
     pk_set: PublicKeySet,
+    // How long a decryption meeting waits for shares before giving up on the remaining actors
+    pub share_timeout: Duration,
     pub fhe_server_key: tfhe::ServerKey,
     pub ecdh_pub_key: k256::PublicKey,
     ecdh_skey: k256::ecdh::EphemeralSecret,
+    // ECDH secrets with each player reveals went to, keyed by their compressed public key
+    reveal_secrets: std::sync::Mutex<ecdh::SharedSecretCache<Vec<u8>>>,
 }
 
 impl MpcNetwork {
     // `n_actors` - the number of actors (members) in the secret society.
     // `threshold` - the number of actors that must collaborate to successfully
     // decrypt a message must exceed this `threshold`.
+    // Each actor runs as its own tokio task, so this must be called within a tokio runtime.
     pub fn new(n_actors: usize, threshold: usize, fhe_server_key: tfhe::ServerKey) -> Self {
         let mut rng = rand::thread_rng();
         let sk_set = SecretKeySet::random(threshold, &mut rng);
@@ -40,17 +85,19 @@ impl MpcNetwork {
         let actors = (0..n_actors).map(|id| {
             let sk_share = sk_set.secret_key_share(id);
             let pk_share = pk_set.public_key_share(id);
-            Actor::new(id, pk_share, sk_share)
-        }).collect::<Vec<Actor>>();
+            Actor::spawn(id, pk_share, sk_share)
+        }).collect::<Vec<ActorHandle>>();
 
         let (ecdh_sk, ecdh_pk) = ecdh::generate_ecdh_keys();
 
         MpcNetwork {
-            actors: actors,
-            pk_set: pk_set,
-            fhe_server_key: fhe_server_key,
+            actors,
+            pk_set,
+            share_timeout: DEFAULT_SHARE_TIMEOUT,
+            fhe_server_key,
             ecdh_pub_key: ecdh_pk,
             ecdh_skey: ecdh_sk,
+            reveal_secrets: std::sync::Mutex::new(ecdh::SharedSecretCache::new(MPC_REVEAL_KEY_INFO)),
         }
     }
 
@@ -59,79 +106,393 @@ impl MpcNetwork {
         self.pk_set.public_key()
     }
 
-    fn get_actor(&mut self, id: usize) -> &mut Actor {
-        self.actors.get_mut(id)
-            .expect(&format!("Actor ID: {} does not exist", id))
+    // Proactive resharing: gives every actor a fresh secret-key share of the same master secret, so
+    // the public key, and every ciphertext already encrypted under it, stays the same. Shares from
+    // before a reshare can't be combined with shares from after it, so an attacker has to collect
+    // threshold + 1 shares between two reshares rather than over the society's whole lifetime.
+    pub fn reshare(&mut self) -> Result<()> {
+        // all or nothing: a half-reshared society can't decrypt
+        if let Some(actor) = self.actors.iter().find(|actor| actor.inbox.is_closed()) {
+            return Err(anyhow!("actor {} has stopped", actor.id));
+        }
+        let zero_sharing = zero_sharing(self.pk_set.threshold(), &mut rand::thread_rng());
+        for actor in &self.actors {
+            // the inbox was open above and only closes when the actor's task ends, which it can't
+            // do while this handle holds the sender
+            let _ = actor.inbox.send(ActorMessage::Reshare(zero_sharing.secret_key_share(actor.id)));
+        }
+
+        self.pk_set = reshared_public_keys(&self.pk_set, &zero_sharing)?;
+        for actor in self.actors.iter_mut() {
+            actor.pk_share = self.pk_set.public_key_share(actor.id);
+        }
+        Ok(())
+    }
+
+    // Simulated network latency: the actor waits this long before answering each ciphertext
+    pub fn set_actor_latency(&self, id: usize, latency: Duration) -> Result<()> {
+        self.actor(id)?.inbox.send(ActorMessage::SetLatency(latency))
+            .map_err(|_| anyhow!("actor {id} has stopped"))
     }
 
-    // Sends an encrypted message to Actor
-    fn send_message(&mut self, id: usize, enc_msg: Ciphertext) {
-        let actor = self.get_actor(id);
-        actor.msg_inbox = Some(enc_msg);
+    fn actor(&self, id: usize) -> Result<&ActorHandle> {
+        self.actors.get(id)
+            .ok_or_else(|| anyhow!("actor {id} is not a member of the MPC network"))
+    }
+
+    // Broadcasts the ciphertext to the `available` actors only, then collects their decryption shares
+    // as they arrive until threshold + 1 are valid. Actors that don't answer within `share_timeout`
+    // (offline or slow) are left out. With `verify_all`, it keeps collecting past the quorum so every
+    // answering actor's share is checked, and any cheater is reported, at the cost of waiting for the slowest.
+    async fn run_decryption_meeting(
+        &self,
+        ciphertext: Ciphertext,
+        available: &[usize],
+        verify_all: bool
+    ) -> Result<DecryptionReport> {
+        for &id in available {
+            self.actor(id)?;
+        }
+
+        // each meeting has its own reply channel, so late shares from an earlier meeting can't mix in
+        let (reply, mut replies) = mpsc::unbounded_channel();
+        for &id in available {
+            // an actor whose task has stopped is as good as offline
+            let _ = self.actors[id].inbox.send(ActorMessage::Decrypt {
+                ciphertext: Box::new(ciphertext.clone()),
+                reply: reply.clone(),
+            });
+        }
+        drop(reply);
+
+        let mut meeting = self.start_decryption_meeting(ciphertext);
+        let mut cheating_actors = Vec::new();
+        let deadline = Instant::now() + self.share_timeout;
+        while verify_all || !meeting.has_quorum() {
+            match tokio::time::timeout_at(deadline, replies.recv()).await {
+                Ok(Some((id, dec_share))) => {
+                    if let Err(invalid) = meeting.accept_decryption_share(id, dec_share) {
+                        cheating_actors.push(invalid.actor);
+                    }
+                }
+                // every available actor has answered, or the rest are too slow
+                Ok(None) | Err(_) => break,
+            }
+        }
+        cheating_actors.sort();
+
+        if !meeting.has_quorum() {
+            return Err(anyhow!(
+                "{} of {} available actors gave a valid decryption share within {:?}, decryption needs at least {} \
+                (invalid shares from {:?})",
+                meeting.dec_shares.len(),
+                available.len(),
+                self.share_timeout,
+                self.pk_set.threshold() + 1,
+                cheating_actors
+            ));
+        }
+        Ok(DecryptionReport { plaintext: meeting.decrypt_message()?, cheating_actors })
     }
 
     // Starts a new meeting of the secret society. Each time the set of actors receive an encrypted
-    // message, at least 2 of them (i.e. 1 more than the threshold) must work together to decrypt
-    // the ciphertext.
-    fn start_decryption_meeting(&self) -> DecryptionMeeting {
+    // message, at least threshold + 1 of them must work together to decrypt the ciphertext.
+    fn start_decryption_meeting(&self, ciphertext: Ciphertext) -> DecryptionMeeting {
         DecryptionMeeting {
             pk_set: self.pk_set.clone(),
-            ciphertext: None,
+            ciphertext,
             dec_shares: BTreeMap::new()
         }
     }
 
-    pub fn mpc_decrypt(&mut self, ciphertext: blsttc::Ciphertext) -> Result<Vec<u8>> {
-        mpc_decrypt(self, ciphertext)
+    pub async fn mpc_decrypt(&self, ciphertext: blsttc::Ciphertext) -> Result<Vec<u8>> {
+        mpc_decrypt(self, ciphertext).await
+    }
+
+    // Decrypt with only the actors in `available` (e.g. the ones online), as long as threshold + 1
+    // of them give valid shares. Errors on an id that isn't an actor, or if too few are available.
+    pub async fn mpc_decrypt_with_available(&self, ciphertext: blsttc::Ciphertext, available: &[usize]) -> Result<Vec<u8>> {
+        Ok(self.run_decryption_meeting(ciphertext, available, false).await?.plaintext)
+    }
+
+    // Decrypt with every actor, verifying every share that arrives within share_timeout (not just the
+    // first threshold + 1), and report the actors whose shares were invalid
+    pub async fn mpc_decrypt_verify_all(&self, ciphertext: blsttc::Ciphertext) -> Result<DecryptionReport> {
+        let everyone: Vec<usize> = (0..self.actors.len()).collect();
+        self.run_decryption_meeting(ciphertext, &everyone, true).await
+    }
+
+    // Public-key shares identify the actors (i.e. real nodes), independent of their position in `actors`
+    pub fn public_key_shares(&self) -> Vec<PublicKeyShare> {
+        self.actors.iter().map(|actor| actor.pk_share).collect()
+    }
+
+    // Decrypt with exactly the actors holding these public-key shares. Errors if a participant is not
+    // a member of the network, or if fewer than threshold + 1 distinct participants are named.
+    pub async fn decrypt_with(&self, ciphertext: blsttc::Ciphertext, participants: &[PublicKeyShare]) -> Result<Vec<u8>> {
+
+        let mut ids = participants.iter().map(|pk_share| {
+            self.actors.iter()
+                .find(|actor| actor.pk_share == *pk_share)
+                .map(|actor| actor.id)
+                .ok_or_else(|| anyhow!("participant {pk_share:?} is not a member of the MPC network"))
+        }).collect::<Result<Vec<usize>>>()?;
+        ids.sort();
+        ids.dedup();
+
+        let quorum = self.pk_set.threshold() + 1;
+        if ids.len() < quorum {
+            return Err(anyhow!("{} participants named, decryption needs at least {quorum}", ids.len()));
+        }
+        Ok(self.run_decryption_meeting(ciphertext, &ids, false).await?.plaintext)
+    }
+
+    // `aad` binds the message to its context (see Axis::aad), the recipient must decrypt with the same aad
+    // The ECDH secret is derived on a player's first reveal and reused after that (see reveal_secret_derivations)
+    pub fn ecdh_encrypt(&self, msg: &[u8], target_public_key: &k256::PublicKey, aad: &[u8]) -> Result<Vec<u8>> {
+        let mut reveal_secrets = self.reveal_secrets.lock().map_err(|_| anyhow!("reveal secrets lock poisoned"))?;
+        let peer = ecdh::public_key_to_sec1_compressed(target_public_key);
+        let shared_secret_key = reveal_secrets.get_or_derive(peer, &self.ecdh_skey, target_public_key);
+        Ok(ecdh::encrypt(msg, shared_secret_key, aad, ecdh::AeadAlgorithm::ChaCha20Poly1305)?)
     }
 
-    pub fn ecdh_encrypt(&self, msg: &[u8], target_public_key: &k256::PublicKey) -> Vec<u8> {
-        let shared_secret_key = ecdh::compute_shared_secret(&self.ecdh_skey, target_public_key);
-        ecdh::encrypt(&msg, &shared_secret_key)
+    // Number of ECDH computations ecdh_encrypt has done, one per player it has sent to
+    pub fn reveal_secret_derivations(&self) -> u64 {
+        self.reveal_secrets.lock().map(|secrets| secrets.derivations()).unwrap_or_default()
+    }
+
+    // Proxy re-encryption: threshold-decrypts a ciphertext under the MPC public key and ECDH-encrypts it
+    // for `target_public_key` in one step. Only the ECDH ciphertext is returned, the plaintext never leaves
+    // this method and is zeroized as soon as it's re-encrypted.
+    pub async fn reencrypt_for(
+        &self,
+        ciphertext: blsttc::Ciphertext,
+        target_public_key: &k256::PublicKey,
+        aad: &[u8]
+    ) -> Result<Vec<u8>> {
+        let plaintext = Zeroizing::new(self.mpc_decrypt(ciphertext).await?);
+        self.ecdh_encrypt(&plaintext, target_public_key, aad)
+    }
+
+    // Verifies a bounds proof: the encrypted boolean from `fhe_within_bounds`, serialized and encrypted
+    // with the MPC public key. The network threshold-decrypts it and learns only in/out of bounds,
+    // never the coordinates. Anything that doesn't deserialize to an FheBool is rejected.
+    pub async fn verify_in_bounds(&self, proof: blsttc::Ciphertext, fhe_client_key: &tfhe::ClientKey) -> Result<bool> {
+        let in_bounds = self.mpc_decrypt(proof).await?;
+        let in_bounds: FheBool = bincode::deserialize(&in_bounds)
+            .context("bounds proof is not an encrypted boolean")?;
+        Ok(in_bounds.decrypt(fhe_client_key))
+    }
+
+    // Partial reveal (e.g. a radar sweep): decrypts only the requested axis of an FHE encrypted
+    // position and ECDH-encrypts it for the requester. The other axis is never decrypted.
+    pub fn reveal_axis(
+        &self,
+        axis: Axis,
+        x: &FheUint32,
+        y: &FheUint32,
+        fhe_client_key: &tfhe::ClientKey,
+        target_public_key: &k256::PublicKey
+    ) -> Result<Vec<u8>> {
+        let coordinate = match axis {
+            Axis::X => x,
+            Axis::Y => y,
+        };
+        let revealed: u32 = coordinate.decrypt(fhe_client_key);
+        self.ecdh_encrypt(revealed.to_string().as_bytes(), target_public_key, axis.aad())
+    }
+
+    // Reveals the requested axes of `target` to the observer, only if `target` is within `range` of
+    // `observer`, measured with range.metric(). The range check and every reveal run on the same (x, y) pair, and each axis goes
+    // through reveal_axis, so a coordinate can't be decrypted from the wrong ciphertext.
+    // Returns None (and decrypts nothing but the range check) when out of range.
+    pub fn reveal_within_range(
+        &self,
+        observer: (&FheUint32, &FheUint32),
+        target: (&FheUint32, &FheUint32),
+        range: &ScaledThreshold,
+        axes: &[Axis],
+        fhe_client_key: &tfhe::ClientKey,
+        observer_public_key: &k256::PublicKey
+    ) -> Result<Option<Vec<RevealedAxis>>> {
+        let (x1, y1) = observer;
+        let (x2, y2) = target;
+        let within_range: bool = fhe_within_view(x1, y1, x2, y2, range).decrypt(fhe_client_key);
+        if !within_range {
+            return Ok(None);
+        }
+        axes.iter()
+            .map(|&axis| Ok((axis, self.reveal_axis(axis, x2, y2, fhe_client_key, observer_public_key)?)))
+            .collect::<Result<Vec<_>>>()
+            .map(Some)
+    }
+}
+
+#[cfg(feature = "test-vectors")]
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct MpcTestVectors {
+    pub seed: u64,
+    pub threshold: usize,
+    pub n_actors: usize,
+    pub master_public_key: String,
+    pub public_key_shares: Vec<String>,
+    pub secret_key_shares: Vec<String>,
+    pub message: String,
+    pub ciphertext: String,
+    pub decrypted: String,
+}
+
+#[cfg(feature = "test-vectors")]
+impl MpcNetwork {
+    // Reference values for the blsttc scheme, generated from a fixed seed. All byte values are hex encoded.
+    // Regenerating with the same seed must give byte-identical JSON, so changes in blsttc behaviour
+    // across dependency upgrades are caught.
+    pub fn test_vectors(seed: u64) -> Result<String> {
+        use rand::SeedableRng;
+
+        let (n_actors, threshold) = (3, 1);
+        let mut rng = rand_chacha::ChaCha20Rng::seed_from_u64(seed);
+        let sk_set = SecretKeySet::random(threshold, &mut rng);
+        let pk_set = sk_set.public_keys();
+
+        let message = b"fhe-zama mpc test vector".to_vec();
+        let ciphertext = pk_set.public_key().encrypt_with_rng(&mut rng, &message);
+
+        let dec_shares = (0..=threshold).map(|id| {
+            let dec_share = sk_set.secret_key_share(id).decrypt_share(&ciphertext)
+                .ok_or_else(|| anyhow!("invalid ciphertext"))?;
+            Ok((id, dec_share))
+        }).collect::<Result<BTreeMap<usize, DecryptionShare>>>()?;
+        let decrypted = pk_set.decrypt(&dec_shares, &ciphertext)
+            .map_err(|e| anyhow!("decryption failed {e}"))?;
+
+        let vectors = MpcTestVectors {
+            seed,
+            threshold,
+            n_actors,
+            master_public_key: hex::encode(pk_set.public_key().to_bytes()),
+            public_key_shares: (0..n_actors)
+                .map(|id| hex::encode(pk_set.public_key_share(id).to_bytes()))
+                .collect(),
+            secret_key_shares: (0..n_actors)
+                .map(|id| hex::encode(sk_set.secret_key_share(id).to_bytes()))
+                .collect(),
+            message: hex::encode(&message),
+            ciphertext: hex::encode(ciphertext.to_bytes()),
+            decrypted: hex::encode(decrypted),
+        };
+        Ok(serde_json::to_string_pretty(&vectors)?)
+    }
+}
+
+// Which coordinate of a position to reveal in a partial reveal
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Axis {
+    X,
+    Y,
+}
+
+impl Axis {
+    // AAD for a revealed coordinate, so an x reveal can't be passed off as a y reveal
+    pub fn aad(&self) -> &'static [u8] {
+        match self {
+            Axis::X => b"reveal-x",
+            Axis::Y => b"reveal-y",
+        }
     }
 }
 
 
-// assumes 3 nodes for this example.
-pub fn mpc_decrypt(
-    society: &mut MpcNetwork,
+// Decrypts with every actor available
+pub async fn mpc_decrypt(
+    society: &MpcNetwork,
     ciphertext: blsttc::Ciphertext,
 ) -> Result<Vec<u8>> {
     // In practice this will be implemented in some network which broadcasts ciphertexts to nodes
-    // in rounds before beginning the decryption
-    let alice = society.get_actor(0).id;
-    let bob = society.get_actor(1).id;
-    let clara = society.get_actor(2).id;
+    // in rounds before beginning the decryption. Here every actor gets the ciphertext over its
+    // channel, and the first threshold + 1 valid shares to come back decrypt it.
+    let everyone: Vec<usize> = (0..society.actors.len()).collect();
+    Ok(society.run_decryption_meeting(ciphertext, &everyone, false).await?.plaintext)
+}
 
-    society.send_message(alice, ciphertext.clone());
-    society.send_message(bob, ciphertext.clone());
-    society.send_message(clara, ciphertext.clone());
 
-    let mut meeting = society.start_decryption_meeting();
+enum ActorMessage {
+    // Asks for a decryption share, answered with (actor id, share) on `reply`
+    Decrypt {
+        ciphertext: Box<Ciphertext>,
+        reply: mpsc::UnboundedSender<(usize, DecryptionShare)>,
+    },
+    // Simulated network latency before each answer
+    SetLatency(Duration),
+    // This actor's share of a zero_sharing, added to its secret-key share
+    Reshare(SecretKeyShare),
+}
+
+// A random sharing of zero: adding its shares to the actors' re-randomizes them, and leaves the
+// master secret (the sharing's value at 0) as it was
+fn zero_sharing<R: rand::Rng>(threshold: usize, rng: &mut R) -> SecretKeySet {
+    let poly = Poly::random(threshold, rng);
+    let constant = poly.evaluate(0_u64);
+    SecretKeySet::from(poly - constant)
+}
 
-    meeting.accept_decryption_share(society.get_actor(alice));
-    meeting.accept_decryption_share(society.get_actor(bob));
+fn add_key_shares(a: &SecretKeyShare, b: &SecretKeyShare) -> SecretKeyShare {
+    let to_fr = |share: &SecretKeyShare| -> Fr {
+        Option::from(Fr::from_bytes_be(&share.to_bytes())).expect("secret-key shares are valid scalars")
+    };
+    let mut sum = to_fr(a) + to_fr(b);
+    SecretKeyShare::from_mut(&mut sum)
+}
 
-    let res = meeting.decrypt_message()?;
-    Ok(res)
+// The public keys matching shares that had zero_sharing's shares added to them
+fn reshared_public_keys(pk_set: &PublicKeySet, zero_sharing: &SecretKeySet) -> Result<PublicKeySet> {
+    // PublicKeySet doesn't expose its commitment, but serializes as exactly that
+    let commitment = |pk_set: &PublicKeySet| Commitment::from_bytes(pk_set.to_bytes());
+    Ok(PublicKeySet::from(commitment(pk_set)? + commitment(&zero_sharing.public_keys())?))
 }
 
+// A member of the secret society, running as its own task. Only the task holds the secret-key share.
+struct Actor {
+    id: usize,
+    sk_share: SecretKeyShare,
+    latency: Duration,
+}
 
-#[derive(Debug, Clone)]
-pub struct Actor {
+// What the network keeps of each actor: who it is, and its inbox
+struct ActorHandle {
     id: usize,
     pk_share: PublicKeyShare,
-    sk_share: SecretKeyShare,
-    msg_inbox: Option<Ciphertext>,
+    inbox: mpsc::UnboundedSender<ActorMessage>,
 }
 
 impl Actor {
-    fn new(id: usize, pk_share: PublicKeyShare, sk_share: SecretKeyShare) -> Self {
-        Actor {
-            id: id,
-            pk_share: pk_share,
-            sk_share: sk_share,
-            msg_inbox: None
+    fn spawn(id: usize, pk_share: PublicKeyShare, sk_share: SecretKeyShare) -> ActorHandle {
+        let (inbox, messages) = mpsc::unbounded_channel();
+        let actor = Actor {
+            id,
+            sk_share,
+            latency: Duration::ZERO,
+        };
+        tokio::spawn(actor.run(messages));
+        ActorHandle { id, pk_share, inbox }
+    }
+
+    // Answers ciphertexts in the order they arrive, until the network is dropped
+    async fn run(mut self, mut messages: mpsc::UnboundedReceiver<ActorMessage>) {
+        while let Some(message) = messages.recv().await {
+            match message {
+                ActorMessage::SetLatency(latency) => self.latency = latency,
+                ActorMessage::Reshare(zero_share) => self.sk_share = add_key_shares(&self.sk_share, &zero_share),
+                ActorMessage::Decrypt { ciphertext, reply } => {
+                    if !self.latency.is_zero() {
+                        tokio::time::sleep(self.latency).await;
+                    }
+                    // an invalid ciphertext gets no share
+                    if let Some(dec_share) = self.sk_share.decrypt_share(&ciphertext) {
+                        let _ = reply.send((self.id, dec_share));
+                    }
+                }
+            }
         }
     }
 }
@@ -139,41 +500,354 @@ impl Actor {
 // A meeting where Actors collaborate and decrypt a shared ciphertext
 pub struct DecryptionMeeting {
     pk_set: PublicKeySet,
-    ciphertext: Option<Ciphertext>,
+    ciphertext: Ciphertext,
     dec_shares: BTreeMap<usize, DecryptionShare>
 }
 
 impl DecryptionMeeting {
 
-    fn accept_decryption_share(&mut self, actor: &mut Actor) {
-        // Check that the actor's ciphertext is the same ciphertext decrypted at the meeting.
-        // The first actor to arrive at the decryption meeting sets the meeting's ciphertext.
-        let ciphertext = actor.msg_inbox.take()
-            .expect("no ciphertexts in the msg_inbox");
-
-        if let Some(ref meeting_ciphertext) = self.ciphertext {
-            if ciphertext != *meeting_ciphertext {
-                return;
-            }
-        } else {
-            self.ciphertext = Some(ciphertext.clone());
+    fn accept_decryption_share(&mut self, id: usize, dec_share: DecryptionShare) -> Result<(), InvalidDecryptionShare> {
+        // Each share is checked against the actor's public-key share, so a faulty actor can't
+        // spoil the decryption.
+        if !self.pk_set.public_key_share(id).verify_decryption_share(&dec_share, &self.ciphertext) {
+            return Err(InvalidDecryptionShare { actor: id });
         }
+        self.dec_shares.insert(id, dec_share);
+        Ok(())
+    }
 
-        let dec_share = actor.sk_share.decrypt_share(&ciphertext)
-            .expect("decrypt_share() err");
-
-        if actor.pk_share.verify_decryption_share(&dec_share, &ciphertext) {
-            self.dec_shares.insert(actor.id, dec_share);
-        } else {
-            println!("invalid decryption share for actor {}", actor.id);
-            return;
-        }
+    // More than `threshold` valid shares, enough to decrypt
+    fn has_quorum(&self) -> bool {
+        self.dec_shares.len() > self.pk_set.threshold()
     }
 
     // Tries to decrypt the shared ciphertext using the decryption shares.
     fn decrypt_message(&self) -> Result<Vec<u8>> {
-        let ciphertext = self.ciphertext.clone().expect("unwrap None ciphertext err");
-        self.pk_set.decrypt(&self.dec_shares, &ciphertext)
+        self.pk_set.decrypt(&self.dec_shares, &self.ciphertext)
             .map_err(|e| anyhow!("decryption failed {e}"))
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tfhe::{ConfigBuilder, generate_keys};
+    use crate::fhe_distance::{Bounds, FOW_VIEW_RANGE, Precision, fhe_within_bounds, set_server_key};
+
+    #[tokio::test]
+    async fn reveal_axis_only_sends_requested_coordinate() {
+        let config = ConfigBuilder::default().build();
+        let (fhe_client_key, fhe_server_key) = generate_keys(config);
+        let mpc_network = MpcNetwork::new(3, 1, fhe_server_key);
+        let (alice_skey, alice_pubkey) = ecdh::generate_ecdh_keys();
+
+        let x = FheUint32::encrypt(4_u32, &fhe_client_key);
+        let y = FheUint32::encrypt(7_u32, &fhe_client_key);
+
+        let x_for_alice = mpc_network.reveal_axis(Axis::X, &x, &y, &fhe_client_key, &alice_pubkey).unwrap();
+
+        let shared_secret = ecdh::compute_shared_secret(&alice_skey, &mpc_network.ecdh_pub_key);
+        let shared_secret_key = ecdh::derive_key(&shared_secret, MPC_REVEAL_KEY_INFO);
+        assert!(ecdh::decrypt(&x_for_alice, &shared_secret_key, Axis::Y.aad()).is_err());
+        let revealed = ecdh::decrypt(&x_for_alice, &shared_secret_key, Axis::X.aad()).unwrap();
+        let revealed = std::str::from_utf8(&revealed).unwrap();
+
+        // Alice receives exactly the x coordinate, and nothing about y
+        assert_eq!(revealed, "4");
+        assert!(!revealed.contains('7'));
+    }
+
+    #[tokio::test]
+    async fn reveals_to_the_same_player_reuse_the_ecdh_secret() {
+        let config = ConfigBuilder::default().build();
+        let (fhe_client_key, fhe_server_key) = generate_keys(config);
+        let mpc_network = MpcNetwork::new(3, 1, fhe_server_key);
+        let (alice_skey, alice_pubkey) = ecdh::generate_ecdh_keys();
+        let (_, bob_pubkey) = ecdh::generate_ecdh_keys();
+
+        let x = FheUint32::encrypt(4_u32, &fhe_client_key);
+        let y = FheUint32::encrypt(7_u32, &fhe_client_key);
+        let x_for_alice = mpc_network.reveal_axis(Axis::X, &x, &y, &fhe_client_key, &alice_pubkey).unwrap();
+        let y_for_alice = mpc_network.reveal_axis(Axis::Y, &x, &y, &fhe_client_key, &alice_pubkey).unwrap();
+        assert_eq!(mpc_network.reveal_secret_derivations(), 1);
+        mpc_network.reveal_axis(Axis::X, &x, &y, &fhe_client_key, &bob_pubkey).unwrap();
+        assert_eq!(mpc_network.reveal_secret_derivations(), 2);
+
+        let shared_secret_key = ecdh::SharedSecret::derive(&alice_skey, &mpc_network.ecdh_pub_key, MPC_REVEAL_KEY_INFO);
+        assert_eq!(ecdh::decrypt(&x_for_alice, &shared_secret_key, Axis::X.aad()).unwrap(), b"4");
+        assert_eq!(ecdh::decrypt(&y_for_alice, &shared_secret_key, Axis::Y.aad()).unwrap(), b"7");
+    }
+
+    #[tokio::test]
+    async fn reveal_within_range_decrypts_each_axis_from_its_own_coordinate() {
+        let config = ConfigBuilder::default().build();
+        let (fhe_client_key, fhe_server_key) = generate_keys(config);
+        set_server_key(fhe_server_key.clone());
+        let mpc_network = MpcNetwork::new(3, 1, fhe_server_key);
+        let (alice_skey, alice_pubkey) = ecdh::generate_ecdh_keys();
+        let shared_secret = ecdh::compute_shared_secret(&alice_skey, &mpc_network.ecdh_pub_key);
+        let shared_secret_key = ecdh::derive_key(&shared_secret, MPC_REVEAL_KEY_INFO);
+        let view_range = ScaledThreshold::new(FOW_VIEW_RANGE, Precision::default()).unwrap();
+
+        let encrypt = |v: u32| FheUint32::encrypt(v, &fhe_client_key);
+        let (x1, y1) = (encrypt(2), encrypt(2));
+        // asymmetric, so decrypting x in place of y shows up
+        let (x2, y2) = (encrypt(4), encrypt(7));
+
+        let revealed = mpc_network.reveal_within_range(
+            (&x1, &y1), (&x2, &y2), &view_range, &[Axis::X, Axis::Y], &fhe_client_key, &alice_pubkey
+        ).unwrap().expect("bob is within range");
+        let revealed: Vec<(Axis, String)> = revealed.into_iter().map(|(axis, ciphertext)| {
+            let plaintext = ecdh::decrypt(&ciphertext, &shared_secret_key, axis.aad()).unwrap();
+            (axis, String::from_utf8(plaintext).unwrap())
+        }).collect();
+        assert_eq!(revealed, vec![(Axis::X, "4".to_string()), (Axis::Y, "7".to_string())]);
+
+        // out of range, nothing is revealed
+        let (far_x, far_y) = (encrypt(30), encrypt(30));
+        let revealed = mpc_network.reveal_within_range(
+            (&far_x, &far_y), (&x2, &y2), &view_range, &[Axis::X, Axis::Y], &fhe_client_key, &alice_pubkey
+        ).unwrap();
+        assert!(revealed.is_none());
+    }
+
+    #[tokio::test]
+    async fn bounds_proof_verifies_without_revealing_position() {
+        let config = ConfigBuilder::default().build();
+        let (fhe_client_key, fhe_server_key) = generate_keys(config);
+        set_server_key(fhe_server_key.clone());
+        let mpc_network = MpcNetwork::new(3, 1, fhe_server_key);
+        let mpc_pub_key = mpc_network.publish_public_key();
+
+        let playable_area = Bounds { min_x: 0, min_y: 0, max_x: 10, max_y: 10 };
+        let cases = [((5_u32, 10_u32), true), ((12, 3), false)];
+
+        for ((x, y), expected) in cases {
+            let x = FheUint32::encrypt(x, &fhe_client_key);
+            let y = FheUint32::encrypt(y, &fhe_client_key);
+            // AVS evaluates the bounds check homomorphically, only the boolean goes to the MPC network
+            let proof = bincode::serialize(&fhe_within_bounds(&x, &y, &playable_area)).unwrap();
+            let proof = mpc_pub_key.encrypt(proof);
+
+            assert_eq!(mpc_network.verify_in_bounds(proof, &fhe_client_key).await.unwrap(), expected);
+        }
+
+        // a coordinate passed off as a proof is rejected
+        let not_a_proof = bincode::serialize(&FheUint32::encrypt(5_u32, &fhe_client_key)).unwrap();
+        assert!(mpc_network.verify_in_bounds(mpc_pub_key.encrypt(not_a_proof), &fhe_client_key).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn decrypt_with_selects_participants_by_public_key_share() {
+        let config = ConfigBuilder::default().build();
+        let (_, fhe_server_key) = generate_keys(config);
+        let mpc_network = MpcNetwork::new(4, 2, fhe_server_key);
+        let mpc_pub_key = mpc_network.publish_public_key();
+        let shares = mpc_network.public_key_shares();
+        let msg = b"bob is in range".to_vec();
+
+        // any 3 of 4 actors form a quorum, in any order
+        let quorum = [shares[3], shares[0], shares[2]];
+        assert_eq!(mpc_network.decrypt_with(mpc_pub_key.encrypt(&msg), &quorum).await.unwrap(), msg);
+
+        // too few, including when a participant is named twice
+        let too_few = [shares[1], shares[3], shares[1]];
+        assert!(mpc_network.decrypt_with(mpc_pub_key.encrypt(&msg), &too_few).await.is_err());
+
+        // a share from another network is not a participant
+        let (_, other_server_key) = generate_keys(ConfigBuilder::default().build());
+        let stranger = MpcNetwork::new(4, 2, other_server_key).public_key_shares()[0];
+        let with_stranger = [shares[0], shares[1], stranger];
+        assert!(mpc_network.decrypt_with(mpc_pub_key.encrypt(&msg), &with_stranger).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reencrypt_for_only_returns_the_ecdh_ciphertext() {
+        let config = ConfigBuilder::default().build();
+        let (_, fhe_server_key) = generate_keys(config);
+        let mpc_network = MpcNetwork::new(3, 1, fhe_server_key);
+        let (alice_skey, alice_pubkey) = ecdh::generate_ecdh_keys();
+        let bob_position = b"bob's serialized position".to_vec();
+        let aad = b"bob-position";
+
+        let ciphertext = mpc_network.publish_public_key().encrypt(&bob_position);
+        let for_alice = mpc_network.reencrypt_for(ciphertext, &alice_pubkey, aad).await.unwrap();
+        assert!(!for_alice.windows(bob_position.len()).any(|window| window == bob_position));
+
+        let shared_secret = ecdh::compute_shared_secret(&alice_skey, &mpc_network.ecdh_pub_key);
+        let shared_secret_key = ecdh::derive_key(&shared_secret, MPC_REVEAL_KEY_INFO);
+        assert_eq!(ecdh::decrypt(&for_alice, &shared_secret_key, aad).unwrap(), bob_position);
+        assert!(ecdh::decrypt(&for_alice, &shared_secret_key, Axis::X.aad()).is_err());
+
+        // nobody else can read it
+        let (eve_skey, _) = ecdh::generate_ecdh_keys();
+        let shared_secret = ecdh::compute_shared_secret(&eve_skey, &mpc_network.ecdh_pub_key);
+        let eve_key = ecdh::derive_key(&shared_secret, MPC_REVEAL_KEY_INFO);
+        assert!(ecdh::decrypt(&for_alice, &eve_key, aad).is_err());
+    }
+
+    #[tokio::test]
+    async fn mpc_decrypt_collects_threshold_plus_one_shares() {
+        let config = ConfigBuilder::default().build();
+        let (_, fhe_server_key) = generate_keys(config);
+        let mpc_network = MpcNetwork::new(5, 2, fhe_server_key);
+        let mpc_pub_key = mpc_network.publish_public_key();
+        let msg = b"bob is in range".to_vec();
+
+        assert_eq!(mpc_network.mpc_decrypt(mpc_pub_key.encrypt(&msg)).await.unwrap(), msg);
+
+        // 2 actors can't decrypt, a 3rd share is enough
+        let ciphertext = mpc_pub_key.encrypt(&msg);
+        assert!(mpc_network.mpc_decrypt_with_available(ciphertext.clone(), &[4, 1]).await.is_err());
+        assert_eq!(mpc_network.mpc_decrypt_with_available(ciphertext, &[4, 1, 2]).await.unwrap(), msg);
+    }
+
+    #[tokio::test]
+    async fn mpc_decrypt_tolerates_unavailable_actors_above_the_threshold() {
+        let config = ConfigBuilder::default().build();
+        let (_, fhe_server_key) = generate_keys(config);
+        let mpc_network = MpcNetwork::new(5, 2, fhe_server_key);
+        let mpc_pub_key = mpc_network.publish_public_key();
+        let msg = b"bob is in range".to_vec();
+
+        // actors 0 and 3 are offline
+        let available = [1, 2, 4];
+        assert_eq!(mpc_network.mpc_decrypt_with_available(mpc_pub_key.encrypt(&msg), &available).await.unwrap(), msg);
+
+        // a third dropout leaves too few
+        let err = mpc_network.mpc_decrypt_with_available(mpc_pub_key.encrypt(&msg), &[1, 4]).await.unwrap_err();
+        assert!(err.to_string().contains("2 of 2 available actors"), "{err}");
+        assert!(mpc_network.mpc_decrypt_with_available(mpc_pub_key.encrypt(&msg), &[1, 2, 5]).await.is_err());
+
+        assert_eq!(mpc_network.mpc_decrypt(mpc_pub_key.encrypt(&msg)).await.unwrap(), msg);
+    }
+
+    #[tokio::test]
+    async fn slow_actors_past_the_share_timeout_are_left_out() {
+        let config = ConfigBuilder::default().build();
+        let (_, fhe_server_key) = generate_keys(config);
+        let mut mpc_network = MpcNetwork::new(5, 2, fhe_server_key);
+        mpc_network.share_timeout = Duration::from_secs(2);
+        let mpc_pub_key = mpc_network.publish_public_key();
+        let msg = b"bob is in range".to_vec();
+        let slow = Duration::from_secs(60);
+        let start = Instant::now();
+
+        // 2 slow actors, the other 3 still meet the threshold
+        for id in [0, 3] {
+            mpc_network.set_actor_latency(id, slow).unwrap();
+        }
+        assert_eq!(mpc_network.mpc_decrypt(mpc_pub_key.encrypt(&msg)).await.unwrap(), msg);
+
+        // a 3rd slow actor: the meeting gives up at the timeout instead of waiting for them
+        mpc_network.set_actor_latency(4, slow).unwrap();
+        let err = mpc_network.mpc_decrypt(mpc_pub_key.encrypt(&msg)).await.unwrap_err();
+        assert!(err.to_string().contains("2 of 5 available actors"), "{err}");
+        assert!(start.elapsed() < slow);
+
+        assert!(mpc_network.set_actor_latency(5, slow).is_err());
+    }
+
+    #[tokio::test]
+    async fn reshare_keeps_the_public_key_and_old_ciphertexts_decrypt() {
+        let config = ConfigBuilder::default().build();
+        let (_, fhe_server_key) = generate_keys(config);
+        let mut mpc_network = MpcNetwork::new(5, 2, fhe_server_key);
+        let mpc_pub_key = mpc_network.publish_public_key();
+        let old_shares = mpc_network.public_key_shares();
+        let msg = b"bob is in range".to_vec();
+        let ciphertext = mpc_pub_key.encrypt(&msg);
+
+        mpc_network.reshare().unwrap();
+        assert_eq!(mpc_network.publish_public_key(), mpc_pub_key);
+        assert!(mpc_network.public_key_shares().iter().zip(&old_shares).all(|(new, old)| new != old));
+        assert_eq!(mpc_network.mpc_decrypt(ciphertext).await.unwrap(), msg);
+
+        mpc_network.reshare().unwrap();
+        assert_eq!(mpc_network.mpc_decrypt(mpc_pub_key.encrypt(&msg)).await.unwrap(), msg);
+    }
+
+    #[test]
+    fn shares_from_before_a_reshare_no_longer_validate() {
+        let mut rng = rand::thread_rng();
+        let sk_set = SecretKeySet::random(2, &mut rng);
+        let pk_set = sk_set.public_keys();
+        let zero_sharing = zero_sharing(2, &mut rng);
+        let new_pk_set = reshared_public_keys(&pk_set, &zero_sharing).unwrap();
+        let old_share = |id: usize| sk_set.secret_key_share(id);
+        let new_share = |id: usize| add_key_shares(&old_share(id), &zero_sharing.secret_key_share(id));
+
+        let msg = b"bob is in range".to_vec();
+        let ciphertext = pk_set.public_key().encrypt(&msg);
+        assert_eq!(new_pk_set.public_key(), pk_set.public_key());
+
+        // new shares verify and decrypt, old ones don't verify against the new public-key shares
+        let dec_shares: BTreeMap<usize, DecryptionShare> = (0..3)
+            .map(|id| (id, new_share(id).decrypt_share(&ciphertext).unwrap()))
+            .collect();
+        for (id, dec_share) in &dec_shares {
+            assert!(new_pk_set.public_key_share(id).verify_decryption_share(dec_share, &ciphertext));
+            let old_dec_share = old_share(*id).decrypt_share(&ciphertext).unwrap();
+            assert!(!new_pk_set.public_key_share(id).verify_decryption_share(&old_dec_share, &ciphertext));
+        }
+        assert_eq!(new_pk_set.decrypt(&dec_shares, &ciphertext).unwrap(), msg);
+
+        // a leaked old share mixed with new ones doesn't decrypt
+        let mut mixed = dec_shares.clone();
+        mixed.insert(0, old_share(0).decrypt_share(&ciphertext).unwrap());
+        assert_ne!(new_pk_set.decrypt(&mixed, &ciphertext).ok(), Some(msg));
+    }
+
+    #[tokio::test]
+    async fn verify_all_reports_an_actor_with_a_corrupted_share() {
+        let config = ConfigBuilder::default().build();
+        let (_, fhe_server_key) = generate_keys(config);
+        let mpc_network = MpcNetwork::new(4, 1, fhe_server_key);
+        let mpc_pub_key = mpc_network.publish_public_key();
+        let msg = b"bob is in range".to_vec();
+
+        // actor 2's secret-key share no longer matches its public-key share
+        let garbage = SecretKeySet::random(1, &mut rand::thread_rng()).secret_key_share(0);
+        mpc_network.actors[2].inbox.send(ActorMessage::Reshare(garbage)).unwrap();
+
+        let report = mpc_network.mpc_decrypt_verify_all(mpc_pub_key.encrypt(&msg)).await.unwrap();
+        assert_eq!(report.plaintext, msg);
+        assert_eq!(report.cheating_actors, vec![2]);
+
+        // the cheater counts as absent: it and 1 honest actor aren't a quorum
+        let err = mpc_network.mpc_decrypt_with_available(mpc_pub_key.encrypt(&msg), &[0, 2]).await.unwrap_err();
+        assert!(err.to_string().contains("invalid shares from [2]"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn mpc_decrypt_fails_with_fewer_actors_than_the_quorum() {
+        let config = ConfigBuilder::default().build();
+        let (_, fhe_server_key) = generate_keys(config);
+        let mpc_network = MpcNetwork::new(2, 2, fhe_server_key);
+        let mpc_pub_key = mpc_network.publish_public_key();
+
+        let err = mpc_network.mpc_decrypt(mpc_pub_key.encrypt(b"bob is in range")).await.unwrap_err();
+        assert!(err.to_string().contains("needs at least 3"), "{err}");
+    }
+
+    #[cfg(feature = "test-vectors")]
+    #[test]
+    fn test_vectors_are_reproducible_from_seed() {
+        let vectors = MpcNetwork::test_vectors(42).unwrap();
+        assert_eq!(vectors, MpcNetwork::test_vectors(42).unwrap());
+        assert_ne!(vectors, MpcNetwork::test_vectors(43).unwrap());
+
+        let vectors: MpcTestVectors = serde_json::from_str(&vectors).unwrap();
+        assert_eq!(vectors.decrypted, vectors.message);
+        assert_eq!(vectors.secret_key_shares.len(), vectors.n_actors);
+    }
+
+    // Checked-in output for seed 42. If this fails after a dependency upgrade, blsttc's keys or
+    // ciphertexts changed: make sure that's intended before regenerating the file.
+    #[cfg(feature = "test-vectors")]
+    #[test]
+    fn test_vectors_match_the_golden_file() {
+        let golden = include_str!("../test-vectors/mpc_seed_42.json");
+        assert_eq!(MpcNetwork::test_vectors(42).unwrap(), golden.trim_end());
+    }
+}
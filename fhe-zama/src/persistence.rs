@@ -0,0 +1,87 @@
+use std::fs;
+use std::path::Path;
+use anyhow::{Context, Result};
+use serde::{Serialize, de::DeserializeOwned};
+use tfhe::{generate_keys, ClientKey, Config, FheUint32, ServerKey};
+
+
+// bincode on disk, the same encoding ciphertexts are sent to the MPC network in.
+// Lets a run reuse keys and encrypted positions from an earlier one instead of regenerating them.
+
+pub fn save_ciphertext(path: impl AsRef<Path>, ciphertext: &FheUint32) -> Result<()> {
+    save(path.as_ref(), ciphertext)
+}
+
+pub fn load_ciphertext(path: impl AsRef<Path>) -> Result<FheUint32> {
+    load(path.as_ref())
+}
+
+// The client key decrypts everything encrypted under it: keep it out of anything shared
+pub fn save_client_key(path: impl AsRef<Path>, client_key: &ClientKey) -> Result<()> {
+    save(path.as_ref(), client_key)
+}
+
+pub fn load_client_key(path: impl AsRef<Path>) -> Result<ClientKey> {
+    load(path.as_ref())
+}
+
+pub fn save_server_key(path: impl AsRef<Path>, server_key: &ServerKey) -> Result<()> {
+    save(path.as_ref(), server_key)
+}
+
+pub fn load_server_key(path: impl AsRef<Path>) -> Result<ServerKey> {
+    load(path.as_ref())
+}
+
+// Loads client_key.bin and server_key.bin from `dir`, or generates a new pair and saves it there
+pub fn load_or_generate_keys(dir: impl AsRef<Path>, config: Config) -> Result<(ClientKey, ServerKey)> {
+    let dir = dir.as_ref();
+    let (client_key_path, server_key_path) = (dir.join("client_key.bin"), dir.join("server_key.bin"));
+    if client_key_path.exists() && server_key_path.exists() {
+        return Ok((load_client_key(&client_key_path)?, load_server_key(&server_key_path)?));
+    }
+
+    let (client_key, server_key) = generate_keys(config);
+    fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+    save_client_key(&client_key_path, &client_key)?;
+    save_server_key(&server_key_path, &server_key)?;
+    Ok((client_key, server_key))
+}
+
+fn save<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let bytes = bincode::serialize(value)?;
+    fs::write(path, bytes).with_context(|| format!("writing {}", path.display()))
+}
+
+fn load<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let bytes = fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    bincode::deserialize(&bytes).with_context(|| format!("decoding {}", path.display()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tfhe::ConfigBuilder;
+    use tfhe::prelude::*;
+
+    #[test]
+    fn ciphertexts_and_keys_survive_a_round_trip_to_disk() {
+        let dir = std::env::temp_dir().join(format!("fhe-zama-persistence-{}", std::process::id()));
+        let config = ConfigBuilder::default().build();
+        let (client_key, _server_key) = load_or_generate_keys(&dir, config).unwrap();
+
+        let path = dir.join("x.bin");
+        save_ciphertext(&path, &FheUint32::encrypt(42_u32, &client_key)).unwrap();
+
+        // a later run: the same keys come back from disk, and decrypt the saved ciphertext
+        let (reloaded_key, _server_key) = load_or_generate_keys(&dir, config).unwrap();
+        let x: u32 = load_ciphertext(&path).unwrap().decrypt(&reloaded_key);
+        assert_eq!(x, 42);
+
+        assert!(load_ciphertext(dir.join("missing.bin")).is_err());
+        fs::write(&path, b"not a ciphertext").unwrap();
+        assert!(load_ciphertext(&path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}